@@ -0,0 +1,42 @@
+#!/bin/sh
+# Builds and tests the crate under every combination of its features.
+#
+# The features that are only code in this crate are tried in every
+# combination, without the defaults, so a feature can't come to rely on
+# another being on. Those that pull in a dependency (a GPU, Python, a
+# dynamic loader...) need it installed to build, so each is tried on its
+# own on top of the defaults; list the ones to skip in SKIP, e.g.
+#
+#     SKIP="gpu python" ci/features.sh
+set -e
+
+local_features="json binary decimal linalg complex simd arena fuzzy capi"
+dep_features="gpu native python wasm arrow"
+
+run() {
+    echo "== features: ${1:-(none)}"
+    cargo build --no-default-features --features "$1"
+    cargo test --no-default-features --features "$1"
+}
+
+count=$(echo $local_features | wc -w)
+mask=0
+while [ $mask -lt $((1 << count)) ]; do
+    selected=""
+    i=0
+    for f in $local_features; do
+        if [ $(((mask >> i) & 1)) -eq 1 ]; then
+            selected="$selected $f"
+        fi
+        i=$((i + 1))
+    done
+    run "${selected# }"
+    mask=$((mask + 1))
+done
+
+for f in $dep_features; do
+    case " $SKIP " in
+        *" $f "*) echo "== features: default $f (skipped)" ;;
+        *) run "default $f" ;;
+    esac
+done
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::Add;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, SlotScope, Slot, VariableExp, Iterable, Aggregate, Average, NumVal, FloatVal, OptionVal, MapVal};
+use array::{StagedEach, stage_each};
+use ops::E;
+use reify::{Expr, node, binder};
+
+// Aggregations over an array or range: sum, count, avg, min and max of its
+// elements, over all of them or per group, with the group of an element
+// given by an expression of it. Avg, min and max of no elements are none.
+// Avg is taken in floating point, and min and max keep the first of equal
+// elements.
+//
+// Staged over a map or filter, an aggregation runs in the same loop as it,
+// and as the nodes under that, so a pipeline like
+//
+//     items.filter(|x| ...).map(|x| ...).sum()
+//
+// goes through `items` once and builds no array in between. Interpreted,
+// the arrays are built as usual.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sum;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Count;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Avg;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Min;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Max;
+
+impl<T: 'static+Clone+Default+Add<Output=T>> Aggregate<T> for Sum {
+    type Acc = T;
+    type Output = T;
+
+    const NAME: &'static str = "sum";
+
+    fn start() -> T {
+        T::default()
+    }
+
+    fn add(acc: &mut T, x: T) {
+        *acc = acc.clone() + x;
+    }
+
+    fn finish(acc: T) -> T {
+        acc
+    }
+}
+
+impl<T> Aggregate<T> for Count {
+    type Acc = i64;
+    type Output = NumVal;
+
+    const NAME: &'static str = "count";
+
+    fn start() -> i64 {
+        0
+    }
+
+    fn add(acc: &mut i64, _: T) {
+        *acc += 1;
+    }
+
+    fn finish(acc: i64) -> NumVal {
+        NumVal { v: acc }
+    }
+}
+
+impl Average for NumVal {
+    fn to_f64(&self) -> f64 {
+        self.v as f64
+    }
+}
+
+impl Average for FloatVal {
+    fn to_f64(&self) -> f64 {
+        self.v
+    }
+}
+
+impl<T: Average> Aggregate<T> for Avg {
+    type Acc = (f64, i64);
+    type Output = OptionVal<FloatVal>;
+
+    const NAME: &'static str = "avg";
+
+    fn start() -> (f64, i64) {
+        (0.0, 0)
+    }
+
+    fn add(acc: &mut (f64, i64), x: T) {
+        acc.0 += x.to_f64();
+        acc.1 += 1;
+    }
+
+    fn finish(acc: (f64, i64)) -> OptionVal<FloatVal> {
+        OptionVal {
+            v: if acc.1 == 0 { None } else { Some(FloatVal { v: acc.0 / acc.1 as f64 }) },
+        }
+    }
+}
+
+impl<T: 'static+Clone+PartialOrd> Aggregate<T> for Min {
+    type Acc = Option<T>;
+    type Output = OptionVal<T>;
+
+    const NAME: &'static str = "min";
+
+    fn start() -> Option<T> {
+        None
+    }
+
+    fn add(acc: &mut Option<T>, x: T) {
+        if acc.as_ref().is_none_or(|min| x < *min) {
+            *acc = Some(x);
+        }
+    }
+
+    fn finish(acc: Option<T>) -> OptionVal<T> {
+        OptionVal { v: acc }
+    }
+}
+
+impl<T: 'static+Clone+PartialOrd> Aggregate<T> for Max {
+    type Acc = Option<T>;
+    type Output = OptionVal<T>;
+
+    const NAME: &'static str = "max";
+
+    fn start() -> Option<T> {
+        None
+    }
+
+    fn add(acc: &mut Option<T>, x: T) {
+        if acc.as_ref().is_none_or(|max| x > *max) {
+            *acc = Some(x);
+        }
+    }
+
+    fn finish(acc: Option<T>) -> OptionVal<T> {
+        OptionVal { v: acc }
+    }
+}
+
+#[derive(Clone)]
+pub struct AggExp<C: 'static+Clone+Iterable, G: Aggregate<C::Elem>> {
+    items: Box<Exp<Output=C>>,
+    _agg: PhantomData<G>,
+}
+
+pub struct AggStagedExp<T: 'static, G: Aggregate<T>> {
+    source: Box<StagedEach<Elem=T>>,
+    _agg: PhantomData<G>,
+}
+
+pub type SumExp<C> = AggExp<C, Sum>;
+pub type CountExp<C> = AggExp<C, Count>;
+pub type AvgExp<C> = AggExp<C, Avg>;
+pub type MinExp<C> = AggExp<C, Min>;
+pub type MaxExp<C> = AggExp<C, Max>;
+
+impl<C: 'static+Clone+Iterable, G: Aggregate<C::Elem>> Exp for AggExp<C, G> {
+    type Output = G::Output;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged: AggStagedExp<C::Elem, G> = AggStagedExp {
+            source: stage_each(&*self.items),
+            _agg: PhantomData,
+        };
+        box staged
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut acc = G::start();
+        self.items.interpret().each(&mut |x| G::add(&mut acc, x));
+        G::finish(acc)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node(G::NAME, vec![self.items.reify()])
+    }
+}
+
+impl<T: 'static, G: Aggregate<T>> StagedExp for AggStagedExp<T, G> {
+    type Output = G::Output;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut acc = G::start();
+        self.source.each(ctx, &mut |x| G::add(&mut acc, x));
+        G::finish(acc)
+    }
+}
+
+#[derive(Clone)]
+pub struct GroupByExp<C: 'static+Clone+Iterable, K: 'static+Clone+Eq+Hash, G: Aggregate<C::Elem>> {
+    items: Box<Exp<Output=C>>,
+    key: Rc<Fn(VariableExp<C::Elem>) -> Box<Exp<Output=K>>>,
+    _agg: PhantomData<G>,
+}
+
+pub struct GroupByStagedExp<T: 'static+Clone, K: 'static+Clone+Eq+Hash, G: Aggregate<T>> {
+    source: Box<StagedEach<Elem=T>>,
+    elem_slot: Slot<T>,
+    staged_key: Box<StagedExp<Output=K>>,
+    _agg: PhantomData<G>,
+}
+
+fn groups<T, K: 'static+Clone+Eq+Hash, G: Aggregate<T>>(accs: HashMap<K, G::Acc>) -> MapVal<K, G::Output> {
+    MapVal {
+        v: Rc::new(accs.into_iter().map(|(k, acc)| (k, G::finish(acc))).collect()),
+    }
+}
+
+impl<C: 'static+Clone+Iterable, K: 'static+Clone+Eq+Hash, G: Aggregate<C::Elem>> Exp for GroupByExp<C, K, G> {
+    type Output = MapVal<K, G::Output>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let source = stage_each(&*self.items);
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        let staged: GroupByStagedExp<C::Elem, K, G> = GroupByStagedExp {
+            source,
+            elem_slot: scope.bind(&elem_var),
+            staged_key: (self.key)(elem_var).stage(),
+            _agg: PhantomData,
+        };
+        box staged
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut accs = HashMap::new();
+        self.items.interpret().each(&mut |x| {
+            let k = (self.key)(VariableExp::fresh_with_val(x.clone())).interpret();
+            G::add(accs.entry(k).or_insert_with(G::start), x);
+        });
+        groups::<C::Elem, K, G>(accs)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let elem_var = VariableExp::fresh();
+        let key = (self.key)(elem_var.clone()).reify();
+        binder(&format!("group_{}", G::NAME), vec![elem_var.id], vec![self.items.reify(), key])
+    }
+}
+
+impl<T: 'static+Clone, K: 'static+Clone+Eq+Hash, G: Aggregate<T>> StagedExp for GroupByStagedExp<T, K, G> {
+    type Output = MapVal<K, G::Output>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut accs = HashMap::new();
+        let mut elem = ctx.bind(&self.elem_slot);
+        self.source.each(ctx, &mut |x| {
+            elem.set(x.clone());
+            let k = self.staged_key.run(ctx);
+            G::add(accs.entry(k).or_insert_with(G::start), x);
+        });
+        groups::<T, K, G>(accs)
+    }
+}
+
+pub fn agg_exp<C: 'static+Clone+Iterable, G: Aggregate<C::Elem>>(items: Box<Exp<Output=C>>) -> AggExp<C, G> {
+    AggExp {
+        items,
+        _agg: PhantomData,
+    }
+}
+
+pub fn group_by_exp<C: 'static+Clone+Iterable, K: 'static+Clone+Eq+Hash, G: Aggregate<C::Elem>>(items: Box<Exp<Output=C>>,
+                                                                                               key: Box<Fn(VariableExp<C::Elem>) -> Box<Exp<Output=K>>>) -> GroupByExp<C, K, G> {
+    GroupByExp {
+        items,
+        key: Rc::from(key),
+        _agg: PhantomData,
+    }
+}
+
+impl<C: 'static+Clone+Iterable> E<C> {
+    pub fn aggregate<G: Aggregate<C::Elem>>(self, _agg: G) -> E<G::Output> {
+        E::new(agg_exp::<C, G>(self.0))
+    }
+
+    pub fn sum(self) -> E<C::Elem> where Sum: Aggregate<C::Elem, Output=C::Elem> {
+        self.aggregate(Sum)
+    }
+
+    pub fn count(self) -> E<NumVal> {
+        self.aggregate(Count)
+    }
+
+    pub fn avg(self) -> E<OptionVal<FloatVal>> where C::Elem: Average {
+        self.aggregate(Avg)
+    }
+
+    pub fn min(self) -> E<OptionVal<C::Elem>> where C::Elem: PartialOrd {
+        self.aggregate(Min)
+    }
+
+    pub fn max(self) -> E<OptionVal<C::Elem>> where C::Elem: PartialOrd {
+        self.aggregate(Max)
+    }
+
+    // `agg` of the elements of each group, by the key `key` gives them.
+    pub fn group_by<K: 'static+Clone+Eq+Hash, G: Aggregate<C::Elem>, F>(self, key: F, _agg: G) -> E<MapVal<K, G::Output>>
+        where F: Fn(VariableExp<C::Elem>) -> E<K> + 'static {
+        E::new(group_by_exp::<C, K, G>(self.0, box move |x| key(x).0))
+    }
+}
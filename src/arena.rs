@@ -0,0 +1,187 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Puts the memory a program takes into an allocator the embedder chooses.
+// With the arena feature ArenaAlloc is the global allocator, and while
+// `in_arena(arena, f)` runs, everything allocated on that thread comes from
+// `arena`: the nodes `f` builds, their staged forms, and the values they
+// compute. Memory goes back to the arena it came from when it's freed, on
+// whatever thread and whenever that is, so an arena must outlive everything
+// allocated from it; `in_arena` takes a 'static one for that reason.
+//
+// Every allocation carries a header naming its arena, which costs a few
+// bytes each whether or not an arena is in use.
+
+pub unsafe trait Arena: Sync {
+    // Null if the arena can't satisfy `layout`.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8;
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout);
+}
+
+struct SystemArena;
+
+unsafe impl Arena for SystemArena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+static SYSTEM: SystemArena = SystemArena;
+
+thread_local! {
+    static CURRENT: Cell<Option<&'static Arena>> = const { Cell::new(None) };
+}
+
+// Runs `f` with allocations on this thread coming from `arena`, then puts
+// back whichever arena was in use before.
+pub fn in_arena<R, F: FnOnce() -> R>(arena: &'static Arena, f: F) -> R {
+    struct Restore(Option<&'static Arena>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let _ = CURRENT.try_with(|c| c.set(self.0));
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|c| c.replace(Some(arena))));
+    f()
+}
+
+fn current() -> &'static Arena {
+    // No arena while the thread's locals are being torn down.
+    CURRENT.try_with(|c| c.get()).ok().and_then(|a| a).unwrap_or(&SYSTEM)
+}
+
+type Header = &'static Arena;
+
+// The allocation `layout` is carved from, and how far into it the caller's
+// part starts. The header sits just before that.
+fn with_header(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let offset = mem::size_of::<Header>().max(align);
+    let full = Layout::from_size_align(layout.size().checked_add(offset)?, align).ok()?;
+    Some((full, offset))
+}
+
+pub struct ArenaAlloc;
+
+unsafe impl GlobalAlloc for ArenaAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (full, offset) = match with_header(layout) {
+            Some(l) => l,
+            None => return ptr::null_mut(),
+        };
+        let arena = current();
+        let base = arena.alloc(full);
+        if base.is_null() {
+            return base;
+        }
+        let p = base.add(offset);
+        ptr::write((p as *mut Header).sub(1), arena);
+        p
+    }
+
+    unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+        let (full, offset) = with_header(layout).unwrap();
+        let arena = ptr::read((p as *mut Header).sub(1));
+        arena.dealloc(p.sub(offset), full);
+    }
+}
+
+// The system allocator, counting what goes through it, to account for a
+// program's memory apart from the rest of the process.
+#[derive(Default)]
+pub struct CountingArena {
+    live: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl CountingArena {
+    pub const fn new() -> CountingArena {
+        CountingArena {
+            live: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+        }
+    }
+
+    // Bytes allocated and not yet freed, headers included.
+    pub fn live(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    // Bytes ever allocated, headers included.
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+unsafe impl Arena for CountingArena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc(layout);
+        if !p.is_null() {
+            self.live.fetch_add(layout.size(), Ordering::Relaxed);
+            self.total.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+        self.live.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(p, layout)
+    }
+}
+
+// Hands out a fixed block of memory in order and never takes any back, so
+// allocating is a bump of a pointer. Once the block is used up allocations
+// fail, which aborts the process; size it for the whole program.
+pub struct BumpArena {
+    start: usize,
+    end: usize,
+    next: AtomicUsize,
+}
+
+impl BumpArena {
+    // The block comes from the system allocator and is never freed.
+    pub fn new(capacity: usize) -> BumpArena {
+        let layout = Layout::from_size_align(capacity.max(1), 16).expect("arena too big");
+        let start = unsafe { System.alloc(layout) } as usize;
+        if start == 0 {
+            panic!("couldn't allocate an arena of {} bytes", capacity);
+        }
+        BumpArena {
+            start,
+            end: start + capacity,
+            next: AtomicUsize::new(start),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.next.load(Ordering::Relaxed) - self.start
+    }
+}
+
+unsafe impl Arena for BumpArena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let p = (next + layout.align() - 1) & !(layout.align() - 1);
+            let end = match p.checked_add(layout.size()) {
+                Some(end) if end <= self.end => end,
+                _ => return ptr::null_mut(),
+            };
+            match self.next.compare_exchange_weak(next, end, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return p as *mut u8,
+                Err(n) => next = n,
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
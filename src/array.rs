@@ -0,0 +1,573 @@
+use std::any::Any;
+use std::mem;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, SlotScope, Slot, VariableExp, ArrayVal, BoolVal, UnitVal, Iterable, for_each_exp};
+use canon::pure;
+use ops::E;
+use reify::{Expr, node, binder};
+use sandbox;
+#[cfg(feature = "simd")]
+use simd;
+
+// Adds an element to an array being built, charging it to the sandbox.
+fn push<T>(v: &mut Vec<T>, x: T) {
+    sandbox::alloc(mem::size_of::<T>());
+    v.push(x);
+}
+
+#[derive(Clone)]
+pub struct ArrayExp<T: 'static+Clone> {
+    elems: Vec<Box<Exp<Output=T>>>,
+}
+
+pub struct ArrayStagedExp<T: 'static+Clone> {
+    staged_elems: Vec<Box<StagedExp<Output=T>>>,
+}
+
+impl<T: 'static+Clone> Exp for ArrayExp<T> {
+    type Output = ArrayVal<T>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ArrayStagedExp {
+            staged_elems: self.elems.iter().map(|e| e.stage()).collect(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        sandbox::alloc(self.elems.len() * mem::size_of::<T>());
+        Self::Output {
+            v: self.elems.iter().map(|e| e.interpret()).collect()
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("array", self.elems.iter().map(|e| e.reify()).collect())
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for ArrayStagedExp<T> {
+    type Output = ArrayVal<T>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        sandbox::alloc(self.staged_elems.len() * mem::size_of::<T>());
+        Self::Output {
+            v: self.staged_elems.iter().map(|e| e.run(ctx)).collect()
+        }
+    }
+}
+
+// The element function is staged once, against a single variable that is
+// reassigned for each element, rather than once per element. Any Iterable
+// (an array or a range) can be mapped, filtered or folded; the result of a
+// map or filter is always an array. Maps and sums of plain arithmetic over
+// arrays of numbers stage to `simd` kernels instead, except for a fold over
+// a map or filter, which is run in their loop; see `FusedFoldStagedExp`.
+#[derive(Clone)]
+pub struct MapExp<C: 'static+Clone+Iterable, U: 'static+Clone> {
+    items: Box<Exp<Output=C>>,
+    f: Rc<Fn(VariableExp<C::Elem>) -> Box<Exp<Output=U>>>,
+}
+
+pub struct MapStagedExp<C: 'static+Clone+Iterable, U: 'static+Clone> {
+    staged_items: Box<StagedExp<Output=C>>,
+    elem_slot: Slot<C::Elem>,
+    staged_f: Box<StagedExp<Output=U>>,
+}
+
+impl<C: 'static+Clone+Iterable, U: 'static+Clone> Exp for MapExp<C, U> {
+    type Output = ArrayVal<U>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        #[cfg(feature = "simd")]
+        if let Some(staged) = simd::stage_map(&*self.items, &*self.f) {
+            return staged;
+        }
+        let staged_items = self.items.stage();
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        box MapStagedExp {
+            staged_items,
+            elem_slot: scope.bind(&elem_var),
+            staged_f: (self.f)(elem_var).stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        // Not borrowed: the body may assign the variable holding the items.
+        let mut v = Vec::new();
+        self.items.interpret().each(&mut |x| {
+            push(&mut v, (self.f)(VariableExp::fresh_with_val(x)).interpret())
+        });
+        Self::Output {
+            v
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let elem_var = VariableExp::fresh();
+        let f = (self.f)(elem_var.clone()).reify();
+        binder("map", vec![elem_var.id], vec![self.items.reify(), f])
+    }
+
+    fn stage_each(&self) -> Option<Box<Any>> {
+        let source = stage_each(&*self.items);
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        let each: Box<StagedEach<Elem=U>> = box MapEach {
+            source,
+            elem_slot: scope.bind(&elem_var),
+            staged_f: (self.f)(elem_var).stage(),
+        };
+        Some(box each)
+    }
+}
+
+impl<C: 'static+Clone+Iterable, U: 'static+Clone> StagedExp for MapStagedExp<C, U> {
+    type Output = ArrayVal<U>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        // Not borrowed: the body may assign the variable holding the items.
+        let items = self.staged_items.run(ctx);
+        let mut v = Vec::new();
+        let mut elem = ctx.bind(&self.elem_slot);
+        items.each(&mut |x| {
+            elem.set(x);
+            push(&mut v, self.staged_f.run(ctx));
+        });
+        Self::Output {
+            v
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FilterExp<C: 'static+Clone+Iterable> {
+    items: Box<Exp<Output=C>>,
+    pred: Rc<Fn(VariableExp<C::Elem>) -> Box<Exp<Output=BoolVal>>>,
+}
+
+pub struct FilterStagedExp<C: 'static+Clone+Iterable> {
+    staged_items: Box<StagedExp<Output=C>>,
+    elem_slot: Slot<C::Elem>,
+    staged_pred: Box<StagedExp<Output=BoolVal>>,
+}
+
+impl<C: 'static+Clone+Iterable> Exp for FilterExp<C> {
+    type Output = ArrayVal<C::Elem>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_items = self.items.stage();
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        box FilterStagedExp {
+            staged_items,
+            elem_slot: scope.bind(&elem_var),
+            staged_pred: (self.pred)(elem_var).stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut v = Vec::new();
+        self.items.interpret().each(&mut |x| {
+            if (self.pred)(VariableExp::fresh_with_val(x.clone())).interpret().v {
+                push(&mut v, x);
+            }
+        });
+        Self::Output {
+            v
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let elem_var = VariableExp::fresh();
+        let pred = (self.pred)(elem_var.clone()).reify();
+        binder("filter", vec![elem_var.id], vec![self.items.reify(), pred])
+    }
+
+    fn stage_each(&self) -> Option<Box<Any>> {
+        let source = stage_each(&*self.items);
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        let each: Box<StagedEach<Elem=C::Elem>> = box FilterEach {
+            source,
+            elem_slot: scope.bind(&elem_var),
+            staged_pred: (self.pred)(elem_var).stage(),
+        };
+        Some(box each)
+    }
+}
+
+impl<C: 'static+Clone+Iterable> StagedExp for FilterStagedExp<C> {
+    type Output = ArrayVal<C::Elem>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let items = self.staged_items.run(ctx);
+        let mut v = Vec::new();
+        let mut elem = ctx.bind(&self.elem_slot);
+        items.each(&mut |x| {
+            elem.set(x.clone());
+            if self.staged_pred.run(ctx).v {
+                push(&mut v, x);
+            }
+        });
+        Self::Output {
+            v
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FoldExp<C: 'static+Clone+Iterable, A: 'static+Clone> {
+    items: Box<Exp<Output=C>>,
+    init: Box<Exp<Output=A>>,
+    f: Rc<Fn(VariableExp<A>, VariableExp<C::Elem>) -> Box<Exp<Output=A>>>,
+}
+
+pub struct FoldStagedExp<C: 'static+Clone+Iterable, A: 'static+Clone> {
+    staged_items: Box<StagedExp<Output=C>>,
+    staged_init: Box<StagedExp<Output=A>>,
+    acc_slot: Slot<A>,
+    elem_slot: Slot<C::Elem>,
+    staged_f: Box<StagedExp<Output=A>>,
+}
+
+impl<C: 'static+Clone+Iterable, A: 'static+Clone+Default> FoldExp<C, A> {
+    // Stages the fold in the loop of the map or filter it's over, if it's
+    // over one and can be. Its variables are kept bound from one element to
+    // the next, so they're given their slots first, and the nodes in the
+    // loop the ones after.
+    fn stage_fused(&self) -> Option<Box<StagedExp<Output=A>>> {
+        if !fusable(&self.reify()) {
+            return None;
+        }
+        let acc_var = VariableExp::fresh();
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        let acc_slot = scope.bind(&acc_var);
+        let elem_slot = scope.bind(&elem_var);
+        let source = self.items.stage_each()?.downcast::<Box<StagedEach<Elem=C::Elem>>>().ok()?;
+        Some(box FusedFoldStagedExp {
+            source: *source,
+            staged_init: self.init.stage(),
+            acc_slot,
+            elem_slot,
+            staged_f: (self.f)(acc_var, elem_var).stage(),
+        })
+    }
+}
+
+impl<C: 'static+Clone+Iterable, A: 'static+Clone+Default> Exp for FoldExp<C, A> {
+    type Output = A;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        // Before simd's, whose kernels would build the array a map gives.
+        if let Some(staged) = self.stage_fused() {
+            return staged;
+        }
+        #[cfg(feature = "simd")]
+        if let Some(staged) = simd::stage_fold(&*self.items, &*self.init, &*self.f) {
+            return staged;
+        }
+        let staged_items = self.items.stage();
+        let staged_init = self.init.stage();
+        let acc_var = VariableExp::fresh();
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        box FoldStagedExp {
+            staged_items,
+            staged_init,
+            acc_slot: scope.bind(&acc_var),
+            elem_slot: scope.bind(&elem_var),
+            staged_f: (self.f)(acc_var, elem_var).stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let items = self.items.interpret();
+        let mut acc = Some(self.init.interpret());
+        items.each(&mut |x| {
+            let prev = acc.take().unwrap();
+            acc = Some((self.f)(VariableExp::fresh_with_val(prev), VariableExp::fresh_with_val(x)).interpret());
+        });
+        acc.unwrap()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let acc_var = VariableExp::fresh();
+        let elem_var = VariableExp::fresh();
+        let f = (self.f)(acc_var.clone(), elem_var.clone()).reify();
+        binder("fold", vec![acc_var.id, elem_var.id], vec![self.items.reify(), self.init.reify(), f])
+    }
+}
+
+impl<C: 'static+Clone+Iterable, A: 'static+Clone> StagedExp for FoldStagedExp<C, A> {
+    type Output = A;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let items = self.staged_items.run(ctx);
+        let mut acc = ctx.bind(&self.acc_slot);
+        let mut elem = ctx.bind(&self.elem_slot);
+        acc.set(self.staged_init.run(ctx));
+        items.each(&mut |x| {
+            elem.set(x);
+            let next = self.staged_f.run(ctx);
+            acc.set(next);
+        });
+        // Read before the bindings put the outer values back.
+        acc.get()
+    }
+}
+
+// A fold over a map or filter, run in the same loop as it, and as the nodes
+// under that, so the arrays between them aren't built. The fold's
+// functions then run between the map's and filter's rather than after all
+// of them, which is only the same if none of them has effects, so a fold
+// is only fused if they don't; see `fusable`. The items are still
+// evaluated before the fold's initial value, as it's run when the first
+// element arrives, or after the loop if none does.
+pub struct FusedFoldStagedExp<T: 'static+Clone, A: 'static+Clone> {
+    source: Box<StagedEach<Elem=T>>,
+    staged_init: Box<StagedExp<Output=A>>,
+    acc_slot: Slot<A>,
+    elem_slot: Slot<T>,
+    staged_f: Box<StagedExp<Output=A>>,
+}
+
+// Whether the functions of the maps and filters `expr` is a chain of
+// have no effects. What the chain goes through can have them.
+fn pure_stages(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Node { ref kind, ref children, .. } if (kind == "map" || kind == "filter") && children.len() == 2 => {
+            pure(&children[1]) && pure_stages(&children[0])
+        }
+        _ => true,
+    }
+}
+
+// Whether the fold `expr` is over a map or filter and runs in its loop
+// when it's staged.
+pub fn fusable(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Node { ref kind, ref children, .. } if kind == "fold" && children.len() == 3 => {
+            matches!(children[0], Expr::Node { ref kind, .. } if kind == "map" || kind == "filter")
+                && pure_stages(&children[0]) && pure(&children[1]) && pure(&children[2])
+        }
+        _ => false,
+    }
+}
+
+impl<T: 'static+Clone, A: 'static+Clone> StagedExp for FusedFoldStagedExp<T, A> {
+    type Output = A;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut acc = ctx.bind(&self.acc_slot);
+        let mut elem = ctx.bind(&self.elem_slot);
+        let mut started = false;
+        self.source.each(ctx, &mut |x| {
+            if !started {
+                acc.set(self.staged_init.run(ctx));
+                started = true;
+            }
+            elem.set(x);
+            let next = self.staged_f.run(ctx);
+            acc.set(next);
+        });
+        if !started {
+            acc.set(self.staged_init.run(ctx));
+        }
+        acc.get()
+    }
+}
+
+// A staged loop over a collection's elements, which for a map or filter
+// runs the one under it rather than building an array in between.
+pub trait StagedEach {
+    type Elem;
+
+    fn each(&self, ctx: &EvalContext, f: &mut FnMut(Self::Elem));
+}
+
+struct ItemsEach<C: 'static+Clone+Iterable> {
+    staged_items: Box<StagedExp<Output=C>>,
+}
+
+impl<C: 'static+Clone+Iterable> StagedEach for ItemsEach<C> {
+    type Elem = C::Elem;
+
+    fn each(&self, ctx: &EvalContext, f: &mut FnMut(C::Elem)) {
+        // Not borrowed: what `f` runs may assign the variable holding the
+        // items.
+        self.staged_items.run(ctx).each(f);
+    }
+}
+
+struct MapEach<T: 'static+Clone, U: 'static+Clone> {
+    source: Box<StagedEach<Elem=T>>,
+    elem_slot: Slot<T>,
+    staged_f: Box<StagedExp<Output=U>>,
+}
+
+impl<T: 'static+Clone, U: 'static+Clone> StagedEach for MapEach<T, U> {
+    type Elem = U;
+
+    fn each(&self, ctx: &EvalContext, f: &mut FnMut(U)) {
+        let mut elem = ctx.bind(&self.elem_slot);
+        self.source.each(ctx, &mut |x| {
+            elem.set(x);
+            f(self.staged_f.run(ctx))
+        });
+    }
+}
+
+struct FilterEach<T: 'static+Clone> {
+    source: Box<StagedEach<Elem=T>>,
+    elem_slot: Slot<T>,
+    staged_pred: Box<StagedExp<Output=BoolVal>>,
+}
+
+impl<T: 'static+Clone> StagedEach for FilterEach<T> {
+    type Elem = T;
+
+    fn each(&self, ctx: &EvalContext, f: &mut FnMut(T)) {
+        let mut elem = ctx.bind(&self.elem_slot);
+        self.source.each(ctx, &mut |x| {
+            elem.set(x.clone());
+            if self.staged_pred.run(ctx).v {
+                f(x)
+            }
+        });
+    }
+}
+
+// The staged loop over the elements of `items`, fused with it if it's a
+// map or filter.
+pub fn stage_each<C: 'static+Clone+Iterable>(items: &Exp<Output=C>) -> Box<StagedEach<Elem=C::Elem>> {
+    if let Some(each) = items.stage_each() {
+        if let Ok(each) = each.downcast::<Box<StagedEach<Elem=C::Elem>>>() {
+            return *each;
+        }
+    }
+    box ItemsEach {
+        staged_items: items.stage(),
+    }
+}
+
+pub fn array_exp<T: 'static+Clone>(elems: Vec<Box<Exp<Output=T>>>) -> ArrayExp<T> {
+    ArrayExp {
+        elems
+    }
+}
+
+pub fn map_exp<C: 'static+Clone+Iterable, U: 'static+Clone>(items: Box<Exp<Output=C>>,
+                                                            f: Box<Fn(VariableExp<C::Elem>) -> Box<Exp<Output=U>>>) -> MapExp<C, U> {
+    MapExp {
+        items,
+        f: Rc::from(f)
+    }
+}
+
+pub fn filter_exp<C: 'static+Clone+Iterable>(items: Box<Exp<Output=C>>,
+                                             pred: Box<Fn(VariableExp<C::Elem>) -> Box<Exp<Output=BoolVal>>>) -> FilterExp<C> {
+    FilterExp {
+        items,
+        pred: Rc::from(pred)
+    }
+}
+
+pub fn fold_exp<C: 'static+Clone+Iterable, A: 'static+Clone+Default>(items: Box<Exp<Output=C>>,
+                                                                     init: Box<Exp<Output=A>>,
+                                                                     f: Box<Fn(VariableExp<A>, VariableExp<C::Elem>) -> Box<Exp<Output=A>>>) -> FoldExp<C, A> {
+    FoldExp {
+        items,
+        init,
+        f: Rc::from(f)
+    }
+}
+
+impl<C: 'static+Clone+Iterable> E<C> {
+    pub fn map<U: 'static+Clone, F>(self, f: F) -> E<ArrayVal<U>>
+        where F: Fn(VariableExp<C::Elem>) -> E<U> + 'static {
+        E::new(map_exp(self.0, box move |x| f(x).0))
+    }
+
+    pub fn filter<F>(self, pred: F) -> E<ArrayVal<C::Elem>>
+        where F: Fn(VariableExp<C::Elem>) -> E<BoolVal> + 'static {
+        E::new(filter_exp(self.0, box move |x| pred(x).0))
+    }
+
+    pub fn fold<A: 'static+Clone+Default, F>(self, init: E<A>, f: F) -> E<A>
+        where F: Fn(VariableExp<A>, VariableExp<C::Elem>) -> E<A> + 'static {
+        E::new(fold_exp(self.0, init.0, box move |acc, x| f(acc, x).0))
+    }
+
+    pub fn for_each<F>(self, body: F) -> E<UnitVal>
+        where F: Fn(VariableExp<C::Elem>) -> E<UnitVal> + 'static {
+        E::new(for_each_exp(self.0, box move |x| body(x).0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {NumVal, set_exp, seq_exp, unit_exp};
+
+    fn nums(v: &[i64]) -> E<ArrayVal<NumVal>> {
+        E::new(array_exp(v.iter().map(|&v| -> Box<Exp<Output=NumVal>> { box unit_exp(NumVal { v }) }).collect()))
+    }
+
+    // `then`, after setting `arr` to an empty array.
+    fn clearing<T: 'static+Clone>(arr: &VariableExp<ArrayVal<NumVal>>, then: E<T>) -> E<T> {
+        E::new(seq_exp(box set_exp(arr.clone(), nums(&[]).0), then.0))
+    }
+
+    fn staged<T: 'static>(exp: &E<T>) -> T {
+        exp.0.stage().run(&EvalContext::new())
+    }
+
+    #[test]
+    fn staged_map_body_assigns_its_items() {
+        let arr = VariableExp::fresh_with_val(ArrayVal { v: vec![NumVal { v: 1 }, NumVal { v: 2 }] });
+        let a = arr.clone();
+        let exp = E::from(&arr).map(move |x| clearing(&a, E::from(&x) * 2i64));
+        assert_eq!(staged(&exp).v, vec![NumVal { v: 2 }, NumVal { v: 4 }]);
+    }
+
+    #[test]
+    fn staged_filter_body_assigns_its_items() {
+        let arr = VariableExp::fresh_with_val(ArrayVal { v: vec![NumVal { v: 1 }, NumVal { v: 2 }] });
+        let a = arr.clone();
+        let exp = E::from(&arr).filter(move |x| clearing(&a, E::from(&x).lt(2i64)));
+        assert_eq!(staged(&exp).v, vec![NumVal { v: 1 }]);
+    }
+
+    #[test]
+    fn staged_each_body_assigns_its_items() {
+        let arr = VariableExp::fresh_with_val(ArrayVal { v: vec![NumVal { v: 1 }, NumVal { v: 2 }] });
+        let a = arr.clone();
+        let each = stage_each(&*E::from(&arr).map(move |x| clearing(&a, E::from(&x) * 2i64)).0);
+        let mut v = Vec::new();
+        each.each(&EvalContext::new(), &mut |x: NumVal| v.push(x.v));
+        assert_eq!(v, vec![2, 4]);
+    }
+
+    #[test]
+    fn staged_fold_body_assigns_its_items() {
+        let arr = VariableExp::fresh_with_val(ArrayVal { v: vec![NumVal { v: 1 }, NumVal { v: 2 }] });
+        let a = arr.clone();
+        let exp = E::from(&arr).fold(E::from(0i64), move |acc, x| clearing(&a, E::from(&acc) + &x));
+        assert_eq!(staged(&exp).v, 3);
+    }
+}
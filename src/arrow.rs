@@ -0,0 +1,95 @@
+use std::fmt;
+use std::sync::Arc;
+
+use arrow_array::{Array, ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray};
+
+use {NumVal, BoolVal, FloatVal, StrVal};
+use batch::StagedBatch;
+
+// Arrow arrays in and out of batch evaluation, so a program can serve as an
+// expression over the columns of a dataframe: each input variable is bound
+// to an Int64 array, the program is run over all their rows at once with
+// `batch`, and the results come back as an Arrow array of the program's
+// type. The engine has no null, so input columns with nulls are refused
+// rather than guessed at.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnError {
+    pub msg: String,
+}
+
+impl fmt::Display for ColumnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+// Values a program can give back as an Arrow array.
+pub trait ToArrow: Sized {
+    fn to_array(vals: Vec<Self>) -> ArrayRef;
+}
+
+impl ToArrow for NumVal {
+    fn to_array(vals: Vec<NumVal>) -> ArrayRef {
+        Arc::new(Int64Array::from_iter_values(vals.into_iter().map(|x| x.v)))
+    }
+}
+
+impl ToArrow for BoolVal {
+    fn to_array(vals: Vec<BoolVal>) -> ArrayRef {
+        Arc::new(vals.into_iter().map(|x| Some(x.v)).collect::<BooleanArray>())
+    }
+}
+
+impl ToArrow for FloatVal {
+    fn to_array(vals: Vec<FloatVal>) -> ArrayRef {
+        Arc::new(Float64Array::from_iter_values(vals.into_iter().map(|x| x.v)))
+    }
+}
+
+impl ToArrow for StrVal {
+    fn to_array(vals: Vec<StrVal>) -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(vals.into_iter().map(|x| x.v)))
+    }
+}
+
+fn column(array: &Array, i: usize) -> Result<Vec<i64>, ColumnError> {
+    let ints = match array.as_any().downcast_ref::<Int64Array>() {
+        Some(ints) => ints,
+        None => return Err(ColumnError { msg: format!("input {} is {}, not Int64", i, array.data_type()) }),
+    };
+    if ints.null_count() != 0 {
+        return Err(ColumnError { msg: format!("input {} has {} nulls", i, ints.null_count()) });
+    }
+    Ok(ints.values().to_vec())
+}
+
+// Runs `batch` over the rows of `inputs`, one array for each of its inputs
+// in order, all the same length.
+pub fn run_arrow<T: 'static+Clone+ToArrow>(batch: &StagedBatch<T>, inputs: &[&Array]) -> Result<ArrayRef, ColumnError> {
+    let mut columns = Vec::new();
+    for (i, &array) in inputs.iter().enumerate() {
+        columns.push(column(array, i)?);
+    }
+    if columns.len() != batch.inputs() {
+        return Err(ColumnError { msg: format!("{} inputs given for {}", columns.len(), batch.inputs()) });
+    }
+    if columns.iter().any(|c| c.len() != columns[0].len()) {
+        return Err(ColumnError { msg: "inputs of different lengths".to_string() });
+    }
+    Ok(T::to_array(batch.run_batch(&columns)))
+}
+
+// Runs `batch` over the rows of `record_batch`, taking its inputs from the
+// columns named `names`, in order.
+pub fn run_record_batch<T: 'static+Clone+ToArrow>(batch: &StagedBatch<T>, record_batch: &RecordBatch,
+                                                  names: &[&str]) -> Result<ArrayRef, ColumnError> {
+    let mut inputs = Vec::new();
+    for name in names {
+        match record_batch.column_by_name(name) {
+            Some(array) => inputs.push(&**array),
+            None => return Err(ColumnError { msg: format!("no column named {}", name) }),
+        }
+    }
+    run_arrow(batch, &inputs)
+}
@@ -0,0 +1,161 @@
+use std::cell::Cell;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use {Exp, StagedExp, EvalContext, BoolVal, UnitVal};
+use ops::E;
+use patterns::Binder;
+use reify::{Expr, Value, node, reify_with_vars, value_of};
+
+// Assertions inside programs, for finding where a generated program goes
+// wrong: `assert(cond, message)` does nothing if `cond` holds and fails the
+// run if it doesn't. The failure says which assertion failed, the
+// condition as its tree prints, and the values the variables it reads held
+// at the time, so
+//
+//     x.lt(n).assert("x in range")
+//
+// fails with `assertion failed: x in range: lt(v3, v7) with v3 = 12, v7 = 10`.
+//
+// A failure unwinds to the nearest `checked` (or `interpret_checked` or
+// `run_checked`), which gives it as an error, passing other panics through
+// as Sandbox::run does. Outside of one it panics with the same text. Like
+// a sandbox's budget running out, a failure can leave a staged program
+// part way through a run; stage it afresh rather than running it again.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssertionFailed {
+    pub message: String,
+    pub condition: String,
+    // Each variable the condition reads, as it prints, and its value;
+    // `<opaque>` for values that don't print.
+    pub values: Vec<(String, String)>,
+}
+
+impl fmt::Display for AssertionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "assertion failed: {}: {}", self.message, self.condition)?;
+        let values: Vec<String> = self.values.iter().map(|&(ref var, ref v)| format!("{} = {}", var, v)).collect();
+        if !values.is_empty() {
+            write!(f, " with {}", values.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    // How many `checked` calls are running on this thread.
+    static CHECKING: Cell<usize> = const { Cell::new(0) };
+}
+
+// Leaves a `checked` call even if it unwinds.
+struct Leave;
+
+impl Drop for Leave {
+    fn drop(&mut self) {
+        CHECKING.with(|c| c.set(c.get() - 1));
+    }
+}
+
+// Runs `f`, giving the failure if an assertion in it fails.
+pub fn checked<R, F: FnOnce() -> R>(f: F) -> Result<R, AssertionFailed> {
+    CHECKING.with(|c| c.set(c.get() + 1));
+    let _leave = Leave;
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(v) => Ok(v),
+        Err(payload) => match payload.downcast::<AssertionFailed>() {
+            Ok(failed) => Err(*failed),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+pub fn interpret_checked<T>(exp: &Exp<Output=T>) -> Result<T, AssertionFailed> {
+    checked(|| exp.interpret())
+}
+
+pub fn run_checked<T>(staged_exp: &StagedExp<Output=T>) -> Result<T, AssertionFailed> {
+    checked(|| staged_exp.run(&EvalContext::new()))
+}
+
+fn fail(message: &str, condition: &Expr, vars: &[Box<Binder>]) -> ! {
+    let failed = AssertionFailed {
+        message: message.to_string(),
+        condition: condition.to_string(),
+        values: vars.iter().map(|var| (Expr::Var(var.id()).to_string(), value_of(&*var.save()).to_string())).collect(),
+    };
+    if CHECKING.with(|c| c.get()) == 0 {
+        panic!("{}", failed);
+    }
+    panic::resume_unwind(Box::new(failed))
+}
+
+#[derive(Clone)]
+pub struct AssertExp {
+    cond: Box<Exp<Output=BoolVal>>,
+    message: String,
+    // The condition's tree and the variables it reads, for the failure.
+    condition: Expr,
+    vars: Vec<Box<Binder>>,
+}
+
+pub struct AssertStagedExp {
+    staged_cond: Box<StagedExp<Output=BoolVal>>,
+    message: String,
+    condition: Expr,
+    vars: Vec<Box<Binder>>,
+}
+
+impl Exp for AssertExp {
+    type Output = UnitVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box AssertStagedExp {
+            staged_cond: self.cond.stage(),
+            message: self.message.clone(),
+            condition: self.condition.clone(),
+            vars: self.vars.clone(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        if !self.cond.interpret().v {
+            fail(&self.message, &self.condition, &self.vars);
+        }
+        UnitVal
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("assert", vec![self.cond.reify(), Expr::Const(Value::Str(self.message.clone()))])
+    }
+}
+
+impl StagedExp for AssertStagedExp {
+    type Output = UnitVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        if !self.staged_cond.run(ctx).v {
+            fail(&self.message, &self.condition, &self.vars);
+        }
+        UnitVal
+    }
+}
+
+pub fn assert_exp(cond: Box<Exp<Output=BoolVal>>, message: &str) -> AssertExp {
+    let (condition, vars) = reify_with_vars(&*cond);
+    AssertExp {
+        cond,
+        message: message.to_string(),
+        condition,
+        vars,
+    }
+}
+
+impl E<BoolVal> {
+    pub fn assert(self, message: &str) -> E<UnitVal> {
+        E::new(assert_exp(self.0, message))
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use {Exp, StagedExp, EvalContext, NumVal, BoolVal};
+use check::{CheckError, Typed};
+use ops::E;
+use reify::{Expr, Value, node};
+
+// Numbers shared between a program and the tasks it spawns, for counters,
+// flags and claiming work without data races: an AtomicVarExp reads like a
+// variable, `fetch_add` adds to it and gives what it held, and `cas` sets
+// it to a new value if it holds the expected one, saying whether it did.
+// Each is a single atomic operation on the number, and all of them are
+// sequentially consistent, so every thread sees them happen in one order.
+//
+// Unlike a variable, an atomic is shared rather than copied when a task is
+// spawned: the task's expression names it and the task works on the same
+// number. In a reified tree it's `atomic(id)`, naming an atomic of this
+// process; it can only be loaded while the atomic still exists.
+
+struct Atomic {
+    id: u64,
+    v: AtomicI64,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Every atomic of the process, so one named in a tree can be found from
+// any thread.
+static ATOMICS: Mutex<BTreeMap<u64, Weak<Atomic>>> = Mutex::new(BTreeMap::new());
+
+impl Drop for Atomic {
+    fn drop(&mut self) {
+        ATOMICS.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Clone)]
+pub struct AtomicVarExp {
+    atomic: Arc<Atomic>,
+}
+
+impl AtomicVarExp {
+    pub fn new(v: i64) -> AtomicVarExp {
+        let atomic = Arc::new(Atomic {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            v: AtomicI64::new(v),
+        });
+        ATOMICS.lock().unwrap().insert(atomic.id, Arc::downgrade(&atomic));
+        AtomicVarExp {
+            atomic,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.atomic.id
+    }
+
+    // For the host to read and set between runs.
+    pub fn load(&self) -> i64 {
+        self.atomic.v.load(Ordering::SeqCst)
+    }
+
+    pub fn store(&self, v: i64) {
+        self.atomic.v.store(v, Ordering::SeqCst)
+    }
+
+    pub fn fetch_add<D: Into<E<NumVal>>>(&self, delta: D) -> E<NumVal> {
+        E::new(fetch_add_exp(self.clone(), delta.into().0))
+    }
+
+    pub fn cas<X: Into<E<NumVal>>, N: Into<E<NumVal>>>(&self, expected: X, new: N) -> E<BoolVal> {
+        E::new(cas_exp(self.clone(), expected.into().0, new.into().0))
+    }
+}
+
+impl Exp for AtomicVarExp {
+    type Output = NumVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box self.clone()
+    }
+    fn interpret(&self) -> Self::Output {
+        NumVal { v: self.load() }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("atomic", vec![Expr::Const(Value::Num(self.atomic.id as i64))])
+    }
+}
+
+impl StagedExp for AtomicVarExp {
+    type Output = NumVal;
+
+    fn run(&self, _ctx: &EvalContext) -> Self::Output {
+        NumVal { v: self.load() }
+    }
+}
+
+impl<'a> From<&'a AtomicVarExp> for E<NumVal> {
+    fn from(var: &'a AtomicVarExp) -> E<NumVal> {
+        E::new(var.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct FetchAddExp {
+    var: AtomicVarExp,
+    delta: Box<Exp<Output=NumVal>>,
+}
+
+pub struct FetchAddStagedExp {
+    var: AtomicVarExp,
+    staged_delta: Box<StagedExp<Output=NumVal>>,
+}
+
+// Wraps on overflow, as AtomicI64::fetch_add does.
+fn fetch_add(var: &AtomicVarExp, delta: NumVal) -> NumVal {
+    NumVal { v: var.atomic.v.fetch_add(delta.v, Ordering::SeqCst) }
+}
+
+impl Exp for FetchAddExp {
+    type Output = NumVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box FetchAddStagedExp {
+            var: self.var.clone(),
+            staged_delta: self.delta.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        fetch_add(&self.var, self.delta.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("fetch_add", vec![self.var.reify(), self.delta.reify()])
+    }
+}
+
+impl StagedExp for FetchAddStagedExp {
+    type Output = NumVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        fetch_add(&self.var, self.staged_delta.run(ctx))
+    }
+}
+
+#[derive(Clone)]
+pub struct CasExp {
+    var: AtomicVarExp,
+    expected: Box<Exp<Output=NumVal>>,
+    new: Box<Exp<Output=NumVal>>,
+}
+
+pub struct CasStagedExp {
+    var: AtomicVarExp,
+    staged_expected: Box<StagedExp<Output=NumVal>>,
+    staged_new: Box<StagedExp<Output=NumVal>>,
+}
+
+fn cas(var: &AtomicVarExp, expected: NumVal, new: NumVal) -> BoolVal {
+    BoolVal { v: var.atomic.v.compare_exchange(expected.v, new.v, Ordering::SeqCst, Ordering::SeqCst).is_ok() }
+}
+
+impl Exp for CasExp {
+    type Output = BoolVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box CasStagedExp {
+            var: self.var.clone(),
+            staged_expected: self.expected.stage(),
+            staged_new: self.new.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let expected = self.expected.interpret();
+        cas(&self.var, expected, self.new.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("cas", vec![self.var.reify(), self.expected.reify(), self.new.reify()])
+    }
+}
+
+impl StagedExp for CasStagedExp {
+    type Output = BoolVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let expected = self.staged_expected.run(ctx);
+        cas(&self.var, expected, self.staged_new.run(ctx))
+    }
+}
+
+pub fn fetch_add_exp(var: AtomicVarExp, delta: Box<Exp<Output=NumVal>>) -> FetchAddExp {
+    FetchAddExp {
+        var,
+        delta,
+    }
+}
+
+pub fn cas_exp(var: AtomicVarExp, expected: Box<Exp<Output=NumVal>>, new: Box<Exp<Output=NumVal>>) -> CasExp {
+    CasExp {
+        var,
+        expected,
+        new,
+    }
+}
+
+// The atomic `expr` names, for `check`.
+pub fn lookup(expr: &Expr) -> Result<AtomicVarExp, CheckError> {
+    if let Expr::Node { ref kind, ref children, .. } = *expr {
+        if let (&"atomic", &[Expr::Const(Value::Num(id))]) = (&&kind[..], &children[..]) {
+            let atomic = ATOMICS.lock().unwrap().get(&(id as u64)).and_then(Weak::upgrade);
+            return match atomic {
+                Some(atomic) => Ok(AtomicVarExp { atomic }),
+                None => Err(CheckError::new(format!("atomic {} doesn't exist", id))),
+            };
+        }
+    }
+    Err(CheckError::new("an atomic must be named by an atomic node".to_string()))
+}
+
+// The atomic node `expr`, for `check`.
+pub fn check_atomic(expr: &Expr) -> Result<Typed, CheckError> {
+    Ok(Typed::Num(box lookup(expr)?))
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use {Exp, StagedExp, EvalContext, CompiledStagedExp};
+
+pub struct VariantTiming {
+    pub name: String,
+    // Time spent turning the expression into something runnable (zero for interpret).
+    pub prepare: Duration,
+    pub run: Duration,
+    // Interpreted run time divided by this variant's run time.
+    pub speedup: f64,
+    // Iterations after which preparing pays for itself, if it ever does.
+    pub break_even: Option<u64>,
+}
+
+pub struct BenchReport {
+    pub iterations: u32,
+    pub rounds: u32,
+    pub variants: Vec<VariantTiming>,
+}
+
+fn nanos(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e9 + d.subsec_nanos() as f64
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} iterations, best of {} rounds", self.iterations, self.rounds)?;
+        writeln!(f, "{:<16} {:>12} {:>12} {:>9} {:>12}", "variant", "prepare ns", "ns/iter", "speedup", "break-even")?;
+        for v in &self.variants {
+            let break_even = match v.break_even {
+                Some(n) => n.to_string(),
+                None => "-".to_string(),
+            };
+            writeln!(f, "{:<16} {:>12.0} {:>12.1} {:>8.2}x {:>12}",
+                     v.name, nanos(v.prepare), nanos(v.run) / self.iterations as f64, v.speedup, break_even)?;
+        }
+        Ok(())
+    }
+}
+
+type Prepare<'a, T> = Box<Fn(&Exp<Output=T>) -> Box<StagedExp<Output=T>> + 'a>;
+
+// Times `interpret()` against each registered way of preparing the expression.
+// `stage` is always included; optimizing or compiling backends add themselves
+// through `variant`. Each round times every variant in turn, and each keeps
+// its best round, so load on the machine that comes and goes doesn't favour
+// whichever ran while it was quiet.
+pub struct Bench<'a, T: 'static> {
+    exp: &'a Exp<Output=T>,
+    iterations: u32,
+    rounds: u32,
+    variants: Vec<(String, Prepare<'a, T>)>,
+}
+
+impl<'a, T: 'static> Bench<'a, T> {
+    pub fn new(exp: &'a Exp<Output=T>, iterations: u32) -> Bench<'a, T> {
+        Bench {
+            exp,
+            iterations,
+            rounds: 5,
+            variants: vec![("stage".to_string(), box |exp: &Exp<Output=T>| exp.stage())],
+        }
+    }
+
+    pub fn variant<F>(mut self, name: &str, prepare: F) -> Self
+        where F: Fn(&Exp<Output=T>) -> Box<StagedExp<Output=T>> + 'a {
+        self.variants.push((name.to_string(), box prepare));
+        self
+    }
+
+    pub fn rounds(mut self, rounds: u32) -> Self {
+        self.rounds = rounds.max(1);
+        self
+    }
+
+    pub fn run(&self) -> BenchReport {
+        let mut prepared = vec![];
+        for &(ref name, ref prepare) in &self.variants {
+            let start = Instant::now();
+            let staged = prepare(self.exp);
+            prepared.push((name, start.elapsed(), staged));
+        }
+
+        let mut interpret = Duration::MAX;
+        let mut runs = vec![Duration::MAX; prepared.len()];
+        for _ in 0..self.rounds {
+            let start = Instant::now();
+            for _ in 0..self.iterations {
+                black_box(self.exp.interpret());
+            }
+            interpret = interpret.min(start.elapsed());
+
+            for (variant, best) in prepared.iter().zip(runs.iter_mut()) {
+                let start = Instant::now();
+                for _ in 0..self.iterations {
+                    black_box(variant.2.run(&EvalContext::new()));
+                }
+                *best = (*best).min(start.elapsed());
+            }
+        }
+
+        let mut variants = vec![VariantTiming {
+            name: "interpret".to_string(),
+            prepare: Duration::new(0, 0),
+            run: interpret,
+            speedup: 1.0,
+            break_even: None,
+        }];
+
+        for ((name, prepared, _), run) in prepared.into_iter().zip(runs) {
+            let iterations = self.iterations as f64;
+            let saved_per_iter = (nanos(interpret) - nanos(run)) / iterations;
+            variants.push(VariantTiming {
+                name: name.clone(),
+                prepare: prepared,
+                run,
+                speedup: nanos(interpret) / nanos(run).max(1.0),
+                break_even: if saved_per_iter > 0.0 {
+                    Some((nanos(prepared) / saved_per_iter).ceil() as u64)
+                } else {
+                    None
+                },
+            });
+        }
+
+        BenchReport {
+            iterations: self.iterations,
+            rounds: self.rounds,
+            variants,
+        }
+    }
+}
+
+// Prepares through `stage_compiled`, for use with `variant`.
+pub fn compiled<T: 'static>(exp: &Exp<Output=T>) -> Box<StagedExp<Output=T>> {
+    box CompiledStagedExp {
+        compiled: exp.stage_compiled(),
+    }
+}
+
+pub fn bench<T: 'static>(exp: &Exp<Output=T>, iterations: u32) -> BenchReport {
+    Bench::new(exp, iterations).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use builder::ExpBuilder;
+    use super::{Bench, compiled};
+
+    // What's timed varies from run to run, so this only checks the report
+    // is put together from the timings: every variant is there, in order,
+    // and its speedup is interpret's time over its own.
+    #[test]
+    fn report_compares_each_variant_with_interpret() {
+        let b = ExpBuilder::new();
+        let count = b.build(b.let_(b.num(0), |b, i| {
+            b.seq(b.while_(b.get(i).lt(100), b.set(i, b.get(i) + 1)),
+                  b.get(i))
+        }));
+        let report = Bench::new(&*count, 3).variant("compiled", compiled).rounds(2).run();
+        let names: Vec<&str> = report.variants.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["interpret", "stage", "compiled"]);
+        assert_eq!(report.rounds, 2);
+        let interpret = report.variants[0].run.as_nanos() as f64;
+        for v in &report.variants {
+            let run = (v.run.as_nanos() as f64).max(1.0);
+            assert!((v.speedup - interpret / run).abs() < 1e-9 * v.speedup.max(1.0), "{}", v.name);
+        }
+        assert!(report.to_string().starts_with("3 iterations, best of 2 rounds\n"));
+    }
+}
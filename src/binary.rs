@@ -0,0 +1,434 @@
+use std::fmt;
+
+use Exp;
+use check::{check_with, CheckError, Typed};
+use limits::{Budget, LimitError, Limits};
+use reify::{Expr, Value};
+
+// A compact binary form of a program, for preparing programs on one machine
+// and loading them on another. Staged programs are closures and can't be
+// written out, so what's stored is the alpha-normalized untyped tree;
+// loading validates and type-checks it, leaving only staging to the device.
+//
+// Layout, integers as LEB128 varints (signed ones zigzag-encoded):
+//
+//   "TGLB" version:u8
+//   kind count, then each node kind as length + UTF-8 bytes
+//   the tree in preorder, each Expr a tag byte followed by:
+//     0 num: signed    1 bool: u8    2 unit    3 str: length + bytes
+//     4 float: 8 bytes little-endian    5 var: signed    6 bound: signed
+//     7 node: kind index, bind count + signed binds, child count + children
+//   FNV-1a 32 checksum of everything before it, 4 bytes little-endian
+pub const VERSION: u8 = 1;
+
+const MAGIC: &[u8] = b"TGLB";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    // Encoding only: the program has nodes that don't reify.
+    Opaque,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadChecksum,
+    Truncated,
+    // Well-formed bytes that don't describe a valid tree.
+    Malformed(String),
+    Limit(LimitError),
+    Check(CheckError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Opaque => write!(f, "the program has parts that can't be encoded"),
+            DecodeError::BadMagic => write!(f, "not a program"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported format version {} (expected {})", v, VERSION),
+            DecodeError::BadChecksum => write!(f, "checksum mismatch"),
+            DecodeError::Truncated => write!(f, "unexpected end of data"),
+            DecodeError::Malformed(ref msg) => write!(f, "malformed program: {}", msg),
+            DecodeError::Limit(ref e) => write!(f, "{}", e),
+            DecodeError::Check(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut h: u32 = 0x811c_9dc5;
+    for &b in bytes {
+        h = (h ^ b as u32).wrapping_mul(0x0100_0193);
+    }
+    h
+}
+
+fn put_uint(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn put_int(out: &mut Vec<u8>, v: i64) {
+    put_uint(out, ((v << 1) ^ (v >> 63)) as u64);
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_uint(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Encoder {
+    kinds: Vec<String>,
+    tree: Vec<u8>,
+}
+
+impl Encoder {
+    fn kind(&mut self, kind: &str) -> u64 {
+        match self.kinds.iter().position(|k| k == kind) {
+            Some(i) => i as u64,
+            None => {
+                self.kinds.push(kind.to_string());
+                (self.kinds.len() - 1) as u64
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<(), DecodeError> {
+        match *expr {
+            Expr::Const(Value::Num(n)) => {
+                self.tree.push(0);
+                put_int(&mut self.tree, n);
+            }
+            Expr::Const(Value::Bool(b)) => {
+                self.tree.push(1);
+                self.tree.push(b as u8);
+            }
+            Expr::Const(Value::Unit) => self.tree.push(2),
+            Expr::Const(Value::Str(ref s)) => {
+                self.tree.push(3);
+                put_str(&mut self.tree, s);
+            }
+            Expr::Const(Value::Float(bits)) => {
+                self.tree.push(4);
+                for i in 0..8 {
+                    self.tree.push((bits >> (i * 8)) as u8);
+                }
+            }
+            Expr::Var(id) => {
+                self.tree.push(5);
+                put_int(&mut self.tree, id as i64);
+            }
+            Expr::Bound(n) => {
+                self.tree.push(6);
+                put_int(&mut self.tree, n as i64);
+            }
+            Expr::Node { ref kind, ref binds, ref children } => {
+                let kind = self.kind(kind);
+                self.tree.push(7);
+                put_uint(&mut self.tree, kind);
+                put_uint(&mut self.tree, binds.len() as u64);
+                for &b in binds {
+                    put_int(&mut self.tree, b as i64);
+                }
+                put_uint(&mut self.tree, children.len() as u64);
+                for c in children {
+                    self.expr(c)?;
+                }
+            }
+            Expr::Const(Value::Opaque) | Expr::Opaque => {
+                return Err(DecodeError::Opaque);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn encode_expr(expr: &Expr) -> Result<Vec<u8>, DecodeError> {
+    let mut enc = Encoder {
+        kinds: vec![],
+        tree: vec![],
+    };
+    enc.expr(expr)?;
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    put_uint(&mut out, enc.kinds.len() as u64);
+    for k in &enc.kinds {
+        put_str(&mut out, k);
+    }
+    out.extend_from_slice(&enc.tree);
+    let sum = checksum(&out);
+    for i in 0..4 {
+        out.push((sum >> (i * 8)) as u8);
+    }
+    Ok(out)
+}
+
+pub fn encode<T>(exp: &Exp<Output=T>) -> Result<Vec<u8>, DecodeError> {
+    encode_expr(&exp.reify().alpha_normalized())
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    kinds: Vec<String>,
+    budget: Budget,
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        match self.bytes.get(self.pos) {
+            Some(&b) => {
+                self.pos += 1;
+                Ok(b)
+            }
+            None => Err(DecodeError::Truncated),
+        }
+    }
+
+    fn uint(&mut self) -> Result<u64, DecodeError> {
+        let mut v = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= 64 || (shift == 63 && b > 1) {
+                return Err(DecodeError::Malformed("varint too long".to_string()));
+            }
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+            shift += 7;
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, DecodeError> {
+        let v = self.uint()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn int32(&mut self) -> Result<i32, DecodeError> {
+        let v = self.int()?;
+        if v < i32::min_value() as i64 || v > i32::max_value() as i64 {
+            return Err(DecodeError::Malformed("variable number out of range".to_string()));
+        }
+        Ok(v as i32)
+    }
+
+    // A length, checked against what's left so a bad one can't cause a huge
+    // allocation.
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        let n = self.uint()?;
+        if n > (self.bytes.len() - self.pos) as u64 {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(n as usize)
+    }
+
+    fn str(&mut self) -> Result<String, DecodeError> {
+        let n = self.len()?;
+        let s = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        String::from_utf8(s.to_vec()).map_err(|_| DecodeError::Malformed("invalid utf-8".to_string()))
+    }
+
+    fn expr(&mut self) -> Result<Expr, DecodeError> {
+        Ok(match self.byte()? {
+            7 => {
+                let kind = self.uint()?;
+                let kind = match self.kinds.get(kind as usize) {
+                    Some(k) => k.clone(),
+                    None => return Err(DecodeError::Malformed("bad node kind".to_string())),
+                };
+                self.budget.enter(Some(&kind)).map_err(DecodeError::Limit)?;
+                let mut binds = Vec::new();
+                for _ in 0..self.len()? {
+                    binds.push(self.int32()?);
+                }
+                let mut children = Vec::new();
+                for _ in 0..self.len()? {
+                    children.push(self.expr()?);
+                }
+                self.budget.leave(Some(&kind));
+                Expr::Node {
+                    kind,
+                    binds,
+                    children,
+                }
+            }
+            tag => {
+                self.budget.enter(None).map_err(DecodeError::Limit)?;
+                self.budget.leave(None);
+                self.leaf(tag)?
+            }
+        })
+    }
+
+    fn leaf(&mut self, tag: u8) -> Result<Expr, DecodeError> {
+        Ok(match tag {
+            0 => Expr::Const(Value::Num(self.int()?)),
+            1 => match self.byte()? {
+                0 => Expr::Const(Value::Bool(false)),
+                1 => Expr::Const(Value::Bool(true)),
+                _ => return Err(DecodeError::Malformed("bad bool".to_string())),
+            },
+            2 => Expr::Const(Value::Unit),
+            3 => Expr::Const(Value::Str(self.str()?)),
+            4 => {
+                let mut bits = 0u64;
+                for i in 0..8 {
+                    bits |= (self.byte()? as u64) << (i * 8);
+                }
+                Expr::Const(Value::Float(bits))
+            }
+            5 => Expr::Var(self.int32()?),
+            6 => Expr::Bound(self.int32()?),
+            tag => return Err(DecodeError::Malformed(format!("unknown tag {}", tag))),
+        })
+    }
+}
+
+pub fn decode_expr(bytes: &[u8]) -> Result<Expr, DecodeError> {
+    decode_expr_with(bytes, &Limits::default())
+}
+
+// Decodes a program that must stay within `limits`; for untrusted input.
+pub fn decode_expr_with(bytes: &[u8], limits: &Limits) -> Result<Expr, DecodeError> {
+    if bytes.len() < MAGIC.len() + 1 + 4 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    let version = bytes[MAGIC.len()];
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let (body, sum) = bytes.split_at(bytes.len() - 4);
+    let sum = sum.iter().enumerate().fold(0u32, |acc, (i, &b)| acc | (b as u32) << (i * 8));
+    if checksum(body) != sum {
+        return Err(DecodeError::BadChecksum);
+    }
+    let mut dec = Decoder {
+        bytes: body,
+        pos: MAGIC.len() + 1,
+        kinds: vec![],
+        budget: limits.budget(),
+    };
+    for _ in 0..dec.len()? {
+        let kind = dec.str()?;
+        dec.kinds.push(kind);
+    }
+    let expr = dec.expr()?;
+    if dec.pos != body.len() {
+        return Err(DecodeError::Malformed("trailing bytes".to_string()));
+    }
+    Ok(expr)
+}
+
+// Decodes and type-checks a program, ready to stage.
+pub fn load(bytes: &[u8]) -> Result<Typed, DecodeError> {
+    load_with(bytes, &Limits::default())
+}
+
+pub fn load_with(bytes: &[u8], limits: &Limits) -> Result<Typed, DecodeError> {
+    check_with(&decode_expr_with(bytes, limits)?, limits).map_err(DecodeError::Check)
+}
+
+#[cfg(test)]
+mod tests {
+    use NumVal;
+    use builder::ExpBuilder;
+    use dynamic::DynVal;
+    use limits::{Limit, LimitError, Limits};
+    use reify::{Expr, Value, node};
+    use super::*;
+
+    fn count() -> Box<Exp<Output=NumVal>> {
+        let b = ExpBuilder::new();
+        b.build(b.let_(b.num(1), |b, i| {
+            b.seq(b.while_(b.get(i).lt(10), b.set(i, b.get(i) + 1)),
+                  b.get(i))
+        }))
+    }
+
+    // `body` with the header in front and its checksum after, as if encoded.
+    fn sealed(body: &[u8]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend_from_slice(body);
+        let sum = checksum(&out);
+        out.extend_from_slice(&[sum as u8, (sum >> 8) as u8, (sum >> 16) as u8, (sum >> 24) as u8]);
+        out
+    }
+
+    #[test]
+    fn loaded_programs_run_alike() {
+        let exp = count();
+        let bytes = encode(&*exp).unwrap();
+        assert_eq!(decode_expr(&bytes).unwrap(), exp.reify().alpha_normalized());
+        let typed = load(&bytes).unwrap();
+        assert_eq!(typed.eval_interpreted(), DynVal::Num(10));
+        assert_eq!(typed.eval(), DynVal::Num(10));
+        assert_eq!(typed.eval_compiled(), DynVal::Num(10));
+    }
+
+    #[test]
+    fn constants_round_trip() {
+        let expr = node("array", vec![
+            Expr::Const(Value::Num(i64::MIN)), Expr::Const(Value::Num(i64::MAX)), Expr::Const(Value::Num(-1)),
+            Expr::Const(Value::Bool(true)), Expr::Const(Value::Unit), Expr::Const(Value::Str("é\u{0}".to_string())),
+            Expr::Const(Value::Float(f64::NAN.to_bits())), Expr::Var(i32::MIN), Expr::Bound(3),
+        ]);
+        assert_eq!(decode_expr(&encode_expr(&expr).unwrap()).unwrap(), expr);
+        assert_eq!(encode_expr(&node("seq", vec![Expr::Opaque])), Err(DecodeError::Opaque));
+    }
+
+    // Every corrupted or cut-short encoding is rejected, none panics.
+    #[test]
+    fn damaged_bytes_are_rejected() {
+        let bytes = encode(&*count()).unwrap();
+        for i in 0..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[i] ^= 0x10;
+            assert!(decode_expr(&damaged).is_err(), "byte {}", i);
+            assert!(decode_expr(&bytes[..i]).is_err(), "length {}", i);
+        }
+        let mut old = bytes.clone();
+        old[MAGIC.len()] = VERSION + 1;
+        assert_eq!(decode_expr(&old), Err(DecodeError::UnsupportedVersion(VERSION + 1)));
+        assert_eq!(decode_expr(b"JSON{}"), Err(DecodeError::BadMagic));
+    }
+
+    #[test]
+    fn malformed_trees_are_rejected() {
+        let malformed = |e: Result<Expr, DecodeError>| match e {
+            Err(DecodeError::Malformed(msg)) => msg,
+            e => panic!("{:?}", e),
+        };
+        assert_eq!(malformed(decode_expr(&sealed(&[0, 9]))), "unknown tag 9");
+        assert_eq!(malformed(decode_expr(&sealed(&[0, 7, 0, 0, 0]))), "bad node kind");
+        assert_eq!(malformed(decode_expr(&sealed(&[0, 2, 2]))), "trailing bytes");
+        assert_eq!(malformed(decode_expr(&sealed(&[0, 1, 2]))), "bad bool");
+        assert_eq!(malformed(decode_expr(&sealed(&[0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]))),
+                   "varint too long");
+        // A length longer than the data left can't make a huge allocation.
+        assert_eq!(decode_expr(&sealed(&[0, 3, 0xff, 0xff, 0xff, 0xff, 0x0f])), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn limits_and_types_are_checked_on_load() {
+        let bytes = encode(&*count()).unwrap();
+        let e = decode_expr_with(&bytes, &Limits::new().max_depth(3));
+        assert_eq!(e, Err(DecodeError::Limit(LimitError { limit: Limit::Depth, max: 3 })));
+        let e = decode_expr_with(&bytes, &Limits::new().max_loop_nesting(0));
+        assert_eq!(e, Err(DecodeError::Limit(LimitError { limit: Limit::LoopNesting, max: 0 })));
+
+        let ill_typed = encode_expr(&node("add", vec![Expr::Const(Value::Num(1)), Expr::Const(Value::Bool(true))])).unwrap();
+        match load(&ill_typed) {
+            Err(DecodeError::Check(_)) => {}
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("loaded an ill-typed program"),
+        }
+    }
+}
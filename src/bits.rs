@@ -0,0 +1,405 @@
+use std::ops::{BitAnd, BitOr, BitXor, Not, Shl, Shr};
+
+use {Exp, StagedExp, EvalContext, Compiled, Val, NumVal};
+use ops::E;
+use reify::{Expr, node};
+
+// Bitwise operations, for bit-manipulation kernels such as hashes and masks.
+// On NumVal they work on the two's complement bits of the i64.
+
+#[derive(Clone)]
+pub struct BitAndExp<T: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=T>>,
+}
+
+pub struct BitAndStagedExp<T: 'static+Clone> {
+    staged_exp1: Box<StagedExp<Output=T>>,
+    staged_exp2: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone+Val+BitAnd<Output=T>> Exp for BitAndExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box BitAndStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.exp1.interpret() & self.exp2.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("bit_and", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp1 = self.exp1.stage_compiled();
+        let compiled_exp2 = self.exp2.stage_compiled();
+        box move || compiled_exp1() & compiled_exp2()
+    }
+}
+
+impl<T: 'static+Clone+Val+BitAnd<Output=T>> StagedExp for BitAndStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_exp1.run(ctx) & self.staged_exp2.run(ctx)
+    }
+}
+
+#[derive(Clone)]
+pub struct BitOrExp<T: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=T>>,
+}
+
+pub struct BitOrStagedExp<T: 'static+Clone> {
+    staged_exp1: Box<StagedExp<Output=T>>,
+    staged_exp2: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone+Val+BitOr<Output=T>> Exp for BitOrExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box BitOrStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.exp1.interpret() | self.exp2.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("bit_or", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp1 = self.exp1.stage_compiled();
+        let compiled_exp2 = self.exp2.stage_compiled();
+        box move || compiled_exp1() | compiled_exp2()
+    }
+}
+
+impl<T: 'static+Clone+Val+BitOr<Output=T>> StagedExp for BitOrStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_exp1.run(ctx) | self.staged_exp2.run(ctx)
+    }
+}
+
+#[derive(Clone)]
+pub struct BitXorExp<T: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=T>>,
+}
+
+pub struct BitXorStagedExp<T: 'static+Clone> {
+    staged_exp1: Box<StagedExp<Output=T>>,
+    staged_exp2: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone+Val+BitXor<Output=T>> Exp for BitXorExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box BitXorStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.exp1.interpret() ^ self.exp2.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("bit_xor", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp1 = self.exp1.stage_compiled();
+        let compiled_exp2 = self.exp2.stage_compiled();
+        box move || compiled_exp1() ^ compiled_exp2()
+    }
+}
+
+impl<T: 'static+Clone+Val+BitXor<Output=T>> StagedExp for BitXorStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_exp1.run(ctx) ^ self.staged_exp2.run(ctx)
+    }
+}
+
+#[derive(Clone)]
+pub struct BitNotExp<T: 'static+Clone> {
+    exp: Box<Exp<Output=T>>,
+}
+
+pub struct BitNotStagedExp<T: 'static+Clone> {
+    staged_exp: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone+Val+Not<Output=T>> Exp for BitNotExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box BitNotStagedExp {
+            staged_exp: self.exp.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        !self.exp.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("bit_not", vec![self.exp.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp = self.exp.stage_compiled();
+        box move || !compiled_exp()
+    }
+}
+
+impl<T: 'static+Clone+Val+Not<Output=T>> StagedExp for BitNotStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        !self.staged_exp.run(ctx)
+    }
+}
+
+// Shift amounts are taken modulo 64, the way x86 shift instructions do: a
+// shift by 64 leaves the value as it is and a shift by -1 is a shift by 63,
+// rather than an error or a shift of every bit out. Right shifts are
+// arithmetic, copying the sign bit in. Every way of running a shift goes
+// through these, so they all agree.
+fn shl(v: NumVal, by: NumVal) -> NumVal {
+    NumVal {
+        v: v.v.wrapping_shl(by.v as u32)
+    }
+}
+
+fn shr(v: NumVal, by: NumVal) -> NumVal {
+    NumVal {
+        v: v.v.wrapping_shr(by.v as u32)
+    }
+}
+
+#[derive(Clone)]
+pub struct ShlExp {
+    exp1: Box<Exp<Output=NumVal>>,
+    exp2: Box<Exp<Output=NumVal>>,
+}
+
+pub struct ShlStagedExp {
+    staged_exp1: Box<StagedExp<Output=NumVal>>,
+    staged_exp2: Box<StagedExp<Output=NumVal>>,
+}
+
+impl Exp for ShlExp {
+    type Output = NumVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ShlStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let v = self.exp1.interpret();
+        shl(v, self.exp2.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("shl", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp1 = self.exp1.stage_compiled();
+        let compiled_exp2 = self.exp2.stage_compiled();
+        box move || {
+            let v = compiled_exp1();
+            shl(v, compiled_exp2())
+        }
+    }
+}
+
+impl StagedExp for ShlStagedExp {
+    type Output = NumVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let v = self.staged_exp1.run(ctx);
+        shl(v, self.staged_exp2.run(ctx))
+    }
+}
+
+#[derive(Clone)]
+pub struct ShrExp {
+    exp1: Box<Exp<Output=NumVal>>,
+    exp2: Box<Exp<Output=NumVal>>,
+}
+
+pub struct ShrStagedExp {
+    staged_exp1: Box<StagedExp<Output=NumVal>>,
+    staged_exp2: Box<StagedExp<Output=NumVal>>,
+}
+
+impl Exp for ShrExp {
+    type Output = NumVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ShrStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let v = self.exp1.interpret();
+        shr(v, self.exp2.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("shr", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp1 = self.exp1.stage_compiled();
+        let compiled_exp2 = self.exp2.stage_compiled();
+        box move || {
+            let v = compiled_exp1();
+            shr(v, compiled_exp2())
+        }
+    }
+}
+
+impl StagedExp for ShrStagedExp {
+    type Output = NumVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let v = self.staged_exp1.run(ctx);
+        shr(v, self.staged_exp2.run(ctx))
+    }
+}
+
+pub fn bit_and_exp<T: 'static+Clone+Val+BitAnd<Output=T>>(exp1: Box<Exp<Output=T>>,
+                                                          exp2: Box<Exp<Output=T>>) -> BitAndExp<T> {
+    BitAndExp {
+        exp1,
+        exp2
+    }
+}
+
+pub fn bit_or_exp<T: 'static+Clone+Val+BitOr<Output=T>>(exp1: Box<Exp<Output=T>>,
+                                                        exp2: Box<Exp<Output=T>>) -> BitOrExp<T> {
+    BitOrExp {
+        exp1,
+        exp2
+    }
+}
+
+pub fn bit_xor_exp<T: 'static+Clone+Val+BitXor<Output=T>>(exp1: Box<Exp<Output=T>>,
+                                                          exp2: Box<Exp<Output=T>>) -> BitXorExp<T> {
+    BitXorExp {
+        exp1,
+        exp2
+    }
+}
+
+pub fn bit_not_exp<T: 'static+Clone+Val+Not<Output=T>>(exp: Box<Exp<Output=T>>) -> BitNotExp<T> {
+    BitNotExp {
+        exp
+    }
+}
+
+pub fn shl_exp(exp1: Box<Exp<Output=NumVal>>, exp2: Box<Exp<Output=NumVal>>) -> ShlExp {
+    ShlExp {
+        exp1,
+        exp2
+    }
+}
+
+pub fn shr_exp(exp1: Box<Exp<Output=NumVal>>, exp2: Box<Exp<Output=NumVal>>) -> ShrExp {
+    ShrExp {
+        exp1,
+        exp2
+    }
+}
+
+impl<T, R> BitAnd<R> for E<T> where T: 'static+Clone+Val+BitAnd<Output=T>, R: Into<E<T>> {
+    type Output = E<T>;
+
+    fn bitand(self, rhs: R) -> E<T> {
+        E::new(bit_and_exp(self.0, rhs.into().0))
+    }
+}
+
+impl<T, R> BitOr<R> for E<T> where T: 'static+Clone+Val+BitOr<Output=T>, R: Into<E<T>> {
+    type Output = E<T>;
+
+    fn bitor(self, rhs: R) -> E<T> {
+        E::new(bit_or_exp(self.0, rhs.into().0))
+    }
+}
+
+impl<T, R> BitXor<R> for E<T> where T: 'static+Clone+Val+BitXor<Output=T>, R: Into<E<T>> {
+    type Output = E<T>;
+
+    fn bitxor(self, rhs: R) -> E<T> {
+        E::new(bit_xor_exp(self.0, rhs.into().0))
+    }
+}
+
+impl<T: 'static+Clone+Val+Not<Output=T>> Not for E<T> {
+    type Output = E<T>;
+
+    fn not(self) -> E<T> {
+        E::new(bit_not_exp(self.0))
+    }
+}
+
+impl<R: Into<E<NumVal>>> Shl<R> for E<NumVal> {
+    type Output = E<NumVal>;
+
+    fn shl(self, rhs: R) -> E<NumVal> {
+        E::new(shl_exp(self.0, rhs.into().0))
+    }
+}
+
+impl<R: Into<E<NumVal>>> Shr<R> for E<NumVal> {
+    type Output = E<NumVal>;
+
+    fn shr(self, rhs: R) -> E<NumVal> {
+        E::new(shr_exp(self.0, rhs.into().0))
+    }
+}
@@ -0,0 +1,386 @@
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::ops::{Add, Sub, Mul};
+use std::rc::Rc;
+
+use {Exp, StagedExp, LetStagedExp, SlotScope, Val, fresh_id, VariableExp, NumVal, BoolVal, FloatVal, UnitVal};
+use {Compiled, ForStagedExp, for_range, compile_for, reify_for, unit_exp, set_exp, while_exp, seq_exp, if_exp};
+use ops::E;
+use reify::{Expr, binder};
+
+// Builds expression trees without hand-boxing closures. Variables bound by
+// `let_` are allocated by the builder as the tree is built, with ids from
+// the global counter like any other variable's, so they can't collide with
+// those of another builder or of a closure binder, which staging, `reify`
+// and the scope checks all tell variables apart by.
+pub struct ExpBuilder;
+
+// A let whose variable was allocated at build time, so its body is an
+// ordinary tree rather than a closure to call at staging time.
+#[derive(Clone)]
+pub struct BoundLetExp<T: 'static+Clone, U: 'static+Clone> {
+    var: VariableExp<T>,
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=U>>,
+}
+
+impl<T: 'static+Clone, U: 'static+Clone> Exp for BoundLetExp<T,U> {
+    type Output = U;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_exp1 = self.exp1.stage();
+        let scope = SlotScope::enter();
+        box LetStagedExp {
+            staged_exp1,
+            exp1_slot: scope.bind(&self.var),
+            staged_exp2: self.exp2.stage(),
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        self.var.assign(self.exp1.interpret());
+        self.exp2.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        binder("let", vec![self.var.id], vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let var = self.var.clone();
+        let compiled_exp1 = self.exp1.stage_compiled();
+        let compiled_exp2 = self.exp2.stage_compiled();
+        box move || {
+            var.assign(compiled_exp1());
+            compiled_exp2()
+        }
+    }
+
+    fn stage_tail(&self, fn_id: i32) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_exp1 = self.exp1.stage();
+        let scope = SlotScope::enter();
+        box LetStagedExp {
+            staged_exp1,
+            exp1_slot: scope.bind(&self.var),
+            staged_exp2: self.exp2.stage_tail(fn_id),
+        }
+    }
+
+    fn interpret_tail(&self, fn_id: i32) -> Self::Output {
+        self.var.assign(self.exp1.interpret());
+        self.exp2.interpret_tail(fn_id)
+    }
+}
+
+// The counted loop over a builder-allocated index variable.
+#[derive(Clone)]
+pub struct BoundForExp {
+    start_exp: Box<Exp<Output=NumVal>>,
+    end_exp: Box<Exp<Output=NumVal>>,
+    step_exp: Option<Box<Exp<Output=NumVal>>>,
+    index_var: VariableExp<NumVal>,
+    body_exp: Box<Exp<Output=UnitVal>>,
+}
+
+impl Exp for BoundForExp {
+    type Output = UnitVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_start_exp = self.start_exp.stage();
+        let staged_end_exp = self.end_exp.stage();
+        let staged_step_exp = self.step_exp.as_ref().map(|e| e.stage());
+        let scope = SlotScope::enter();
+        box ForStagedExp {
+            staged_start_exp,
+            staged_end_exp,
+            staged_step_exp,
+            index_slot: scope.bind(&self.index_var),
+            staged_body_exp: self.body_exp.stage(),
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        let start = self.start_exp.interpret().v;
+        let end = self.end_exp.interpret().v;
+        let step = self.step_exp.as_ref().map_or(1, |e| e.interpret().v);
+        for_range(start, end, step, &mut |i| {
+            self.index_var.assign(NumVal { v: i });
+            self.body_exp.interpret();
+        });
+        UnitVal
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        reify_for(self.start_exp.reify(), self.end_exp.reify(), self.step_exp.as_ref().map(|e| e.reify()),
+                  self.index_var.id, self.body_exp.reify())
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        compile_for(self.start_exp.stage_compiled(), self.end_exp.stage_compiled(),
+                    self.step_exp.as_ref().map(|e| e.stage_compiled()), self.index_var.clone(),
+                    self.body_exp.stage_compiled())
+    }
+}
+
+pub fn bound_let_exp<T: 'static+Clone, U: 'static+Clone>(var: VariableExp<T>, exp1: Box<Exp<Output=T>>,
+                                                         exp2: Box<Exp<Output=U>>) -> BoundLetExp<T,U> {
+    BoundLetExp {
+        var,
+        exp1,
+        exp2
+    }
+}
+
+pub fn bound_for_exp(start_exp: Box<Exp<Output=NumVal>>, end_exp: Box<Exp<Output=NumVal>>,
+                     step_exp: Option<Box<Exp<Output=NumVal>>>, index_var: VariableExp<NumVal>,
+                     body_exp: Box<Exp<Output=UnitVal>>) -> BoundForExp {
+    BoundForExp {
+        start_exp,
+        end_exp,
+        step_exp,
+        index_var,
+        body_exp
+    }
+}
+
+impl ExpBuilder {
+    pub fn new() -> ExpBuilder {
+        ExpBuilder
+    }
+
+    fn var<T: 'static+Clone>(&self, init: T) -> VariableExp<T> {
+        VariableExp {
+            id: fresh_id(),
+            var_val: Rc::new(RefCell::new(init)),
+        }
+    }
+
+    pub fn lit<T: 'static+Clone>(&self, v: T) -> E<T> {
+        E::new(unit_exp(v))
+    }
+
+    pub fn num(&self, v: i64) -> E<NumVal> {
+        self.lit(NumVal { v })
+    }
+
+    pub fn float(&self, v: f64) -> E<FloatVal> {
+        self.lit(FloatVal { v })
+    }
+
+    pub fn bool(&self, v: bool) -> E<BoolVal> {
+        self.lit(BoolVal { v })
+    }
+
+    pub fn get<T: 'static+Clone>(&self, var: &VariableExp<T>) -> E<T> {
+        E::new(var.clone())
+    }
+
+    pub fn set<T: 'static+Clone>(&self, var: &VariableExp<T>, exp: E<T>) -> E<UnitVal> {
+        E::new(set_exp(var.clone(), exp.0))
+    }
+
+    pub fn let_<T, U, F>(&self, init: E<T>, body: F) -> E<U>
+        where T: 'static+Clone+Default, U: 'static+Clone, F: FnOnce(&ExpBuilder, &VariableExp<T>) -> E<U> {
+        let var = self.var(T::default());
+        let exp2 = body(self, &var).0;
+        E::new(bound_let_exp(var, init.0, exp2))
+    }
+
+    pub fn while_(&self, cond: E<BoolVal>, body: E<UnitVal>) -> E<UnitVal> {
+        E::new(while_exp(cond.0, body.0))
+    }
+
+    pub fn for_<F>(&self, start: E<NumVal>, end: E<NumVal>, step: Option<E<NumVal>>, body: F) -> E<UnitVal>
+        where F: FnOnce(&ExpBuilder, &VariableExp<NumVal>) -> E<UnitVal> {
+        let index_var = self.var(NumVal::default());
+        let body_exp = body(self, &index_var).0;
+        E::new(bound_for_exp(start.0, end.0, step.map(|e| e.0), index_var, body_exp))
+    }
+
+    pub fn if_<T: 'static+Clone>(&self, cond: E<BoolVal>, then_exp: E<T>, else_exp: E<T>) -> E<T> {
+        E::new(if_exp(cond.0, then_exp.0, else_exp.0))
+    }
+
+    // Evaluates `first` for its effects, then `then`.
+    pub fn seq<T: 'static+Clone, U: 'static+Clone>(&self, first: E<T>, then: E<U>) -> E<U> {
+        E::new(seq_exp(first.0, then.0))
+    }
+
+    pub fn build<T: 'static>(&self, exp: E<T>) -> Box<Exp<Output=T>> {
+        exp.0
+    }
+
+    // Builds a program whose variables can't be used outside it: the scope,
+    // its variables and the expressions built from them all carry a brand
+    // that only exists inside `f`, so stashing any of them somewhere that
+    // outlives the call, or using one in another scoped program, doesn't
+    // compile. Within one program a variable can still be read outside its
+    // let, where it holds whatever the let last set.
+    pub fn scoped<T, F>(&self, f: F) -> E<T>
+        where T: 'static, F: for<'s> FnOnce(&Scope<'s>) -> Term<'s, T> {
+        let scope = Scope {
+            builder: self,
+            _brand: PhantomData,
+        };
+        f(&scope).exp
+    }
+}
+
+// Invariant in 's, so brands from different scopes never unify.
+type Brand<'s> = PhantomData<Cell<&'s ()>>;
+
+pub struct Scope<'s> {
+    builder: &'s ExpBuilder,
+    _brand: Brand<'s>,
+}
+
+pub struct Var<'s, T: 'static+Clone> {
+    var: VariableExp<T>,
+    _brand: Brand<'s>,
+}
+
+pub struct Term<'s, T: 'static> {
+    exp: E<T>,
+    _brand: Brand<'s>,
+}
+
+impl<'s, T: 'static> Clone for Term<'s, T> {
+    fn clone(&self) -> Self {
+        Term {
+            exp: self.exp.clone(),
+            _brand: PhantomData,
+        }
+    }
+}
+
+fn term<'s, T: 'static>(exp: E<T>) -> Term<'s, T> {
+    Term {
+        exp,
+        _brand: PhantomData,
+    }
+}
+
+impl<'s> Scope<'s> {
+    pub fn lit<T: 'static+Clone>(&self, v: T) -> Term<'s, T> {
+        term(self.builder.lit(v))
+    }
+
+    pub fn num(&self, v: i64) -> Term<'s, NumVal> {
+        term(self.builder.num(v))
+    }
+
+    pub fn float(&self, v: f64) -> Term<'s, FloatVal> {
+        term(self.builder.float(v))
+    }
+
+    pub fn bool(&self, v: bool) -> Term<'s, BoolVal> {
+        term(self.builder.bool(v))
+    }
+
+    pub fn get<T: 'static+Clone>(&self, var: &Var<'s, T>) -> Term<'s, T> {
+        term(self.builder.get(&var.var))
+    }
+
+    pub fn set<T: 'static+Clone>(&self, var: &Var<'s, T>, exp: Term<'s, T>) -> Term<'s, UnitVal> {
+        term(self.builder.set(&var.var, exp.exp))
+    }
+
+    pub fn let_<T, U, F>(&self, init: Term<'s, T>, body: F) -> Term<'s, U>
+        where T: 'static+Clone+Default, U: 'static+Clone, F: FnOnce(&Scope<'s>, &Var<'s, T>) -> Term<'s, U> {
+        let var = Var {
+            var: self.builder.var(T::default()),
+            _brand: PhantomData,
+        };
+        let exp2 = body(self, &var).exp.0;
+        term(E::new(bound_let_exp(var.var, init.exp.0, exp2)))
+    }
+
+    pub fn while_(&self, cond: Term<'s, BoolVal>, body: Term<'s, UnitVal>) -> Term<'s, UnitVal> {
+        term(self.builder.while_(cond.exp, body.exp))
+    }
+
+    pub fn for_<F>(&self, start: Term<'s, NumVal>, end: Term<'s, NumVal>, step: Option<Term<'s, NumVal>>, body: F) -> Term<'s, UnitVal>
+        where F: FnOnce(&Scope<'s>, &Var<'s, NumVal>) -> Term<'s, UnitVal> {
+        let index_var = self.builder.var(NumVal::default());
+        let body_exp = body(self, &Var { var: index_var.clone(), _brand: PhantomData }).exp.0;
+        term(E::new(bound_for_exp(start.exp.0, end.exp.0, step.map(|e| e.exp.0), index_var, body_exp)))
+    }
+
+    pub fn if_<T: 'static+Clone>(&self, cond: Term<'s, BoolVal>, then_exp: Term<'s, T>, else_exp: Term<'s, T>) -> Term<'s, T> {
+        term(self.builder.if_(cond.exp, then_exp.exp, else_exp.exp))
+    }
+
+    pub fn seq<T: 'static+Clone, U: 'static+Clone>(&self, first: Term<'s, T>, then: Term<'s, U>) -> Term<'s, U> {
+        term(self.builder.seq(first.exp, then.exp))
+    }
+}
+
+impl<'s, T: 'static+Clone+Val+Ord> Term<'s, T> {
+    pub fn lt(self, rhs: Term<'s, T>) -> Term<'s, BoolVal> {
+        term(self.exp.lt(rhs.exp))
+    }
+}
+
+impl<'s, T: 'static+Clone+Val+Add<Output=T>> Add for Term<'s, T> {
+    type Output = Term<'s, T>;
+
+    fn add(self, rhs: Term<'s, T>) -> Term<'s, T> {
+        term(self.exp + rhs.exp)
+    }
+}
+
+impl<'s, T: 'static+Clone+Val+Sub<Output=T>> Sub for Term<'s, T> {
+    type Output = Term<'s, T>;
+
+    fn sub(self, rhs: Term<'s, T>) -> Term<'s, T> {
+        term(self.exp - rhs.exp)
+    }
+}
+
+impl<'s, T: 'static+Clone+Val+Mul<Output=T>> Mul for Term<'s, T> {
+    type Output = Term<'s, T>;
+
+    fn mul(self, rhs: Term<'s, T>) -> Term<'s, T> {
+        term(self.exp * rhs.exp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {EvalContext, let_exp};
+
+    fn staged<T: 'static>(exp: &E<T>) -> T {
+        exp.0.stage().run(&EvalContext::new())
+    }
+
+    #[test]
+    fn nested_builders_bind_distinct_variables() {
+        let outer = ExpBuilder::new();
+        let exp = outer.let_(outer.num(1), |_, x| {
+            let inner = ExpBuilder::new();
+            inner.let_(inner.num(2), |b, y| b.get(x) + b.get(y))
+        });
+        assert_eq!(exp.0.interpret().v, 3);
+        assert_eq!(staged(&exp).v, 3);
+    }
+
+    #[test]
+    fn builder_let_around_let_exp() {
+        let b = ExpBuilder::new();
+        let exp = b.let_(b.num(1), |_, x| {
+            let x = x.clone();
+            E::new(let_exp(box unit_exp(NumVal { v: 2 }), box move |y| (E::from(&x) + &y).0))
+        });
+        assert_eq!(exp.0.interpret().v, 3);
+        assert_eq!(staged(&exp).v, 3);
+    }
+}
@@ -0,0 +1,82 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use {Exp, StagedExp};
+use bench;
+use canon::canonicalize;
+use reify::Expr;
+
+type Prepare<T> = fn(&Exp<Output=T>) -> Box<StagedExp<Output=T>>;
+
+// Stages each program shape once. Expressions are keyed by their reified
+// tree with bound variables renamed and the operands of commutative
+// operators in canonical order, so rebuilding the same program (say, per
+// request), even with `b + a` where it had `a + b`, finds the earlier
+// artifact. Free variables are part of the
+// key, so a hit never runs against another program's variables.
+//
+// Trees containing nodes that can't reify themselves are staged every time.
+// Like any staged program, an artifact must not be run reentrantly.
+pub struct StageCache<T: 'static> {
+    entries: RefCell<HashMap<Expr, Rc<StagedExp<Output=T>>>>,
+    prepare: Prepare<T>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+fn stage<T: 'static>(exp: &Exp<Output=T>) -> Box<StagedExp<Output=T>> {
+    exp.stage()
+}
+
+impl<T: 'static> StageCache<T> {
+    pub fn new() -> StageCache<T> {
+        StageCache::with_prepare(stage)
+    }
+
+    // Caches the closure-compiled form instead of the staged tree.
+    pub fn compiled() -> StageCache<T> {
+        StageCache::with_prepare(bench::compiled)
+    }
+
+    pub fn with_prepare(prepare: Prepare<T>) -> StageCache<T> {
+        StageCache {
+            entries: RefCell::new(HashMap::new()),
+            prepare,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    pub fn get(&self, exp: &Exp<Output=T>) -> Rc<StagedExp<Output=T>> {
+        let key = canonicalize(&exp.reify()).alpha_normalized();
+        if key.is_opaque() {
+            self.misses.set(self.misses.get() + 1);
+            return Rc::from((self.prepare)(exp));
+        }
+        if let Some(staged) = self.entries.borrow().get(&key) {
+            self.hits.set(self.hits.get() + 1);
+            return staged.clone();
+        }
+        self.misses.set(self.misses.get() + 1);
+        let staged: Rc<StagedExp<Output=T>> = Rc::from((self.prepare)(exp));
+        self.entries.borrow_mut().insert(key, staged.clone());
+        staged
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.get()
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.get()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn clear(&self) {
+        self.entries.borrow_mut().clear();
+    }
+}
@@ -0,0 +1,260 @@
+use std::cmp::Ordering;
+
+use Exp;
+use reify::{Expr, Value};
+
+// A normal form for trees of commutative operators, so trees that differ
+// only in the order or grouping of their operands, like `a + b` and
+// `b + a`, or `(a + b) + c` and `a + (b + c)`, come out the same and hash
+// and compare equal, and a cache or a pass looking for repeated subtrees
+// sees one tree where there were several.
+//
+// The operands of add, mul, bit_and, bit_or and bit_xor are put in a fixed
+// order: nodes, then variables, then constants, each by their shape, with
+// bound variables compared by where they're bound rather than by id, so
+// trees equal up to renaming still order their operands alike. Chains of
+// one operator are flattened before sorting and rebuilt nested to the
+// left. Operands are only reordered when none of them has an effect or
+// reads something another could change; if one does, the chain is left as
+// it is.
+//
+// The bitwise operators commute and associate for every type they take,
+// but add and mul don't: adding strings concatenates them, and multiplying
+// matrices depends on their order. Types aren't in an Expr, so add and mul
+// are only reordered when an operand shows they're of numbers, such as a
+// number constant. Float addition and multiplication round at each step,
+// so `(a + b) + c` can differ from `a + (b + c)`: chains are only regrouped
+// when they're of NumVals, and otherwise just the two operands of each
+// node are put in order. Regrouped arithmetic wraps as NumVal's does in
+// release builds.
+
+const COMMUTATIVE: &[&str] = &["add", "mul", "bit_and", "bit_or", "bit_xor"];
+
+// Operators that are also associative for every type they take.
+const ASSOCIATIVE: &[&str] = &["bit_and", "bit_or", "bit_xor"];
+
+// Nodes with no effects, which give the same value wherever they run.
+const PURE: &[&str] = &["add", "sub", "mul", "div", "pow", "lt", "partial_lt", "bit_and", "bit_or", "bit_xor", "bit_not",
+                        "shl", "shr", "if", "let", "seq", "str_eq", "str_len", "substring", "concat", "format",
+                        "record", "field", "variant", "array", "range", "range_step", "map_get", "map_contains",
+                        "contains", "complex", "complex_abs", "conj", "dot", "decimal_div", "rescale", "map", "filter",
+                        "fold", "sum", "count", "avg", "min", "max"];
+
+// Nodes whose value is a NumVal, whatever their operands.
+const NUMERIC: &[&str] = &["str_len", "count"];
+
+// Whether nodes of `kind` have no effects of their own.
+pub fn pure_kind(kind: &str) -> bool {
+    PURE.contains(&kind)
+}
+
+// Whether `expr` has no effects and reads nothing but its variables.
+pub fn pure(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Const(_) | Expr::Var(_) | Expr::Bound(_) => true,
+        Expr::Node { ref kind, ref children, .. } => pure_kind(kind) && children.iter().all(pure),
+        Expr::Opaque => false,
+    }
+}
+
+// Whether `expr` is known to be a NumVal, or with `floats`, a NumVal or a
+// FloatVal.
+fn numeric(expr: &Expr, floats: bool) -> bool {
+    match *expr {
+        Expr::Const(Value::Num(_)) => true,
+        Expr::Const(Value::Float(_)) => floats,
+        Expr::Node { ref kind, ref children, .. } => {
+            NUMERIC.contains(&&kind[..])
+                || (matches!(&kind[..], "add" | "sub" | "mul" | "bit_and" | "bit_or" | "bit_xor")
+                    && children.iter().any(|c| numeric(c, floats)))
+        }
+        _ => false,
+    }
+}
+
+// `expr` with the variables bound in it and by `scope` numbered by how
+// deep their binder is, so it doesn't depend on their ids.
+fn key(expr: &Expr, scope: &mut Vec<i32>) -> Expr {
+    match *expr {
+        Expr::Var(id) => match scope.iter().rposition(|&b| b == id) {
+            Some(level) => Expr::Bound(level as i32),
+            None => Expr::Var(id),
+        },
+        Expr::Node { ref kind, ref binds, ref children } => {
+            let levels = (scope.len()..scope.len() + binds.len()).map(|l| l as i32).collect();
+            scope.extend(binds);
+            let children = children.iter().map(|c| key(c, scope)).collect();
+            scope.truncate(scope.len() - binds.len());
+            Expr::Node {
+                kind: kind.clone(),
+                binds: levels,
+                children,
+            }
+        }
+        ref e => e.clone(),
+    }
+}
+
+fn rank(expr: &Expr) -> u8 {
+    match *expr {
+        Expr::Node { .. } => 0,
+        Expr::Var(_) => 1,
+        Expr::Bound(_) => 2,
+        Expr::Const(_) => 3,
+        Expr::Opaque => 4,
+    }
+}
+
+fn value_rank(v: &Value) -> u8 {
+    match *v {
+        Value::Num(_) => 0,
+        Value::Bool(_) => 1,
+        Value::Unit => 2,
+        Value::Str(_) => 3,
+        Value::Float(_) => 4,
+        Value::Opaque => 5,
+    }
+}
+
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (&Value::Num(a), &Value::Num(b)) => a.cmp(&b),
+        (&Value::Bool(a), &Value::Bool(b)) => a.cmp(&b),
+        (&Value::Str(ref a), &Value::Str(ref b)) => a.cmp(b),
+        (&Value::Float(a), &Value::Float(b)) => a.cmp(&b),
+        _ => value_rank(a).cmp(&value_rank(b)),
+    }
+}
+
+fn compare(a: &Expr, b: &Expr) -> Ordering {
+    match (a, b) {
+        (&Expr::Node { kind: ref ka, binds: ref ba, children: ref ca },
+         &Expr::Node { kind: ref kb, binds: ref bb, children: ref cb }) => {
+            ka.cmp(kb)
+                .then_with(|| ba.cmp(bb))
+                .then_with(|| ca.len().cmp(&cb.len()))
+                .then_with(|| ca.iter().zip(cb).map(|(a, b)| compare(a, b)).find(|o| *o != Ordering::Equal)
+                                 .unwrap_or(Ordering::Equal))
+        }
+        (&Expr::Var(a), &Expr::Var(b)) | (&Expr::Bound(a), &Expr::Bound(b)) => a.cmp(&b),
+        (&Expr::Const(ref a), &Expr::Const(ref b)) => compare_values(a, b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+// The operands of the chain of `kind` nodes at `expr`.
+fn flatten(kind: &str, expr: Expr, leaves: &mut Vec<Expr>) {
+    match expr {
+        Expr::Node { kind: k, binds, mut children } if k == kind && binds.is_empty() && children.len() == 2 => {
+            let b = children.pop().unwrap();
+            let a = children.pop().unwrap();
+            flatten(kind, a, leaves);
+            flatten(kind, b, leaves);
+        }
+        e => leaves.push(e),
+    }
+}
+
+fn canon(expr: &Expr, scope: &mut Vec<i32>) -> Expr {
+    let (kind, binds, children) = match *expr {
+        Expr::Node { ref kind, ref binds, ref children } => (kind, binds, children),
+        ref e => return e.clone(),
+    };
+    scope.extend(binds);
+    let children: Vec<Expr> = children.iter().map(|c| canon(c, scope)).collect();
+    scope.truncate(scope.len() - binds.len());
+    let associative = ASSOCIATIVE.contains(&&kind[..]);
+    if !COMMUTATIVE.contains(&&kind[..]) || !binds.is_empty() || children.len() != 2 || !children.iter().all(pure)
+        || !(associative || children.iter().any(|c| numeric(c, true))) {
+        return Expr::Node {
+            kind: kind.clone(),
+            binds: binds.clone(),
+            children,
+        };
+    }
+    let leaves = if associative || children.iter().any(|c| numeric(c, false)) {
+        let mut leaves = Vec::new();
+        for c in children {
+            flatten(kind, c, &mut leaves);
+        }
+        leaves
+    } else {
+        children
+    };
+    let mut keyed: Vec<(Expr, Expr)> = leaves.into_iter().map(|l| (key(&l, scope), l)).collect();
+    keyed.sort_by(|a, b| compare(&a.0, &b.0));
+    let mut leaves = keyed.into_iter().map(|(_, l)| l);
+    let first = leaves.next().unwrap();
+    leaves.fold(first, |acc, l| Expr::Node {
+        kind: kind.clone(),
+        binds: vec![],
+        children: vec![acc, l],
+    })
+}
+
+// `expr` with the operands of its commutative operators in canonical
+// order. Variable ids are kept, so the result can be checked and rebuilt
+// as `expr` can.
+pub fn canonicalize(expr: &Expr) -> Expr {
+    canon(expr, &mut Vec::new())
+}
+
+// A hash of the canonical tree, as `hash_exp` is of the tree itself.
+pub fn canonical_hash<T>(exp: &Exp<Output=T>) -> u64 {
+    canonicalize(&exp.reify()).stable_hash()
+}
+
+// Equality up to renaming of bound variables and the order and grouping of
+// operands, as `canonicalize` changes them.
+pub fn canonical_eq<T>(a: &Exp<Output=T>, b: &Exp<Output=T>) -> bool {
+    let a = a.reify();
+    let b = b.reify();
+    !a.is_opaque() && !b.is_opaque() && canonicalize(&a).alpha_normalized() == canonicalize(&b).alpha_normalized()
+}
+
+#[cfg(test)]
+mod tests {
+    use reify::{Expr, Value, node};
+    use super::canonicalize;
+
+    fn num(n: i64) -> Expr {
+        Expr::Const(Value::Num(n))
+    }
+
+    fn float(f: f64) -> Expr {
+        Expr::Const(Value::Float(f.to_bits()))
+    }
+
+    #[test]
+    fn number_chains_are_reordered_and_regrouped() {
+        let a = node("add", vec![node("add", vec![num(1), Expr::Var(1)]), Expr::Var(2)]);
+        let b = node("add", vec![Expr::Var(2), node("add", vec![Expr::Var(1), num(1)])]);
+        assert_eq!(canonicalize(&a), canonicalize(&b));
+        assert_eq!(canonicalize(&a), node("add", vec![node("add", vec![Expr::Var(1), Expr::Var(2)]), num(1)]));
+    }
+
+    // Strings concatenate and matrices multiply in order, and nothing in the
+    // tree says these operands aren't either.
+    #[test]
+    fn add_and_mul_of_unknown_types_keep_their_order() {
+        for kind in &["add", "mul"] {
+            let e = node(kind, vec![Expr::Var(2), Expr::Var(1)]);
+            assert_eq!(canonicalize(&e), e);
+        }
+        let concat = node("add", vec![Expr::Const(Value::Str("b".to_string())), Expr::Var(1)]);
+        assert_eq!(canonicalize(&concat), concat);
+    }
+
+    #[test]
+    fn float_chains_are_reordered_but_not_regrouped() {
+        let e = node("add", vec![float(1.0), node("add", vec![Expr::Var(2), Expr::Var(1)])]);
+        assert_eq!(canonicalize(&e), node("add", vec![node("add", vec![Expr::Var(2), Expr::Var(1)]), float(1.0)]));
+    }
+
+    #[test]
+    fn bitwise_operands_are_reordered_whatever_their_type() {
+        let a = node("bit_xor", vec![Expr::Var(2), node("bit_xor", vec![Expr::Var(3), Expr::Var(1)])]);
+        let b = node("bit_xor", vec![node("bit_xor", vec![Expr::Var(1), Expr::Var(2)]), Expr::Var(3)]);
+        assert_eq!(canonicalize(&a), canonicalize(&b));
+    }
+}
@@ -0,0 +1,374 @@
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use {NumVal, BoolVal, StrVal, FloatVal};
+use check::{Ty, Typed, check_open};
+use dynamic::DynVal;
+use patterns::Binder;
+use reify::{Expr, Value};
+
+// A C interface for embedding the engine in non-Rust hosts. Programs are
+// built as untyped trees, checked into programs with typed inputs, and run
+// interpreted or staged; everything crosses as an opaque handle.
+//
+// Conventions:
+// - Functions that can fail return a TL_* code, TL_OK on success, and write
+//   their result through an out pointer. The message for the latest failure
+//   on the thread is `tl_last_error()`, valid until the next call that
+//   fails.
+// - A handle returned to the caller is theirs to free with the matching
+//   `tl_*_free`. `tl_expr_node` takes ownership of the children passed to
+//   it; nothing else takes ownership of its arguments.
+// - Strings go in as NUL-terminated UTF-8. Strings coming out are freed
+//   with `tl_string_free`.
+// - A panic never unwinds into the host: a failing run returns
+//   TL_ERR_RUN.
+//
+// The crate builds as a binary, so for now a host links these from a build
+// of it as a library.
+
+pub const TL_OK: c_int = 0;
+pub const TL_ERR_NULL: c_int = 1;
+pub const TL_ERR_UTF8: c_int = 2;
+pub const TL_ERR_CHECK: c_int = 3;
+pub const TL_ERR_TYPE: c_int = 4;
+pub const TL_ERR_NO_INPUT: c_int = 5;
+pub const TL_ERR_RUN: c_int = 6;
+
+// Types, for declaring inputs and reading results.
+pub const TL_NUM: c_int = 0;
+pub const TL_BOOL: c_int = 1;
+pub const TL_UNIT: c_int = 2;
+pub const TL_STR: c_int = 3;
+pub const TL_FLOAT: c_int = 4;
+pub const TL_RECORD: c_int = 5;
+pub const TL_VARIANT: c_int = 6;
+
+pub struct TlExpr(Expr);
+
+pub struct TlProgram {
+    typed: Typed,
+    inputs: Vec<(i32, Ty, Box<Binder>)>,
+    staged: Option<Box<Fn() -> DynVal>>,
+}
+
+pub struct TlValue(DynVal);
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn fail(code: c_int, msg: &str) -> c_int {
+    let msg = CString::new(msg.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = msg);
+    code
+}
+
+#[no_mangle]
+pub extern "C" fn tl_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(fail(TL_ERR_NULL, "null string"));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| fail(TL_ERR_UTF8, "string isn't UTF-8"))
+}
+
+fn ty_of(code: c_int) -> Option<Ty> {
+    match code {
+        TL_NUM => Some(Ty::Num),
+        TL_BOOL => Some(Ty::Bool),
+        TL_UNIT => Some(Ty::Unit),
+        TL_STR => Some(Ty::Str),
+        TL_FLOAT => Some(Ty::Float),
+        _ => None,
+    }
+}
+
+fn expr(e: Expr) -> *mut TlExpr {
+    Box::into_raw(box TlExpr(e))
+}
+
+#[no_mangle]
+pub extern "C" fn tl_expr_num(v: i64) -> *mut TlExpr {
+    expr(Expr::Const(Value::Num(v)))
+}
+
+#[no_mangle]
+pub extern "C" fn tl_expr_bool(v: c_int) -> *mut TlExpr {
+    expr(Expr::Const(Value::Bool(v != 0)))
+}
+
+#[no_mangle]
+pub extern "C" fn tl_expr_unit() -> *mut TlExpr {
+    expr(Expr::Const(Value::Unit))
+}
+
+#[no_mangle]
+pub extern "C" fn tl_expr_float(v: f64) -> *mut TlExpr {
+    expr(Expr::Const(Value::Float(v.to_bits())))
+}
+
+// Null if `v` isn't a UTF-8 string.
+#[no_mangle]
+pub unsafe extern "C" fn tl_expr_str(v: *const c_char) -> *mut TlExpr {
+    match str_arg(v) {
+        Ok(v) => expr(Expr::Const(Value::Str(v.to_string()))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn tl_expr_var(id: i32) -> *mut TlExpr {
+    expr(Expr::Var(id))
+}
+
+// A node of `kind`, e.g. "add", binding `binds` over `children`, which it
+// takes ownership of even if it fails. Null if `kind` or a child is null or
+// `kind` isn't UTF-8.
+#[no_mangle]
+pub unsafe extern "C" fn tl_expr_node(kind: *const c_char, binds: *const i32, n_binds: usize,
+                                      children: *const *mut TlExpr, n_children: usize) -> *mut TlExpr {
+    let children: Vec<*mut TlExpr> = if n_children == 0 {
+        vec![]
+    } else {
+        slice::from_raw_parts(children, n_children).to_vec()
+    };
+    if children.iter().any(|c| c.is_null()) {
+        for c in children {
+            tl_expr_free(c);
+        }
+        return ptr::null_mut();
+    }
+    let children: Vec<Expr> = children.into_iter().map(|c| Box::from_raw(c).0).collect();
+    let kind = match str_arg(kind) {
+        Ok(kind) => kind,
+        Err(_) => return ptr::null_mut(),
+    };
+    let binds = if n_binds == 0 { vec![] } else { slice::from_raw_parts(binds, n_binds).to_vec() };
+    expr(Expr::Node {
+        kind: kind.to_string(),
+        binds,
+        children,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_expr_free(e: *mut TlExpr) {
+    if !e.is_null() {
+        drop(Box::from_raw(e));
+    }
+}
+
+// Checks `e` into a program whose inputs are the variables `input_ids`, of
+// the TL_* types `input_types`. Inputs start as their type's default.
+#[no_mangle]
+pub unsafe extern "C" fn tl_program_new(e: *const TlExpr, input_ids: *const i32, input_types: *const c_int,
+                                        n_inputs: usize, out: *mut *mut TlProgram) -> c_int {
+    if e.is_null() || out.is_null() || (n_inputs > 0 && (input_ids.is_null() || input_types.is_null())) {
+        return fail(TL_ERR_NULL, "null argument");
+    }
+    let mut inputs = Vec::new();
+    for i in 0..n_inputs {
+        match ty_of(*input_types.add(i)) {
+            Some(ty) => inputs.push((*input_ids.add(i), ty)),
+            None => return fail(TL_ERR_TYPE, &format!("input {} has no type {}", i, *input_types.add(i))),
+        }
+    }
+    let (typed, binders) = match check_open(&(*e).0, &inputs) {
+        Ok(checked) => checked,
+        Err(err) => return fail(TL_ERR_CHECK, &err.to_string()),
+    };
+    let inputs = inputs.into_iter().zip(binders).map(|((id, ty), b)| (id, ty, b)).collect();
+    *out = Box::into_raw(box TlProgram {
+        typed,
+        inputs,
+        staged: None,
+    });
+    TL_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_program_free(p: *mut TlProgram) {
+    if !p.is_null() {
+        drop(Box::from_raw(p));
+    }
+}
+
+unsafe fn set(p: *mut TlProgram, id: i32, ty: Ty, v: &::std::any::Any) -> c_int {
+    if p.is_null() {
+        return fail(TL_ERR_NULL, "null program");
+    }
+    match (*p).inputs.iter().find(|input| input.0 == id) {
+        Some(&(_, ref input_ty, ref binder)) if *input_ty == ty => {
+            binder.bind(v);
+            TL_OK
+        }
+        Some(&(_, ref input_ty, _)) => fail(TL_ERR_TYPE, &format!("input {} is {}, not {}", id, input_ty, ty)),
+        None => fail(TL_ERR_NO_INPUT, &format!("the program has no input {}", id)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_program_set_num(p: *mut TlProgram, id: i32, v: i64) -> c_int {
+    set(p, id, Ty::Num, &NumVal { v })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_program_set_bool(p: *mut TlProgram, id: i32, v: c_int) -> c_int {
+    set(p, id, Ty::Bool, &BoolVal { v: v != 0 })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_program_set_float(p: *mut TlProgram, id: i32, v: f64) -> c_int {
+    set(p, id, Ty::Float, &FloatVal { v })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_program_set_str(p: *mut TlProgram, id: i32, v: *const c_char) -> c_int {
+    match str_arg(v) {
+        Ok(v) => set(p, id, Ty::Str, &StrVal { v: v.to_string() }),
+        Err(code) => code,
+    }
+}
+
+// Stages the program, so later runs run the staged form. Staging again
+// throws away the previous staged form.
+#[no_mangle]
+pub unsafe extern "C" fn tl_program_stage(p: *mut TlProgram) -> c_int {
+    if p.is_null() {
+        return fail(TL_ERR_NULL, "null program");
+    }
+    (*p).staged = Some((*p).typed.stage_eval());
+    TL_OK
+}
+
+unsafe fn run(p: *mut TlProgram, out: *mut *mut TlValue, staged: bool) -> c_int {
+    if p.is_null() || out.is_null() {
+        return fail(TL_ERR_NULL, "null argument");
+    }
+    let p = &*p;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match p.staged {
+        Some(ref staged_exp) if staged => staged_exp(),
+        _ => p.typed.eval_interpreted(),
+    }));
+    match result {
+        Ok(v) => {
+            *out = Box::into_raw(box TlValue(v));
+            TL_OK
+        }
+        Err(err) => {
+            let msg = err.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| err.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            fail(TL_ERR_RUN, &msg)
+        }
+    }
+}
+
+// Runs the staged form, staging the program first if it hasn't been.
+#[no_mangle]
+pub unsafe extern "C" fn tl_program_run(p: *mut TlProgram, out: *mut *mut TlValue) -> c_int {
+    if !p.is_null() && (*p).staged.is_none() {
+        tl_program_stage(p);
+    }
+    run(p, out, true)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_program_interpret(p: *mut TlProgram, out: *mut *mut TlValue) -> c_int {
+    run(p, out, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_value_free(v: *mut TlValue) {
+    if !v.is_null() {
+        drop(Box::from_raw(v));
+    }
+}
+
+// The value's TL_* type, or -1 for a null value.
+#[no_mangle]
+pub unsafe extern "C" fn tl_value_type(v: *const TlValue) -> c_int {
+    if v.is_null() {
+        return -1;
+    }
+    match (*v).0 {
+        DynVal::Num(_) => TL_NUM,
+        DynVal::Bool(_) => TL_BOOL,
+        DynVal::Unit => TL_UNIT,
+        DynVal::Str(_) => TL_STR,
+        DynVal::Float(_) => TL_FLOAT,
+        DynVal::Record(_) => TL_RECORD,
+        DynVal::Variant(..) => TL_VARIANT,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_value_num(v: *const TlValue, out: *mut i64) -> c_int {
+    if v.is_null() || out.is_null() {
+        return fail(TL_ERR_NULL, "null argument");
+    }
+    match (*v).0 {
+        DynVal::Num(n) => {
+            *out = n;
+            TL_OK
+        }
+        ref other => fail(TL_ERR_TYPE, &format!("the value is {}, not num", other.ty())),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_value_bool(v: *const TlValue, out: *mut c_int) -> c_int {
+    if v.is_null() || out.is_null() {
+        return fail(TL_ERR_NULL, "null argument");
+    }
+    match (*v).0 {
+        DynVal::Bool(b) => {
+            *out = b as c_int;
+            TL_OK
+        }
+        ref other => fail(TL_ERR_TYPE, &format!("the value is {}, not bool", other.ty())),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tl_value_float(v: *const TlValue, out: *mut f64) -> c_int {
+    if v.is_null() || out.is_null() {
+        return fail(TL_ERR_NULL, "null argument");
+    }
+    match (*v).0 {
+        DynVal::Float(x) => {
+            *out = x;
+            TL_OK
+        }
+        ref other => fail(TL_ERR_TYPE, &format!("the value is {}, not float", other.ty())),
+    }
+}
+
+// A str value's contents; any other value as it displays, e.g.
+// `{x: 1, y: true}`. Null for a null value or a string holding NUL.
+#[no_mangle]
+pub unsafe extern "C" fn tl_value_to_string(v: *const TlValue) -> *mut c_char {
+    if v.is_null() {
+        return ptr::null_mut();
+    }
+    let s = match (*v).0 {
+        DynVal::Str(ref s) => s.clone(),
+        ref other => other.to_string(),
+    };
+    CString::new(s).map(|s| s.into_raw()).unwrap_or(ptr::null_mut())
+}
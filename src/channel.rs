@@ -0,0 +1,354 @@
+use std::any::Any;
+use std::collections::{BTreeMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use {Exp, StagedExp, EvalContext, ChannelVal, NumVal, BoolVal, UnitVal, StrVal, FloatVal};
+use check::{CheckError, Scalar, Ty, Typed};
+use dynamic::DynVal;
+use ops::E;
+use reify::{Expr, Value, node};
+use sandbox;
+
+// Message passing between a program and the tasks it spawns, or between
+// tasks: `send` puts a value on a channel and `recv` takes the oldest one
+// off it. Channels are unbounded, so a send never blocks; a recv blocks
+// until there's a value to take, for good if none is ever sent. Under a
+// sandbox a blocked recv charges a step each time it wakes to look, at
+// least every `sandbox::WAIT`, and reads the clock, so the sandbox's step
+// budget or timeout stops it. Each value goes to exactly one recv, and
+// values from one sender arrive in the order they were sent.
+//
+// A channel can be spawned with, as an input holding it, and the spawned
+// task's sends and receives go to the same channel. In a reified tree a
+// channel is `channel(id, type)`, naming a channel of this process by its
+// id; it can only be loaded while the channel still exists.
+
+pub struct Channel {
+    id: u64,
+    queue: Mutex<VecDeque<DynVal>>,
+    ready: Condvar,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Every channel of the process, so one named in a tree can be found from
+// any thread.
+static CHANNELS: Mutex<BTreeMap<u64, Weak<Channel>>> = Mutex::new(BTreeMap::new());
+
+impl Channel {
+    pub fn new() -> Arc<Channel> {
+        let chan = Arc::new(Channel {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            queue: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+        });
+        CHANNELS.lock().unwrap().insert(chan.id, Arc::downgrade(&chan));
+        chan
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn send(&self, v: DynVal) {
+        self.queue.lock().unwrap().push_back(v);
+        self.ready.notify_one();
+    }
+
+    fn try_recv(&self) -> Option<DynVal> {
+        self.queue.lock().unwrap().pop_front()
+    }
+
+    fn recv(&self) -> DynVal {
+        loop {
+            {
+                let queue = self.queue.lock().unwrap();
+                let (mut queue, _) = self.ready.wait_timeout_while(queue, sandbox::WAIT, |q| q.is_empty()).unwrap();
+                if let Some(v) = queue.pop_front() {
+                    return v;
+                }
+            }
+            // Not holding the lock, as this unwinds when the sandbox's
+            // budget runs out.
+            sandbox::wait();
+        }
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        CHANNELS.lock().unwrap().remove(&self.id);
+    }
+}
+
+// The types a channel can carry.
+pub trait Message: Scalar {
+    fn ty() -> Ty;
+    fn typed(exp: Box<Exp<Output=Self>>) -> Typed;
+}
+
+macro_rules! message {
+    ($t:ident, $variant:ident) => {
+        impl Message for $t {
+            fn ty() -> Ty {
+                Ty::$variant
+            }
+
+            fn typed(exp: Box<Exp<Output=Self>>) -> Typed {
+                Typed::$variant(exp)
+            }
+        }
+    }
+}
+
+message!(NumVal, Num);
+message!(BoolVal, Bool);
+message!(UnitVal, Unit);
+message!(StrVal, Str);
+message!(FloatVal, Float);
+
+impl<T: Message> ChannelVal<T> {
+    pub fn new() -> ChannelVal<T> {
+        ChannelVal::default()
+    }
+
+    fn of(chan: Arc<Channel>) -> ChannelVal<T> {
+        ChannelVal {
+            chan,
+            _elem: PhantomData,
+        }
+    }
+
+    // For the host to talk to programs.
+    pub fn send(&self, v: T) {
+        self.chan.send(DynVal::of(&v, &T::ty()));
+    }
+
+    pub fn recv(&self) -> T {
+        self.chan.recv().downcast::<T>().expect("a channel's value is of its type")
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        self.chan.try_recv().map(|v| v.downcast::<T>().expect("a channel's value is of its type"))
+    }
+}
+
+fn reify_channel<T: Message>(chan: &ChannelVal<T>) -> Expr {
+    node("channel", vec![Expr::Const(Value::Num(chan.chan.id as i64)), Expr::Const(Value::Str(T::name().to_string()))])
+}
+
+// The tree for the channel `v` holds, and the channel, if it holds one.
+pub fn channel_of(v: &Any) -> Option<(Expr, Arc<Channel>)> {
+    fn of<T: Message>(v: &Any) -> Option<(Expr, Arc<Channel>)> {
+        v.downcast_ref::<ChannelVal<T>>().map(|chan| (reify_channel(chan), chan.chan.clone()))
+    }
+    of::<NumVal>(v).or_else(|| of::<BoolVal>(v)).or_else(|| of::<UnitVal>(v))
+        .or_else(|| of::<StrVal>(v)).or_else(|| of::<FloatVal>(v))
+}
+
+#[derive(Clone)]
+pub struct ChannelExp<T: 'static> {
+    chan: ChannelVal<T>,
+}
+
+pub struct ChannelStagedExp<T: 'static> {
+    chan: ChannelVal<T>,
+}
+
+impl<T: Message> Exp for ChannelExp<T> {
+    type Output = ChannelVal<T>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ChannelStagedExp {
+            chan: self.chan.clone(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.chan.clone()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        reify_channel(&self.chan)
+    }
+}
+
+impl<T: Message> StagedExp for ChannelStagedExp<T> {
+    type Output = ChannelVal<T>;
+
+    fn run(&self, _ctx: &EvalContext) -> Self::Output {
+        self.chan.clone()
+    }
+}
+
+#[derive(Clone)]
+pub struct SendExp<T: 'static> {
+    chan: Box<Exp<Output=ChannelVal<T>>>,
+    value: Box<Exp<Output=T>>,
+}
+
+pub struct SendStagedExp<T: 'static> {
+    staged_chan: Box<StagedExp<Output=ChannelVal<T>>>,
+    staged_value: Box<StagedExp<Output=T>>,
+}
+
+impl<T: Message> Exp for SendExp<T> {
+    type Output = UnitVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box SendStagedExp {
+            staged_chan: self.chan.stage(),
+            staged_value: self.value.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.chan.interpret().send(self.value.interpret());
+        UnitVal
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("send", vec![self.chan.reify(), self.value.reify()])
+    }
+}
+
+impl<T: Message> StagedExp for SendStagedExp<T> {
+    type Output = UnitVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_chan.run(ctx).send(self.staged_value.run(ctx));
+        UnitVal
+    }
+}
+
+#[derive(Clone)]
+pub struct RecvExp<T: 'static> {
+    chan: Box<Exp<Output=ChannelVal<T>>>,
+}
+
+pub struct RecvStagedExp<T: 'static> {
+    staged_chan: Box<StagedExp<Output=ChannelVal<T>>>,
+}
+
+impl<T: Message> Exp for RecvExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box RecvStagedExp {
+            staged_chan: self.chan.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.chan.interpret().recv()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("recv", vec![self.chan.reify()])
+    }
+}
+
+impl<T: Message> StagedExp for RecvStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_chan.run(ctx).recv()
+    }
+}
+
+pub fn channel_exp<T: Message>(chan: ChannelVal<T>) -> ChannelExp<T> {
+    ChannelExp {
+        chan,
+    }
+}
+
+pub fn send_exp<T: Message>(chan: Box<Exp<Output=ChannelVal<T>>>, value: Box<Exp<Output=T>>) -> SendExp<T> {
+    SendExp {
+        chan,
+        value,
+    }
+}
+
+pub fn recv_exp<T: Message>(chan: Box<Exp<Output=ChannelVal<T>>>) -> RecvExp<T> {
+    RecvExp {
+        chan,
+    }
+}
+
+impl<T: Message> E<ChannelVal<T>> {
+    pub fn send<V: Into<E<T>>>(self, value: V) -> E<UnitVal> {
+        E::new(send_exp(self.0, value.into().0))
+    }
+
+    pub fn recv(self) -> E<T> {
+        E::new(recv_exp(self.0))
+    }
+}
+
+impl<T: Message> From<ChannelVal<T>> for E<ChannelVal<T>> {
+    fn from(chan: ChannelVal<T>) -> E<ChannelVal<T>> {
+        E::new(channel_exp(chan))
+    }
+}
+
+// The channel `expr` names, for `check`.
+fn lookup(expr: &Expr) -> Result<(Arc<Channel>, String), CheckError> {
+    if let Expr::Node { ref kind, ref children, .. } = *expr {
+        if let (&"channel", &[Expr::Const(Value::Num(id)), Expr::Const(Value::Str(ref ty))]) = (&&kind[..], &children[..]) {
+            let chan = CHANNELS.lock().unwrap().get(&(id as u64)).and_then(Weak::upgrade);
+            return match chan {
+                Some(chan) => Ok((chan, ty.clone())),
+                None => Err(CheckError::new(format!("channel {} doesn't exist", id))),
+            };
+        }
+    }
+    Err(CheckError::new("a channel must be named by a channel node".to_string()))
+}
+
+fn check_send_of<T: Message>(chan: Arc<Channel>, value: Typed) -> Result<Typed, CheckError> {
+    match T::untyped(value) {
+        Ok(value) => Ok(Typed::Unit(box send_exp(box channel_exp(ChannelVal::<T>::of(chan)), value))),
+        Err(other) => Err(CheckError::new(format!("can't send {} on a channel of {}", other.ty(), T::name()))),
+    }
+}
+
+fn check_recv_of<T: Message>(chan: Arc<Channel>) -> Typed {
+    T::typed(box recv_exp(box channel_exp(ChannelVal::<T>::of(chan))))
+}
+
+// A send to the channel `chan` names of `value`, for `check`.
+pub fn check_send(chan: &Expr, value: Typed) -> Result<Typed, CheckError> {
+    let (chan, ty) = lookup(chan)?;
+    match &ty[..] {
+        "num" => check_send_of::<NumVal>(chan, value),
+        "bool" => check_send_of::<BoolVal>(chan, value),
+        "unit" => check_send_of::<UnitVal>(chan, value),
+        "str" => check_send_of::<StrVal>(chan, value),
+        "float" => check_send_of::<FloatVal>(chan, value),
+        _ => Err(CheckError::new(format!("no channels carry {}", ty))),
+    }
+}
+
+// A receive from the channel `chan` names, for `check`.
+pub fn check_recv(chan: &Expr) -> Result<Typed, CheckError> {
+    let (chan, ty) = lookup(chan)?;
+    match &ty[..] {
+        "num" => Ok(check_recv_of::<NumVal>(chan)),
+        "bool" => Ok(check_recv_of::<BoolVal>(chan)),
+        "unit" => Ok(check_recv_of::<UnitVal>(chan)),
+        "str" => Ok(check_recv_of::<StrVal>(chan)),
+        "float" => Ok(check_recv_of::<FloatVal>(chan)),
+        _ => Err(CheckError::new(format!("no channels carry {}", ty))),
+    }
+}
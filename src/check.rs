@@ -0,0 +1,1154 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use {Exp, EvalContext, VariableExp, NumVal, BoolVal, UnitVal, StrVal, FloatVal, RecordVal, VariantVal};
+use {unit_exp, add_exp, sub_exp, mul_exp, div_exp, pow_exp, less_than_exp, partial_less_than_exp, if_exp, set_exp, while_exp, seq_exp};
+use bits::{bit_and_exp, bit_or_exp, bit_xor_exp, bit_not_exp, shl_exp, shr_exp};
+use assert::assert_exp;
+use atomic::{self, fetch_add_exp, cas_exp};
+use builder::{bound_let_exp, bound_for_exp};
+use channel;
+use dynamic::DynVal;
+use limits::{LimitError, Limits};
+use effects::{print_exp, read_exp, rand_exp};
+use exhaustive::{Shape, useful, missing, show};
+use observe::observed_exp_as;
+use patterns::{Binder, Pattern, pattern_match_exp};
+use records::{record_exp, field_get_exp, with_exp};
+use registry;
+use reify::{Expr, Value, value_of};
+use span::{Locator, node_paths};
+use strings::{concat_exp, str_eq_exp, contains_exp, str_len_exp, substring_exp, format_exp, parse_template};
+use switch::switch_exp;
+use variants::{variant_exp, match_exp, MatchExp};
+
+// A checked program whose type is only known at run time.
+pub enum Typed {
+    Num(Box<Exp<Output=NumVal>>),
+    Bool(Box<Exp<Output=BoolVal>>),
+    Unit(Box<Exp<Output=UnitVal>>),
+    Str(Box<Exp<Output=StrVal>>),
+    Float(Box<Exp<Output=FloatVal>>),
+    // With the record's fields and their types.
+    Record(Box<Exp<Output=RecordVal>>, Vec<(String, Ty)>),
+    // With the cases the variant may be and their payloads' types.
+    Variant(Box<Exp<Output=VariantVal>>, Vec<(String, Ty)>),
+}
+
+// The type of a checked expression. Record fields and variant cases are
+// sorted by name, so types with the same fields or cases are equal whatever
+// order they were written in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ty {
+    Num,
+    Bool,
+    Unit,
+    Str,
+    Float,
+    Record(Vec<(String, Ty)>),
+    Variant(Vec<(String, Ty)>),
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Ty::Num => write!(f, "num"),
+            Ty::Bool => write!(f, "bool"),
+            Ty::Unit => write!(f, "unit"),
+            Ty::Str => write!(f, "str"),
+            Ty::Float => write!(f, "float"),
+            Ty::Record(ref fields) => {
+                write!(f, "{{")?;
+                for (i, &(ref name, ref ty)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", name, ty)?;
+                }
+                write!(f, "}}")
+            }
+            Ty::Variant(ref cases) => {
+                write!(f, "<")?;
+                for (i, &(ref tag, ref ty)) in cases.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " | ")?;
+                    }
+                    write!(f, "{}: {}", tag, ty)?;
+                }
+                write!(f, ">")
+            }
+        }
+    }
+}
+
+// The smallest type both `a` and `b` fit: a variant may be any case either
+// may be. None if there isn't one.
+pub fn join(a: &Ty, b: &Ty) -> Option<Ty> {
+    match (a, b) {
+        (&Ty::Record(ref fa), &Ty::Record(ref fb)) => {
+            if fa.len() != fb.len() {
+                return None;
+            }
+            let mut fields = Vec::new();
+            for (fa, fb) in fa.iter().zip(fb) {
+                if fa.0 != fb.0 {
+                    return None;
+                }
+                fields.push((fa.0.clone(), join(&fa.1, &fb.1)?));
+            }
+            Some(Ty::Record(fields))
+        }
+        (&Ty::Variant(ref ca), &Ty::Variant(ref cb)) => {
+            let mut cases = ca.clone();
+            for cb in cb {
+                match cases.binary_search_by(|c| c.0.cmp(&cb.0)) {
+                    Ok(i) => cases[i].1 = join(&cases[i].1, &cb.1)?,
+                    Err(i) => cases.insert(i, cb.clone()),
+                }
+            }
+            Some(Ty::Variant(cases))
+        }
+        (a, b) if a == b => Some(a.clone()),
+        _ => None,
+    }
+}
+
+// `typed` as the wider type `ty`, which must be a join of its own.
+fn retype(typed: Typed, ty: Ty) -> Typed {
+    match (typed, ty) {
+        (Typed::Record(exp, _), Ty::Record(fields)) => Typed::Record(exp, fields),
+        (Typed::Variant(exp, _), Ty::Variant(cases)) => Typed::Variant(exp, cases),
+        (typed, _) => typed,
+    }
+}
+
+#[derive(Clone)]
+enum Var {
+    Num(VariableExp<NumVal>),
+    Bool(VariableExp<BoolVal>),
+    Unit(VariableExp<UnitVal>),
+    Str(VariableExp<StrVal>),
+    Float(VariableExp<FloatVal>),
+    Record(VariableExp<RecordVal>, Vec<(String, Ty)>),
+    Variant(VariableExp<VariantVal>, Vec<(String, Ty)>),
+}
+
+impl Var {
+    fn fresh(ty: &Ty) -> Var {
+        match *ty {
+            Ty::Num => Var::Num(VariableExp::fresh()),
+            Ty::Bool => Var::Bool(VariableExp::fresh()),
+            Ty::Unit => Var::Unit(VariableExp::fresh()),
+            Ty::Str => Var::Str(VariableExp::fresh()),
+            Ty::Float => Var::Float(VariableExp::fresh()),
+            Ty::Record(ref fields) => Var::Record(VariableExp::fresh(), fields.clone()),
+            Ty::Variant(ref cases) => Var::Variant(VariableExp::fresh(), cases.clone()),
+        }
+    }
+
+    fn binder(&self) -> Box<Binder> {
+        match *self {
+            Var::Num(ref v) => box v.clone(),
+            Var::Bool(ref v) => box v.clone(),
+            Var::Unit(ref v) => box v.clone(),
+            Var::Str(ref v) => box v.clone(),
+            Var::Float(ref v) => box v.clone(),
+            Var::Record(ref v, _) => box v.clone(),
+            Var::Variant(ref v, _) => box v.clone(),
+        }
+    }
+
+    fn ty(&self) -> Ty {
+        match *self {
+            Var::Num(_) => Ty::Num,
+            Var::Bool(_) => Ty::Bool,
+            Var::Unit(_) => Ty::Unit,
+            Var::Str(_) => Ty::Str,
+            Var::Float(_) => Ty::Float,
+            Var::Record(_, ref fields) => Ty::Record(fields.clone()),
+            Var::Variant(_, ref cases) => Ty::Variant(cases.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckError {
+    pub msg: String,
+    // Set when the program was rejected for going over a limit.
+    pub limit: Option<LimitError>,
+    // The node the error is about, as the child indexes leading to it from
+    // the root, when it's a particular node.
+    pub path: Option<Vec<usize>>,
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl CheckError {
+    pub fn new(msg: String) -> CheckError {
+        CheckError {
+            msg,
+            limit: None,
+            path: None,
+        }
+    }
+}
+
+fn err<T>(msg: String) -> Result<T, CheckError> {
+    Err(CheckError::new(msg))
+}
+
+// The value types a loaded program can compute with.
+pub trait Scalar: 'static+Clone+Default+fmt::Display {
+    fn name() -> &'static str;
+    fn untyped(typed: Typed) -> Result<Box<Exp<Output=Self>>, Typed>;
+}
+
+macro_rules! scalar {
+    ($t:ident, $variant:ident, $name:expr) => {
+        impl Scalar for $t {
+            fn name() -> &'static str {
+                $name
+            }
+
+            fn untyped(typed: Typed) -> Result<Box<Exp<Output=Self>>, Typed> {
+                match typed {
+                    Typed::$variant(exp) => Ok(exp),
+                    other => Err(other),
+                }
+            }
+        }
+    }
+}
+
+scalar!(NumVal, Num, "num");
+scalar!(BoolVal, Bool, "bool");
+scalar!(UnitVal, Unit, "unit");
+scalar!(StrVal, Str, "str");
+scalar!(FloatVal, Float, "float");
+
+// Any record; callers compare the fields' types where they matter.
+impl Scalar for RecordVal {
+    fn name() -> &'static str {
+        "a record"
+    }
+
+    fn untyped(typed: Typed) -> Result<Box<Exp<Output=Self>>, Typed> {
+        match typed {
+            Typed::Record(exp, _) => Ok(exp),
+            other => Err(other),
+        }
+    }
+}
+
+// Any variant; callers compare the cases' types where they matter.
+impl Scalar for VariantVal {
+    fn name() -> &'static str {
+        "a variant"
+    }
+
+    fn untyped(typed: Typed) -> Result<Box<Exp<Output=Self>>, Typed> {
+        match typed {
+            Typed::Variant(exp, _) => Ok(exp),
+            other => Err(other),
+        }
+    }
+}
+
+// Runs `$body` with `$x` bound to the expression inside `$typed`, whatever
+// its type. In the second form `$wrap` makes a Typed of an expression of
+// that same type, and `$var` a Var of a variable of it.
+macro_rules! each_typed {
+    ($typed:expr, $x:pat => $body:expr) => {
+        each_typed!($typed, $x, _wrap, _var => $body)
+    };
+    ($typed:expr, $x:pat, $wrap:ident, $var:ident => $body:expr) => {
+        match $typed {
+            Typed::Num($x) => { let $wrap = Typed::Num; let $var = Var::Num; $body }
+            Typed::Bool($x) => { let $wrap = Typed::Bool; let $var = Var::Bool; $body }
+            Typed::Unit($x) => { let $wrap = Typed::Unit; let $var = Var::Unit; $body }
+            Typed::Str($x) => { let $wrap = Typed::Str; let $var = Var::Str; $body }
+            Typed::Float($x) => { let $wrap = Typed::Float; let $var = Var::Float; $body }
+            Typed::Record($x, ref ty) => {
+                let $wrap = |exp: Box<Exp<Output=RecordVal>>| Typed::Record(exp, ty.clone());
+                let $var = |var: VariableExp<RecordVal>| Var::Record(var, ty.clone());
+                $body
+            }
+            Typed::Variant($x, ref ty) => {
+                let $wrap = |exp: Box<Exp<Output=VariantVal>>| Typed::Variant(exp, ty.clone());
+                let $var = |var: VariableExp<VariantVal>| Var::Variant(var, ty.clone());
+                $body
+            }
+        }
+    }
+}
+
+// A Typed of type `$ty`, from `$body` built with `$t` the matching value
+// type.
+macro_rules! by_ty {
+    ($ty:expr, $t:ident => $body:expr) => {
+        match $ty {
+            Ty::Num => { type $t = NumVal; Typed::Num($body) }
+            Ty::Bool => { type $t = BoolVal; Typed::Bool($body) }
+            Ty::Unit => { type $t = UnitVal; Typed::Unit($body) }
+            Ty::Str => { type $t = StrVal; Typed::Str($body) }
+            Ty::Float => { type $t = FloatVal; Typed::Float($body) }
+            Ty::Record(fields) => { type $t = RecordVal; Typed::Record($body, fields) }
+            Ty::Variant(cases) => { type $t = VariantVal; Typed::Variant($body, cases) }
+        }
+    }
+}
+
+fn add_arm<R: 'static+Clone>(m: MatchExp<R>, tag: &str, var: Var, body: Box<Exp<Output=R>>) -> MatchExp<R> {
+    match var {
+        Var::Num(v) => m.bound_arm(tag, v, body),
+        Var::Bool(v) => m.bound_arm(tag, v, body),
+        Var::Unit(v) => m.bound_arm(tag, v, body),
+        Var::Str(v) => m.bound_arm(tag, v, body),
+        Var::Float(v) => m.bound_arm(tag, v, body),
+        Var::Record(v, _) => m.bound_arm(tag, v, body),
+        Var::Variant(v, _) => m.bound_arm(tag, v, body),
+    }
+}
+
+fn build_match<R: Scalar>(scrutinee: Box<Exp<Output=VariantVal>>, arms: Vec<(String, Var, Typed)>,
+                          otherwise: Option<Typed>) -> Result<Box<Exp<Output=R>>, CheckError> {
+    let mut m = match_exp(scrutinee);
+    for (tag, var, body) in arms {
+        m = add_arm(m, &tag, var, expect(body, "a match arm")?);
+    }
+    if let Some(e) = otherwise {
+        m = m.otherwise(expect(e, "a match arm")?);
+    }
+    Ok(box m)
+}
+
+fn binder(var: Var) -> Box<Binder> {
+    match var {
+        Var::Num(v) => box v,
+        Var::Bool(v) => box v,
+        Var::Unit(v) => box v,
+        Var::Str(v) => box v,
+        Var::Float(v) => box v,
+        Var::Record(v, _) => box v,
+        Var::Variant(v, _) => box v,
+    }
+}
+
+type Case = (Pattern, Option<Box<Exp<Output=BoolVal>>>, Typed);
+
+fn build_pmatch<S: 'static+Clone, R: Scalar>(scrutinee: Box<Exp<Output=S>>, cases: Vec<Case>) -> Result<Box<Exp<Output=R>>, CheckError> {
+    let mut m = pattern_match_exp(scrutinee);
+    for (pattern, guard, body) in cases {
+        m = m.case(pattern, guard, expect(body, "a match arm")?);
+    }
+    Ok(box m)
+}
+
+fn build_switch<R: Scalar>(scrutinee: Box<Exp<Output=NumVal>>, cases: Vec<(i64, Typed)>,
+                           default: Typed) -> Result<Box<Exp<Output=R>>, CheckError> {
+    let mut s = switch_exp(scrutinee, expect(default, "a switch case")?);
+    for (k, body) in cases {
+        s = s.case(k, expect(body, "a switch case")?);
+    }
+    Ok(box s)
+}
+
+fn expect<T: Scalar>(typed: Typed, what: &str) -> Result<Box<Exp<Output=T>>, CheckError> {
+    T::untyped(typed).or_else(|other| err(format!("{} must be {}, not {}", what, T::name(), other.ty())))
+}
+
+impl Typed {
+    pub fn ty(&self) -> Ty {
+        match *self {
+            Typed::Num(_) => Ty::Num,
+            Typed::Bool(_) => Ty::Bool,
+            Typed::Unit(_) => Ty::Unit,
+            Typed::Str(_) => Ty::Str,
+            Typed::Float(_) => Ty::Float,
+            Typed::Record(_, ref fields) => Ty::Record(fields.clone()),
+            Typed::Variant(_, ref cases) => Ty::Variant(cases.clone()),
+        }
+    }
+
+    pub fn run(&self) -> Value {
+        each_typed!(*self, ref exp => value_of(&exp.stage().run(&EvalContext::new()) as &Any))
+    }
+
+    pub fn interpret(&self) -> Value {
+        each_typed!(*self, ref exp => value_of(&exp.interpret() as &Any))
+    }
+
+    // Like `run` and `interpret`, but for values of any type the program
+    // can have, records and variants included.
+    pub fn eval(&self) -> DynVal {
+        let ty = self.ty();
+        each_typed!(*self, ref exp => DynVal::of(&exp.stage().run(&EvalContext::new()), &ty))
+    }
+
+    pub fn eval_interpreted(&self) -> DynVal {
+        let ty = self.ty();
+        each_typed!(*self, ref exp => DynVal::of(&exp.interpret(), &ty))
+    }
+
+    pub fn eval_compiled(&self) -> DynVal {
+        let ty = self.ty();
+        each_typed!(*self, ref exp => DynVal::of(&(exp.stage_compiled())(), &ty))
+    }
+
+    // The staged program, as something to run for its value.
+    pub fn stage_eval(&self) -> Box<Fn() -> DynVal> {
+        let ty = self.ty();
+        each_typed!(*self, ref exp => {
+            let staged = exp.stage();
+            box move || DynVal::of(&staged.run(&EvalContext::new()), &ty)
+        })
+    }
+
+    // The same, compiled to closures.
+    pub fn compile_eval(&self) -> Box<Fn() -> DynVal> {
+        let ty = self.ty();
+        each_typed!(*self, ref exp => {
+            let compiled = exp.stage_compiled();
+            box move || DynVal::of(&compiled(), &ty)
+        })
+    }
+
+    pub fn reify(&self) -> Expr {
+        each_typed!(*self, ref exp => exp.reify())
+    }
+
+    // The program as an expression of type T, e.g. `downcast::<NumVal>()`,
+    // or an error naming the type it has instead.
+    pub fn downcast<T: Scalar>(self) -> Result<Box<Exp<Output=T>>, CheckError> {
+        expect(self, "the program")
+    }
+}
+
+struct Checker {
+    // Variables in scope, by the number their binder gave them.
+    env: HashMap<i32, Var>,
+    // Whether to wrap each node for `observe::observing`.
+    observed: bool,
+    // The innermost node that failed to check.
+    failed: Option<*const Expr>,
+    // The copy of each node an observed node reports, and the node.
+    copies: Vec<(*const Expr, *const Expr)>,
+}
+
+impl Checker {
+    fn args<'a>(&self, kind: &str, args: &'a [Expr], n: usize) -> Result<&'a [Expr], CheckError> {
+        if args.len() == n {
+            Ok(args)
+        } else {
+            err(format!("{} takes {} arguments, not {}", kind, n, args.len()))
+        }
+    }
+
+    fn lookup(&self, id: i32) -> Result<Var, CheckError> {
+        match self.env.get(&id) {
+            Some(var) => Ok(var.clone()),
+            None => err(format!("variable {} is not bound", id)),
+        }
+    }
+
+    // Checks `body` with `id` bound to `var`, restoring any outer binding.
+    fn with_var<R>(&mut self, id: i32, var: Var, body: &Fn(&mut Checker) -> R) -> R {
+        let prev = self.env.insert(id, var);
+        let result = body(self);
+        match prev {
+            Some(prev) => self.env.insert(id, prev),
+            None => self.env.remove(&id),
+        };
+        result
+    }
+
+    fn with_vars<R>(&mut self, vars: &[(i32, Var)], body: &Fn(&mut Checker) -> R) -> R {
+        match vars.split_first() {
+            Some((var, rest)) => self.with_var(var.0, var.1.clone(), &|c| c.with_vars(rest, body)),
+            None => body(self),
+        }
+    }
+
+    fn check_let<T: Scalar>(&mut self, id: i32, init: Box<Exp<Output=T>>, bound: &Fn(VariableExp<T>) -> Var,
+                            body: &Expr) -> Result<Typed, CheckError> {
+        let var = VariableExp::<T>::fresh();
+        let body = self.with_var(id, bound(var.clone()), &|c| c.check(body))?;
+        Ok(each_typed!(body, body, wrap, _var => wrap(box bound_let_exp(var, init, body))))
+    }
+
+    fn join_arm(&self, ty: Option<Ty>, arm: &Typed) -> Result<Option<Ty>, CheckError> {
+        match ty {
+            None => Ok(Some(arm.ty())),
+            Some(ty) => match join(&ty, &arm.ty()) {
+                Some(joined) => Ok(Some(joined)),
+                None => err(format!("match arms differ: {} and {}", ty, arm.ty())),
+            },
+        }
+    }
+
+    fn name<'a>(&self, expr: &'a Expr) -> Result<&'a str, CheckError> {
+        match *expr {
+            Expr::Const(Value::Str(ref name)) => Ok(name),
+            _ => err("a field name must be a string".to_string()),
+        }
+    }
+
+    fn record(&mut self, expr: &Expr, what: &str) -> Result<(Box<Exp<Output=RecordVal>>, Vec<(String, Ty)>), CheckError> {
+        match self.check(expr)? {
+            Typed::Record(exp, fields) => Ok((exp, fields)),
+            other => err(format!("{} must be a record, not {}", what, other.ty())),
+        }
+    }
+
+    // Checks a pattern against the type of the value it matches, adding the
+    // variables it binds, which must be among `binds`, to `vars`. None if it
+    // names a case the value can never be.
+    // The guard, if any, and the body of a case.
+    fn case(&mut self, parts: &[Expr]) -> Result<(Option<Box<Exp<Output=BoolVal>>>, Typed), CheckError> {
+        let guard = if parts.len() == 3 {
+            Some(expect(self.check(&parts[1])?, "a match guard")?)
+        } else {
+            None
+        };
+        Ok((guard, self.check(&parts[parts.len() - 1])?))
+    }
+
+    fn pattern(&self, expr: &Expr, ty: &Ty, binds: &[i32], vars: &mut Vec<(i32, Var)>)
+               -> Result<Option<(Pattern, Shape)>, CheckError> {
+        let (kind, args) = match *expr {
+            Expr::Node { ref kind, ref binds, ref children } if binds.is_empty() => (&kind[..], &children[..]),
+            _ => return err("a match case must start with a pattern".to_string()),
+        };
+        match kind {
+            "pat_wild" => {
+                self.args(kind, args, 0)?;
+                Ok(Some((Pattern::Wild, Shape::Wild)))
+            }
+            "pat_bind" => {
+                let args = self.args(kind, args, 2)?;
+                let id = match args[0] {
+                    Expr::Var(id) | Expr::Bound(id) if binds.contains(&id) => id,
+                    _ => return err("a pattern can only bind the match's variables".to_string()),
+                };
+                if vars.iter().any(|v| v.0 == id) {
+                    return err(format!("variable {} is bound twice in a pattern", id));
+                }
+                let var = Var::fresh(ty);
+                vars.push((id, var.clone()));
+                Ok(self.pattern(&args[1], ty, binds, vars)?.map(|(p, shape)| (Pattern::Bind(binder(var), box p), shape)))
+            }
+            "pat_lit" => {
+                let args = self.args(kind, args, 1)?;
+                let (lit, lit_ty) = match args[0] {
+                    Expr::Const(ref v) => (v.clone(), match *v {
+                        Value::Num(_) => Ty::Num,
+                        Value::Bool(_) => Ty::Bool,
+                        Value::Unit => Ty::Unit,
+                        Value::Str(_) => Ty::Str,
+                        Value::Float(_) => Ty::Float,
+                        Value::Opaque => return err("the program has parts that can't be loaded".to_string()),
+                    }),
+                    _ => return err("a literal pattern must be a constant".to_string()),
+                };
+                if lit_ty != *ty {
+                    return err(format!("a {} pattern can't match {}", lit_ty, ty));
+                }
+                Ok(Some((Pattern::Lit(lit.clone()), Shape::Lit(lit))))
+            }
+            "pat_variant" => {
+                let args = self.args(kind, args, 2)?;
+                let tag = self.name(&args[0])?;
+                let case = match *ty {
+                    Ty::Variant(ref cases) => match cases.iter().find(|c| c.0 == tag) {
+                        Some(case) => case.1.clone(),
+                        None => return Ok(None),
+                    },
+                    _ => return err(format!("a variant pattern can't match {}", ty)),
+                };
+                Ok(self.pattern(&args[1], &case, binds, vars)?
+                    .map(|(p, shape)| (Pattern::Variant(tag.to_string(), box p), Shape::Variant(tag.to_string(), box shape))))
+            }
+            "pat_record" => {
+                let fields = match *ty {
+                    Ty::Record(ref fields) => fields,
+                    _ => return err(format!("a record pattern can't match {}", ty)),
+                };
+                if args.len() % 2 != 0 {
+                    return err("pat_record takes a name and a pattern for each field".to_string());
+                }
+                let mut pats: Vec<(String, Pattern)> = Vec::new();
+                let mut shapes = vec![Shape::Wild; fields.len()];
+                for pair in args.chunks(2) {
+                    let name = self.name(&pair[0])?;
+                    let i = match fields.iter().position(|f| f.0 == name) {
+                        Some(i) => i,
+                        None => return err(format!("{} has no field {:?}", ty, name)),
+                    };
+                    if pats.iter().any(|p| p.0 == name) {
+                        return err(format!("field {:?} appears twice in a pattern", name));
+                    }
+                    match self.pattern(&pair[1], &fields[i].1, binds, vars)? {
+                        Some((p, shape)) => {
+                            pats.push((name.to_string(), p));
+                            shapes[i] = shape;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                Ok(Some((Pattern::Record(pats), Shape::Record(shapes))))
+            }
+            _ => err(format!("unknown pattern {:?}", kind)),
+        }
+    }
+
+    fn check(&mut self, expr: &Expr) -> Result<Typed, CheckError> {
+        let typed = match self.check_node(expr) {
+            Ok(typed) => typed,
+            Err(e) => {
+                if self.failed.is_none() {
+                    self.failed = Some(expr);
+                }
+                return Err(e);
+            }
+        };
+        if !self.observed {
+            return Ok(typed);
+        }
+        let node = Rc::new(expr.clone());
+        self.copies.push((&*node, expr));
+        Ok(each_typed!(typed, exp, wrap, _var => wrap(box observed_exp_as(exp, node))))
+    }
+
+    fn check_node(&mut self, expr: &Expr) -> Result<Typed, CheckError> {
+        let (kind, binds, args) = match *expr {
+            Expr::Const(Value::Num(v)) => return Ok(Typed::Num(box unit_exp(NumVal { v }))),
+            Expr::Const(Value::Bool(v)) => return Ok(Typed::Bool(box unit_exp(BoolVal { v }))),
+            Expr::Const(Value::Unit) => return Ok(Typed::Unit(box unit_exp(UnitVal))),
+            Expr::Const(Value::Str(ref v)) => return Ok(Typed::Str(box unit_exp(StrVal { v: v.clone() }))),
+            Expr::Const(Value::Float(bits)) => return Ok(Typed::Float(box unit_exp(FloatVal { v: f64::from_bits(bits) }))),
+            Expr::Var(id) | Expr::Bound(id) => return Ok(match self.lookup(id)? {
+                Var::Num(v) => Typed::Num(box v),
+                Var::Bool(v) => Typed::Bool(box v),
+                Var::Unit(v) => Typed::Unit(box v),
+                Var::Str(v) => Typed::Str(box v),
+                Var::Float(v) => Typed::Float(box v),
+                Var::Record(v, fields) => Typed::Record(box v, fields),
+                Var::Variant(v, cases) => Typed::Variant(box v, cases),
+            }),
+            Expr::Const(Value::Opaque) | Expr::Opaque => return err("the program has parts that can't be loaded".to_string()),
+            Expr::Node { ref kind, ref binds, ref children } => (&kind[..], binds, &children[..]),
+        };
+        if !binds.is_empty() && kind != "let" && kind != "for" && kind != "for_step" && kind != "match"
+            && kind != "pmatch" {
+            return err(format!("{} doesn't bind variables", kind));
+        }
+        match kind {
+            "add" | "sub" | "mul" => {
+                let args = self.args(kind, args, 2)?;
+                let a = self.check(&args[0])?;
+                let b = self.check(&args[1])?;
+                match (a, b) {
+                    (Typed::Num(a), Typed::Num(b)) => Ok(Typed::Num(match kind {
+                        "add" => box add_exp(a, b),
+                        "sub" => box sub_exp(a, b),
+                        _ => box mul_exp(a, b),
+                    })),
+                    (Typed::Float(a), Typed::Float(b)) => Ok(Typed::Float(match kind {
+                        "add" => box add_exp(a, b),
+                        "sub" => box sub_exp(a, b),
+                        _ => box mul_exp(a, b),
+                    })),
+                    (a, b) => err(format!("can't {} {} and {}", kind, a.ty(), b.ty())),
+                }
+            }
+            "div" | "pow" => {
+                let args = self.args(kind, args, 2)?;
+                match (self.check(&args[0])?, self.check(&args[1])?) {
+                    (Typed::Float(a), Typed::Float(b)) => Ok(Typed::Float(match kind {
+                        "div" => box div_exp(a, b),
+                        _ => box pow_exp(a, b),
+                    })),
+                    (a, b) => err(format!("can't {} {} and {}", kind, a.ty(), b.ty())),
+                }
+            }
+            "shl" | "shr" => {
+                let args = self.args(kind, args, 2)?;
+                match (self.check(&args[0])?, self.check(&args[1])?) {
+                    (Typed::Num(a), Typed::Num(b)) => Ok(Typed::Num(match kind {
+                        "shl" => box shl_exp(a, b),
+                        _ => box shr_exp(a, b),
+                    })),
+                    (a, b) => err(format!("can't {} {} by {}", kind, a.ty(), b.ty())),
+                }
+            }
+            "bit_and" | "bit_or" | "bit_xor" => {
+                let args = self.args(kind, args, 2)?;
+                match (self.check(&args[0])?, self.check(&args[1])?) {
+                    (Typed::Num(a), Typed::Num(b)) => Ok(Typed::Num(match kind {
+                        "bit_and" => box bit_and_exp(a, b),
+                        "bit_or" => box bit_or_exp(a, b),
+                        _ => box bit_xor_exp(a, b),
+                    })),
+                    (a, b) => err(format!("can't {} {} and {}", kind, a.ty(), b.ty())),
+                }
+            }
+            "bit_not" => {
+                let args = self.args(kind, args, 1)?;
+                match self.check(&args[0])? {
+                    Typed::Num(a) => Ok(Typed::Num(box bit_not_exp(a))),
+                    a => err(format!("can't bit_not {}", a.ty())),
+                }
+            }
+            "lt" => {
+                let args = self.args(kind, args, 2)?;
+                match (self.check(&args[0])?, self.check(&args[1])?) {
+                    (Typed::Num(a), Typed::Num(b)) => Ok(Typed::Bool(box less_than_exp(a, b))),
+                    (Typed::Str(a), Typed::Str(b)) => Ok(Typed::Bool(box less_than_exp(a, b))),
+                    (Typed::Bool(a), Typed::Bool(b)) => Ok(Typed::Bool(box less_than_exp(a, b))),
+                    (a, b) => err(format!("can't compare {} and {}", a.ty(), b.ty())),
+                }
+            }
+            "partial_lt" => {
+                let args = self.args(kind, args, 2)?;
+                match (self.check(&args[0])?, self.check(&args[1])?) {
+                    (Typed::Float(a), Typed::Float(b)) => Ok(Typed::Bool(box partial_less_than_exp(a, b))),
+                    (Typed::Num(a), Typed::Num(b)) => Ok(Typed::Bool(box partial_less_than_exp(a, b))),
+                    (a, b) => err(format!("can't compare {} and {}", a.ty(), b.ty())),
+                }
+            }
+            "if" => {
+                let args = self.args(kind, args, 3)?;
+                let cond = expect::<BoolVal>(self.check(&args[0])?, "an if condition")?;
+                let then_exp = self.check(&args[1])?;
+                let else_exp = self.check(&args[2])?;
+                let ty = match join(&then_exp.ty(), &else_exp.ty()) {
+                    Some(ty) => ty,
+                    None => return err(format!("if branches differ: {} and {}", then_exp.ty(), else_exp.ty())),
+                };
+                each_typed!(then_exp, then_exp, wrap, _var => {
+                    Ok(retype(wrap(box if_exp(cond, then_exp, expect(else_exp, "the else branch")?)), ty))
+                })
+            }
+            "seq" => {
+                let args = self.args(kind, args, 2)?;
+                let first = self.check(&args[0])?;
+                let then = self.check(&args[1])?;
+                Ok(each_typed!(first, first => each_typed!(then, then, wrap, _var => wrap(box seq_exp(first, then)))))
+            }
+            "let" => {
+                let args = self.args(kind, args, 2)?;
+                if binds.len() != 1 {
+                    return err("let binds one variable".to_string());
+                }
+                let init = self.check(&args[0])?;
+                each_typed!(init, init, _wrap, var => self.check_let(binds[0], init, &var, &args[1]))
+            }
+            "set" => {
+                let args = self.args(kind, args, 2)?;
+                let id = match args[0] {
+                    Expr::Var(id) | Expr::Bound(id) => id,
+                    _ => return err("set needs a variable".to_string()),
+                };
+                let exp = self.check(&args[1])?;
+                let var = self.lookup(id)?;
+                if join(&var.ty(), &exp.ty()) != Some(var.ty()) {
+                    return err(format!("can't assign {} to a variable of type {}", exp.ty(), var.ty()));
+                }
+                Ok(Typed::Unit(match var {
+                    Var::Num(v) => box set_exp(v, expect(exp, "the assigned value")?),
+                    Var::Bool(v) => box set_exp(v, expect(exp, "the assigned value")?),
+                    Var::Unit(v) => box set_exp(v, expect(exp, "the assigned value")?),
+                    Var::Str(v) => box set_exp(v, expect(exp, "the assigned value")?),
+                    Var::Float(v) => box set_exp(v, expect(exp, "the assigned value")?),
+                    Var::Record(v, _) => box set_exp(v, expect(exp, "the assigned value")?),
+                    Var::Variant(v, _) => box set_exp(v, expect(exp, "the assigned value")?),
+                }))
+            }
+            "while" => {
+                let args = self.args(kind, args, 2)?;
+                let cond = expect(self.check(&args[0])?, "a while condition")?;
+                let body = expect(self.check(&args[1])?, "a while body")?;
+                Ok(Typed::Unit(box while_exp(cond, body)))
+            }
+            "for" | "for_step" => {
+                let args = self.args(kind, args, if kind == "for" { 3 } else { 4 })?;
+                if binds.len() != 1 {
+                    return err(format!("{} binds one variable", kind));
+                }
+                let start = expect(self.check(&args[0])?, "a loop bound")?;
+                let end = expect(self.check(&args[1])?, "a loop bound")?;
+                let step = if kind == "for_step" {
+                    Some(expect(self.check(&args[2])?, "a loop step")?)
+                } else {
+                    None
+                };
+                let index_var = VariableExp::fresh();
+                let body = &args[args.len() - 1];
+                let body = self.with_var(binds[0], Var::Num(index_var.clone()), &|c| c.check(body))?;
+                let body = expect(body, "a loop body")?;
+                Ok(Typed::Unit(box bound_for_exp(start, end, step, index_var, body)))
+            }
+            "concat" | "str_eq" | "contains" => {
+                let args = self.args(kind, args, 2)?;
+                let a = expect(self.check(&args[0])?, kind)?;
+                let b = expect(self.check(&args[1])?, kind)?;
+                Ok(match kind {
+                    "concat" => Typed::Str(box concat_exp(a, b)),
+                    "str_eq" => Typed::Bool(box str_eq_exp(a, b)),
+                    _ => Typed::Bool(box contains_exp(a, b)),
+                })
+            }
+            "format" => {
+                let template = match args.first() {
+                    Some(&Expr::Const(Value::Str(ref template))) => template,
+                    _ => return err("format needs a template string".to_string()),
+                };
+                let holes = match parse_template(template) {
+                    Ok(texts) => texts.len() - 1,
+                    Err(msg) => return err(msg),
+                };
+                if holes != args.len() - 1 {
+                    return err(format!("format template {:?} has {} holes but {} arguments", template, holes, args.len() - 1));
+                }
+                let mut f = format_exp(template);
+                for arg in &args[1..] {
+                    f = match self.check(arg)? {
+                        Typed::Num(a) => f.arg(a),
+                        Typed::Bool(a) => f.arg(a),
+                        Typed::Unit(a) => f.arg(a),
+                        Typed::Str(a) => f.arg(a),
+                        Typed::Float(a) => f.arg(a),
+                        other => return err(format!("can't format {}", other.ty())),
+                    };
+                }
+                Ok(Typed::Str(box f))
+            }
+            "str_len" => {
+                let args = self.args(kind, args, 1)?;
+                Ok(Typed::Num(box str_len_exp(expect(self.check(&args[0])?, kind)?)))
+            }
+            "substring" => {
+                let args = self.args(kind, args, 3)?;
+                let s = expect(self.check(&args[0])?, kind)?;
+                let start = expect(self.check(&args[1])?, "a substring start")?;
+                let len = expect(self.check(&args[2])?, "a substring length")?;
+                Ok(Typed::Str(box substring_exp(s, start, len)))
+            }
+            "assert" => {
+                let args = self.args(kind, args, 2)?;
+                let cond = expect(self.check(&args[0])?, "a condition")?;
+                match args[1] {
+                    Expr::Const(Value::Str(ref message)) => Ok(Typed::Unit(box assert_exp(cond, message))),
+                    _ => Err(CheckError::new("an assertion's message must be a string constant".to_string())),
+                }
+            }
+            "atomic" => atomic::check_atomic(expr),
+            "fetch_add" => {
+                let args = self.args(kind, args, 2)?;
+                let var = atomic::lookup(&args[0])?;
+                let delta = expect(self.check(&args[1])?, "an amount to add")?;
+                Ok(Typed::Num(box fetch_add_exp(var, delta)))
+            }
+            "cas" => {
+                let args = self.args(kind, args, 3)?;
+                let var = atomic::lookup(&args[0])?;
+                let expected = expect(self.check(&args[1])?, "an expected value")?;
+                let new = expect(self.check(&args[2])?, "a new value")?;
+                Ok(Typed::Bool(box cas_exp(var, expected, new)))
+            }
+            "send" => {
+                let args = self.args(kind, args, 2)?;
+                let value = self.check(&args[1])?;
+                channel::check_send(&args[0], value)
+            }
+            "recv" => {
+                let args = self.args(kind, args, 1)?;
+                channel::check_recv(&args[0])
+            }
+            "print" => {
+                let args = self.args(kind, args, 1)?;
+                let exp = self.check(&args[0])?;
+                Ok(Typed::Unit(each_typed!(exp, exp => box print_exp(exp))))
+            }
+            "read" => {
+                self.args(kind, args, 0)?;
+                Ok(Typed::Num(box read_exp()))
+            }
+            "rand" => {
+                let args = self.args(kind, args, 2)?;
+                let lo = expect(self.check(&args[0])?, "a random bound")?;
+                let hi = expect(self.check(&args[1])?, "a random bound")?;
+                Ok(Typed::Num(box rand_exp(lo, hi)))
+            }
+            "record" => {
+                if args.len() % 2 != 0 {
+                    return err("record takes a name and a value for each field".to_string());
+                }
+                let mut record = record_exp();
+                let mut fields: Vec<(String, Ty)> = Vec::new();
+                for pair in args.chunks(2) {
+                    let name = self.name(&pair[0])?;
+                    let pos = match fields.binary_search_by(|f| f.0[..].cmp(name)) {
+                        Ok(_) => return err(format!("field {:?} is given twice", name)),
+                        Err(pos) => pos,
+                    };
+                    let exp = self.check(&pair[1])?;
+                    fields.insert(pos, (name.to_string(), exp.ty()));
+                    record = each_typed!(exp, exp => record.field(name, exp));
+                }
+                Ok(Typed::Record(box record, fields))
+            }
+            "field" => {
+                let args = self.args(kind, args, 2)?;
+                let (record, fields) = self.record(&args[0], "a field's record")?;
+                let name = self.name(&args[1])?;
+                let ty = match fields.iter().find(|f| f.0 == name) {
+                    Some(f) => f.1.clone(),
+                    None => return err(format!("{} has no field {:?}", Ty::Record(fields.clone()), name)),
+                };
+                Ok(by_ty!(ty, T => box field_get_exp::<T>(record, name)))
+            }
+            "with" => {
+                let args = self.args(kind, args, 3)?;
+                let (record, mut fields) = self.record(&args[0], "an updated record")?;
+                let name = self.name(&args[1])?;
+                let val = self.check(&args[2])?;
+                match fields.binary_search_by(|f| f.0[..].cmp(name)) {
+                    Ok(i) => fields[i].1 = val.ty(),
+                    Err(i) => fields.insert(i, (name.to_string(), val.ty())),
+                }
+                Ok(each_typed!(val, val => Typed::Record(box with_exp(record, name, val), fields)))
+            }
+            "variant" => {
+                let args = self.args(kind, args, 2)?;
+                let tag = self.name(&args[0])?;
+                let payload = self.check(&args[1])?;
+                let cases = vec![(tag.to_string(), payload.ty())];
+                Ok(each_typed!(payload, payload => Typed::Variant(box variant_exp(tag, payload), cases)))
+            }
+            "match" => {
+                if args.is_empty() || binds.len() != (args.len() - 1) / 2 {
+                    return err("match binds one variable for each arm".to_string());
+                }
+                let (scrutinee, cases) = match self.check(&args[0])? {
+                    Typed::Variant(exp, cases) => (exp, cases),
+                    other => return err(format!("can't match on {}", other.ty())),
+                };
+                let mut arms: Vec<(String, Var, Typed)> = Vec::new();
+                let mut tags: Vec<&str> = Vec::new();
+                let mut ty: Option<Ty> = None;
+                for (i, pair) in args[1..].chunks(2).enumerate() {
+                    if pair.len() < 2 {
+                        break;
+                    }
+                    let tag = self.name(&pair[0])?;
+                    if tags.contains(&tag) {
+                        return err(format!("more than one match arm for {:?}", tag));
+                    }
+                    tags.push(tag);
+                    match cases.iter().find(|c| c.0 == tag) {
+                        Some(case) => {
+                            let var = Var::fresh(&case.1);
+                            let body = self.with_var(binds[i], var.clone(), &|c| c.check(&pair[1]))?;
+                            ty = self.join_arm(ty, &body)?;
+                            arms.push((tag.to_string(), var, body));
+                        }
+                        // The arm for a case the variant can't be never runs, so
+                        // its payload has no type: it's checked without its
+                        // variable and left out.
+                        None => {
+                            let body = self.check(&pair[1])?;
+                            ty = self.join_arm(ty, &body)?;
+                        }
+                    }
+                }
+                let otherwise = if args.len() % 2 == 0 {
+                    let e = self.check(&args[args.len() - 1])?;
+                    ty = self.join_arm(ty, &e)?;
+                    Some(e)
+                } else {
+                    None
+                };
+                if otherwise.is_none() {
+                    if let Some(case) = cases.iter().find(|c| !arms.iter().any(|a| a.0 == c.0)) {
+                        return err(format!("match has no arm for {:?}", case.0));
+                    }
+                }
+                let ty = match ty {
+                    Some(ty) => ty,
+                    None => return err("match has no arms".to_string()),
+                };
+                Ok(by_ty!(ty, T => build_match::<T>(scrutinee, arms, otherwise)?))
+            }
+            "switch" => {
+                if args.is_empty() || args.len() % 2 != 0 {
+                    return err("switch takes a value, a constant and a body for each case, and a default".to_string());
+                }
+                let scrutinee = expect::<NumVal>(self.check(&args[0])?, "a switch value")?;
+                let mut cases: Vec<(i64, Typed)> = Vec::new();
+                let mut ty: Option<Ty> = None;
+                for pair in args[1..args.len() - 1].chunks(2) {
+                    let k = match pair[0] {
+                        Expr::Const(Value::Num(k)) => k,
+                        _ => return err("a switch case must be a num constant".to_string()),
+                    };
+                    if cases.iter().any(|c| c.0 == k) {
+                        return err(format!("more than one switch case for {}", k));
+                    }
+                    let body = self.check(&pair[1])?;
+                    ty = self.join_arm(ty, &body)?;
+                    cases.push((k, body));
+                }
+                let default = self.check(&args[args.len() - 1])?;
+                let ty = self.join_arm(ty, &default)?.unwrap();
+                Ok(by_ty!(ty, T => build_switch::<T>(scrutinee, cases, default)?))
+            }
+            "pmatch" => {
+                if args.len() < 2 {
+                    return err("pmatch takes a value and at least one case".to_string());
+                }
+                let scrutinee = self.check(&args[0])?;
+                let sty = scrutinee.ty();
+                let mut cases: Vec<Case> = Vec::new();
+                // The shapes of the unguarded cases so far.
+                let mut rows: Vec<Vec<Shape>> = Vec::new();
+                let mut ty: Option<Ty> = None;
+                for (i, case) in args[1..].iter().enumerate() {
+                    let parts = match *case {
+                        Expr::Node { ref kind, ref binds, ref children }
+                            if kind == "case" && binds.is_empty() && (children.len() == 2 || children.len() == 3) => children,
+                        _ => return err("a pmatch case must be a case node of a pattern, maybe a guard, and a body".to_string()),
+                    };
+                    let mut vars = Vec::new();
+                    let checked = self.pattern(&parts[0], &sty, binds, &mut vars)?;
+                    let (guard, body) = match checked {
+                        Some(_) => self.with_vars(&vars, &|c| c.case(parts))?,
+                        // As for match, a case for something the value can
+                        // never be is checked without its variables and left
+                        // out.
+                        None => self.case(parts)?,
+                    };
+                    ty = self.join_arm(ty, &body)?;
+                    if let Some((pattern, shape)) = checked {
+                        if !useful(&rows, &[shape.clone()], &[sty.clone()]) {
+                            return err(format!("match case {} is redundant: the cases before it match everything it does", i + 1));
+                        }
+                        if guard.is_none() {
+                            rows.push(vec![shape]);
+                        }
+                        cases.push((pattern, guard, body));
+                    }
+                }
+                if let Some(w) = missing(&rows, &[sty.clone()]) {
+                    return err(format!("match doesn't cover {}", show(&w[0], &sty)));
+                }
+                let ty = ty.unwrap();
+                Ok(each_typed!(scrutinee, scrutinee => by_ty!(ty, T => build_pmatch::<_, T>(scrutinee, cases)?)))
+            }
+            _ => match registry::lookup(kind) {
+                Some(extension) => {
+                    let args = args.iter().map(|a| self.check(a)).collect::<Result<Vec<_>, _>>()?;
+                    extension.check(args)
+                }
+                None => err(format!("unknown or unsupported node {:?}", kind)),
+            },
+        }
+    }
+}
+
+// Type-checks an untyped program, e.g. one read with `json::from_json`, and
+// builds the typed tree for it. Covers the nodes over num, bool, unit, str
+// and float values and records and variants of them: arithmetic, bitwise
+// operations and shifts, comparisons, if, switch, let, set, seq, while,
+// for, the string nodes and format, print, read, rand, record, field, with,
+// variant, match and pmatch, and the kinds registered with `registry`. Free
+// variables are rejected, since a loaded program can't refer to the host's
+// variables, as are pmatch cases that can't match anything the cases before
+// them don't and pmatches that some value gets through.
+pub fn check(expr: &Expr) -> Result<Typed, CheckError> {
+    check_with(expr, &Limits::default())
+}
+
+// Checks `expr` against `limits` first, so a program built in memory rather
+// than read through json or binary is held to them too.
+pub fn check_with(expr: &Expr, limits: &Limits) -> Result<Typed, CheckError> {
+    check_program(expr, limits, false, vec![])
+}
+
+// Like `check`, but with every node reporting to the observer installed by
+// `observe::observing` as it's evaluated. Each node keeps a copy of its own
+// subtree to report, so this takes memory in proportion to the program's
+// size times its depth.
+pub fn check_observed(expr: &Expr) -> Result<Typed, CheckError> {
+    check_program(expr, &Limits::default(), true, vec![])
+}
+
+// Like `check`, but with the variables of `inputs` free in `expr`, of the
+// types given, for the host to set before each run. They come back with the
+// program, in the same order.
+pub fn check_open(expr: &Expr, inputs: &[(i32, Ty)]) -> Result<(Typed, Vec<Box<Binder>>), CheckError> {
+    let vars: Vec<(i32, Var)> = inputs.iter().map(|&(id, ref ty)| (id, Var::fresh(ty))).collect();
+    let binders = vars.iter().map(|&(_, ref var)| var.binder()).collect();
+    let typed = check_program(expr, &Limits::default(), false, vars)?;
+    Ok((typed, binders))
+}
+
+fn check_program(expr: &Expr, limits: &Limits, observed: bool, inputs: Vec<(i32, Var)>) -> Result<Typed, CheckError> {
+    run_checker(expr, limits, observed, inputs).map(|(typed, _)| typed)
+}
+
+fn run_checker(expr: &Expr, limits: &Limits, observed: bool, inputs: Vec<(i32, Var)>)
+               -> Result<(Typed, Checker), CheckError> {
+    if let Err(e) = limits.check(expr) {
+        return Err(CheckError {
+            msg: e.to_string(),
+            limit: Some(e),
+            path: None,
+        });
+    }
+    let mut checker = Checker {
+        env: inputs.into_iter().collect(),
+        observed,
+        failed: None,
+        copies: Vec::new(),
+    };
+    match checker.check(expr) {
+        Ok(typed) => Ok((typed, checker)),
+        Err(mut e) => {
+            if let Some(failed) = checker.failed {
+                e.path = node_paths(expr).remove(&failed);
+            }
+            Err(e)
+        }
+    }
+}
+
+// Like `check_observed`, with where in `expr` each node the observer is
+// shown came from, so a failure while running can be traced to its node.
+pub fn check_located(expr: &Expr) -> Result<(Typed, Locator), CheckError> {
+    locate(expr, vec![])
+}
+
+// Like `check_located`, with the variables of `inputs` free in `expr`, as
+// `check_open` has them.
+pub fn check_located_open(expr: &Expr, inputs: &[(i32, Ty)]) -> Result<(Typed, Vec<Box<Binder>>, Locator), CheckError> {
+    let vars: Vec<(i32, Var)> = inputs.iter().map(|&(id, ref ty)| (id, Var::fresh(ty))).collect();
+    let binders = vars.iter().map(|&(_, ref var)| var.binder()).collect();
+    let (typed, locator) = locate(expr, vars)?;
+    Ok((typed, binders, locator))
+}
+
+fn locate(expr: &Expr, inputs: Vec<(i32, Var)>) -> Result<(Typed, Locator), CheckError> {
+    let (typed, checker) = run_checker(expr, &Limits::default(), true, inputs)?;
+    let mut paths = node_paths(expr);
+    let locator = Locator::new(checker.copies.into_iter()
+        .filter_map(|(copy, node)| paths.remove(&node).map(|path| (copy, path)))
+        .collect());
+    Ok((typed, locator))
+}
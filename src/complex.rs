@@ -0,0 +1,314 @@
+use {Exp, StagedExp, EvalContext, Compiled, FloatVal, ComplexVal};
+use ops::E;
+use reify::{Expr, node};
+
+// Complex numbers add, subtract and multiply with the generic nodes; these
+// make them from and take them apart into floats.
+
+// `re + im i`.
+#[derive(Clone)]
+pub struct ComplexExp {
+    re: Box<Exp<Output=FloatVal>>,
+    im: Box<Exp<Output=FloatVal>>,
+}
+
+pub struct ComplexStagedExp {
+    staged_re: Box<StagedExp<Output=FloatVal>>,
+    staged_im: Box<StagedExp<Output=FloatVal>>,
+}
+
+impl Exp for ComplexExp {
+    type Output = ComplexVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ComplexStagedExp {
+            staged_re: self.re.stage(),
+            staged_im: self.im.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let re = self.re.interpret().v;
+        Self::Output {
+            re,
+            im: self.im.interpret().v
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("complex", vec![self.re.reify(), self.im.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_re = self.re.stage_compiled();
+        let compiled_im = self.im.stage_compiled();
+        box move || {
+            let re = compiled_re().v;
+            ComplexVal { re, im: compiled_im().v }
+        }
+    }
+}
+
+impl StagedExp for ComplexStagedExp {
+    type Output = ComplexVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let re = self.staged_re.run(ctx).v;
+        Self::Output {
+            re,
+            im: self.staged_im.run(ctx).v
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConjExp {
+    exp: Box<Exp<Output=ComplexVal>>,
+}
+
+pub struct ConjStagedExp {
+    staged_exp: Box<StagedExp<Output=ComplexVal>>,
+}
+
+fn conj(z: ComplexVal) -> ComplexVal {
+    ComplexVal {
+        re: z.re,
+        im: -z.im
+    }
+}
+
+impl Exp for ConjExp {
+    type Output = ComplexVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ConjStagedExp {
+            staged_exp: self.exp.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        conj(self.exp.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("conj", vec![self.exp.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp = self.exp.stage_compiled();
+        box move || conj(compiled_exp())
+    }
+}
+
+impl StagedExp for ConjStagedExp {
+    type Output = ComplexVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        conj(self.staged_exp.run(ctx))
+    }
+}
+
+// The distance from zero, without overflowing for large parts.
+#[derive(Clone)]
+pub struct ComplexAbsExp {
+    exp: Box<Exp<Output=ComplexVal>>,
+}
+
+pub struct ComplexAbsStagedExp {
+    staged_exp: Box<StagedExp<Output=ComplexVal>>,
+}
+
+fn abs(z: ComplexVal) -> FloatVal {
+    FloatVal {
+        v: z.re.hypot(z.im)
+    }
+}
+
+impl Exp for ComplexAbsExp {
+    type Output = FloatVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ComplexAbsStagedExp {
+            staged_exp: self.exp.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        abs(self.exp.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("complex_abs", vec![self.exp.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp = self.exp.stage_compiled();
+        box move || abs(compiled_exp())
+    }
+}
+
+impl StagedExp for ComplexAbsStagedExp {
+    type Output = FloatVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        abs(self.staged_exp.run(ctx))
+    }
+}
+
+// The real part if `im` is false, else the imaginary part.
+#[derive(Clone)]
+pub struct PartExp {
+    exp: Box<Exp<Output=ComplexVal>>,
+    im: bool,
+}
+
+pub struct PartStagedExp {
+    staged_exp: Box<StagedExp<Output=ComplexVal>>,
+    im: bool,
+}
+
+fn part(z: ComplexVal, im: bool) -> FloatVal {
+    FloatVal {
+        v: if im { z.im } else { z.re }
+    }
+}
+
+impl Exp for PartExp {
+    type Output = FloatVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box PartStagedExp {
+            staged_exp: self.exp.stage(),
+            im: self.im,
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        part(self.exp.interpret(), self.im)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node(if self.im { "im" } else { "re" }, vec![self.exp.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp = self.exp.stage_compiled();
+        let im = self.im;
+        box move || part(compiled_exp(), im)
+    }
+}
+
+impl StagedExp for PartStagedExp {
+    type Output = FloatVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        part(self.staged_exp.run(ctx), self.im)
+    }
+}
+
+pub fn complex_exp(re: Box<Exp<Output=FloatVal>>, im: Box<Exp<Output=FloatVal>>) -> ComplexExp {
+    ComplexExp {
+        re,
+        im
+    }
+}
+
+pub fn conj_exp(exp: Box<Exp<Output=ComplexVal>>) -> ConjExp {
+    ConjExp {
+        exp
+    }
+}
+
+pub fn complex_abs_exp(exp: Box<Exp<Output=ComplexVal>>) -> ComplexAbsExp {
+    ComplexAbsExp {
+        exp
+    }
+}
+
+pub fn re_exp(exp: Box<Exp<Output=ComplexVal>>) -> PartExp {
+    PartExp {
+        exp,
+        im: false
+    }
+}
+
+pub fn im_exp(exp: Box<Exp<Output=ComplexVal>>) -> PartExp {
+    PartExp {
+        exp,
+        im: true
+    }
+}
+
+impl E<ComplexVal> {
+    pub fn conj(self) -> E<ComplexVal> {
+        E::new(conj_exp(self.0))
+    }
+
+    pub fn abs(self) -> E<FloatVal> {
+        E::new(complex_abs_exp(self.0))
+    }
+
+    pub fn re(self) -> E<FloatVal> {
+        E::new(re_exp(self.0))
+    }
+
+    pub fn im(self) -> E<FloatVal> {
+        E::new(im_exp(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, StagedExp, EvalContext, VariableExp, FloatVal, ComplexVal, unit_exp, mul_exp, sub_exp};
+    use super::*;
+
+    fn z(re: f64, im: f64) -> Box<Exp<Output=ComplexVal>> {
+        box complex_exp(box unit_exp(FloatVal { v: re }), box unit_exp(FloatVal { v: im }))
+    }
+
+    // The value of `exp`, after checking the staged and compiled forms give
+    // it too.
+    fn all<T: 'static+Clone+PartialEq+::std::fmt::Debug>(exp: &Exp<Output=T>) -> T {
+        let v = exp.interpret();
+        assert_eq!(exp.stage().run(&EvalContext::new()), v);
+        assert_eq!(exp.stage_compiled()(), v);
+        v
+    }
+
+    #[test]
+    fn arithmetic_follows_the_complex_rules() {
+        assert_eq!(all(&mul_exp(z(1.0, 2.0), z(3.0, -1.0))), ComplexVal { re: 5.0, im: 5.0 });
+        assert_eq!(all(&sub_exp(z(1.0, 2.0), z(3.0, -1.0))), ComplexVal { re: -2.0, im: 3.0 });
+        assert_eq!(all(&conj_exp(z(1.0, 2.0))), ComplexVal { re: 1.0, im: -2.0 });
+    }
+
+    #[test]
+    fn parts_and_abs() {
+        assert_eq!(all(&re_exp(z(3.0, 4.0))).v, 3.0);
+        assert_eq!(all(&im_exp(z(3.0, 4.0))).v, 4.0);
+        assert_eq!(all(&complex_abs_exp(z(3.0, 4.0))).v, 5.0);
+        assert!(all(&complex_abs_exp(z(1e300, 1e300))).v.is_finite());
+    }
+
+    #[test]
+    fn staged_parts_read_their_variables_each_run() {
+        let re = VariableExp::fresh_with_val(FloatVal { v: 3.0 });
+        let exp = complex_abs_exp(box complex_exp(box re.clone(), box unit_exp(FloatVal { v: 4.0 })));
+        let (staged, compiled) = (exp.stage(), exp.stage_compiled());
+        re.assign(FloatVal { v: 0.0 });
+        assert_eq!(staged.run(&EvalContext::new()).v, 4.0);
+        assert_eq!(compiled().v, 4.0);
+    }
+}
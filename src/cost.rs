@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use Exp;
+use reify::{Expr, Value};
+
+// Estimates of what running a program costs, in units of a table of
+// per-operation costs, for deciding whether a program is worth staging or
+// compiling before it's run, and for passes choosing between equivalent
+// trees. The estimate is of a single run and only as good as the table;
+// it's meant for comparing programs, not predicting times.
+//
+// Loops are charged for each iteration: a for over constant bounds, or a
+// map, fold or aggregation over such a range or an array literal, for the
+// iterations it will make, and any other loop for the table's guess. An if
+// is charged for its costlier branch. A call is charged for the body of
+// the function only when that's a lambda written in place; otherwise the
+// body isn't known and only the call is.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostTable {
+    // Arithmetic, comparisons and bit operations.
+    pub arith: f64,
+    pub var: f64,
+    pub constant: f64,
+    // Set, assign and let.
+    pub write: f64,
+    // If, match and switch, besides their chosen branch.
+    pub branch: f64,
+    pub call: f64,
+    // Building arrays, records, strings, cells and closures.
+    pub alloc: f64,
+    // Each iteration of a loop, besides its body.
+    pub iteration: f64,
+    // How many times a loop whose bounds aren't known is taken to run.
+    pub loop_iterations: f64,
+    // Any other node.
+    pub other: f64,
+    // Costs of particular node kinds, in place of their category's.
+    pub kinds: HashMap<String, f64>,
+}
+
+impl Default for CostTable {
+    fn default() -> CostTable {
+        CostTable {
+            arith: 1.0,
+            var: 1.0,
+            constant: 0.5,
+            write: 1.0,
+            branch: 1.0,
+            call: 5.0,
+            alloc: 10.0,
+            iteration: 1.0,
+            loop_iterations: 100.0,
+            other: 2.0,
+            kinds: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CostEstimate {
+    pub cost: f64,
+    pub nodes: usize,
+    pub loops: usize,
+    // Loops charged for `loop_iterations` because their bounds aren't known.
+    pub guessed_loops: usize,
+    // Whether the tree had parts that can't be reified, charged as `other`.
+    pub opaque: bool,
+}
+
+const ARITH: &[&str] = &["add", "sub", "mul", "div", "pow", "lt", "partial_lt", "bit_and", "bit_or", "bit_xor", "bit_not",
+                         "shl", "shr", "str_eq", "str_len", "decimal_div", "rescale", "complex_abs", "conj", "dot"];
+const WRITE: &[&str] = &["set", "assign", "let"];
+const BRANCH: &[&str] = &["if", "match", "pmatch", "switch"];
+const CALL: &[&str] = &["apply", "force"];
+const ALLOC: &[&str] = &["array", "record", "variant", "alloc", "concat", "format", "substring", "map_insert",
+                         "lambda", "delay", "memo", "complex", "mat_mul"];
+// Loops over their first child, whose bodies are their last.
+const EACH: &[&str] = &["for_each", "map", "filter", "fold", "stream_map", "stream_filter", "stream_fold"];
+const AGG: &[&str] = &["sum", "count", "avg", "min", "max"];
+
+fn num(expr: &Expr) -> Option<i64> {
+    match *expr {
+        Expr::Const(Value::Num(v)) => Some(v),
+        _ => None,
+    }
+}
+
+// How many times a for from `start` to `end` by `step` runs, if they're
+// constants.
+fn trips(start: &Expr, end: &Expr, step: Option<&Expr>) -> Option<f64> {
+    let (start, end) = (num(start)?, num(end)?);
+    let step = match step {
+        Some(step) => num(step)?,
+        None => 1,
+    };
+    let span = if step > 0 { end.saturating_sub(start) } else { start.saturating_sub(end) };
+    if step == 0 || span <= 0 {
+        return Some(0.0);
+    }
+    let step = step.unsigned_abs() as f64;
+    Some((span as f64 / step).ceil())
+}
+
+// How many elements the collection `expr` has, if that's known: at most
+// that many for a filter.
+fn length(expr: &Expr) -> Option<f64> {
+    match *expr {
+        Expr::Node { ref kind, ref children, .. } => match (&kind[..], &children[..]) {
+            ("range", &[ref start, ref end]) => trips(start, end, None),
+            ("range_step", &[ref start, ref end, ref step]) => trips(start, end, Some(step)),
+            ("array", _) => Some(children.len() as f64),
+            ("map", _) | ("filter", _) => length(&children[0]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl CostTable {
+    pub fn new() -> CostTable {
+        CostTable::default()
+    }
+
+    pub fn kind(mut self, kind: &str, cost: f64) -> Self {
+        self.kinds.insert(kind.to_string(), cost);
+        self
+    }
+
+    pub fn cost<T>(&self, exp: &Exp<Output=T>) -> CostEstimate {
+        self.estimate(&exp.reify())
+    }
+
+    pub fn estimate(&self, expr: &Expr) -> CostEstimate {
+        let mut est = CostEstimate::default();
+        est.cost = self.walk(expr, &mut est);
+        est
+    }
+
+    fn own(&self, kind: &str) -> f64 {
+        if let Some(&cost) = self.kinds.get(kind) {
+            return cost;
+        }
+        if ARITH.contains(&kind) {
+            self.arith
+        } else if WRITE.contains(&kind) {
+            self.write
+        } else if BRANCH.contains(&kind) {
+            self.branch
+        } else if CALL.contains(&kind) {
+            self.call
+        } else if ALLOC.contains(&kind) {
+            self.alloc
+        } else {
+            self.other
+        }
+    }
+
+    // The iterations to charge a loop for, counting it.
+    fn trips(&self, known: Option<f64>, est: &mut CostEstimate) -> f64 {
+        est.loops += 1;
+        known.unwrap_or_else(|| {
+            est.guessed_loops += 1;
+            self.loop_iterations
+        })
+    }
+
+    fn walk(&self, expr: &Expr, est: &mut CostEstimate) -> f64 {
+        est.nodes += 1;
+        let (kind, children) = match *expr {
+            Expr::Const(_) => return self.constant,
+            Expr::Var(_) | Expr::Bound(_) => return self.var,
+            Expr::Opaque => {
+                est.opaque = true;
+                return self.other;
+            }
+            Expr::Node { ref kind, ref children, .. } => (&kind[..], children),
+        };
+        let own = self.own(kind);
+        match (kind, &children[..]) {
+            ("if", &[ref c, ref a, ref b]) => {
+                let c = self.walk(c, est);
+                let (a, b) = (self.walk(a, est), self.walk(b, est));
+                own + c + a.max(b)
+            }
+            ("while", &[ref c, ref body]) => {
+                let (c, body) = (self.walk(c, est), self.walk(body, est));
+                let n = self.trips(None, est);
+                own + c + n * (self.iteration + c + body)
+            }
+            ("for", &[ref start, ref end, ref body]) | ("for_step", &[ref start, ref end, _, ref body]) => {
+                let step = if children.len() == 4 { Some(&children[2]) } else { None };
+                let bounds = self.all(&children[..children.len() - 1], est);
+                let body = self.walk(body, est);
+                let n = self.trips(trips(start, end, step), est);
+                own + bounds + n * (self.iteration + body)
+            }
+            ("apply", &[ref f, ref arg]) => {
+                let (f_cost, arg) = (self.walk(f, est), self.walk(arg, est));
+                // The body of a lambda applied in place runs once.
+                let body = match *f {
+                    Expr::Node { ref kind, ref children, .. } if kind == "lambda" && children.len() == 1 => {
+                        self.walk(&children[0], est)
+                    }
+                    _ => 0.0,
+                };
+                own + f_cost + arg + body
+            }
+            // Its body runs when it's applied, not when it's made.
+            ("lambda", _) => own,
+            (_, &[ref items, ref rest @ ..]) if EACH.contains(&kind) || AGG.contains(&kind) || kind.starts_with("group_") => {
+                let n = self.trips(length(items), est);
+                let items = self.walk(items, est);
+                // A fold's initial value is computed once; what follows runs
+                // for each element.
+                let (init, body) = match kind {
+                    "fold" | "stream_fold" if !rest.is_empty() => (self.walk(&rest[0], est), &rest[1..]),
+                    _ => (0.0, rest),
+                };
+                let body = if AGG.contains(&kind) { self.arith } else { self.all(body, est) };
+                own + items + init + n * (self.iteration + body)
+            }
+            _ => own + self.all(children, est),
+        }
+    }
+
+    fn all(&self, exprs: &[Expr], est: &mut CostEstimate) -> f64 {
+        exprs.iter().map(|e| self.walk(e, est)).sum()
+    }
+}
+
+pub fn cost<T>(exp: &Exp<Output=T>) -> CostEstimate {
+    CostTable::default().cost(exp)
+}
@@ -0,0 +1,159 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+
+use Exp;
+use check::{CheckError, Ty, Typed, check_located_open};
+use dynamic::DynVal;
+use observe::{EvalObserver, observing};
+use patterns::Binder;
+use reify::Expr;
+use span::{Locator, each_node};
+
+// Which parts of a program a set of runs executed, for finding the
+// branches and loop bodies no test reaches. A `Coverage` loads the program
+// with every node observed, runs it as often as asked, each time with its
+// inputs set to the values given, and marks each node that starts
+// evaluating. The report lists the nodes none of the runs reached, by
+// path, with the subtree printed:
+//
+//     5 of 7 nodes executed (71%)
+//       [2]: set(v3, 0)
+//
+// Only the outermost of the unexecuted nodes is listed; what's under it
+// wasn't executed either. Nodes that don't evaluate on their own, like the
+// variable a set writes, aren't counted. Marks add up across runs until
+// `reset`.
+
+struct Marker {
+    locator: Locator,
+    executed: RefCell<HashSet<Vec<usize>>>,
+}
+
+impl EvalObserver for Marker {
+    fn on_enter(&self, node: &Expr) {
+        if let Some(path) = self.locator.path(node) {
+            if !self.executed.borrow().contains(path) {
+                self.executed.borrow_mut().insert(path.to_vec());
+            }
+        }
+    }
+
+    fn on_exit(&self, _node: &Expr, _value: &Any) {}
+}
+
+pub struct Coverage {
+    program: Expr,
+    typed: Typed,
+    inputs: Vec<(Ty, Box<Binder>)>,
+    marker: Rc<Marker>,
+}
+
+// A node no run executed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Missed {
+    pub path: Vec<usize>,
+    // The node's subtree, printed.
+    pub node: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageReport {
+    // How many nodes can be executed, and how many were.
+    pub nodes: usize,
+    pub executed: usize,
+    pub missed: Vec<Missed>,
+}
+
+impl CoverageReport {
+    // The fraction of the nodes that were executed, 1 for a program with
+    // none.
+    pub fn ratio(&self) -> f64 {
+        if self.nodes == 0 {
+            1.0
+        } else {
+            self.executed as f64 / self.nodes as f64
+        }
+    }
+}
+
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} of {} nodes executed ({:.0}%)", self.executed, self.nodes, self.ratio() * 100.0)?;
+        for m in &self.missed {
+            writeln!(f, "  {:?}: {}", m.path, m.node)?;
+        }
+        Ok(())
+    }
+}
+
+impl Coverage {
+    // Loads `program`, with the variables of `inputs` free in it, of the
+    // types given.
+    pub fn new(program: &Expr, inputs: &[(i32, Ty)]) -> Result<Coverage, CheckError> {
+        let (typed, binders, locator) = check_located_open(program, inputs)?;
+        Ok(Coverage {
+            program: program.clone(),
+            typed,
+            inputs: inputs.iter().map(|i| i.1.clone()).zip(binders).collect(),
+            marker: Rc::new(Marker {
+                locator,
+                executed: RefCell::new(HashSet::new()),
+            }),
+        })
+    }
+
+    // Loads `exp`, which reads no variable it doesn't bind.
+    pub fn of<T>(exp: &Exp<Output=T>) -> Result<Coverage, CheckError> {
+        Coverage::new(&exp.reify(), &[])
+    }
+
+    // Runs the program with its inputs set to `values`, in the order they
+    // were declared, marking the nodes it executes. Panics if there are too
+    // few or too many values, or one isn't of its input's type. A panic in
+    // the program itself is passed on, with the nodes executed up to it
+    // marked.
+    pub fn run(&self, values: &[DynVal]) -> DynVal {
+        if values.len() != self.inputs.len() {
+            panic!("program takes {} inputs, not {}", self.inputs.len(), values.len());
+        }
+        for (&(ref ty, ref binder), v) in self.inputs.iter().zip(values) {
+            let fits = match (v, ty) {
+                (&DynVal::Variant(ref tag, _), &Ty::Variant(ref cases)) => cases.iter().any(|c| c.0 == *tag),
+                _ => v.ty() == *ty,
+            };
+            if !fits {
+                panic!("input v{} must be {}", binder.id(), ty);
+            }
+            binder.bind(&*v.to_any());
+        }
+        observing(self.marker.clone(), || self.typed.eval())
+    }
+
+    // Forgets the nodes the runs so far executed.
+    pub fn reset(&self) {
+        self.marker.executed.borrow_mut().clear();
+    }
+
+    pub fn report(&self) -> CoverageReport {
+        let executable: HashSet<&[usize]> = self.marker.locator.paths().into_iter().collect();
+        let executed = self.marker.executed.borrow();
+        let mut missed: Vec<Missed> = Vec::new();
+        each_node(&self.program, &mut |path, node| {
+            if !executable.contains(path) || executed.contains(path) || missed.iter().any(|m| path.starts_with(&m.path)) {
+                return;
+            }
+            missed.push(Missed {
+                path: path.to_vec(),
+                node: node.to_string(),
+            });
+        });
+        CoverageReport {
+            nodes: executable.len(),
+            executed: executed.len(),
+            missed,
+        }
+    }
+}
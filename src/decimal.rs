@@ -0,0 +1,331 @@
+use std::cmp::Ordering;
+
+use {Exp, StagedExp, EvalContext, ConstantExp, DecimalVal, unit_exp};
+use ops::E;
+use reify::{Expr, Value, node};
+
+// The most digits after the point: 10^38 is the largest power of ten an
+// i128 holds.
+pub const MAX_SCALE: u32 = 38;
+
+pub fn check_scale(scale: u32) {
+    if scale > MAX_SCALE {
+        panic!("decimal scale {} is over the limit of {}", scale, MAX_SCALE);
+    }
+}
+
+fn pow10(n: u32) -> i128 {
+    10i128.pow(n)
+}
+
+fn scale_up(v: i128, by: u32) -> Option<i128> {
+    v.checked_mul(pow10(by))
+}
+
+// `n / d` rounded to the nearest whole number, halves away from zero.
+fn round_div(n: i128, d: i128) -> i128 {
+    let q = n / d;
+    let r = (n % d).unsigned_abs();
+    if r >= d.unsigned_abs() - r {
+        if (n < 0) == (d < 0) { q + 1 } else { q - 1 }
+    } else {
+        q
+    }
+}
+
+// Both values' digits at the larger of their scales.
+pub fn align(a: &DecimalVal, b: &DecimalVal) -> (i128, i128, u32) {
+    let scale = a.scale.max(b.scale);
+    let up = |x: &DecimalVal| scale_up(x.v, scale - x.scale).expect("decimal overflow");
+    (up(a), up(b), scale)
+}
+
+impl DecimalVal {
+    pub fn new(v: i128, scale: u32) -> DecimalVal {
+        check_scale(scale);
+        DecimalVal {
+            v,
+            scale
+        }
+    }
+
+    // Reads e.g. "-12.340", keeping every digit written after the point as
+    // the scale. None if it isn't a decimal or doesn't fit.
+    pub fn parse(s: &str) -> Option<DecimalVal> {
+        let (sign, digits) = match s.chars().next() {
+            Some('-') => (-1, &s[1..]),
+            Some('+') => (1, &s[1..]),
+            _ => (1, s),
+        };
+        let (whole, frac) = match digits.find('.') {
+            Some(i) => (&digits[..i], &digits[i + 1..]),
+            None => (digits, ""),
+        };
+        if (whole.is_empty() && frac.is_empty()) || !whole.chars().chain(frac.chars()).all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let scale = frac.len() as u32;
+        if scale > MAX_SCALE {
+            return None;
+        }
+        let mut v: i128 = 0;
+        for c in whole.chars().chain(frac.chars()) {
+            v = v.checked_mul(10)?.checked_add(sign * c.to_digit(10).unwrap() as i128)?;
+        }
+        Some(DecimalVal::new(v, scale))
+    }
+
+    // The same value with `scale` digits after the point, rounded to the
+    // nearest, halves away from zero, if that drops digits.
+    pub fn rescale(&self, scale: u32) -> DecimalVal {
+        check_scale(scale);
+        let v = if scale >= self.scale {
+            scale_up(self.v, scale - self.scale).expect("decimal overflow")
+        } else {
+            round_div(self.v, pow10(self.scale - scale))
+        };
+        DecimalVal::new(v, scale)
+    }
+
+    // `self / rhs` with `scale` digits after the point, rounded as by
+    // `rescale`.
+    pub fn div(&self, rhs: &DecimalVal, scale: u32) -> DecimalVal {
+        check_scale(scale);
+        if rhs.v == 0 {
+            panic!("decimal division by zero");
+        }
+        // The quotient of the digits has scale self.scale - rhs.scale, so
+        // shift whichever side makes it `scale`.
+        let shift = scale as i64 + rhs.scale as i64 - self.scale as i64;
+        let (n, d) = if shift >= 0 {
+            (scale_up(self.v, shift as u32), Some(rhs.v))
+        } else {
+            (Some(self.v), scale_up(rhs.v, (-shift) as u32))
+        };
+        match (n, d) {
+            (Some(n), Some(d)) => DecimalVal::new(round_div(n, d), scale),
+            // The divisor is too big for any digit of the quotient to show.
+            (Some(_), None) => DecimalVal::new(0, scale),
+            _ => panic!("decimal overflow"),
+        }
+    }
+}
+
+impl PartialEq for DecimalVal {
+    fn eq(&self, other: &DecimalVal) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DecimalVal {}
+
+impl PartialOrd for DecimalVal {
+    fn partial_cmp(&self, other: &DecimalVal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DecimalVal {
+    fn cmp(&self, other: &DecimalVal) -> Ordering {
+        // Scaling the one with fewer digits up can only overflow if it's
+        // bigger in size than any value the other can hold.
+        let scale = self.scale.max(other.scale);
+        match (scale_up(self.v, scale - self.scale), scale_up(other.v, scale - other.scale)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (None, _) => self.v.cmp(&0),
+            (_, None) => 0.cmp(&other.v),
+        }
+    }
+}
+
+// `exp` with `scale` digits after the point.
+#[derive(Clone)]
+pub struct RescaleExp {
+    exp: Box<Exp<Output=DecimalVal>>,
+    scale: u32,
+}
+
+pub struct RescaleStagedExp {
+    staged_exp: Box<StagedExp<Output=DecimalVal>>,
+    scale: u32,
+}
+
+impl Exp for RescaleExp {
+    type Output = DecimalVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box RescaleStagedExp {
+            staged_exp: self.exp.stage(),
+            scale: self.scale,
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.exp.interpret().rescale(self.scale)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("rescale", vec![self.exp.reify(), Expr::Const(Value::Num(self.scale as i64))])
+    }
+}
+
+impl StagedExp for RescaleStagedExp {
+    type Output = DecimalVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_exp.run(ctx).rescale(self.scale)
+    }
+}
+
+// `exp1 / exp2` with `scale` digits after the point.
+#[derive(Clone)]
+pub struct DecimalDivExp {
+    exp1: Box<Exp<Output=DecimalVal>>,
+    exp2: Box<Exp<Output=DecimalVal>>,
+    scale: u32,
+}
+
+pub struct DecimalDivStagedExp {
+    staged_exp1: Box<StagedExp<Output=DecimalVal>>,
+    staged_exp2: Box<StagedExp<Output=DecimalVal>>,
+    scale: u32,
+}
+
+impl Exp for DecimalDivExp {
+    type Output = DecimalVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box DecimalDivStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+            scale: self.scale,
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let a = self.exp1.interpret();
+        a.div(&self.exp2.interpret(), self.scale)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("decimal_div", vec![self.exp1.reify(), self.exp2.reify(), Expr::Const(Value::Num(self.scale as i64))])
+    }
+}
+
+impl StagedExp for DecimalDivStagedExp {
+    type Output = DecimalVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let a = self.staged_exp1.run(ctx);
+        a.div(&self.staged_exp2.run(ctx), self.scale)
+    }
+}
+
+// Panics if `v` isn't a decimal, e.g. "19.99".
+pub fn decimal_exp(v: &str) -> ConstantExp<DecimalVal> {
+    match DecimalVal::parse(v) {
+        Some(d) => unit_exp(d),
+        None => panic!("not a decimal: {:?}", v),
+    }
+}
+
+pub fn rescale_exp(exp: Box<Exp<Output=DecimalVal>>, scale: u32) -> RescaleExp {
+    check_scale(scale);
+    RescaleExp {
+        exp,
+        scale
+    }
+}
+
+pub fn decimal_div_exp(exp1: Box<Exp<Output=DecimalVal>>, exp2: Box<Exp<Output=DecimalVal>>, scale: u32) -> DecimalDivExp {
+    check_scale(scale);
+    DecimalDivExp {
+        exp1,
+        exp2,
+        scale
+    }
+}
+
+impl E<DecimalVal> {
+    pub fn rescale(self, scale: u32) -> E<DecimalVal> {
+        E::new(rescale_exp(self.0, scale))
+    }
+
+    pub fn div(self, rhs: E<DecimalVal>, scale: u32) -> E<DecimalVal> {
+        E::new(decimal_div_exp(self.0, rhs.0, scale))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, StagedExp, EvalContext, DecimalVal, add_exp, mul_exp};
+    use super::*;
+
+    fn dec(s: &str) -> DecimalVal {
+        DecimalVal::parse(s).unwrap()
+    }
+
+    // The value a node gives, written out, after checking its staged form
+    // gives the same digits at the same scale.
+    fn both(exp: &Exp<Output=DecimalVal>) -> String {
+        let interpreted = exp.interpret().to_string();
+        assert_eq!(exp.stage().run(&EvalContext::new()).to_string(), interpreted);
+        interpreted
+    }
+
+    #[test]
+    fn parse_keeps_the_written_scale() {
+        assert_eq!(dec("-12.340").to_string(), "-12.340");
+        assert_eq!(dec(".5").to_string(), "0.5");
+        assert_eq!(dec("+7").to_string(), "7");
+        for s in &["", ".", "-", "1.2.3", "1e5", "12a"] {
+            assert!(DecimalVal::parse(s).is_none(), "{}", s);
+        }
+        assert!(DecimalVal::parse(&format!("0.{}", "1".repeat(39))).is_none());
+        assert!(DecimalVal::parse(&"9".repeat(40)).is_none());
+    }
+
+    #[test]
+    fn rescale_rounds_halves_away_from_zero() {
+        assert_eq!(both(&rescale_exp(box decimal_exp("2.345"), 2)), "2.35");
+        assert_eq!(both(&rescale_exp(box decimal_exp("-2.345"), 2)), "-2.35");
+        assert_eq!(both(&rescale_exp(box decimal_exp("2.344"), 2)), "2.34");
+        assert_eq!(both(&rescale_exp(box decimal_exp("1.5"), 3)), "1.500");
+    }
+
+    #[test]
+    fn division_rounds_to_the_requested_scale() {
+        assert_eq!(both(&decimal_div_exp(box decimal_exp("1"), box decimal_exp("3"), 4)), "0.3333");
+        assert_eq!(both(&decimal_div_exp(box decimal_exp("2"), box decimal_exp("3"), 2)), "0.67");
+        assert_eq!(both(&decimal_div_exp(box decimal_exp("-1"), box decimal_exp("8"), 2)), "-0.13");
+        assert_eq!(both(&decimal_div_exp(box decimal_exp("10.00"), box decimal_exp("0.5"), 0)), "20");
+        assert_eq!(DecimalVal::new(1, 38).div(&dec("1000000"), 0).to_string(), "0");
+    }
+
+    #[test]
+    #[should_panic(expected = "decimal division by zero")]
+    fn division_by_zero_panics() {
+        dec("1").div(&dec("0.00"), 2);
+    }
+
+    #[test]
+    fn arithmetic_aligns_scales() {
+        assert_eq!(both(&add_exp(box decimal_exp("1.25"), box decimal_exp("0.1"))), "1.35");
+        assert_eq!(both(&mul_exp(box decimal_exp("1.5"), box decimal_exp("0.20"))), "0.300");
+    }
+
+    #[test]
+    fn values_compare_by_what_they_stand_for() {
+        assert_eq!(dec("1.5"), dec("1.50"));
+        assert!(dec("1.49") < dec("1.5"));
+        assert!(dec("-0.1") < dec("0"));
+        assert!(DecimalVal::new(i128::MAX, 0) > DecimalVal::new(1, 38));
+        assert!(DecimalVal::new(i128::MIN, 0) < DecimalVal::new(-1, 38));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use {Exp, VariableExp, BoolVal, FloatVal, fresh_id};
+use {unit_exp, add_exp, sub_exp, mul_exp, div_exp, pow_exp, partial_less_than_exp, if_exp};
+use builder::bound_let_exp;
+use ops::E;
+use reify::{Expr, Value, node, binder};
+use subst::substitute;
+
+// Symbolic derivatives of float programs: `differentiate(f, x, inputs)` is a
+// program for df/dx, over the same variables as `f`, so a function and its
+// gradient can both be staged and run as the variables change, say in an
+// optimization loop.
+//
+// Programs of add, sub, mul, div, pow, let, and if over float comparisons
+// are supported, with the exponent of a pow not depending on the variable.
+// A let's variable gets a let of its own derivative beside it, so work
+// shared through a let is shared in the derivative too; an if is
+// differentiated branch by branch. The result is simplified: constants are
+// folded, 0 and 1 dropped where they don't change a sum or product, and
+// lets removed that end up unused, bound to a constant or variable, or
+// giving just their variable. Like most simplifiers, this takes 0 * x to
+// be 0, so a derivative can be 0 where the unsimplified one would have
+// been NaN.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivError {
+    pub msg: String,
+}
+
+impl fmt::Display for DerivError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+fn err<T>(msg: String) -> Result<T, DerivError> {
+    Err(DerivError {
+        msg,
+    })
+}
+
+fn float(v: f64) -> Expr {
+    Expr::Const(Value::Float(v.to_bits()))
+}
+
+fn float_of(expr: &Expr) -> Option<f64> {
+    match *expr {
+        Expr::Const(Value::Float(bits)) => Some(f64::from_bits(bits)),
+        _ => None,
+    }
+}
+
+fn is(expr: &Expr, v: f64) -> bool {
+    float_of(expr) == Some(v)
+}
+
+// The derivative of `expr` with respect to `var`. `derivs` holds the
+// variables of the derivatives of the lets around `expr`.
+fn derive(expr: &Expr, var: i32, derivs: &mut HashMap<i32, i32>) -> Result<Expr, DerivError> {
+    let (kind, binds, children) = match *expr {
+        Expr::Const(Value::Float(_)) => return Ok(float(0.0)),
+        Expr::Var(id) if id == var => return Ok(float(1.0)),
+        Expr::Var(id) => return Ok(derivs.get(&id).map_or(float(0.0), |&d| Expr::Var(d))),
+        Expr::Node { ref kind, ref binds, ref children } => (&kind[..], binds, &children[..]),
+        Expr::Const(_) => return err("a constant that isn't a float".to_string()),
+        Expr::Bound(_) | Expr::Opaque => return err("a node that can't be reified".to_string()),
+    };
+    match (kind, children) {
+        ("let", &[ref init, ref body]) if binds.len() == 1 => {
+            let d_init = derive(init, var, derivs)?;
+            let d_var = fresh_id();
+            derivs.insert(binds[0], d_var);
+            let d_body = derive(body, var, derivs);
+            derivs.remove(&binds[0]);
+            Ok(binder("let", binds.clone(), vec![init.clone(), binder("let", vec![d_var], vec![d_init, d_body?])]))
+        }
+        _ if !binds.is_empty() => err(format!("can't differentiate the {} node", kind)),
+        ("add", &[ref a, ref b]) | ("sub", &[ref a, ref b]) => {
+            Ok(node(kind, vec![derive(a, var, derivs)?, derive(b, var, derivs)?]))
+        }
+        ("mul", &[ref a, ref b]) => {
+            let (da, db) = (derive(a, var, derivs)?, derive(b, var, derivs)?);
+            Ok(node("add", vec![node("mul", vec![da, b.clone()]), node("mul", vec![a.clone(), db])]))
+        }
+        ("div", &[ref a, ref b]) => {
+            let (da, db) = (derive(a, var, derivs)?, derive(b, var, derivs)?);
+            let num = node("sub", vec![node("mul", vec![da, b.clone()]), node("mul", vec![a.clone(), db])]);
+            Ok(node("div", vec![num, node("mul", vec![b.clone(), b.clone()])]))
+        }
+        ("pow", &[ref a, ref b]) => {
+            if !is(&simplify(&derive(b, var, derivs)?), 0.0) {
+                return err("can't differentiate a pow whose exponent depends on the variable".to_string());
+            }
+            let da = derive(a, var, derivs)?;
+            let lower = node("pow", vec![a.clone(), node("sub", vec![b.clone(), float(1.0)])]);
+            Ok(node("mul", vec![node("mul", vec![b.clone(), lower]), da]))
+        }
+        ("if", &[ref c, ref a, ref b]) => {
+            Ok(node("if", vec![c.clone(), derive(a, var, derivs)?, derive(b, var, derivs)?]))
+        }
+        _ => err(format!("can't differentiate the {} node", kind)),
+    }
+}
+
+// Whether `var` is read in `expr`.
+fn uses(expr: &Expr, var: i32) -> bool {
+    match *expr {
+        Expr::Var(id) => id == var,
+        Expr::Node { ref children, .. } => children.iter().any(|c| uses(c, var)),
+        _ => false,
+    }
+}
+
+fn simplify(expr: &Expr) -> Expr {
+    let (kind, binds, children) = match *expr {
+        Expr::Node { ref kind, ref binds, ref children } => (&kind[..], binds, children),
+        ref e => return e.clone(),
+    };
+    let children: Vec<Expr> = children.iter().map(simplify).collect();
+    if kind == "let" && binds.len() == 1 && children.len() == 2 {
+        let (init, body) = (&children[0], &children[1]);
+        if !uses(body, binds[0]) {
+            return body.clone();
+        }
+        if *body == Expr::Var(binds[0]) {
+            return init.clone();
+        }
+        if let Expr::Const(_) | Expr::Var(_) = *init {
+            return simplify(&substitute(body, binds[0], init));
+        }
+    }
+    if children.len() != 2 || !binds.is_empty() {
+        return Expr::Node {
+            kind: kind.to_string(),
+            binds: binds.clone(),
+            children,
+        };
+    }
+    let (a, b) = (&children[0], &children[1]);
+    if let (Some(x), Some(y)) = (float_of(a), float_of(b)) {
+        match kind {
+            "add" => return float(x + y),
+            "sub" => return float(x - y),
+            "mul" => return float(x * y),
+            "div" => return float(x / y),
+            "pow" => return float(x.powf(y)),
+            _ => {}
+        }
+    }
+    match kind {
+        "add" if is(a, 0.0) => b.clone(),
+        "add" | "sub" if is(b, 0.0) => a.clone(),
+        "sub" if a == b => float(0.0),
+        "mul" if is(a, 0.0) || is(b, 0.0) => float(0.0),
+        "mul" if is(a, 1.0) => b.clone(),
+        "mul" | "div" | "pow" if is(b, 1.0) => a.clone(),
+        "div" if is(a, 0.0) => float(0.0),
+        "pow" if is(b, 0.0) => float(1.0),
+        _ => node(kind, children.clone()),
+    }
+}
+
+// Builds the typed program for a derivative, with `vars` for the variables
+// it reads.
+struct Builder {
+    vars: HashMap<i32, VariableExp<FloatVal>>,
+}
+
+impl Builder {
+    fn float(&mut self, expr: &Expr) -> Result<Box<Exp<Output=FloatVal>>, DerivError> {
+        let (kind, binds, children) = match *expr {
+            Expr::Const(Value::Float(bits)) => return Ok(box unit_exp(FloatVal { v: f64::from_bits(bits) })),
+            Expr::Var(id) => return match self.vars.get(&id) {
+                Some(v) => Ok(box v.clone()),
+                None => err(format!("variable {} isn't an input", id)),
+            },
+            Expr::Node { ref kind, ref binds, ref children } => (&kind[..], binds, &children[..]),
+            _ => return err("a value that isn't a float".to_string()),
+        };
+        if kind == "let" && binds.len() == 1 && children.len() == 2 {
+            let init = self.float(&children[0])?;
+            let var = VariableExp::fresh();
+            self.vars.insert(binds[0], var.clone());
+            let body = self.float(&children[1]);
+            self.vars.remove(&binds[0]);
+            return Ok(box bound_let_exp(var, init, body?));
+        }
+        Ok(match (kind, children) {
+            ("if", &[ref c, ref a, ref b]) => box if_exp(self.cond(c)?, self.float(a)?, self.float(b)?),
+            (_, &[ref a, ref b]) => {
+                let (a, b) = (self.float(a)?, self.float(b)?);
+                match kind {
+                    "add" => box add_exp(a, b),
+                    "sub" => box sub_exp(a, b),
+                    "mul" => box mul_exp(a, b),
+                    "div" => box div_exp(a, b),
+                    "pow" => box pow_exp(a, b),
+                    _ => return err(format!("the {} node", kind)),
+                }
+            }
+            _ => return err(format!("the {} node", kind)),
+        })
+    }
+
+    fn cond(&mut self, expr: &Expr) -> Result<Box<Exp<Output=BoolVal>>, DerivError> {
+        match *expr {
+            Expr::Const(Value::Bool(v)) => Ok(box unit_exp(BoolVal { v })),
+            Expr::Node { ref kind, ref children, .. } if kind == "partial_lt" && children.len() == 2 => {
+                Ok(box partial_less_than_exp(self.float(&children[0])?, self.float(&children[1])?))
+            }
+            _ => err("a condition other than a comparison of floats".to_string()),
+        }
+    }
+}
+
+// The derivative of `exp` with respect to `var`. `inputs` are the variables
+// `exp` reads, which may include `var`.
+pub fn differentiate(exp: &Exp<Output=FloatVal>, var: &VariableExp<FloatVal>, inputs: &[VariableExp<FloatVal>])
+    -> Result<E<FloatVal>, DerivError> {
+    let d = simplify(&derive(&exp.reify(), var.id, &mut HashMap::new())?);
+    let mut b = Builder {
+        vars: inputs.iter().chain(Some(var)).map(|v| (v.id, v.clone())).collect(),
+    };
+    Ok(E(b.float(&d)?))
+}
+
+// The derivatives of `exp` with respect to each of `inputs`, in order.
+pub fn gradient(exp: &Exp<Output=FloatVal>, inputs: &[VariableExp<FloatVal>]) -> Result<Vec<E<FloatVal>>, DerivError> {
+    inputs.iter().map(|var| differentiate(exp, var, inputs)).collect()
+}
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, Place, VariableExp, MapVal, OptionVal, BoolVal, UnitVal};
+use ops::E;
+use reify::{Expr, node};
+
+pub fn map_val<K: Eq+Hash, V>(pairs: Vec<(K, V)>) -> MapVal<K, V> {
+    MapVal {
+        v: Rc::new(pairs.into_iter().collect::<HashMap<K, V>>())
+    }
+}
+
+#[derive(Clone)]
+pub struct MapGetExp<K: 'static+Clone+Eq+Hash, V: 'static+Clone> {
+    map: Box<Exp<Output=MapVal<K, V>>>,
+    key: Box<Exp<Output=K>>,
+}
+
+pub struct MapGetStagedExp<K: 'static+Clone+Eq+Hash, V: 'static+Clone> {
+    staged_map: Box<StagedExp<Output=MapVal<K, V>>>,
+    staged_key: Box<StagedExp<Output=K>>,
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> Exp for MapGetExp<K, V> {
+    type Output = OptionVal<V>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box MapGetStagedExp {
+            staged_map: self.map.stage(),
+            staged_key: self.key.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut v = None;
+        let map = self.map.interpret();
+        self.key.interpret_with(&mut |key: &K| v = map.v.get(key).cloned());
+        Self::Output {
+            v
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("map_get", vec![self.map.reify(), self.key.reify()])
+    }
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> StagedExp for MapGetStagedExp<K, V> {
+    type Output = OptionVal<V>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut v = None;
+        // Maps share their entries, so this copy is cheap, and the key may
+        // assign the map's variable.
+        let map = self.staged_map.run(ctx);
+        self.staged_key.run_with(ctx, &mut |key: &K| v = map.v.get(key).cloned());
+        Self::Output {
+            v
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MapContainsExp<K: 'static+Clone+Eq+Hash, V: 'static+Clone> {
+    map: Box<Exp<Output=MapVal<K, V>>>,
+    key: Box<Exp<Output=K>>,
+}
+
+pub struct MapContainsStagedExp<K: 'static+Clone+Eq+Hash, V: 'static+Clone> {
+    staged_map: Box<StagedExp<Output=MapVal<K, V>>>,
+    staged_key: Box<StagedExp<Output=K>>,
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> Exp for MapContainsExp<K, V> {
+    type Output = BoolVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box MapContainsStagedExp {
+            staged_map: self.map.stage(),
+            staged_key: self.key.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut v = false;
+        let map = self.map.interpret();
+        self.key.interpret_with(&mut |key: &K| v = map.v.contains_key(key));
+        Self::Output {
+            v
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("map_contains", vec![self.map.reify(), self.key.reify()])
+    }
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> StagedExp for MapContainsStagedExp<K, V> {
+    type Output = BoolVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut v = false;
+        let map = self.staged_map.run(ctx);
+        self.staged_key.run_with(ctx, &mut |key: &K| v = map.v.contains_key(key));
+        Self::Output {
+            v
+        }
+    }
+}
+
+// Produces a new map; the input map is left unchanged. The entries are only
+// copied if the input map is still shared, and a map read from a variable
+// is: the variable holds it too, so `set(m, insert(m, k, v))` copies the
+// whole map each time. Use `MapInsertIntoExp` to add to a map a variable
+// holds, as a loop building one does.
+#[derive(Clone)]
+pub struct MapInsertExp<K: 'static+Clone+Eq+Hash, V: 'static+Clone> {
+    map: Box<Exp<Output=MapVal<K, V>>>,
+    key: Box<Exp<Output=K>>,
+    val: Box<Exp<Output=V>>,
+}
+
+pub struct MapInsertStagedExp<K: 'static+Clone+Eq+Hash, V: 'static+Clone> {
+    staged_map: Box<StagedExp<Output=MapVal<K, V>>>,
+    staged_key: Box<StagedExp<Output=K>>,
+    staged_val: Box<StagedExp<Output=V>>,
+}
+
+fn insert<K: Clone+Eq+Hash, V: Clone>(mut map: MapVal<K, V>, key: K, val: V) -> MapVal<K, V> {
+    Rc::make_mut(&mut map.v).insert(key, val);
+    map
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> Exp for MapInsertExp<K, V> {
+    type Output = MapVal<K, V>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box MapInsertStagedExp {
+            staged_map: self.map.stage(),
+            staged_key: self.key.stage(),
+            staged_val: self.val.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let map = self.map.interpret();
+        insert(map, self.key.interpret(), self.val.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("map_insert", vec![self.map.reify(), self.key.reify(), self.val.reify()])
+    }
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> StagedExp for MapInsertStagedExp<K, V> {
+    type Output = MapVal<K, V>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let map = self.staged_map.run(ctx);
+        insert(map, self.staged_key.run(ctx), self.staged_val.run(ctx))
+    }
+}
+
+// Adds an entry to the map a variable holds, as `set(m, insert(m, k, v))`
+// does, but in place, so the entries are only copied if something besides
+// the variable holds the map. The key and value are evaluated first.
+#[derive(Clone)]
+pub struct MapInsertIntoExp<K: 'static+Clone+Eq+Hash, V: 'static+Clone> {
+    var: VariableExp<MapVal<K, V>>,
+    key: Box<Exp<Output=K>>,
+    val: Box<Exp<Output=V>>,
+}
+
+pub struct MapInsertIntoStagedExp<K: 'static+Clone+Eq+Hash, V: 'static+Clone> {
+    place: Place<MapVal<K, V>>,
+    staged_key: Box<StagedExp<Output=K>>,
+    staged_val: Box<StagedExp<Output=V>>,
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> Exp for MapInsertIntoExp<K, V> {
+    type Output = UnitVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box MapInsertIntoStagedExp {
+            place: Place::of(&self.var),
+            staged_key: self.key.stage(),
+            staged_val: self.val.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut entry = Some((self.key.interpret(), self.val.interpret()));
+        self.var.update(&mut |map| {
+            let (key, val) = entry.take().unwrap();
+            Rc::make_mut(&mut map.v).insert(key, val);
+        });
+        UnitVal
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let insert = node("map_insert", vec![self.var.reify(), self.key.reify(), self.val.reify()]);
+        node("set", vec![self.var.reify(), insert])
+    }
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> StagedExp for MapInsertIntoStagedExp<K, V> {
+    type Output = UnitVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut entry = Some((self.staged_key.run(ctx), self.staged_val.run(ctx)));
+        ctx.update(&self.place, &mut |map| {
+            let (key, val) = entry.take().unwrap();
+            Rc::make_mut(&mut map.v).insert(key, val);
+        });
+        UnitVal
+    }
+}
+
+pub fn map_get_exp<K: 'static+Clone+Eq+Hash, V: 'static+Clone>(map: Box<Exp<Output=MapVal<K, V>>>,
+                                                              key: Box<Exp<Output=K>>) -> MapGetExp<K, V> {
+    MapGetExp {
+        map,
+        key
+    }
+}
+
+pub fn map_contains_exp<K: 'static+Clone+Eq+Hash, V: 'static+Clone>(map: Box<Exp<Output=MapVal<K, V>>>,
+                                                                   key: Box<Exp<Output=K>>) -> MapContainsExp<K, V> {
+    MapContainsExp {
+        map,
+        key
+    }
+}
+
+pub fn map_insert_exp<K: 'static+Clone+Eq+Hash, V: 'static+Clone>(map: Box<Exp<Output=MapVal<K, V>>>,
+                                                                 key: Box<Exp<Output=K>>,
+                                                                 val: Box<Exp<Output=V>>) -> MapInsertExp<K, V> {
+    MapInsertExp {
+        map,
+        key,
+        val
+    }
+}
+
+pub fn map_insert_into_exp<K: 'static+Clone+Eq+Hash, V: 'static+Clone>(var: VariableExp<MapVal<K, V>>,
+                                                                      key: Box<Exp<Output=K>>,
+                                                                      val: Box<Exp<Output=V>>) -> MapInsertIntoExp<K, V> {
+    MapInsertIntoExp {
+        var,
+        key,
+        val
+    }
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> E<MapVal<K, V>> {
+    pub fn get(self, key: E<K>) -> E<OptionVal<V>> {
+        E::new(map_get_exp(self.0, key.0))
+    }
+
+    pub fn contains_key(self, key: E<K>) -> E<BoolVal> {
+        E::new(map_contains_exp(self.0, key.0))
+    }
+
+    pub fn insert(self, key: E<K>, val: E<V>) -> E<MapVal<K, V>> {
+        E::new(map_insert_exp(self.0, key.0, val.0))
+    }
+}
+
+impl<K: 'static+Clone+Eq+Hash, V: 'static+Clone> VariableExp<MapVal<K, V>> {
+    // Adds an entry to the map the variable holds; see `MapInsertIntoExp`.
+    pub fn insert(&self, key: E<K>, val: E<V>) -> E<UnitVal> {
+        E::new(map_insert_into_exp(self.clone(), key.0, val.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use {Exp, EvalContext, VariableExp, MapVal, NumVal, OptionVal, UnitVal, unit_exp};
+    use builder::ExpBuilder;
+    use ops::E;
+    use super::map_val;
+
+    fn squares(b: &ExpBuilder, m: &VariableExp<MapVal<NumVal, NumVal>>) -> E<UnitVal> {
+        b.for_(b.num(0), b.num(100), None, |b, i| m.insert(b.get(i), b.get(i) * b.get(i)))
+    }
+
+    #[test]
+    fn insert_into_a_bound_map() {
+        let b = ExpBuilder::new();
+        let exp = b.build(b.let_(E::new(unit_exp(map_val(vec![]))), |b, m| {
+            b.seq(squares(b, m), b.get(m).get(b.num(7)))
+        }));
+        assert_eq!(exp.interpret(), OptionVal { v: Some(NumVal { v: 49 }) });
+        assert_eq!(exp.stage().run(&EvalContext::new()), OptionVal { v: Some(NumVal { v: 49 }) });
+    }
+
+    // The map the variable holds is changed where it is, not copied.
+    #[test]
+    fn insert_into_a_host_map_in_place() {
+        let b = ExpBuilder::new();
+        let m = VariableExp::fresh_with_val(map_val(vec![]));
+        let map = Rc::as_ptr(&m.var_val.borrow().v);
+        let exp = b.build(squares(&b, &m));
+        exp.interpret();
+        assert_eq!(m.var_val.borrow().v.len(), 100);
+        assert_eq!(Rc::as_ptr(&m.var_val.borrow().v), map);
+        exp.stage().run(&EvalContext::new());
+        assert_eq!(Rc::as_ptr(&m.var_val.borrow().v), map);
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+use Exp;
+use reify::Expr;
+
+// Structural differences between two trees, e.g. a program before and after
+// a pass. Trees are compared alpha-normalized, so two that differ only in
+// their bound variables' ids don't differ, and the nodes in the edits are
+// normalized too.
+//
+// Nodes of the same kind binding the same variables are compared child by
+// child: the children the two have in common, in order, are lined up, and
+// between them children are compared in pairs, with any left over inserted
+// or removed. Any other pair of differing nodes is a change of the
+// whole node. Adding or removing a binder renumbers the bound variables
+// after it, so their binders show as changed too.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    Changed { path: Vec<(String, usize)>, from: Expr, to: Expr },
+    Inserted { path: Vec<(String, usize)>, node: Expr },
+    Removed { path: Vec<(String, usize)>, node: Expr },
+}
+
+// Paths run from the root: the kind of each node passed through and which of
+// its children was taken. Indexes are the new tree's, except a removed
+// node's own, which is where it was in the old one.
+fn write_path(f: &mut fmt::Formatter, path: &[(String, usize)]) -> fmt::Result {
+    if path.is_empty() {
+        return write!(f, "the root");
+    }
+    for (i, &(ref kind, child)) in path.iter().enumerate() {
+        if i > 0 {
+            write!(f, " > ")?;
+        }
+        write!(f, "{}[{}]", kind, child)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Edit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Edit::Changed { ref path, ref from, ref to } => {
+                write!(f, "changed at ")?;
+                write_path(f, path)?;
+                write!(f, ": {:?} to {:?}", from, to)
+            }
+            Edit::Inserted { ref path, ref node } => {
+                write!(f, "inserted at ")?;
+                write_path(f, path)?;
+                write!(f, ": {:?}", node)
+            }
+            Edit::Removed { ref path, ref node } => {
+                write!(f, "removed at ")?;
+                write_path(f, path)?;
+                write!(f, ": {:?}", node)
+            }
+        }
+    }
+}
+
+// The pairs (i, j) of a longest run of equal children a[i] == b[j], in order.
+fn common(a: &[Expr], b: &[Expr]) -> Vec<(usize, usize)> {
+    let mut len = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            len[i][j] = if a[i] == b[j] {
+                len[i + 1][j + 1] + 1
+            } else {
+                len[i + 1][j].max(len[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut pairs = Vec::new();
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if len[i + 1][j] >= len[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+fn child(path: &[(String, usize)], kind: &str, i: usize) -> Vec<(String, usize)> {
+    let mut path = path.to_vec();
+    path.push((kind.to_string(), i));
+    path
+}
+
+fn walk(a: &Expr, b: &Expr, path: &mut Vec<(String, usize)>, edits: &mut Vec<Edit>) {
+    if a == b {
+        return;
+    }
+    match (a, b) {
+        (&Expr::Node { kind: ref ka, binds: ref ba, children: ref ca },
+         &Expr::Node { kind: ref kb, binds: ref bb, children: ref cb }) if ka == kb && ba == bb => {
+            let mut anchors = common(ca, cb);
+            anchors.push((ca.len(), cb.len()));
+            let (mut i, mut j) = (0, 0);
+            for (ai, bj) in anchors {
+                // The children between the last anchor and this one.
+                while i < ai && j < bj {
+                    path.push((kb.clone(), j));
+                    walk(&ca[i], &cb[j], path, edits);
+                    path.pop();
+                    i += 1;
+                    j += 1;
+                }
+                for (i, c) in ca.iter().enumerate().take(ai).skip(i) {
+                    edits.push(Edit::Removed { path: child(path, ka, i), node: c.clone() });
+                }
+                for (j, c) in cb.iter().enumerate().take(bj).skip(j) {
+                    edits.push(Edit::Inserted { path: child(path, kb, j), node: c.clone() });
+                }
+                i = ai + 1;
+                j = bj + 1;
+            }
+        }
+        _ => edits.push(Edit::Changed { path: path.clone(), from: a.clone(), to: b.clone() }),
+    }
+}
+
+// What changed from `a` to `b`, in the order the nodes appear. Empty if
+// they're the same up to their bound variables' ids.
+pub fn diff(a: &Expr, b: &Expr) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    walk(&a.alpha_normalized(), &b.alpha_normalized(), &mut Vec::new(), &mut edits);
+    edits
+}
+
+pub fn diff_exp<T>(a: &Exp<Output=T>, b: &Exp<Output=T>) -> Vec<Edit> {
+    diff(&a.reify(), &b.reify())
+}
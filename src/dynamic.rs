@@ -0,0 +1,139 @@
+use std::any::Any;
+use std::fmt;
+use std::rc::Rc;
+
+use {NumVal, BoolVal, UnitVal, StrVal, FloatVal, RecordVal, VariantVal, unit_exp};
+use check::{Typed, Ty, Scalar};
+
+// Values and programs tagged with their types at run time, for parsers,
+// serializers and anything else that only learns a program's type as it
+// runs. A DynExp is a checked program: `check::check` makes one from an
+// untyped Expr, and `downcast` turns it into an ordinary typed expression.
+pub type DynExp = Typed;
+
+// A value of any type a DynExp can compute. Record fields are kept sorted
+// by name, as RecordVal keeps them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DynVal {
+    Num(i64),
+    Bool(bool),
+    Unit,
+    Str(String),
+    Float(f64),
+    Record(Vec<(String, DynVal)>),
+    Variant(String, Box<DynVal>),
+}
+
+impl DynVal {
+    // A record of `fields`, in any order. Panics if a name is repeated.
+    pub fn record(mut fields: Vec<(String, DynVal)>) -> DynVal {
+        fields.sort_by(|a, b| a.0.cmp(&b.0));
+        if let Some(w) = fields.windows(2).find(|w| w[0].0 == w[1].0) {
+            panic!("record field {:?} given twice", w[0].0);
+        }
+        DynVal::Record(fields)
+    }
+
+    // A variant's type is only known as far as its own case.
+    pub fn ty(&self) -> Ty {
+        match *self {
+            DynVal::Num(_) => Ty::Num,
+            DynVal::Bool(_) => Ty::Bool,
+            DynVal::Unit => Ty::Unit,
+            DynVal::Str(_) => Ty::Str,
+            DynVal::Float(_) => Ty::Float,
+            DynVal::Record(ref fields) => Ty::Record(fields.iter().map(|&(ref name, ref v)| (name.clone(), v.ty())).collect()),
+            DynVal::Variant(ref tag, ref v) => Ty::Variant(vec![(tag.clone(), v.ty())]),
+        }
+    }
+
+    // Reads `v`, a value of type `ty`. Panics if it isn't one.
+    pub fn of(v: &Any, ty: &Ty) -> DynVal {
+        fn get<T: 'static>(v: &Any) -> &T {
+            v.downcast_ref::<T>().expect("value doesn't have its type")
+        }
+        match *ty {
+            Ty::Num => DynVal::Num(get::<NumVal>(v).v),
+            Ty::Bool => DynVal::Bool(get::<BoolVal>(v).v),
+            Ty::Unit => DynVal::Unit,
+            Ty::Str => DynVal::Str(get::<StrVal>(v).v.clone()),
+            Ty::Float => DynVal::Float(get::<FloatVal>(v).v),
+            Ty::Record(ref fields) => {
+                let record = get::<RecordVal>(v);
+                DynVal::Record(fields.iter().zip(record.v.iter()).map(|(&(ref name, ref ty), &(_, ref v))| {
+                    (name.clone(), DynVal::of(&**v, ty))
+                }).collect())
+            }
+            Ty::Variant(ref cases) => {
+                let variant = get::<VariantVal>(v);
+                let ty = &cases.iter().find(|c| *c.0 == *variant.tag).expect("variant has a case its type doesn't").1;
+                DynVal::Variant(variant.tag.to_string(), box DynVal::of(&*variant.v, ty))
+            }
+        }
+    }
+
+    // The value as the host type of its type, e.g. a NumVal for a Num.
+    pub fn to_any(&self) -> Rc<Any> {
+        match *self {
+            DynVal::Num(v) => Rc::new(NumVal { v }),
+            DynVal::Bool(v) => Rc::new(BoolVal { v }),
+            DynVal::Unit => Rc::new(UnitVal),
+            DynVal::Str(ref v) => Rc::new(StrVal { v: v.clone() }),
+            DynVal::Float(v) => Rc::new(FloatVal { v }),
+            DynVal::Record(ref fields) => Rc::new(RecordVal {
+                v: Rc::new(fields.iter().map(|&(ref name, ref v)| (name.clone(), v.to_any())).collect()),
+            }),
+            DynVal::Variant(ref tag, ref v) => Rc::new(VariantVal {
+                tag: Rc::from(&tag[..]),
+                v: v.to_any(),
+            }),
+        }
+    }
+
+    // The value as a T, e.g. `downcast::<NumVal>()`, if it is one.
+    pub fn downcast<T: Scalar>(&self) -> Option<T> {
+        self.to_any().downcast_ref::<T>().cloned()
+    }
+
+    // The program that computes this value.
+    pub fn exp(&self) -> DynExp {
+        match *self {
+            DynVal::Num(v) => Typed::Num(box unit_exp(NumVal { v })),
+            DynVal::Bool(v) => Typed::Bool(box unit_exp(BoolVal { v })),
+            DynVal::Unit => Typed::Unit(box unit_exp(UnitVal)),
+            DynVal::Str(ref v) => Typed::Str(box unit_exp(StrVal { v: v.clone() })),
+            DynVal::Float(v) => Typed::Float(box unit_exp(FloatVal { v })),
+            DynVal::Record(_) | DynVal::Variant(..) => {
+                let v = self.to_any();
+                match self.ty() {
+                    Ty::Record(fields) => Typed::Record(box unit_exp(v.downcast_ref::<RecordVal>().unwrap().clone()), fields),
+                    Ty::Variant(cases) => Typed::Variant(box unit_exp(v.downcast_ref::<VariantVal>().unwrap().clone()), cases),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for DynVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DynVal::Num(v) => write!(f, "{}", v),
+            DynVal::Bool(v) => write!(f, "{}", v),
+            DynVal::Unit => write!(f, "()"),
+            DynVal::Str(ref v) => write!(f, "{:?}", v),
+            DynVal::Float(v) => write!(f, "{}", v),
+            DynVal::Record(ref fields) => {
+                write!(f, "{{")?;
+                for (i, &(ref name, ref v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", name, v)?;
+                }
+                write!(f, "}}")
+            }
+            DynVal::Variant(ref tag, ref v) => write!(f, "<{}: {}>", tag, v),
+        }
+    }
+}
@@ -0,0 +1,602 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::{self, BufRead, BufReader, Stdin};
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use {Exp, StagedExp, EvalContext, UnitVal, NumVal, InstantVal};
+use ops::E;
+use reify::{Expr, node};
+use replay::Recording;
+
+pub trait OutputSink {
+    fn write(&self, line: &str);
+}
+
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn write(&self, line: &str) {
+        println!("{}", line);
+    }
+}
+
+// Collects printed lines in memory; clones share the same buffer.
+#[derive(Clone, Default)]
+pub struct CollectSink {
+    pub lines: Rc<RefCell<Vec<String>>>,
+}
+
+impl OutputSink for CollectSink {
+    fn write(&self, line: &str) {
+        self.lines.borrow_mut().push(line.to_string());
+    }
+}
+
+pub trait InputSource {
+    // The next number, or None once the input is used up.
+    fn next_num(&self) -> Option<i64>;
+}
+
+// Numbers taken from an iterator, e.g. a scripted input in tests.
+pub struct IterInput<I: Iterator<Item=i64>> {
+    iter: RefCell<I>,
+}
+
+impl<I: Iterator<Item=i64>> IterInput<I> {
+    pub fn new(iter: I) -> IterInput<I> {
+        IterInput {
+            iter: RefCell::new(iter),
+        }
+    }
+}
+
+impl<I: Iterator<Item=i64>> InputSource for IterInput<I> {
+    fn next_num(&self) -> Option<i64> {
+        self.iter.borrow_mut().next()
+    }
+}
+
+// Whitespace-separated numbers read from `reader` a line at a time.
+pub struct ReaderInput<R: BufRead> {
+    reader: RefCell<R>,
+    pending: RefCell<VecDeque<String>>,
+}
+
+impl<R: BufRead> ReaderInput<R> {
+    pub fn new(reader: R) -> ReaderInput<R> {
+        ReaderInput {
+            reader: RefCell::new(reader),
+            pending: RefCell::new(VecDeque::new()),
+        }
+    }
+}
+
+impl<R: BufRead> InputSource for ReaderInput<R> {
+    fn next_num(&self) -> Option<i64> {
+        let mut pending = self.pending.borrow_mut();
+        while pending.is_empty() {
+            let mut line = String::new();
+            match self.reader.borrow_mut().read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) => pending.extend(line.split_whitespace().map(|t| t.to_string())),
+                Err(err) => panic!("failed to read input: {}", err),
+            }
+        }
+        let token = pending.pop_front().unwrap();
+        match token.parse() {
+            Ok(v) => Some(v),
+            Err(_) => panic!("expected a number in input, found {:?}", token),
+        }
+    }
+}
+
+pub fn stdin_input() -> ReaderInput<BufReader<Stdin>> {
+    ReaderInput::new(BufReader::new(io::stdin()))
+}
+
+// splitmix64: small and fast, and the same seed gives the same sequence on
+// every platform. Not suitable for anything security related.
+pub struct Rng {
+    state: Cell<u64>,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng {
+            state: Cell::new(seed),
+        }
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let state = self.state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.state.set(state);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [lo, hi).
+    pub fn in_range(&self, lo: i64, hi: i64) -> i64 {
+        if hi <= lo {
+            panic!("empty random range {}..{}", lo, hi);
+        }
+        let span = (hi as u64).wrapping_sub(lo as u64);
+        // Reject the top partial block so every value is equally likely.
+        let zone = u64::max_value() - (u64::max_value() - span + 1) % span;
+        loop {
+            let x = self.next_u64();
+            if x <= zone {
+                return lo.wrapping_add((x % span) as i64);
+            }
+        }
+    }
+}
+
+// Where rand's numbers come from: an Rng, unless they're being replayed.
+pub trait RandomSource {
+    // A number in [lo, hi).
+    fn in_range(&self, lo: i64, hi: i64) -> i64;
+}
+
+impl RandomSource for Rng {
+    fn in_range(&self, lo: i64, hi: i64) -> i64 {
+        Rng::in_range(self, lo, hi)
+    }
+}
+
+pub trait Clock {
+    fn now(&self) -> InstantVal;
+}
+
+// The time since the clock was made.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> InstantVal {
+        let d = self.start.elapsed();
+        InstantVal {
+            nanos: (d.as_secs() as i64).saturating_mul(1_000_000_000).saturating_add(d.subsec_nanos() as i64)
+        }
+    }
+}
+
+// A clock that only moves when told to, for programs whose timing should be
+// the same on every run. Clones share the same time.
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    pub nanos: Rc<Cell<i64>>,
+}
+
+impl ManualClock {
+    pub fn advance(&self, nanos: i64) {
+        self.nanos.set(self.nanos.get() + nanos);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> InstantVal {
+        InstantVal {
+            nanos: self.nanos.get()
+        }
+    }
+}
+
+fn clock_seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() ^ d.subsec_nanos() as u64).unwrap_or(0)
+}
+
+// Where effectful nodes send their effects. Nodes don't hold on to one:
+// they look up the current context each time they run, so the same staged
+// program can be run against different contexts.
+#[derive(Clone)]
+pub struct Effects {
+    out: Rc<OutputSink>,
+    input: Rc<InputSource>,
+    rng: Rc<RandomSource>,
+    clock: Rc<Clock>,
+}
+
+impl Effects {
+    pub fn new() -> Effects {
+        Effects {
+            out: Rc::new(StdoutSink),
+            input: Rc::new(stdin_input()),
+            rng: Rc::new(Rng::new(clock_seed())),
+            clock: Rc::new(SystemClock::new()),
+        }
+    }
+
+    pub fn output(mut self, sink: Rc<OutputSink>) -> Effects {
+        self.out = sink;
+        self
+    }
+
+    pub fn input(mut self, source: Rc<InputSource>) -> Effects {
+        self.input = source;
+        self
+    }
+
+    // Contexts with the same seed produce the same random numbers, whether
+    // the program is interpreted or staged. Clones share one generator.
+    pub fn seed(mut self, seed: u64) -> Effects {
+        self.rng = Rc::new(Rng::new(seed));
+        self
+    }
+
+    pub fn random(mut self, source: Rc<RandomSource>) -> Effects {
+        self.rng = source;
+        self
+    }
+
+    pub fn clock(mut self, clock: Rc<Clock>) -> Effects {
+        self.clock = clock;
+        self
+    }
+
+    // This context, with the numbers its input, generator and clock give
+    // also written to `tape`.
+    pub fn recording(self, tape: Rc<RefCell<Recording>>) -> Effects {
+        Effects {
+            out: self.out,
+            input: Rc::new(Recorded(self.input, tape.clone())),
+            rng: Rc::new(Recorded(self.rng, tape.clone())),
+            clock: Rc::new(Recorded(self.clock, tape)),
+        }
+    }
+}
+
+struct Recorded<S: ?Sized>(Rc<S>, Rc<RefCell<Recording>>);
+
+impl InputSource for Recorded<InputSource> {
+    fn next_num(&self) -> Option<i64> {
+        let v = self.0.next_num();
+        if let Some(v) = v {
+            self.1.borrow_mut().reads.push(v);
+        }
+        v
+    }
+}
+
+impl RandomSource for Recorded<RandomSource> {
+    fn in_range(&self, lo: i64, hi: i64) -> i64 {
+        let v = self.0.in_range(lo, hi);
+        self.1.borrow_mut().draws.push(v);
+        v
+    }
+}
+
+impl Clock for Recorded<Clock> {
+    fn now(&self) -> InstantVal {
+        let t = self.0.now();
+        self.1.borrow_mut().times.push(t.nanos);
+        t
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Effects> = RefCell::new(Effects::new());
+}
+
+pub fn current() -> Effects {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+// Puts the previous context back even if `f` panics.
+struct Restore(Option<Effects>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(prev) = self.0.take() {
+            CURRENT.with(|c| *c.borrow_mut() = prev);
+        }
+    }
+}
+
+// Runs `f` with `effects` as the current context. Contexts nest: the outer
+// one is current again once `f` returns.
+pub fn with_effects<R, F: FnOnce() -> R>(effects: Effects, f: F) -> R {
+    let prev = CURRENT.with(|c| c.replace(effects));
+    let _restore = Restore(Some(prev));
+    f()
+}
+
+pub fn interpret_in<T>(exp: &Exp<Output=T>, effects: Effects) -> T {
+    with_effects(effects, || exp.interpret())
+}
+
+pub fn run_in<T>(staged_exp: &StagedExp<Output=T>, effects: Effects) -> T {
+    with_effects(effects, || staged_exp.run(&EvalContext::new()))
+}
+
+#[derive(Clone)]
+pub struct PrintExp<T: 'static+Clone+Display> {
+    exp: Box<Exp<Output=T>>,
+}
+
+pub struct PrintStagedExp<T: 'static+Clone+Display> {
+    staged_exp: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone+Display> Exp for PrintExp<T> {
+    type Output = UnitVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box PrintStagedExp {
+            staged_exp: self.exp.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut line = String::new();
+        self.exp.interpret_with(&mut |v: &T| line = v.to_string());
+        current().out.write(&line);
+        UnitVal
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("print", vec![self.exp.reify()])
+    }
+}
+
+impl<T: 'static+Clone+Display> StagedExp for PrintStagedExp<T> {
+    type Output = UnitVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut line = String::new();
+        self.staged_exp.run_with(ctx, &mut |v: &T| line = v.to_string());
+        current().out.write(&line);
+        UnitVal
+    }
+}
+
+// Reads the next number from the current input. Running out of input is an
+// error, like any other malformed input.
+#[derive(Clone)]
+pub struct ReadExp;
+
+pub struct ReadStagedExp;
+
+fn read_num() -> NumVal {
+    match current().input.next_num() {
+        Some(v) => NumVal { v },
+        None => panic!("read past the end of the input"),
+    }
+}
+
+impl Exp for ReadExp {
+    type Output = NumVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ReadStagedExp
+    }
+    fn interpret(&self) -> Self::Output {
+        read_num()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("read", vec![])
+    }
+}
+
+impl StagedExp for ReadStagedExp {
+    type Output = NumVal;
+
+    fn run(&self, _ctx: &EvalContext) -> Self::Output {
+        read_num()
+    }
+}
+
+// A random number in [lo, hi) from the current context's generator.
+#[derive(Clone)]
+pub struct RandExp {
+    lo: Box<Exp<Output=NumVal>>,
+    hi: Box<Exp<Output=NumVal>>,
+}
+
+pub struct RandStagedExp {
+    staged_lo: Box<StagedExp<Output=NumVal>>,
+    staged_hi: Box<StagedExp<Output=NumVal>>,
+}
+
+impl Exp for RandExp {
+    type Output = NumVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box RandStagedExp {
+            staged_lo: self.lo.stage(),
+            staged_hi: self.hi.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let lo = self.lo.interpret().v;
+        let hi = self.hi.interpret().v;
+        Self::Output {
+            v: current().rng.in_range(lo, hi)
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("rand", vec![self.lo.reify(), self.hi.reify()])
+    }
+}
+
+impl StagedExp for RandStagedExp {
+    type Output = NumVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let lo = self.staged_lo.run(ctx).v;
+        let hi = self.staged_hi.run(ctx).v;
+        Self::Output {
+            v: current().rng.in_range(lo, hi)
+        }
+    }
+}
+
+// The current context's time. Instants only mean something next to others
+// from the same clock, so compare them or take the time between them.
+#[derive(Clone)]
+pub struct NowExp;
+
+pub struct NowStagedExp;
+
+impl Exp for NowExp {
+    type Output = InstantVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box NowStagedExp
+    }
+    fn interpret(&self) -> Self::Output {
+        current().clock.now()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("now", vec![])
+    }
+}
+
+impl StagedExp for NowStagedExp {
+    type Output = InstantVal;
+
+    fn run(&self, _ctx: &EvalContext) -> Self::Output {
+        current().clock.now()
+    }
+}
+
+pub fn print_exp<T: 'static+Clone+Display>(exp: Box<Exp<Output=T>>) -> PrintExp<T> {
+    PrintExp {
+        exp
+    }
+}
+
+pub fn read_exp() -> ReadExp {
+    ReadExp
+}
+
+pub fn now_exp() -> NowExp {
+    NowExp
+}
+
+pub fn rand_exp(lo: Box<Exp<Output=NumVal>>, hi: Box<Exp<Output=NumVal>>) -> RandExp {
+    RandExp {
+        lo,
+        hi
+    }
+}
+
+impl<T: 'static+Clone+Display> E<T> {
+    pub fn print(self) -> E<UnitVal> {
+        E::new(print_exp(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use {Exp, UnitVal, NumVal, StrVal, unit_exp, seq_exp};
+    use ops::E;
+    use super::{Effects, CollectSink, IterInput, ReaderInput, InputSource};
+    use super::{with_effects, interpret_in, run_in, read_exp};
+
+    fn greeting() -> E<UnitVal> {
+        let hello = E::new(unit_exp(StrVal { v: "hello".to_string() })).print();
+        E::new(seq_exp(hello.0, (E::from(6i64) * 7i64).print().0))
+    }
+
+    #[test]
+    fn print_writes_to_the_given_sink() {
+        let exp = greeting();
+        let sink = CollectSink::default();
+        interpret_in(&*exp.0, Effects::new().output(Rc::new(sink.clone())));
+        assert_eq!(*sink.lines.borrow(), vec!["hello", "42"]);
+    }
+
+    // The staged program looks the sink up each run, so runs under
+    // different contexts write to their own.
+    #[test]
+    fn staged_print_writes_to_each_runs_sink() {
+        let staged = greeting().0.stage();
+        let first = CollectSink::default();
+        let second = CollectSink::default();
+        run_in(&*staged, Effects::new().output(Rc::new(first.clone())));
+        run_in(&*staged, Effects::new().output(Rc::new(second.clone())));
+        run_in(&*staged, Effects::new().output(Rc::new(second.clone())));
+        assert_eq!(first.lines.borrow().len(), 2);
+        assert_eq!(second.lines.borrow().len(), 4);
+    }
+
+    #[test]
+    fn nested_contexts_restore_the_outer_sink() {
+        let outer = CollectSink::default();
+        let inner = CollectSink::default();
+        let one = E::from(1i64).print();
+        let two = E::from(2i64).print();
+        with_effects(Effects::new().output(Rc::new(outer.clone())), || {
+            interpret_in(&*one.0, Effects::new().output(Rc::new(inner.clone())));
+            two.0.interpret();
+        });
+        assert_eq!(*inner.lines.borrow(), vec!["1"]);
+        assert_eq!(*outer.lines.borrow(), vec!["2"]);
+    }
+
+    // The first number read, plus ten times the second.
+    fn two_reads() -> E<NumVal> {
+        E::new(read_exp()) + E::new(read_exp()) * 10i64
+    }
+
+    fn scripted(v: Vec<i64>) -> Effects {
+        Effects::new().input(Rc::new(IterInput::new(v.into_iter())))
+    }
+
+    #[test]
+    fn read_takes_numbers_from_the_given_source() {
+        let exp = two_reads();
+        assert_eq!(interpret_in(&*exp.0, scripted(vec![3, 4])).v, 43);
+        let staged = exp.0.stage();
+        assert_eq!(run_in(&*staged, scripted(vec![5, 6])).v, 65);
+        assert_eq!(run_in(&*staged, scripted(vec![7, 8, 9])).v, 87);
+    }
+
+    #[test]
+    fn reader_input_splits_lines_on_whitespace() {
+        let input: Rc<InputSource> = Rc::new(ReaderInput::new(&b"1  -2\n\n 30\n"[..]));
+        let exp = two_reads();
+        assert_eq!(interpret_in(&*exp.0, Effects::new().input(input.clone())).v, -19);
+        assert_eq!(input.next_num(), Some(30));
+        assert_eq!(input.next_num(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "read past the end of the input")]
+    fn reading_past_the_end_panics() {
+        run_in(&*two_reads().0.stage(), scripted(vec![1]));
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use check::{CheckError, Typed, check};
+use dynamic::DynVal;
+use minimize::minimize;
+use reify::{Expr, Value, binder};
+use sandbox::Sandbox;
+use strength::reduce;
+
+// Runs a program through each of the crate's pipelines and checks they agree.
+// The pipelines are the tree interpreter, the staged runner, the compiled
+// closures of `stage_compiled`, and the staged runner on the program after
+// strength reduction, which should change nothing it computes; the crate
+// has no bytecode VM or code generator to compare against as well.
+//
+// A program that disagrees is shrunk before it's reported, by
+// `minimize::minimize`, for as long as the result still checks and some
+// pair of pipelines still disagrees. Each pipeline
+// checks the program afresh, so none sees variables another has assigned.
+
+const PIPELINES: [&str; 4] = ["interpreted", "staged", "compiled", "reduced"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Value(DynVal),
+    Panicked(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Outcome::Value(ref v) => write!(f, "{}", v),
+            Outcome::Panicked(ref msg) => write!(f, "panic ({})", msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counterexample {
+    // The shrunk program, with its inputs bound by lets around it.
+    pub program: Expr,
+    // The first pipeline to disagree with the interpreter, and what each
+    // gave.
+    pub pipeline: &'static str,
+    pub expected: Outcome,
+    pub actual: Outcome,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "interpreted gives {} but {} gives {} for {:?}", self.expected, self.pipeline, self.actual,
+               self.program)
+    }
+}
+
+// `expr` with each input variable bound to its value around it.
+fn bind_inputs(expr: &Expr, inputs: &[(i32, Value)]) -> Expr {
+    inputs.iter().rev().fold(expr.clone(), |body, &(id, ref v)| {
+        binder("let", vec![id], vec![Expr::Const(v.clone()), body])
+    })
+}
+
+fn run(typed: &Typed, pipeline: &str, sandbox: &Sandbox) -> Option<Outcome> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| sandbox.run(|| match pipeline {
+        "interpreted" => typed.eval_interpreted(),
+        "staged" | "reduced" => typed.eval(),
+        _ => typed.eval_compiled(),
+    })));
+    match result {
+        Ok(Ok(v)) => Some(Outcome::Value(v)),
+        // Out of budget: nothing to compare.
+        Ok(Err(_)) => None,
+        Err(err) => Some(Outcome::Panicked(err.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| err.downcast_ref::<String>().cloned())
+            .unwrap_or_default())),
+    }
+}
+
+// Two values agree if they're equal, or both NaN where they're floats.
+fn same(a: &DynVal, b: &DynVal) -> bool {
+    match (a, b) {
+        (&DynVal::Float(x), &DynVal::Float(y)) => x == y || x.is_nan() && y.is_nan(),
+        (&DynVal::Record(ref xs), &DynVal::Record(ref ys)) => {
+            xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| x.0 == y.0 && same(&x.1, &y.1))
+        }
+        (&DynVal::Variant(ref s, ref x), &DynVal::Variant(ref t, ref y)) => s == t && same(x, y),
+        _ => a == b,
+    }
+}
+
+// Panics agree with each other whatever they say: the message depends on
+// where each pipeline happened to fail.
+fn agree(a: &Outcome, b: &Outcome) -> bool {
+    match (a, b) {
+        (&Outcome::Value(ref x), &Outcome::Value(ref y)) => same(x, y),
+        (&Outcome::Panicked(_), &Outcome::Panicked(_)) => true,
+        _ => false,
+    }
+}
+
+// The first pipeline that disagrees with the interpreter on `program`, if
+// it checks.
+fn diverge(program: &Expr, sandbox: &Sandbox) -> Result<Option<(&'static str, Outcome, Outcome)>, CheckError> {
+    let expected = match run(&check(program)?, PIPELINES[0], sandbox) {
+        Some(outcome) => outcome,
+        None => return Ok(None),
+    };
+    for pipeline in &PIPELINES[1..] {
+        let typed = if *pipeline == "reduced" { check(&reduce(program))? } else { check(program)? };
+        match run(&typed, pipeline, sandbox) {
+            Some(ref actual) if !agree(&expected, actual) => return Ok(Some((pipeline, expected, actual.clone()))),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+// Shrinks `expr`, which diverges, in the body below its input bindings.
+fn shrink(expr: &Expr, inputs: &[(i32, Value)], sandbox: &Sandbox) -> Expr {
+    minimize(expr, |body| matches!(diverge(&bind_inputs(body, inputs), sandbox), Ok(Some(_))))
+}
+
+// Checks that `expr`, with the variables of `inputs` bound to their values,
+// gives the same result through every pipeline, or else returns a shrunk
+// program on which they differ. Errors if `expr` doesn't check.
+pub fn equivalence(expr: &Expr, inputs: &[(i32, Value)]) -> Result<Option<Counterexample>, CheckError> {
+    equivalence_with(expr, inputs, &Sandbox::default())
+}
+
+// Like `equivalence`, with each run held to `sandbox`, so programs that may
+// not finish can be compared. A run that exhausts it is taken to agree.
+pub fn equivalence_with(expr: &Expr, inputs: &[(i32, Value)], sandbox: &Sandbox)
+                        -> Result<Option<Counterexample>, CheckError> {
+    if diverge(&bind_inputs(expr, inputs), sandbox)?.is_none() {
+        return Ok(None);
+    }
+    let program = bind_inputs(&shrink(expr, inputs, sandbox), inputs);
+    let (pipeline, expected, actual) = diverge(&program, sandbox)?.unwrap();
+    Ok(Some(Counterexample {
+        program,
+        pipeline,
+        expected,
+        actual,
+    }))
+}
+
+// Panics with the counterexample if the pipelines disagree on `expr`.
+pub fn assert_equivalent(expr: &Expr, inputs: &[(i32, Value)]) {
+    match equivalence(expr, inputs) {
+        Ok(None) => {}
+        Ok(Some(c)) => panic!("pipelines diverge: {}", c),
+        Err(e) => panic!("program doesn't check: {}", e),
+    }
+}
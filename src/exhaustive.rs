@@ -0,0 +1,191 @@
+use check::Ty;
+use reify::Value;
+
+// Coverage of match cases, after Maranget's "Warnings for pattern matching":
+// whether a case can match anything the cases before it don't, and a value
+// none of the cases match, if there is one.
+
+// A pattern as far as coverage goes: bindings are dropped and a record
+// pattern has every field of the record's type, in order. Cases with guards
+// may not match, so callers leave them out of the rows they cover.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Wild,
+    Lit(Value),
+    Variant(String, Box<Shape>),
+    Record(Vec<Shape>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Ctor {
+    Lit(Value),
+    Variant(String),
+    Record,
+}
+
+fn head(shape: &Shape) -> Option<Ctor> {
+    match *shape {
+        Shape::Wild => None,
+        Shape::Lit(ref v) => Some(Ctor::Lit(v.clone())),
+        Shape::Variant(ref tag, _) => Some(Ctor::Variant(tag.clone())),
+        Shape::Record(_) => Some(Ctor::Record),
+    }
+}
+
+// Every value of `ty` starts with one of these, or None if there's no end
+// to them, as for numbers and strings.
+fn ctors(ty: &Ty) -> Option<Vec<Ctor>> {
+    match *ty {
+        Ty::Bool => Some(vec![Ctor::Lit(Value::Bool(false)), Ctor::Lit(Value::Bool(true))]),
+        Ty::Unit => Some(vec![Ctor::Lit(Value::Unit)]),
+        Ty::Record(_) => Some(vec![Ctor::Record]),
+        Ty::Variant(ref cases) => Some(cases.iter().map(|c| Ctor::Variant(c.0.clone())).collect()),
+        Ty::Num | Ty::Str | Ty::Float => None,
+    }
+}
+
+// The types of what's inside a value of `ty` that starts with `c`.
+fn args(c: &Ctor, ty: &Ty) -> Vec<Ty> {
+    match (c, ty) {
+        (&Ctor::Variant(ref tag), &Ty::Variant(ref cases)) => {
+            cases.iter().filter(|case| &case.0 == tag).map(|case| case.1.clone()).collect()
+        }
+        (&Ctor::Record, &Ty::Record(ref fields)) => fields.iter().map(|f| f.1.clone()).collect(),
+        _ => vec![],
+    }
+}
+
+fn build(c: Ctor, mut args: Vec<Shape>) -> Shape {
+    match c {
+        Ctor::Lit(v) => Shape::Lit(v),
+        Ctor::Variant(tag) => Shape::Variant(tag, box args.pop().unwrap_or(Shape::Wild)),
+        Ctor::Record => Shape::Record(args),
+    }
+}
+
+// The row with its first column opened up for values starting with `c`, or
+// None if the row can't match those.
+fn specialize(row: &[Shape], c: &Ctor, arity: usize) -> Option<Vec<Shape>> {
+    let mut out = match row[0] {
+        Shape::Wild => vec![Shape::Wild; arity],
+        ref shape if head(shape).as_ref() != Some(c) => return None,
+        Shape::Variant(_, ref p) => vec![(**p).clone()],
+        Shape::Record(ref fields) => fields.clone(),
+        Shape::Lit(_) => vec![],
+    };
+    out.extend_from_slice(&row[1..]);
+    Some(out)
+}
+
+fn specialize_all(rows: &[Vec<Shape>], c: &Ctor, arity: usize) -> Vec<Vec<Shape>> {
+    rows.iter().filter_map(|row| specialize(row, c, arity)).collect()
+}
+
+// The rows that match whatever is in the first column, without it.
+fn default(rows: &[Vec<Shape>]) -> Vec<Vec<Shape>> {
+    rows.iter().filter(|row| row[0] == Shape::Wild).map(|row| row[1..].to_vec()).collect()
+}
+
+fn used(rows: &[Vec<Shape>]) -> Vec<Ctor> {
+    let mut used = Vec::new();
+    for c in rows.iter().filter_map(|row| head(&row[0])) {
+        if !used.contains(&c) {
+            used.push(c);
+        }
+    }
+    used
+}
+
+// The constructors of `ty`, if the rows' first column starts with every one.
+fn complete(rows: &[Vec<Shape>], ty: &Ty) -> Option<Vec<Ctor>> {
+    let all = ctors(ty)?;
+    let used = used(rows);
+    if all.iter().all(|c| used.contains(c)) {
+        Some(all)
+    } else {
+        None
+    }
+}
+
+fn with_args(args: Vec<Ty>, tys: &[Ty]) -> Vec<Ty> {
+    let mut out = args;
+    out.extend_from_slice(&tys[1..]);
+    out
+}
+
+// Whether some value, of types `tys`, matches `v` but none of `rows`.
+pub fn useful(rows: &[Vec<Shape>], v: &[Shape], tys: &[Ty]) -> bool {
+    if v.is_empty() {
+        return rows.is_empty();
+    }
+    let open = |c: &Ctor| {
+        let args = args(c, &tys[0]);
+        let v = specialize(v, c, args.len()).unwrap();
+        useful(&specialize_all(rows, c, args.len()), &v, &with_args(args, tys))
+    };
+    match head(&v[0]) {
+        Some(c) => open(&c),
+        None => match complete(rows, &tys[0]) {
+            Some(all) => all.iter().any(open),
+            None => useful(&default(rows), &v[1..], &tys[1..]),
+        },
+    }
+}
+
+// Values, of types `tys`, that none of `rows` match, or None if they cover
+// every value.
+pub fn missing(rows: &[Vec<Shape>], tys: &[Ty]) -> Option<Vec<Shape>> {
+    if tys.is_empty() {
+        return if rows.is_empty() { Some(vec![]) } else { None };
+    }
+    if let Some(all) = complete(rows, &tys[0]) {
+        for c in all {
+            let args = args(&c, &tys[0]);
+            let arity = args.len();
+            if let Some(mut w) = missing(&specialize_all(rows, &c, arity), &with_args(args, tys)) {
+                let rest = w.split_off(arity);
+                let mut out = vec![build(c, w)];
+                out.extend(rest);
+                return Some(out);
+            }
+        }
+        return None;
+    }
+    let mut w = missing(&default(rows), &tys[1..])?;
+    // A constructor the rows don't start with, if the type has few enough
+    // to name one.
+    let used = used(rows);
+    let first = match ctors(&tys[0]).and_then(|all| all.into_iter().find(|c| !used.contains(c))) {
+        Some(c) => {
+            let arity = args(&c, &tys[0]).len();
+            build(c, vec![Shape::Wild; arity])
+        }
+        None => Shape::Wild,
+    };
+    w.insert(0, first);
+    Some(w)
+}
+
+// `shape` written as a value of `ty`, with _ for any value.
+pub fn show(shape: &Shape, ty: &Ty) -> String {
+    match (shape, ty) {
+        (&Shape::Lit(Value::Num(n)), _) => n.to_string(),
+        (&Shape::Lit(Value::Bool(b)), _) => b.to_string(),
+        (&Shape::Lit(Value::Unit), _) => "()".to_string(),
+        (&Shape::Lit(Value::Str(ref s)), _) => format!("{:?}", s),
+        (&Shape::Lit(Value::Float(bits)), _) => f64::from_bits(bits).to_string(),
+        (&Shape::Variant(ref tag, ref p), &Ty::Variant(ref cases)) => {
+            match cases.iter().find(|c| &c.0 == tag) {
+                Some(case) => format!("{}({})", tag, show(p, &case.1)),
+                None => format!("{}(_)", tag),
+            }
+        }
+        (&Shape::Record(ref shapes), &Ty::Record(ref fields)) => {
+            let fields: Vec<String> = shapes.iter().zip(fields)
+                .map(|(s, f)| format!("{}: {}", f.0, show(s, &f.1)))
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+        _ => "_".to_string(),
+    }
+}
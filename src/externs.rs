@@ -0,0 +1,157 @@
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, NumVal, BoolVal, UnitVal, StrVal, FloatVal, RecordVal, VariantVal};
+use check::{CheckError, Scalar, Ty, Typed, join};
+use dynamic::DynVal;
+use registry::{Extension, register};
+use reify::{Expr, node};
+
+// Calls out to Rust for what the language doesn't cover. A host function
+// takes its arguments as DynVals and gives back a DynVal, or an error, which
+// stops the program like any other failure. Its signature is declared up
+// front so `check` can hold calls to it: registered with `register_extern`,
+// a call is a node of the function's name, which loads, prints and
+// serializes like any other and stays a call once staged.
+
+pub type HostFn = Rc<Fn(&[DynVal]) -> Result<DynVal, String>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub params: Vec<Ty>,
+    pub ret: Ty,
+}
+
+pub struct ExternCallExp<T: 'static> {
+    name: Rc<str>,
+    f: HostFn,
+    ret: Ty,
+    args: Rc<Vec<Typed>>,
+    phantom: PhantomData<T>,
+}
+
+impl<T: 'static> Clone for ExternCallExp<T> {
+    fn clone(&self) -> Self {
+        ExternCallExp {
+            name: self.name.clone(),
+            f: self.f.clone(),
+            ret: self.ret.clone(),
+            args: self.args.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+pub struct ExternCallStagedExp<T: 'static> {
+    name: Rc<str>,
+    f: HostFn,
+    ret: Ty,
+    staged_args: Vec<Box<Fn() -> DynVal>>,
+    phantom: PhantomData<T>,
+}
+
+// Calls `f` and checks it gave what it said it would.
+fn call<T: Scalar>(name: &str, f: &HostFn, ret: &Ty, args: &[DynVal]) -> T {
+    let v = match f(args) {
+        Ok(v) => v,
+        Err(msg) => panic!("{} failed: {}", name, msg),
+    };
+    if join(ret, &v.ty()).as_ref() != Some(ret) {
+        panic!("{} returned {}, not {}", name, v.ty(), ret);
+    }
+    v.downcast::<T>().unwrap()
+}
+
+impl<T: Scalar> Exp for ExternCallExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ExternCallStagedExp {
+            name: self.name.clone(),
+            f: self.f.clone(),
+            ret: self.ret.clone(),
+            staged_args: self.args.iter().map(|a| a.stage_eval()).collect(),
+            phantom: PhantomData,
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        let args: Vec<DynVal> = self.args.iter().map(|a| a.eval_interpreted()).collect();
+        call(&self.name, &self.f, &self.ret, &args)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node(&self.name, self.args.iter().map(|a| a.reify()).collect())
+    }
+}
+
+impl<T: Scalar> StagedExp for ExternCallStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, _ctx: &EvalContext) -> Self::Output {
+        let args: Vec<DynVal> = self.staged_args.iter().map(|a| a()).collect();
+        call(&self.name, &self.f, &self.ret, &args)
+    }
+}
+
+pub fn extern_call_exp<T: Scalar>(name: &str, f: HostFn, ret: Ty, args: Vec<Typed>) -> ExternCallExp<T> {
+    ExternCallExp {
+        name: Rc::from(name),
+        f,
+        ret,
+        args: Rc::new(args),
+        phantom: PhantomData,
+    }
+}
+
+// A call of `f` with `args`, or an error if they don't fit its signature.
+pub fn extern_call(name: &str, sig: &Signature, f: HostFn, args: Vec<Typed>) -> Result<Typed, CheckError> {
+    if args.len() != sig.params.len() {
+        return Err(CheckError::new(format!("{} takes {} arguments, not {}", name, sig.params.len(), args.len())));
+    }
+    for (i, (arg, param)) in args.iter().zip(&sig.params).enumerate() {
+        if join(param, &arg.ty()).as_ref() != Some(param) {
+            return Err(CheckError::new(format!("argument {} of {} must be {}, not {}", i + 1, name, param,
+                                               arg.ty())));
+        }
+    }
+    let ret = sig.ret.clone();
+    Ok(match sig.ret.clone() {
+        Ty::Num => Typed::Num(box extern_call_exp::<NumVal>(name, f, ret, args)),
+        Ty::Bool => Typed::Bool(box extern_call_exp::<BoolVal>(name, f, ret, args)),
+        Ty::Unit => Typed::Unit(box extern_call_exp::<UnitVal>(name, f, ret, args)),
+        Ty::Str => Typed::Str(box extern_call_exp::<StrVal>(name, f, ret, args)),
+        Ty::Float => Typed::Float(box extern_call_exp::<FloatVal>(name, f, ret, args)),
+        Ty::Record(fields) => Typed::Record(box extern_call_exp::<RecordVal>(name, f, ret, args), fields),
+        Ty::Variant(cases) => Typed::Variant(box extern_call_exp::<VariantVal>(name, f, ret, args), cases),
+    })
+}
+
+struct ExternFn {
+    name: String,
+    sig: Signature,
+    f: HostFn,
+}
+
+impl Extension for ExternFn {
+    fn kind(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&self, args: Vec<Typed>) -> Result<Typed, CheckError> {
+        extern_call(&self.name, &self.sig, self.f.clone(), args)
+    }
+}
+
+// Lets loaded programs call `f` as `name`, on this thread.
+pub fn register_extern(name: &str, sig: Signature, f: HostFn) {
+    register(Rc::new(ExternFn {
+        name: name.to_string(),
+        sig,
+        f,
+    }));
+}
@@ -0,0 +1,464 @@
+use std::cell::RefCell;
+
+use {Exp, StagedExp, EvalContext, StrVal, NumVal, BoolVal, FloatVal};
+
+// Scratch buffers reused across runs of a staged node, so matching a string
+// against many inputs doesn't allocate per comparison.
+#[derive(Default)]
+pub struct Scratch {
+    a: Vec<char>,
+    b: Vec<char>,
+    row: Vec<usize>,
+    a_flags: Vec<bool>,
+    b_flags: Vec<bool>,
+}
+
+impl Scratch {
+    fn load(&mut self, a: &str, b: &str) {
+        self.a.clear();
+        self.a.extend(a.chars());
+        self.b.clear();
+        self.b.extend(b.chars());
+    }
+
+    fn levenshtein(&mut self, a: &str, b: &str, max: Option<usize>) -> Option<usize> {
+        self.load(a, b);
+        levenshtein_chars(&self.a, &self.b, max, &mut self.row)
+    }
+
+    fn jaro_winkler(&mut self, a: &str, b: &str, threshold: Option<f64>) -> Option<f64> {
+        self.load(a, b);
+        jaro_winkler_chars(&self.a, &self.b, threshold, &mut self.a_flags, &mut self.b_flags)
+    }
+}
+
+// Returns None as soon as the distance is known to exceed `max`.
+fn levenshtein_chars(a: &[char], b: &[char], max: Option<usize>, row: &mut Vec<usize>) -> Option<usize> {
+    let (a, b) = if a.len() < b.len() { (b, a) } else { (a, b) };
+    if let Some(max) = max {
+        if a.len() - b.len() > max {
+            return None;
+        }
+    }
+
+    row.clear();
+    row.extend(0..b.len() + 1);
+    for (i, ca) in a.iter().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        let mut row_min = row[0];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == cb { 0 } else { 1 };
+            let next = (diag + cost).min(row[j] + 1).min(row[j + 1] + 1);
+            diag = row[j + 1];
+            row[j + 1] = next;
+            row_min = row_min.min(next);
+        }
+        if let Some(max) = max {
+            if row_min > max {
+                return None;
+            }
+        }
+    }
+
+    let dist = row[b.len()];
+    match max {
+        Some(max) if dist > max => None,
+        _ => Some(dist),
+    }
+}
+
+const WINKLER_SCALE: f64 = 0.1;
+const WINKLER_PREFIX: usize = 4;
+// Winkler's boost only applies to pairs that are already similar.
+const WINKLER_THRESHOLD: f64 = 0.7;
+
+// Returns None when the similarity provably can't reach `threshold`; the
+// length-based upper bound lets most non-matches skip the match scan.
+fn jaro_winkler_chars(a: &[char], b: &[char], threshold: Option<f64>,
+                      a_flags: &mut Vec<bool>, b_flags: &mut Vec<bool>) -> Option<f64> {
+    if a.is_empty() && b.is_empty() {
+        return Some(1.0);
+    }
+    if a.is_empty() || b.is_empty() {
+        return match threshold {
+            Some(t) if t > 0.0 => None,
+            _ => Some(0.0),
+        };
+    }
+
+    let prefix = a.iter().zip(b.iter())
+        .take(WINKLER_PREFIX)
+        .take_while(|&(ca, cb)| ca == cb)
+        .count() as f64;
+    // Monotone in `jaro`, so boosting an upper bound gives an upper bound.
+    let boost = |jaro: f64| if jaro > WINKLER_THRESHOLD {
+        jaro + prefix * WINKLER_SCALE * (1.0 - jaro)
+    } else {
+        jaro
+    };
+
+    if let Some(t) = threshold {
+        let (short, long) = (a.len().min(b.len()) as f64, a.len().max(b.len()) as f64);
+        if boost((2.0 + short / long) / 3.0) < t {
+            return None;
+        }
+    }
+
+    let window = (a.len().max(b.len()) / 2).saturating_sub(1);
+    a_flags.clear();
+    a_flags.resize(a.len(), false);
+    b_flags.clear();
+    b_flags.resize(b.len(), false);
+
+    let mut matches = 0;
+    for (i, ca) in a.iter().enumerate() {
+        let lo = i.saturating_sub(window);
+        let hi = (i + window + 1).min(b.len());
+        for j in lo..hi {
+            if !b_flags[j] && b[j] == *ca {
+                a_flags[i] = true;
+                b_flags[j] = true;
+                matches += 1;
+                break;
+            }
+        }
+    }
+
+    let sim = if matches == 0 {
+        0.0
+    } else {
+        let mut transpositions = 0;
+        let mut j = 0;
+        for (i, ca) in a.iter().enumerate() {
+            if a_flags[i] {
+                while !b_flags[j] {
+                    j += 1;
+                }
+                if *ca != b[j] {
+                    transpositions += 1;
+                }
+                j += 1;
+            }
+        }
+        let m = matches as f64;
+        let jaro = (m / a.len() as f64 + m / b.len() as f64 + (m - (transpositions / 2) as f64) / m) / 3.0;
+        boost(jaro)
+    };
+
+    match threshold {
+        Some(t) if sim < t => None,
+        _ => Some(sim),
+    }
+}
+
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    Scratch::default().levenshtein(a, b, None).unwrap()
+}
+
+pub fn jaro_winkler(a: &str, b: &str) -> f64 {
+    Scratch::default().jaro_winkler(a, b, None).unwrap()
+}
+
+// Batch forms share one set of scratch buffers across all candidates.
+pub fn levenshtein_batch(query: &str, candidates: &[&str], max: Option<usize>) -> Vec<Option<usize>> {
+    let mut scratch = Scratch::default();
+    candidates.iter().map(|c| scratch.levenshtein(query, c, max)).collect()
+}
+
+pub fn jaro_winkler_batch(query: &str, candidates: &[&str], threshold: Option<f64>) -> Vec<Option<f64>> {
+    let mut scratch = Scratch::default();
+    candidates.iter().map(|c| scratch.jaro_winkler(query, c, threshold)).collect()
+}
+
+#[derive(Clone)]
+pub struct LevenshteinExp {
+    exp1: Box<Exp<Output=StrVal>>,
+    exp2: Box<Exp<Output=StrVal>>,
+}
+
+pub struct LevenshteinStagedExp {
+    staged_exp1: Box<StagedExp<Output=StrVal>>,
+    staged_exp2: Box<StagedExp<Output=StrVal>>,
+    scratch: RefCell<Scratch>,
+}
+
+impl Exp for LevenshteinExp {
+    type Output = NumVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box LevenshteinStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+            scratch: RefCell::new(Scratch::default()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        Self::Output {
+            v: levenshtein(&self.exp1.interpret().v, &self.exp2.interpret().v) as i64
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl StagedExp for LevenshteinStagedExp {
+    type Output = NumVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let (s1, s2) = (self.staged_exp1.run(ctx), self.staged_exp2.run(ctx));
+        Self::Output {
+            v: self.scratch.borrow_mut().levenshtein(&s1.v, &s2.v, None).unwrap() as i64
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LevenshteinWithinExp {
+    exp1: Box<Exp<Output=StrVal>>,
+    exp2: Box<Exp<Output=StrVal>>,
+    max: usize,
+}
+
+pub struct LevenshteinWithinStagedExp {
+    staged_exp1: Box<StagedExp<Output=StrVal>>,
+    staged_exp2: Box<StagedExp<Output=StrVal>>,
+    max: usize,
+    scratch: RefCell<Scratch>,
+}
+
+impl Exp for LevenshteinWithinExp {
+    type Output = BoolVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box LevenshteinWithinStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+            max: self.max,
+            scratch: RefCell::new(Scratch::default()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        Self::Output {
+            v: levenshtein(&self.exp1.interpret().v, &self.exp2.interpret().v) <= self.max
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl StagedExp for LevenshteinWithinStagedExp {
+    type Output = BoolVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let (s1, s2) = (self.staged_exp1.run(ctx), self.staged_exp2.run(ctx));
+        Self::Output {
+            v: self.scratch.borrow_mut().levenshtein(&s1.v, &s2.v, Some(self.max)).is_some()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JaroWinklerExp {
+    exp1: Box<Exp<Output=StrVal>>,
+    exp2: Box<Exp<Output=StrVal>>,
+}
+
+pub struct JaroWinklerStagedExp {
+    staged_exp1: Box<StagedExp<Output=StrVal>>,
+    staged_exp2: Box<StagedExp<Output=StrVal>>,
+    scratch: RefCell<Scratch>,
+}
+
+impl Exp for JaroWinklerExp {
+    type Output = FloatVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box JaroWinklerStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+            scratch: RefCell::new(Scratch::default()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        Self::Output {
+            v: jaro_winkler(&self.exp1.interpret().v, &self.exp2.interpret().v)
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl StagedExp for JaroWinklerStagedExp {
+    type Output = FloatVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let (s1, s2) = (self.staged_exp1.run(ctx), self.staged_exp2.run(ctx));
+        Self::Output {
+            v: self.scratch.borrow_mut().jaro_winkler(&s1.v, &s2.v, None).unwrap()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JaroWinklerAtLeastExp {
+    exp1: Box<Exp<Output=StrVal>>,
+    exp2: Box<Exp<Output=StrVal>>,
+    threshold: f64,
+}
+
+pub struct JaroWinklerAtLeastStagedExp {
+    staged_exp1: Box<StagedExp<Output=StrVal>>,
+    staged_exp2: Box<StagedExp<Output=StrVal>>,
+    threshold: f64,
+    scratch: RefCell<Scratch>,
+}
+
+impl Exp for JaroWinklerAtLeastExp {
+    type Output = BoolVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box JaroWinklerAtLeastStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+            threshold: self.threshold,
+            scratch: RefCell::new(Scratch::default()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        Self::Output {
+            v: jaro_winkler(&self.exp1.interpret().v, &self.exp2.interpret().v) >= self.threshold
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl StagedExp for JaroWinklerAtLeastStagedExp {
+    type Output = BoolVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let (s1, s2) = (self.staged_exp1.run(ctx), self.staged_exp2.run(ctx));
+        Self::Output {
+            v: self.scratch.borrow_mut().jaro_winkler(&s1.v, &s2.v, Some(self.threshold)).is_some()
+        }
+    }
+}
+
+pub fn levenshtein_exp(exp1: Box<Exp<Output=StrVal>>, exp2: Box<Exp<Output=StrVal>>) -> LevenshteinExp {
+    LevenshteinExp {
+        exp1,
+        exp2
+    }
+}
+
+pub fn levenshtein_within_exp(exp1: Box<Exp<Output=StrVal>>, exp2: Box<Exp<Output=StrVal>>,
+                              max: usize) -> LevenshteinWithinExp {
+    LevenshteinWithinExp {
+        exp1,
+        exp2,
+        max
+    }
+}
+
+pub fn jaro_winkler_exp(exp1: Box<Exp<Output=StrVal>>, exp2: Box<Exp<Output=StrVal>>) -> JaroWinklerExp {
+    JaroWinklerExp {
+        exp1,
+        exp2
+    }
+}
+
+pub fn jaro_winkler_at_least_exp(exp1: Box<Exp<Output=StrVal>>, exp2: Box<Exp<Output=StrVal>>,
+                                 threshold: f64) -> JaroWinklerAtLeastExp {
+    JaroWinklerAtLeastExp {
+        exp1,
+        exp2,
+        threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, EvalContext};
+    use strings::str_exp;
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn jaro_winkler_matches_reference_values() {
+        assert!(close(jaro_winkler("MARTHA", "MARHTA"), 0.9611));
+        assert!(close(jaro_winkler("DIXON", "DICKSONX"), 0.8133));
+        assert!(close(jaro_winkler("", ""), 1.0));
+        assert!(close(jaro_winkler("abc", ""), 0.0));
+    }
+
+    // Below the Winkler threshold a shared prefix adds nothing.
+    #[test]
+    fn dissimilar_pairs_get_no_prefix_boost() {
+        let jaro = (1.0 / 4.0 + 1.0 / 4.0 + 1.0) / 3.0;
+        assert!(close(jaro_winkler("axxx", "ayyy"), jaro));
+    }
+
+    const WORDS: &[&str] = &["", "a", "ab", "abc", "kitten", "sitting", "MARTHA", "MARHTA",
+                             "DIXON", "DICKSONX", "abcdefgh", "hgfedcba", "sunday", "saturday"];
+
+    #[test]
+    fn levenshtein_within_agrees_with_unpruned_distance() {
+        for a in WORDS {
+            for b in WORDS {
+                let dist = levenshtein(a, b);
+                for max in 0..10 {
+                    let within = levenshtein_within_exp(box str_exp(a), box str_exp(b), max);
+                    assert_eq!(within.interpret().v, dist <= max, "{} {} {}", a, b, max);
+                    assert_eq!(within.stage().run(&EvalContext::new()).v, dist <= max, "{} {} {}", a, b, max);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn batch_thresholds_agree_with_unpruned_results() {
+        for query in WORDS {
+            let dists = levenshtein_batch(query, WORDS, None);
+            let sims = jaro_winkler_batch(query, WORDS, None);
+            for max in 0..10 {
+                let pruned = levenshtein_batch(query, WORDS, Some(max));
+                for (d, p) in dists.iter().zip(&pruned) {
+                    assert_eq!(*p, Some(d.unwrap()).filter(|&d| d <= max));
+                }
+            }
+            for &t in &[0.0, 0.5, 0.7, 0.75, 0.8133, 0.9, 0.9611, 1.0] {
+                let pruned = jaro_winkler_batch(query, WORDS, Some(t));
+                for (s, p) in sims.iter().zip(&pruned) {
+                    assert_eq!(*p, Some(s.unwrap()).filter(|&s| s >= t));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn staged_matches_interpret() {
+        for a in WORDS {
+            for b in WORDS {
+                let ctx = EvalContext::new();
+                let lev = levenshtein_exp(box str_exp(a), box str_exp(b));
+                assert_eq!(lev.interpret().v, lev.stage().run(&ctx).v);
+                let jw = jaro_winkler_exp(box str_exp(a), box str_exp(b));
+                assert_eq!(jw.interpret().v, jw.stage().run(&ctx).v);
+                let at_least = jaro_winkler_at_least_exp(box str_exp(a), box str_exp(b), 0.8);
+                assert_eq!(at_least.interpret().v, at_least.stage().run(&ctx).v);
+            }
+        }
+    }
+}
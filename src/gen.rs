@@ -0,0 +1,269 @@
+use check::{Ty, Typed, check};
+use effects::Rng;
+use reify::{Expr, Value, node, binder};
+
+// Random well-typed programs, for fuzzing and property tests of the
+// backends and rewrites. Programs are untyped Exprs that `check` accepts,
+// over num, bool, unit and str values; the same seed and config give the
+// same program.
+//
+// Every program finishes: loops count up to small constant bounds and
+// nest only so deep. Arithmetic can still overflow, which panics in a debug
+// build as it would for any program; without `mul` it takes a loop doubling
+// a variable to get there. The crate has no division node, so there's no
+// dividing by zero to turn off.
+
+#[derive(Debug, Clone)]
+pub struct GenConfig {
+    // Roughly how many nodes a program has.
+    pub size: usize,
+    pub loops: bool,
+    pub mul: bool,
+    // Bitwise operations and shifts.
+    pub bits: bool,
+    // Strings and the string nodes; without them programs are num, bool
+    // and unit only.
+    pub strings: bool,
+}
+
+impl Default for GenConfig {
+    fn default() -> GenConfig {
+        GenConfig {
+            size: 30,
+            loops: true,
+            mul: true,
+            bits: true,
+            strings: true,
+        }
+    }
+}
+
+// The most iterations of any one loop, and how deep loops nest.
+const MAX_ITERATIONS: i64 = 8;
+const MAX_LOOP_NESTING: usize = 2;
+
+struct Var {
+    id: i32,
+    ty: Ty,
+    // Loop counters are read-only, so the loops stay bounded.
+    assignable: bool,
+}
+
+pub struct Gen {
+    rng: Rng,
+    config: GenConfig,
+    next_id: i32,
+    scope: Vec<Var>,
+    loop_nesting: usize,
+}
+
+impl Gen {
+    pub fn new(seed: u64, config: GenConfig) -> Gen {
+        Gen {
+            rng: Rng::new(seed),
+            config,
+            next_id: 0,
+            scope: Vec::new(),
+            loop_nesting: 0,
+        }
+    }
+
+    // A program of type `ty`, which must be num, bool, unit or str, and str
+    // only if the config has strings.
+    pub fn program(&mut self, ty: &Ty) -> Expr {
+        let size = self.config.size.max(1);
+        self.exp(ty, size)
+    }
+
+    // A program of a random type, and its type.
+    pub fn any_program(&mut self) -> (Expr, Ty) {
+        let ty = self.ty();
+        (self.program(&ty), ty)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.rng.in_range(0, n as i64) as usize
+    }
+
+    fn ty(&mut self) -> Ty {
+        let n = if self.config.strings { 4 } else { 3 };
+        match self.below(n) {
+            0 => Ty::Num,
+            1 => Ty::Bool,
+            2 => Ty::Unit,
+            _ => Ty::Str,
+        }
+    }
+
+    fn fresh(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    // Splits the size left after a node between `n` children.
+    fn split(&mut self, size: usize, n: usize) -> Vec<usize> {
+        let mut left = size.saturating_sub(1);
+        let mut sizes = Vec::new();
+        for i in 0..n {
+            let s = if i + 1 == n { left } else { self.below(left + 1) };
+            left -= s;
+            sizes.push(s.max(1));
+        }
+        sizes
+    }
+
+    fn vars(&self, ty: &Ty, assignable: bool) -> Vec<i32> {
+        self.scope.iter().filter(|v| v.ty == *ty && (v.assignable || !assignable)).map(|v| v.id).collect()
+    }
+
+    fn leaf(&mut self, ty: &Ty) -> Expr {
+        let vars = self.vars(ty, false);
+        if !vars.is_empty() && self.below(2) == 0 {
+            let i = self.below(vars.len());
+            return Expr::Var(vars[i]);
+        }
+        Expr::Const(match *ty {
+            Ty::Num => Value::Num(self.rng.in_range(-100, 100)),
+            Ty::Bool => Value::Bool(self.below(2) == 0),
+            Ty::Str => {
+                let len = self.below(4);
+                Value::Str((0..len).map(|_| (b'a' + self.below(3) as u8) as char).collect())
+            }
+            _ => Value::Unit,
+        })
+    }
+
+    fn exp(&mut self, ty: &Ty, size: usize) -> Expr {
+        if size <= 1 {
+            return self.leaf(ty);
+        }
+        loop {
+            // Forms any type can take; each returns None if it doesn't apply.
+            let e = match self.below(8) {
+                0 => self.if_(ty, size),
+                1 => self.let_(ty, size),
+                2 => self.seq(ty, size),
+                _ => match *ty {
+                    Ty::Num => self.num(size),
+                    Ty::Bool => self.bool(size),
+                    Ty::Unit => self.unit(size),
+                    _ => self.str(size),
+                },
+            };
+            if let Some(e) = e {
+                return e;
+            }
+        }
+    }
+
+    fn binary(&mut self, kind: &str, a: &Ty, b: &Ty, size: usize) -> Expr {
+        let s = self.split(size, 2);
+        node(kind, vec![self.exp(a, s[0]), self.exp(b, s[1])])
+    }
+
+    fn if_(&mut self, ty: &Ty, size: usize) -> Option<Expr> {
+        let s = self.split(size, 3);
+        Some(node("if", vec![self.exp(&Ty::Bool, s[0]), self.exp(ty, s[1]), self.exp(ty, s[2])]))
+    }
+
+    fn let_(&mut self, ty: &Ty, size: usize) -> Option<Expr> {
+        let s = self.split(size, 2);
+        let var_ty = self.ty();
+        let init = self.exp(&var_ty, s[0]);
+        let id = self.fresh();
+        self.scope.push(Var { id, ty: var_ty, assignable: true });
+        let body = self.exp(ty, s[1]);
+        self.scope.pop();
+        Some(binder("let", vec![id], vec![init, body]))
+    }
+
+    fn seq(&mut self, ty: &Ty, size: usize) -> Option<Expr> {
+        Some(self.binary("seq", &Ty::Unit, ty, size))
+    }
+
+    fn num(&mut self, size: usize) -> Option<Expr> {
+        let kinds: &[&str] = match (self.config.mul, self.config.bits) {
+            (true, true) => &["add", "sub", "mul", "bit_and", "bit_or", "bit_xor", "shl", "shr", "bit_not", "str_len"],
+            (true, false) => &["add", "sub", "mul", "str_len"],
+            (false, true) => &["add", "sub", "bit_and", "bit_or", "bit_xor", "shl", "shr", "bit_not", "str_len"],
+            (false, false) => &["add", "sub", "str_len"],
+        };
+        let kind = kinds[self.below(kinds.len())];
+        Some(match kind {
+            "bit_not" => node(kind, vec![self.exp(&Ty::Num, size - 1)]),
+            "str_len" if self.config.strings => node(kind, vec![self.exp(&Ty::Str, size - 1)]),
+            "str_len" => return None,
+            _ => self.binary(kind, &Ty::Num, &Ty::Num, size),
+        })
+    }
+
+    fn bool(&mut self, size: usize) -> Option<Expr> {
+        Some(match self.below(3) {
+            0 => self.binary("lt", &Ty::Num, &Ty::Num, size),
+            _ if !self.config.strings => return None,
+            1 => self.binary("str_eq", &Ty::Str, &Ty::Str, size),
+            _ => self.binary("contains", &Ty::Str, &Ty::Str, size),
+        })
+    }
+
+    fn unit(&mut self, size: usize) -> Option<Expr> {
+        match self.below(3) {
+            0 => {
+                let ty = self.ty();
+                let vars = self.vars(&ty, true);
+                if vars.is_empty() {
+                    return None;
+                }
+                let id = vars[self.below(vars.len())];
+                Some(node("set", vec![Expr::Var(id), self.exp(&ty, size - 1)]))
+            }
+            _ if !self.config.loops || self.loop_nesting >= MAX_LOOP_NESTING => None,
+            1 => {
+                // for i in start..end, at most MAX_ITERATIONS apart.
+                let start = self.rng.in_range(-MAX_ITERATIONS, MAX_ITERATIONS);
+                let end = start + self.rng.in_range(0, MAX_ITERATIONS + 1);
+                let id = self.fresh();
+                let body = self.loop_body(id, size - 1);
+                Some(binder("for", vec![id], vec![Expr::Const(Value::Num(start)), Expr::Const(Value::Num(end)), body]))
+            }
+            _ => {
+                // let i = 0 in while i < n { body; i = i + 1 }
+                let n = self.rng.in_range(0, MAX_ITERATIONS + 1);
+                let id = self.fresh();
+                let body = self.loop_body(id, size - 1);
+                let step = node("set", vec![Expr::Var(id), node("add", vec![Expr::Var(id), Expr::Const(Value::Num(1))])]);
+                let cond = node("lt", vec![Expr::Var(id), Expr::Const(Value::Num(n))]);
+                let while_ = node("while", vec![cond, node("seq", vec![body, step])]);
+                Some(binder("let", vec![id], vec![Expr::Const(Value::Num(0)), while_]))
+            }
+        }
+    }
+
+    fn loop_body(&mut self, counter: i32, size: usize) -> Expr {
+        self.scope.push(Var { id: counter, ty: Ty::Num, assignable: false });
+        self.loop_nesting += 1;
+        let body = self.exp(&Ty::Unit, size);
+        self.loop_nesting -= 1;
+        self.scope.pop();
+        body
+    }
+
+    fn str(&mut self, size: usize) -> Option<Expr> {
+        Some(match self.below(2) {
+            0 => self.binary("concat", &Ty::Str, &Ty::Str, size),
+            _ => {
+                let s = self.split(size, 3);
+                node("substring", vec![self.exp(&Ty::Str, s[0]), self.exp(&Ty::Num, s[1]), self.exp(&Ty::Num, s[2])])
+            }
+        })
+    }
+}
+
+// A random program of a random type, checked.
+pub fn gen_typed(seed: u64, config: GenConfig) -> Typed {
+    let expr = Gen::new(seed, config).any_program().0;
+    match check(&expr) {
+        Ok(typed) => typed,
+        Err(e) => panic!("generated a program that doesn't check: {}: {:?}", e, expr),
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+use std::rc::Rc;
+use std::sync::mpsc;
+
+use wgpu;
+use wgpu::util::DeviceExt;
+use pollster;
+
+use {Exp, StagedExp, EvalContext, VariableExp, ArrayVal, FloatVal};
+use reify::{Expr, Value, binder};
+
+// Maps over float arrays run as WGSL compute shaders, for bodies that are
+// only arithmetic on the element and float constants. The GPU works in f32,
+// so elements and constants are rounded to f32 on the way there and results
+// come back with f32 precision. Interpreting a GpuMapExp runs it on the CPU
+// in f64, like MapExp.
+
+const WORKGROUP: u32 = 64;
+// The most workgroups in one dimension of a dispatch; bigger arrays use a
+// second dimension.
+const MAX_GROUPS: u32 = 65535;
+
+#[derive(Debug)]
+pub enum GpuError {
+    NoAdapter,
+    Device(String),
+    // The map's body isn't one the shader can express.
+    Unsupported(String),
+}
+
+impl fmt::Display for GpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GpuError::NoAdapter => write!(f, "no GPU adapter found"),
+            GpuError::Device(ref msg) => write!(f, "couldn't open the GPU: {}", msg),
+            GpuError::Unsupported(ref msg) => write!(f, "can't run on the GPU: {}", msg),
+        }
+    }
+}
+
+// An open device. Maps made with the same Gpu share it.
+pub struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+}
+
+impl Gpu {
+    pub fn new() -> Result<Rc<Gpu>, GpuError> {
+        let instance = wgpu::Instance::default();
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+            .ok_or(GpuError::NoAdapter)?;
+        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            label: None,
+            required_features: wgpu::Features::empty(),
+            required_limits: wgpu::Limits::downlevel_defaults(),
+        }, None)).map_err(|e| GpuError::Device(e.to_string()))?;
+        Ok(Rc::new(Gpu {
+            device,
+            queue
+        }))
+    }
+}
+
+// The body as a WGSL expression of `x`.
+fn wgsl_expr(expr: &Expr, elem: i32) -> Result<String, GpuError> {
+    match *expr {
+        Expr::Var(id) if id == elem => Ok("x".to_string()),
+        Expr::Const(Value::Float(bits)) => {
+            let v = f64::from_bits(bits) as f32;
+            if !v.is_finite() {
+                return Err(GpuError::Unsupported(format!("WGSL has no literal for {}", v)));
+            }
+            Ok(format!("{:?}f", v))
+        }
+        Expr::Node { ref kind, ref binds, ref children } if binds.is_empty() && children.len() == 2 => {
+            let op = match &kind[..] {
+                "add" => "+",
+                "sub" => "-",
+                "mul" => "*",
+                _ => return Err(GpuError::Unsupported(format!("the {} node", kind))),
+            };
+            Ok(format!("({} {} {})", wgsl_expr(&children[0], elem)?, op, wgsl_expr(&children[1], elem)?))
+        }
+        Expr::Var(_) | Expr::Bound(_) => Err(GpuError::Unsupported("a variable other than the element".to_string())),
+        Expr::Node { ref kind, .. } => Err(GpuError::Unsupported(format!("the {} node", kind))),
+        Expr::Const(_) | Expr::Opaque => Err(GpuError::Unsupported("a value that isn't a float".to_string())),
+    }
+}
+
+fn shader(body: &str) -> String {
+    format!("@group(0) @binding(0) var<storage, read> input: array<f32>;
+@group(0) @binding(1) var<storage, read_write> output: array<f32>;
+
+@compute @workgroup_size({workgroup})
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {{
+    let i = gid.y * groups.x * {workgroup}u + gid.x;
+    if (i >= arrayLength(&input)) {{
+        return;
+    }}
+    let x = input[i];
+    output[i] = {body};
+}}
+", workgroup = WORKGROUP, body = body)
+}
+
+// A map of `f` over `items` that runs on `gpu` when staged, or an error if
+// `f` does anything but arithmetic on the element.
+pub fn gpu_map_exp(gpu: &Rc<Gpu>, items: Box<Exp<Output=ArrayVal<FloatVal>>>,
+                   f: Box<Fn(VariableExp<FloatVal>) -> Box<Exp<Output=FloatVal>>>) -> Result<GpuMapExp, GpuError> {
+    let elem = VariableExp::<FloatVal>::fresh();
+    let body = wgsl_expr(&f(elem.clone()).reify(), elem.id)?;
+    Ok(GpuMapExp {
+        gpu: gpu.clone(),
+        items,
+        f: Rc::from(f),
+        shader: shader(&body),
+    })
+}
+
+#[derive(Clone)]
+pub struct GpuMapExp {
+    gpu: Rc<Gpu>,
+    items: Box<Exp<Output=ArrayVal<FloatVal>>>,
+    f: Rc<Fn(VariableExp<FloatVal>) -> Box<Exp<Output=FloatVal>>>,
+    shader: String,
+}
+
+// The pipeline is built once, when the map is staged.
+pub struct GpuMapStagedExp {
+    gpu: Rc<Gpu>,
+    staged_items: Box<StagedExp<Output=ArrayVal<FloatVal>>>,
+    pipeline: wgpu::ComputePipeline,
+}
+
+impl Exp for GpuMapExp {
+    type Output = ArrayVal<FloatVal>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let device = &self.gpu.device;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(self.shader.clone().into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: None,
+            module: &module,
+            entry_point: "main",
+        });
+        box GpuMapStagedExp {
+            gpu: self.gpu.clone(),
+            staged_items: self.items.stage(),
+            pipeline,
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        Self::Output {
+            v: self.items.interpret().v.into_iter().map(|x| (self.f)(VariableExp::fresh_with_val(x)).interpret()).collect()
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let elem_var = VariableExp::fresh();
+        let f = (self.f)(elem_var.clone()).reify();
+        binder("gpu_map", vec![elem_var.id], vec![self.items.reify(), f])
+    }
+}
+
+impl GpuMapStagedExp {
+    fn dispatch(&self, xs: &[f32]) -> Vec<f32> {
+        let Gpu { ref device, ref queue } = *self.gpu;
+        let size = (xs.len() * 4) as u64;
+        let bytes: Vec<u8> = xs.iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+        let input = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: &bytes,
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let output = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: input.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: output.as_entire_binding() },
+            ],
+        });
+        let groups = (xs.len() as u32 + WORKGROUP - 1) / WORKGROUP;
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(groups.min(MAX_GROUPS), (groups + MAX_GROUPS - 1) / MAX_GROUPS, 1);
+        }
+        encoder.copy_buffer_to_buffer(&output, 0, &readback, 0, size);
+        queue.submit(Some(encoder.finish()));
+
+        let slice = readback.slice(..);
+        let (tx, rx) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| tx.send(result).unwrap());
+        device.poll(wgpu::Maintain::Wait);
+        rx.recv().unwrap().expect("failed to read back GPU results");
+        let v = slice.get_mapped_range().chunks(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        readback.unmap();
+        v
+    }
+}
+
+impl StagedExp for GpuMapStagedExp {
+    type Output = ArrayVal<FloatVal>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut xs = Vec::new();
+        self.staged_items.run_with(ctx, &mut |items: &ArrayVal<FloatVal>| {
+            xs = items.v.iter().map(|x| x.v as f32).collect();
+        });
+        // Buffers can't be empty.
+        if xs.is_empty() {
+            return ArrayVal { v: vec![] };
+        }
+        Self::Output {
+            v: self.dispatch(&xs).into_iter().map(|x| FloatVal { v: x as f64 }).collect()
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use Exp;
+use cost::CostTable;
+use reify::{Expr, binder};
+use subst::freshen;
+
+// Inlining of calls to functions whose bodies are known, so the code
+// generators and passes that only understand straight-line code see the
+// body where the call was. A call is `apply(f, arg)`, or `apply(apply(f,
+// a), b)` for a two-argument function, where f is a lambda written in
+// place or a variable a let binds to one; it becomes lets binding the
+// arguments around a fresh copy of the body:
+//
+//     let[v1](lambda[v2](mul(v2, v2)), add(apply(v1, 3), 1))
+//
+// becomes `add(let[v3](3, mul(v3, v3)), 1)`. Arguments are still evaluated
+// once each, in the order they were. A lambda's body reads the variables
+// around it when it's called, as the inlined body does, so inlining
+// doesn't change what a program computes.
+//
+// A lambda written where it's applied is always inlined, and so is one
+// bound by a let and called just once, as neither makes the program
+// bigger. Otherwise a let-bound function's calls are only inlined if its
+// body costs no more than the threshold, by the cost table, and the let is
+// dropped once nothing reads it. Recursive functions can't be inlined, and
+// aren't: a let's variable isn't in scope in what it's bound to, and rec's
+// functions don't reify. Calls under a binder of a variable the body reads
+// are left alone, as the body would read the wrong variable there.
+
+pub struct Inliner {
+    table: CostTable,
+    threshold: f64,
+}
+
+impl Default for Inliner {
+    fn default() -> Inliner {
+        Inliner {
+            table: CostTable::default(),
+            threshold: 20.0,
+        }
+    }
+}
+
+// The number of arguments of the function `expr`, if it's a lambda.
+fn arity(expr: &Expr) -> Option<usize> {
+    match *expr {
+        Expr::Node { ref kind, ref binds, ref children } if children.len() == 1 => match (&kind[..], binds.len()) {
+            ("lambda", 1) => Some(1),
+            ("lambda2", 2) => Some(2),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The function and arguments of `expr` if it's a call of `arity` arguments.
+fn call(expr: &Expr, arity: usize) -> Option<(&Expr, Vec<&Expr>)> {
+    match *expr {
+        Expr::Node { ref kind, ref children, .. } if kind == "apply" && children.len() == 2 => {
+            if arity == 1 {
+                return Some((&children[0], vec![&children[1]]));
+            }
+            call(&children[0], arity - 1).map(|(f, mut args)| {
+                args.push(&children[1]);
+                (f, args)
+            })
+        }
+        _ => None,
+    }
+}
+
+// The body of the lambda `f`, freshened, with lets binding `args` to its
+// variables.
+fn beta(f: &Expr, args: Vec<Expr>) -> Expr {
+    match freshen(f) {
+        Expr::Node { binds, mut children, .. } => {
+            let body = children.pop().unwrap();
+            binds.into_iter().zip(args).rev().fold(body, |body, (var, arg)| binder("let", vec![var], vec![arg, body]))
+        }
+        _ => unreachable!(),
+    }
+}
+
+// How often `var` is read in `expr`, and how many of those reads are calls
+// of `arity` arguments.
+fn uses(expr: &Expr, var: i32, arity: usize) -> (usize, usize) {
+    if let Some((&Expr::Var(id), args)) = call(expr, arity) {
+        if id == var {
+            let (reads, calls) = args.iter().fold((0, 0), |(r, c), a| {
+                let (ar, ac) = uses(a, var, arity);
+                (r + ar, c + ac)
+            });
+            return (reads + 1, calls + 1);
+        }
+    }
+    match *expr {
+        Expr::Var(id) if id == var => (1, 0),
+        Expr::Node { ref binds, .. } if binds.contains(&var) => (0, 0),
+        Expr::Node { ref children, .. } => children.iter().fold((0, 0), |(r, c), child| {
+            let (cr, cc) = uses(child, var, arity);
+            (r + cr, c + cc)
+        }),
+        _ => (0, 0),
+    }
+}
+
+// Whether `expr` sets `var`.
+fn assigned(expr: &Expr, var: i32) -> bool {
+    match *expr {
+        Expr::Node { ref kind, ref children, .. } => {
+            (kind == "set" && children.first() == Some(&Expr::Var(var))) || children.iter().any(|c| assigned(c, var))
+        }
+        _ => false,
+    }
+}
+
+// `expr` with the calls of `var`, bound to the lambda `f`, inlined where
+// none of `scope` binds a variable the body reads.
+fn inline_calls(expr: &Expr, var: i32, f: &Expr, free: &[i32], scope: &mut Vec<i32>) -> Expr {
+    let arity = arity(f).unwrap();
+    if let Some((&Expr::Var(id), args)) = call(expr, arity) {
+        if id == var && !scope.iter().any(|b| free.contains(b)) {
+            let args = args.into_iter().map(|a| inline_calls(a, var, f, free, scope)).collect();
+            return beta(f, args);
+        }
+    }
+    match *expr {
+        Expr::Node { ref binds, .. } if binds.contains(&var) => expr.clone(),
+        Expr::Node { ref kind, ref binds, ref children } => {
+            scope.extend(binds);
+            let children = children.iter().map(|c| inline_calls(c, var, f, free, scope)).collect();
+            scope.truncate(scope.len() - binds.len());
+            Expr::Node {
+                kind: kind.clone(),
+                binds: binds.clone(),
+                children,
+            }
+        }
+        ref e => e.clone(),
+    }
+}
+
+impl Inliner {
+    pub fn new() -> Inliner {
+        Inliner::default()
+    }
+
+    pub fn cost_table(mut self, table: CostTable) -> Self {
+        self.table = table;
+        self
+    }
+
+    // The most a let-bound function's body may cost for calls to it to be
+    // inlined when there's more than one.
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    fn small(&self, f: &Expr) -> bool {
+        match *f {
+            Expr::Node { ref children, .. } => self.table.estimate(&children[0]).cost <= self.threshold,
+            _ => false,
+        }
+    }
+
+    // `expr`, with its children already inlined, inlined at its root.
+    fn at(&self, expr: Expr) -> Expr {
+        for arity in 1..3 {
+            if let Some((f, args)) = call(&expr, arity) {
+                if self::arity(f) == Some(arity) {
+                    return beta(f, args.into_iter().cloned().collect());
+                }
+            }
+        }
+        let (binds, init, body) = match expr {
+            Expr::Node { ref kind, ref binds, ref children } if kind == "let" && binds.len() == 1 && children.len() == 2 => {
+                (binds, &children[0], &children[1])
+            }
+            _ => return expr,
+        };
+        let var = binds[0];
+        let arity = match arity(init) {
+            Some(arity) if !assigned(body, var) => arity,
+            _ => return expr,
+        };
+        let (reads, calls) = uses(body, var, arity);
+        if calls == 0 || !(reads == 1 || self.small(init)) {
+            return expr;
+        }
+        let body = inline_calls(body, var, init, &init.free_vars(), &mut Vec::new());
+        if uses(&body, var, arity).0 == 0 {
+            return body;
+        }
+        binder("let", binds.clone(), vec![init.clone(), body])
+    }
+
+    pub fn inline(&self, expr: &Expr) -> Expr {
+        match *expr {
+            Expr::Node { ref kind, ref binds, ref children } => self.at(Expr::Node {
+                kind: kind.clone(),
+                binds: binds.clone(),
+                children: children.iter().map(|c| self.inline(c)).collect(),
+            }),
+            ref e => e.clone(),
+        }
+    }
+
+    pub fn inline_exp<T>(&self, exp: &Exp<Output=T>) -> Expr {
+        self.inline(&exp.reify())
+    }
+}
+
+// Inlines with the default cost table and threshold.
+pub fn inline<T>(exp: &Exp<Output=T>) -> Expr {
+    Inliner::default().inline_exp(exp)
+}
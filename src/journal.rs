@@ -0,0 +1,142 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use VariableExp;
+
+// A record of the writes a program makes to its variables, for stepping its
+// state backwards and forwards once it's run. Writes are recorded while
+// `Journal::record` runs: by let, set, loops, match arms and patterns
+// binding their variables as they're interpreted. A staged run keeps the
+// variables it binds in its EvalContext, so only its sets of the host's
+// variables are recorded. Undoing puts a variable back to what it held before the write,
+// and redoing makes the write again; neither is itself recorded.
+//
+// Each recorded write keeps copies of the old and new values.
+
+#[derive(Clone)]
+pub struct Change {
+    // The variable's id, as `Expr::Var` has it.
+    pub var: i32,
+    pub old: Rc<Any>,
+    pub new: Rc<Any>,
+    set: Rc<Fn(&Any)>,
+}
+
+#[derive(Default)]
+struct Log {
+    changes: Vec<Change>,
+    // How many of `changes` are in effect; the rest have been undone.
+    position: usize,
+}
+
+// Clones share the same record.
+#[derive(Clone, Default)]
+pub struct Journal {
+    log: Rc<RefCell<Log>>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Journal>> = const { RefCell::new(None) };
+}
+
+// Journals recording on any thread, so writes skip the thread-local when
+// there are none.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+impl Journal {
+    pub fn new() -> Journal {
+        Journal::default()
+    }
+
+    // Runs `f` with the writes it makes on this thread recorded here. Writes
+    // after an undo drop the changes that could have been redone.
+    pub fn record<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        struct Restore(Option<Journal>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT.with(|c| *c.borrow_mut() = self.0.take());
+                ACTIVE.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        let _restore = Restore(CURRENT.with(|c| c.borrow_mut().replace(self.clone())));
+        f()
+    }
+
+    // Undoes the latest change still in effect, and returns it.
+    pub fn undo(&self) -> Option<Change> {
+        let mut log = self.log.borrow_mut();
+        if log.position == 0 {
+            return None;
+        }
+        log.position -= 1;
+        let change = log.changes[log.position].clone();
+        (change.set)(&*change.old);
+        Some(change)
+    }
+
+    // Makes the earliest undone change again, and returns it.
+    pub fn redo(&self) -> Option<Change> {
+        let mut log = self.log.borrow_mut();
+        if log.position == log.changes.len() {
+            return None;
+        }
+        let change = log.changes[log.position].clone();
+        log.position += 1;
+        (change.set)(&*change.new);
+        Some(change)
+    }
+
+    // Every change recorded, oldest first, including those undone.
+    pub fn history(&self) -> Vec<Change> {
+        self.log.borrow().changes.clone()
+    }
+
+    // How many of `history` are in effect.
+    pub fn position(&self) -> usize {
+        self.log.borrow().position
+    }
+
+    pub fn clear(&self) {
+        *self.log.borrow_mut() = Log::default();
+    }
+}
+
+fn current() -> Option<Journal> {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+// Called by `VariableExp::assign` once it has written `new` over `old`.
+pub fn record_write<T: 'static+Clone>(var: &VariableExp<T>, old: &T, new: &T) {
+    let journal = match current() {
+        Some(journal) => journal,
+        None => return,
+    };
+    let var_val = var.var_val.clone();
+    let change = Change {
+        var: var.id,
+        old: Rc::new(old.clone()),
+        new: Rc::new(new.clone()),
+        set: Rc::new(move |v: &Any| {
+            var_val.replace(v.downcast_ref::<T>().unwrap().clone());
+        }),
+    };
+    let mut log = journal.log.borrow_mut();
+    let position = log.position;
+    log.changes.truncate(position);
+    log.changes.push(change);
+    log.position += 1;
+}
+
+// Whether writes need to be passed to `record_write`.
+#[inline]
+pub fn recording() -> bool {
+    ACTIVE.load(Ordering::Relaxed) != 0
+}
@@ -0,0 +1,565 @@
+use std::fmt;
+
+use Exp;
+use limits::{Budget, Limit, LimitError, Limits};
+use reify::{Expr, Value};
+use span::{Span, Spans, each_node};
+
+// Programs as JSON. Each Expr is one object with a single tag:
+//
+//   {"num": 5}  {"bool": true}  {"unit": null}  {"str": "abc"}
+//   {"float": 1.5}                  also "NaN", "inf" or "-inf" as strings
+//   {"var": 7}                      a free variable, by id
+//   {"bound": 0}                    a variable bound by an enclosing node
+//   {"node": "add", "args": [...]}
+//   {"node": "let", "binds": [0], "args": [init, body]}
+//   {"opaque": null}                a part that couldn't be exported
+//
+// `binds` may be left out when empty. Node names and their arguments are
+// those produced by `Exp::reify`; `check` lists the ones it can load.
+// Programs are exported alpha-normalized, so binders are numbered from 0 in
+// the order they appear and referred to with "bound".
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    // Keys in the order they were written.
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonError {
+    // Byte offset into the input, or 0 for errors in the program's shape.
+    pub pos: usize,
+    pub msg: String,
+    // Set when the input was rejected for going over a limit.
+    pub limit: Option<LimitError>,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at {}: {}", self.pos, self.msg)
+    }
+}
+
+fn err<T>(pos: usize, msg: &str) -> Result<T, JsonError> {
+    Err(JsonError {
+        pos,
+        msg: msg.to_string(),
+        limit: None,
+    })
+}
+
+fn limit_err<T>(pos: usize, e: LimitError) -> Result<T, JsonError> {
+    Err(JsonError {
+        pos,
+        msg: e.to_string(),
+        limit: Some(e),
+    })
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Int(n) => write!(f, "{}", n),
+            // Debug keeps the ".0" on whole numbers, so they read back as floats.
+            Json::Float(x) => write!(f, "{:?}", x),
+            Json::Str(ref s) => write_str(f, s),
+            Json::Array(ref items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (i, &(ref k, ref v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    depth: usize,
+    max_depth: usize,
+    // Where each object starts and ends, in the order they start.
+    objects: Vec<(usize, usize)>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_ws(&mut self) {
+        while self.pos < self.src.len() && (self.src[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        self.src.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), JsonError> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            err(self.pos, &format!("expected '{}'", c as char))
+        }
+    }
+
+    fn literal(&mut self, word: &str, v: Json) -> Result<Json, JsonError> {
+        if self.src[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(v)
+        } else {
+            err(self.pos, "unexpected token")
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        match self.peek() {
+            None => err(self.pos, "unexpected end of input"),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::Str),
+            Some(b'[') | Some(b'{') => {
+                self.depth += 1;
+                if self.depth > self.max_depth {
+                    return limit_err(self.pos, LimitError { limit: Limit::Depth, max: self.max_depth });
+                }
+                let v = self.nested();
+                self.depth -= 1;
+                v
+            }
+            Some(_) => self.number(),
+        }
+    }
+
+    // An array or object; `value` has checked the nesting depth.
+    fn nested(&mut self) -> Result<Json, JsonError> {
+        match self.src[self.pos] {
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return err(self.pos, "expected ',' or ']'"),
+                    }
+                }
+            }
+            _ => {
+                let object = self.objects.len();
+                self.objects.push((self.pos, self.pos));
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    self.objects[object].1 = self.pos;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return err(self.pos, "expected a key");
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            self.objects[object].1 = self.pos;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return err(self.pos, "expected ',' or '}'"),
+                    }
+                }
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.src.get(self.pos..self.pos + 4)
+            .and_then(|d| ::std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok());
+        match digits {
+            Some(v) => {
+                self.pos += 4;
+                Ok(v)
+            }
+            None => err(self.pos, "bad \\u escape"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            match self.src.get(self.pos).cloned() {
+                None => return err(self.pos, "unterminated string"),
+                Some(b'"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = match self.src.get(self.pos).cloned() {
+                        Some(c) => c,
+                        None => return err(self.pos, "unterminated string"),
+                    };
+                    self.pos += 1;
+                    let ch = match c {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hi = self.hex4()?;
+                            let code = if hi >= 0xd800 && hi < 0xdc00 && self.src[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let lo = self.hex4()?;
+                                0x10000 + ((hi - 0xd800) << 10) + (lo.wrapping_sub(0xdc00) & 0x3ff)
+                            } else {
+                                hi
+                            };
+                            match ::std::char::from_u32(code) {
+                                Some(ch) => ch,
+                                None => return err(self.pos, "bad \\u escape"),
+                            }
+                        }
+                        _ => return err(self.pos - 1, "bad escape"),
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                }
+                Some(c) => {
+                    out.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        match String::from_utf8(out) {
+            Ok(s) => Ok(s),
+            Err(_) => err(self.pos, "invalid utf-8 in string"),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        let mut is_float = false;
+        while let Some(&c) = self.src.get(self.pos) {
+            match c {
+                b'0'..=b'9' | b'-' | b'+' => {}
+                b'.' | b'e' | b'E' => is_float = true,
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let text = ::std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        if !is_float {
+            if let Ok(n) = text.parse() {
+                return Ok(Json::Int(n));
+            }
+        }
+        match text.parse() {
+            Ok(x) => Ok(Json::Float(x)),
+            Err(_) => err(start, "bad number"),
+        }
+    }
+}
+
+// Nesting deeper than Limits::default allows for a program is rejected.
+pub fn parse_json(src: &str) -> Result<Json, JsonError> {
+    parse(src, json_depth(&Limits::default()))
+}
+
+// Each level of a program is an object and its "args" array.
+fn json_depth(limits: &Limits) -> usize {
+    limits.max_depth.saturating_mul(2).saturating_add(1)
+}
+
+fn parse(src: &str, max_depth: usize) -> Result<Json, JsonError> {
+    parse_objects(src, max_depth).map(|(v, _)| v)
+}
+
+fn parse_objects(src: &str, max_depth: usize) -> Result<(Json, Vec<(usize, usize)>), JsonError> {
+    let mut p = Parser {
+        src: src.as_bytes(),
+        pos: 0,
+        depth: 0,
+        max_depth,
+        objects: Vec::new(),
+    };
+    let v = p.value()?;
+    if p.peek().is_some() {
+        return err(p.pos, "trailing characters");
+    }
+    Ok((v, p.objects))
+}
+
+fn tagged(tag: &str, v: Json) -> Json {
+    Json::Object(vec![(tag.to_string(), v)])
+}
+
+pub fn float_json(x: f64) -> Json {
+    if x.is_nan() {
+        Json::Str("NaN".to_string())
+    } else if x.is_infinite() {
+        Json::Str(if x > 0.0 { "inf" } else { "-inf" }.to_string())
+    } else {
+        Json::Float(x)
+    }
+}
+
+impl Expr {
+    pub fn to_json(&self) -> Json {
+        match *self {
+            Expr::Const(Value::Num(n)) => tagged("num", Json::Int(n)),
+            Expr::Const(Value::Bool(b)) => tagged("bool", Json::Bool(b)),
+            Expr::Const(Value::Unit) => tagged("unit", Json::Null),
+            Expr::Const(Value::Str(ref s)) => tagged("str", Json::Str(s.clone())),
+            Expr::Const(Value::Float(bits)) => tagged("float", float_json(f64::from_bits(bits))),
+            Expr::Const(Value::Opaque) | Expr::Opaque => tagged("opaque", Json::Null),
+            Expr::Var(id) => tagged("var", Json::Int(id as i64)),
+            Expr::Bound(n) => tagged("bound", Json::Int(n as i64)),
+            Expr::Node { ref kind, ref binds, ref children } => {
+                let mut fields = vec![("node".to_string(), Json::Str(kind.clone()))];
+                if !binds.is_empty() {
+                    fields.push(("binds".to_string(), Json::Array(binds.iter().map(|&b| Json::Int(b as i64)).collect())));
+                }
+                fields.push(("args".to_string(), Json::Array(children.iter().map(|c| c.to_json()).collect())));
+                Json::Object(fields)
+            }
+        }
+    }
+
+    pub fn from_json(json: &Json) -> Result<Expr, JsonError> {
+        Expr::from_json_with(json, &Limits::default())
+    }
+
+    pub fn from_json_with(json: &Json, limits: &Limits) -> Result<Expr, JsonError> {
+        Expr::read_json(json, &mut limits.budget())
+    }
+
+    fn read_json(json: &Json, budget: &mut Budget) -> Result<Expr, JsonError> {
+        let fields = match *json {
+            Json::Object(ref fields) => fields,
+            _ => return err(0, "expected an object"),
+        };
+        let field = |name: &str| fields.iter().find(|f| f.0 == name).map(|f| &f.1);
+        let id = |v: &Json| match *v {
+            Json::Int(n) if n >= i32::min_value() as i64 && n <= i32::max_value() as i64 => Ok(n as i32),
+            _ => err(0, "expected a variable number"),
+        };
+        if let Some(kind) = field("node") {
+            let kind = match *kind {
+                Json::Str(ref s) => s.clone(),
+                _ => return err(0, "\"node\" must be a string"),
+            };
+            let binds = match field("binds") {
+                None => vec![],
+                Some(&Json::Array(ref items)) => items.iter().map(|b| id(b)).collect::<Result<_, _>>()?,
+                Some(_) => return err(0, "\"binds\" must be an array"),
+            };
+            if let Err(e) = budget.enter(Some(&kind)) {
+                return limit_err(0, e);
+            }
+            let children = match field("args") {
+                None => vec![],
+                Some(&Json::Array(ref items)) => items.iter().map(|c| Expr::read_json(c, budget)).collect::<Result<_, _>>()?,
+                Some(_) => return err(0, "\"args\" must be an array"),
+            };
+            budget.leave(Some(&kind));
+            return Ok(Expr::Node {
+                kind,
+                binds,
+                children,
+            });
+        }
+        if fields.len() != 1 {
+            return err(0, "expected a single tag");
+        }
+        if let Err(e) = budget.enter(None) {
+            return limit_err(0, e);
+        }
+        budget.leave(None);
+        let (ref tag, ref v) = fields[0];
+        match (&tag[..], v) {
+            ("num", &Json::Int(n)) => Ok(Expr::Const(Value::Num(n))),
+            ("bool", &Json::Bool(b)) => Ok(Expr::Const(Value::Bool(b))),
+            ("unit", &Json::Null) => Ok(Expr::Const(Value::Unit)),
+            ("str", &Json::Str(ref s)) => Ok(Expr::Const(Value::Str(s.clone()))),
+            ("float", &Json::Float(x)) => Ok(Expr::Const(Value::Float(x.to_bits()))),
+            ("float", &Json::Int(n)) => Ok(Expr::Const(Value::Float((n as f64).to_bits()))),
+            ("float", &Json::Str(ref s)) => match &s[..] {
+                "NaN" => Ok(Expr::Const(Value::Float(::std::f64::NAN.to_bits()))),
+                "inf" => Ok(Expr::Const(Value::Float(::std::f64::INFINITY.to_bits()))),
+                "-inf" => Ok(Expr::Const(Value::Float(::std::f64::NEG_INFINITY.to_bits()))),
+                _ => err(0, "bad float"),
+            },
+            ("var", v) => Ok(Expr::Var(id(v)?)),
+            ("bound", v) => Ok(Expr::Bound(id(v)?)),
+            ("opaque", _) => Ok(Expr::Opaque),
+            _ => err(0, &format!("bad or unknown tag {:?}", tag)),
+        }
+    }
+}
+
+// The program as alpha-normalized JSON text.
+pub fn to_json<T>(exp: &Exp<Output=T>) -> String {
+    exp.reify().alpha_normalized().to_json().to_string()
+}
+
+pub fn from_json(src: &str) -> Result<Expr, JsonError> {
+    from_json_with(src, &Limits::default())
+}
+
+// Reads a program that must stay within `limits`; for untrusted input.
+pub fn from_json_with(src: &str, limits: &Limits) -> Result<Expr, JsonError> {
+    Expr::from_json_with(&parse(src, json_depth(limits))?, limits)
+}
+
+// Reads a program along with where each of its nodes was in `src`, for
+// pointing at them in errors.
+pub fn from_json_spanned(src: &str) -> Result<(Expr, Spans), JsonError> {
+    let limits = Limits::default();
+    let (json, objects) = parse_objects(src, json_depth(&limits))?;
+    let expr = Expr::from_json_with(&json, &limits)?;
+    // Each node is one object, and they're read in the order they're written.
+    let mut objects = objects.into_iter();
+    let mut spans = Spans::new();
+    each_node(&expr, &mut |path, _| {
+        if let Some((start, end)) = objects.next() {
+            spans.insert(path.to_vec(), Span { start, end });
+        }
+    });
+    Ok((expr, spans))
+}
+
+#[cfg(test)]
+mod tests {
+    use builder::ExpBuilder;
+    use check::check;
+    use dynamic::DynVal;
+    use limits::{Limit, LimitError, Limits};
+    use reify::{Expr, Value, node};
+    use span::Span;
+    use super::*;
+
+    #[test]
+    fn exported_programs_load_and_run_alike() {
+        let b = ExpBuilder::new();
+        let count = b.build(b.let_(b.num(1), |b, i| {
+            b.seq(b.while_(b.get(i).lt(1000), b.set(i, b.get(i) + 1)),
+                  b.get(i))
+        }));
+        let expr = from_json(&to_json(&*count)).unwrap();
+        assert_eq!(expr, count.reify().alpha_normalized());
+        let typed = check(&expr).unwrap();
+        assert_eq!(typed.eval_interpreted(), DynVal::Num(1000));
+        assert_eq!(typed.eval(), DynVal::Num(1000));
+        assert_eq!(typed.eval_compiled(), DynVal::Num(1000));
+    }
+
+    #[test]
+    fn constants_round_trip() {
+        let floats = [1.0, -0.0, 1e300, 2.5e-8, f64::INFINITY, f64::NEG_INFINITY, f64::NAN];
+        let mut args: Vec<Expr> = floats.iter().map(|x| Expr::Const(Value::Float(x.to_bits()))).collect();
+        args.push(Expr::Const(Value::Str("\"\\\n\t\u{1}é\u{1f600}".to_string())));
+        args.push(Expr::Const(Value::Num(i64::MIN)));
+        args.push(Expr::Const(Value::Unit));
+        args.push(Expr::Var(7));
+        let expr = node("array", args);
+        let text = expr.to_json().to_string();
+        assert_eq!(from_json(&text).unwrap(), expr);
+    }
+
+    #[test]
+    fn escapes_and_surrogate_pairs_are_decoded() {
+        assert_eq!(parse_json(r#""aé😀\/\n""#).unwrap(), Json::Str("aé\u{1f600}/\n".to_string()));
+        assert_eq!(parse_json(r#""\q""#).unwrap_err().msg, "bad escape");
+    }
+
+    #[test]
+    fn errors_point_at_the_input() {
+        let e = parse_json(r#"{"num": 1} x"#).unwrap_err();
+        assert_eq!((e.pos, &e.msg[..]), (11, "trailing characters"));
+        let e = parse_json("[1,").unwrap_err();
+        assert_eq!((e.pos, &e.msg[..]), (3, "unexpected end of input"));
+        assert_eq!(from_json(r#"{"nmu": 1}"#).unwrap_err().msg, "bad or unknown tag \"nmu\"");
+    }
+
+    #[test]
+    fn limits_are_enforced_while_reading() {
+        let deep = format!("{}{}", "[".repeat(5000), "]".repeat(5000));
+        let e = parse_json(&deep).unwrap_err();
+        assert_eq!(e.limit, Some(LimitError { limit: Limit::Depth, max: json_depth(&Limits::default()) }));
+
+        let src = r#"{"node":"add","args":[{"node":"add","args":[{"num":1},{"num":2}]},{"num":3}]}"#;
+        let e = from_json_with(src, &Limits::new().max_depth(2)).unwrap_err();
+        assert_eq!(e.limit, Some(LimitError { limit: Limit::Depth, max: 2 }));
+        let e = from_json_with(src, &Limits::new().max_nodes(4)).unwrap_err();
+        assert_eq!(e.limit, Some(LimitError { limit: Limit::Nodes, max: 4 }));
+        assert!(from_json_with(src, &Limits::new().max_depth(3).max_nodes(5)).is_ok());
+    }
+
+    #[test]
+    fn spans_cover_each_node() {
+        let src = r#"{"node": "add", "args": [{"num": 1}, {"var": 2}]}"#;
+        let (_, spans) = from_json_spanned(src).unwrap();
+        assert_eq!(spans.get(&[]), Some(Span { start: 0, end: src.len() }));
+        let var = src.find(r#"{"var""#).unwrap();
+        assert_eq!(spans.get(&[1]), Some(Span { start: var, end: var + r#"{"var": 2}"#.len() }));
+    }
+}
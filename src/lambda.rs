@@ -0,0 +1,223 @@
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, SlotScope, Slot, VariableExp, FnVal};
+use ops::E;
+use reify::{Expr, node, binder};
+
+// Functions as values. A lambda's body may read variables bound around it,
+// but reads them when the function is called, not when it's made, so a
+// function that outlives their binders sees whatever they hold then. Values
+// that must be kept with the function are passed as arguments instead: a
+// two-argument lambda is curried, and applying it to its first argument
+// makes a function that holds on to it.
+#[derive(Clone)]
+pub struct LambdaExp<A: 'static+Clone, R: 'static+Clone> {
+    body: Rc<Fn(VariableExp<A>) -> Box<Exp<Output=R>>>,
+}
+
+// Each run makes a function of the run's context, whose body is staged once
+// and binds its argument there the way staged binders do, so it may be
+// called recursively.
+pub struct LambdaStagedExp<A: 'static+Clone, R: 'static+Clone> {
+    slot: Slot<A>,
+    staged_body: Rc<StagedExp<Output=R>>,
+}
+
+impl<A: 'static+Clone+Default, R: 'static+Clone> Exp for LambdaExp<A, R> {
+    type Output = FnVal<A, R>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        box LambdaStagedExp {
+            slot: scope.bind(&var),
+            staged_body: Rc::from((self.body)(var).stage()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let body = self.body.clone();
+        FnVal {
+            f: Rc::new(move |a| body(VariableExp::fresh_with_val(a)).interpret()),
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let var = VariableExp::<A>::fresh();
+        binder("lambda", vec![var.id], vec![(self.body)(var.clone()).reify()])
+    }
+}
+
+impl<A: 'static+Clone, R: 'static+Clone> StagedExp for LambdaStagedExp<A, R> {
+    type Output = FnVal<A, R>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let ctx = ctx.clone();
+        let slot = self.slot.clone();
+        let staged_body = self.staged_body.clone();
+        FnVal {
+            f: Rc::new(move |a| {
+                let mut binding = ctx.bind(&slot);
+                binding.set(a);
+                staged_body.run(&ctx)
+            }),
+        }
+    }
+}
+
+// A function of two arguments, curried: applied to the first it gives a
+// function of the second that keeps the first's value.
+#[derive(Clone)]
+pub struct Lambda2Exp<A: 'static+Clone, B: 'static+Clone, R: 'static+Clone> {
+    body: Rc<Fn(VariableExp<A>, VariableExp<B>) -> Box<Exp<Output=R>>>,
+}
+
+pub struct Lambda2StagedExp<A: 'static+Clone, B: 'static+Clone, R: 'static+Clone> {
+    a_slot: Slot<A>,
+    b_slot: Slot<B>,
+    staged_body: Rc<StagedExp<Output=R>>,
+}
+
+impl<A: 'static+Clone+Default, B: 'static+Clone+Default, R: 'static+Clone> Exp for Lambda2Exp<A, B, R> {
+    type Output = FnVal<A, FnVal<B, R>>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let a_var = VariableExp::fresh();
+        let b_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        box Lambda2StagedExp {
+            a_slot: scope.bind(&a_var),
+            b_slot: scope.bind(&b_var),
+            staged_body: Rc::from((self.body)(a_var, b_var).stage()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let body = self.body.clone();
+        FnVal {
+            f: Rc::new(move |a: A| {
+                let body = body.clone();
+                FnVal {
+                    f: Rc::new(move |b| body(VariableExp::fresh_with_val(a.clone()), VariableExp::fresh_with_val(b)).interpret()),
+                }
+            }),
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let a_var = VariableExp::<A>::fresh();
+        let b_var = VariableExp::<B>::fresh();
+        binder("lambda2", vec![a_var.id, b_var.id], vec![(self.body)(a_var.clone(), b_var.clone()).reify()])
+    }
+}
+
+impl<A: 'static+Clone, B: 'static+Clone, R: 'static+Clone> StagedExp for Lambda2StagedExp<A, B, R> {
+    type Output = FnVal<A, FnVal<B, R>>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let ctx = ctx.clone();
+        let a_slot = self.a_slot.clone();
+        let b_slot = self.b_slot.clone();
+        let staged_body = self.staged_body.clone();
+        FnVal {
+            f: Rc::new(move |a: A| {
+                let ctx = ctx.clone();
+                let a_slot = a_slot.clone();
+                let b_slot = b_slot.clone();
+                let staged_body = staged_body.clone();
+                FnVal {
+                    f: Rc::new(move |b| {
+                        let mut a_binding = ctx.bind(&a_slot);
+                        let mut b_binding = ctx.bind(&b_slot);
+                        a_binding.set(a.clone());
+                        b_binding.set(b);
+                        staged_body.run(&ctx)
+                    }),
+                }
+            }),
+        }
+    }
+}
+
+// The function is evaluated before its argument.
+#[derive(Clone)]
+pub struct ApplyExp<A: 'static+Clone, R: 'static+Clone> {
+    f: Box<Exp<Output=FnVal<A, R>>>,
+    arg: Box<Exp<Output=A>>,
+}
+
+pub struct ApplyStagedExp<A: 'static+Clone, R: 'static+Clone> {
+    staged_f: Box<StagedExp<Output=FnVal<A, R>>>,
+    staged_arg: Box<StagedExp<Output=A>>,
+}
+
+impl<A: 'static+Clone, R: 'static+Clone> Exp for ApplyExp<A, R> {
+    type Output = R;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ApplyStagedExp {
+            staged_f: self.f.stage(),
+            staged_arg: self.arg.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let f = self.f.interpret();
+        (f.f)(self.arg.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("apply", vec![self.f.reify(), self.arg.reify()])
+    }
+}
+
+impl<A: 'static+Clone, R: 'static+Clone> StagedExp for ApplyStagedExp<A, R> {
+    type Output = R;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let f = self.staged_f.run(ctx);
+        (f.f)(self.staged_arg.run(ctx))
+    }
+}
+
+pub fn lambda_exp<A: 'static+Clone+Default, R: 'static+Clone>(body: Box<Fn(VariableExp<A>) -> Box<Exp<Output=R>>>) -> LambdaExp<A, R> {
+    LambdaExp {
+        body: Rc::from(body)
+    }
+}
+
+pub fn lambda2_exp<A: 'static+Clone+Default, B: 'static+Clone+Default, R: 'static+Clone>(
+    body: Box<Fn(VariableExp<A>, VariableExp<B>) -> Box<Exp<Output=R>>>) -> Lambda2Exp<A, B, R> {
+    Lambda2Exp {
+        body: Rc::from(body)
+    }
+}
+
+pub fn apply_exp<A: 'static+Clone, R: 'static+Clone>(f: Box<Exp<Output=FnVal<A, R>>>, arg: Box<Exp<Output=A>>) -> ApplyExp<A, R> {
+    ApplyExp {
+        f,
+        arg
+    }
+}
+
+// Applies a curried function to both its arguments.
+pub fn apply2_exp<A: 'static+Clone, B: 'static+Clone, R: 'static+Clone>(f: Box<Exp<Output=FnVal<A, FnVal<B, R>>>>,
+                                                                        a: Box<Exp<Output=A>>,
+                                                                        b: Box<Exp<Output=B>>) -> ApplyExp<B, R> {
+    apply_exp(box apply_exp(f, a), b)
+}
+
+impl<A: 'static+Clone, R: 'static+Clone> E<FnVal<A, R>> {
+    pub fn apply(self, arg: E<A>) -> E<R> {
+        E::new(apply_exp(self.0, arg.0))
+    }
+}
@@ -0,0 +1,115 @@
+use {Exp, fresh_id};
+use canon::{pure, pure_kind};
+use reify::{Expr, binder};
+
+// Loop-invariant code motion: parts of a loop that give the same value on
+// every iteration are computed once, before the loop, by lets around it,
+// and the loop reads their variables instead. So in
+//
+//     while(lt(v1, mul(v2, v3)), set(v1, add(v1, str_len(v4))))
+//
+// `mul(v2, v3)` and `str_len(v4)` are hoisted, if nothing in the loop sets
+// v2, v3 or v4. The pass works on Exprs, so check the result to run it.
+//
+// A part is hoisted if it has no effects, reads no variable the loop sets
+// or binds, and is evaluated on every iteration the loop makes: in a
+// while's condition, or in a loop's body but not in an if's branches or a
+// nested loop's body, which might not run. Loops are done inside out, so
+// what's hoisted out of an inner loop can then be hoisted out of the
+// outer one too. Equal parts share one let. A part hoisted from a body is
+// still evaluated once when the loop makes no iterations; as it has no
+// effects, that only costs the time it takes.
+
+// Loops, and their children that run on each iteration.
+fn repeated(kind: &str, children: &[Expr]) -> Option<Vec<usize>> {
+    match kind {
+        "while" if children.len() == 2 => Some(vec![0, 1]),
+        "for" | "for_step" if !children.is_empty() => Some(vec![children.len() - 1]),
+        _ => None,
+    }
+}
+
+// The children of a node that run whenever it does.
+fn always(kind: &str, binds: &[i32], children: &[Expr]) -> Vec<usize> {
+    match kind {
+        "if" => vec![0],
+        "let" | "seq" | "set" => (0..children.len()).collect(),
+        // A while's condition runs at least once, and a for's bounds once.
+        "while" => vec![0],
+        "for" | "for_step" => (0..children.len() - 1).collect(),
+        _ if binds.is_empty() && pure_kind(kind) => (0..children.len()).collect(),
+        _ => vec![],
+    }
+}
+
+// The variables `expr` sets or binds.
+fn written(expr: &Expr, out: &mut Vec<i32>) {
+    if let Expr::Node { ref kind, ref binds, ref children } = *expr {
+        out.extend(binds);
+        if let (true, Some(&Expr::Var(id))) = (kind == "set", children.first()) {
+            out.push(id);
+        }
+        for c in children {
+            written(c, out);
+        }
+    }
+}
+
+fn invariant(expr: &Expr, variant: &[i32]) -> bool {
+    matches!(*expr, Expr::Node { .. }) && pure(expr) && !expr.free_vars().iter().any(|v| variant.contains(v))
+}
+
+// `expr`, part of a loop that sets or binds `variant`, with its invariant
+// parts that run whenever it does replaced by variables, added to
+// `hoisted`.
+fn hoist_from(expr: &Expr, variant: &[i32], hoisted: &mut Vec<(i32, Expr)>) -> Expr {
+    if invariant(expr, variant) {
+        let id = match hoisted.iter().find(|h| h.1 == *expr) {
+            Some(&(id, _)) => id,
+            None => {
+                let id = fresh_id();
+                hoisted.push((id, expr.clone()));
+                id
+            }
+        };
+        return Expr::Var(id);
+    }
+    match *expr {
+        Expr::Node { ref kind, ref binds, ref children } => {
+            let always = always(kind, binds, children);
+            Expr::Node {
+                kind: kind.clone(),
+                binds: binds.clone(),
+                children: children.iter().enumerate().map(|(i, c)| {
+                    if always.contains(&i) { hoist_from(c, variant, hoisted) } else { c.clone() }
+                }).collect(),
+            }
+        }
+        ref e => e.clone(),
+    }
+}
+
+// `expr` with the invariant parts of its loops hoisted.
+pub fn hoist(expr: &Expr) -> Expr {
+    let (kind, binds, children) = match *expr {
+        Expr::Node { ref kind, ref binds, ref children } => (kind, binds, children.iter().map(hoist).collect::<Vec<_>>()),
+        ref e => return e.clone(),
+    };
+    let body = match repeated(kind, &children) {
+        Some(body) => body,
+        None => return binder(kind, binds.clone(), children),
+    };
+    let mut variant = binds.clone();
+    for c in &children {
+        written(c, &mut variant);
+    }
+    let mut hoisted = Vec::new();
+    let children = children.iter().enumerate().map(|(i, c)| {
+        if body.contains(&i) { hoist_from(c, &variant, &mut hoisted) } else { c.clone() }
+    }).collect();
+    hoisted.into_iter().rev().fold(binder(kind, binds.clone(), children), |body, (id, e)| binder("let", vec![id], vec![e, body]))
+}
+
+pub fn hoist_exp<T>(exp: &Exp<Output=T>) -> Expr {
+    hoist(&exp.reify())
+}
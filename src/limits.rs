@@ -0,0 +1,199 @@
+use std::fmt;
+
+use reify::Expr;
+
+// Bounds on the shape of a program read from outside, so a hostile input
+// gets an error instead of exhausting memory or overflowing the stack. The
+// readers (`json::from_json_with`, `binary::decode_expr_with`) enforce them
+// while building the tree, and `check::check_with` before checking it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Nodes,
+    Depth,
+    LoopNesting,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitError {
+    pub limit: Limit,
+    pub max: usize,
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.limit {
+            Limit::Nodes => "nodes",
+            Limit::Depth => "levels of nesting",
+            Limit::LoopNesting => "nested loops",
+        };
+        write!(f, "the program has more than {} {}", self.max, what)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_nodes: usize,
+    pub max_depth: usize,
+    pub max_loop_nesting: usize,
+}
+
+// Generous enough for any hand-written program; the readers use these when
+// no limits are given.
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_nodes: 1_000_000,
+            max_depth: 512,
+            max_loop_nesting: 32,
+        }
+    }
+}
+
+// Nodes that run their body more than once.
+pub fn is_loop(kind: &str) -> bool {
+    match kind {
+        "while" | "for" | "for_step" | "for_each" | "map" | "filter" | "fold" => true,
+        _ => false,
+    }
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits::default()
+    }
+
+    pub fn max_nodes(mut self, n: usize) -> Limits {
+        self.max_nodes = n;
+        self
+    }
+
+    pub fn max_depth(mut self, n: usize) -> Limits {
+        self.max_depth = n;
+        self
+    }
+
+    pub fn max_loop_nesting(mut self, n: usize) -> Limits {
+        self.max_loop_nesting = n;
+        self
+    }
+
+    pub fn budget(&self) -> Budget {
+        Budget {
+            limits: *self,
+            nodes: 0,
+            depth: 0,
+            loops: 0,
+        }
+    }
+
+    // Checks an already built tree. Stops descending as soon as a limit is
+    // hit, so a tree deeper than max_depth can't overflow the stack here.
+    pub fn check(&self, expr: &Expr) -> Result<(), LimitError> {
+        self.budget().walk(expr)
+    }
+}
+
+// Counts a tree as it's built or walked: `enter` each node before its
+// children and `leave` it after.
+pub struct Budget {
+    limits: Limits,
+    nodes: usize,
+    depth: usize,
+    loops: usize,
+}
+
+impl Budget {
+    // `kind` is the node's kind, or None for leaves.
+    pub fn enter(&mut self, kind: Option<&str>) -> Result<(), LimitError> {
+        self.nodes += 1;
+        self.depth += 1;
+        if kind.map_or(false, is_loop) {
+            self.loops += 1;
+        }
+        let l = self.limits;
+        if self.nodes > l.max_nodes {
+            Err(LimitError { limit: Limit::Nodes, max: l.max_nodes })
+        } else if self.depth > l.max_depth {
+            Err(LimitError { limit: Limit::Depth, max: l.max_depth })
+        } else if self.loops > l.max_loop_nesting {
+            Err(LimitError { limit: Limit::LoopNesting, max: l.max_loop_nesting })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn leave(&mut self, kind: Option<&str>) {
+        self.depth -= 1;
+        if kind.map_or(false, is_loop) {
+            self.loops -= 1;
+        }
+    }
+
+    fn walk(&mut self, expr: &Expr) -> Result<(), LimitError> {
+        match *expr {
+            Expr::Node { ref kind, ref children, .. } => {
+                self.enter(Some(kind))?;
+                for c in children {
+                    self.walk(c)?;
+                }
+                self.leave(Some(kind));
+            }
+            _ => {
+                self.enter(None)?;
+                self.leave(None);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use check::check_with;
+    use reify::{Expr, Value, node};
+    use super::*;
+
+    fn num(n: i64) -> Expr {
+        Expr::Const(Value::Num(n))
+    }
+
+    fn while_(body: Expr) -> Expr {
+        node("while", vec![Expr::Const(Value::Bool(false)), body])
+    }
+
+    // `depth` nested negations of a number.
+    fn chain(depth: usize) -> Expr {
+        (1..depth).fold(num(1), |e, _| node("sub", vec![num(0), e]))
+    }
+
+    #[test]
+    fn each_limit_is_reported_just_past_it() {
+        let sum = node("add", vec![node("add", vec![num(1), num(2)]), num(3)]);
+        assert_eq!(Limits::new().max_nodes(5).check(&sum), Ok(()));
+        assert_eq!(Limits::new().max_nodes(4).check(&sum), Err(LimitError { limit: Limit::Nodes, max: 4 }));
+        assert_eq!(Limits::new().max_depth(3).check(&sum), Ok(()));
+        assert_eq!(Limits::new().max_depth(2).check(&sum), Err(LimitError { limit: Limit::Depth, max: 2 }));
+    }
+
+    // Loops one after another don't nest; only loops inside loops count.
+    #[test]
+    fn loop_nesting_counts_enclosing_loops() {
+        let siblings = node("seq", vec![while_(num(1)), while_(num(2))]);
+        assert_eq!(Limits::new().max_loop_nesting(1).check(&siblings), Ok(()));
+        let nested = while_(while_(num(1)));
+        assert_eq!(Limits::new().max_loop_nesting(1).check(&nested),
+                   Err(LimitError { limit: Limit::LoopNesting, max: 1 }));
+    }
+
+    #[test]
+    fn check_holds_built_programs_to_the_limits() {
+        let e = match check_with(&chain(20), &Limits::new().max_depth(10)) {
+            Err(e) => e,
+            Ok(_) => panic!("checked a program over the limit"),
+        };
+        assert_eq!(e.limit, Some(LimitError { limit: Limit::Depth, max: 10 }));
+        assert_eq!(e.to_string(), "the program has more than 10 levels of nesting");
+        assert!(check_with(&chain(10), &Limits::new().max_depth(10)).is_ok());
+    }
+}
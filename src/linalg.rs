@@ -0,0 +1,340 @@
+use std::convert::TryInto;
+
+use {Exp, StagedExp, EvalContext, ConstantExp, FloatVal, VecVal, MatVal, unit_exp};
+use ops::E;
+use reify::{Expr, node};
+
+// Vectors and matrices of floats. Elementwise sums and products use the
+// generic add and mul nodes; these are the dot and matrix products. When an
+// operand is a constant its size is known at staging time, and small square
+// sizes get kernels with the size fixed, which the compiler unrolls.
+
+pub fn zip(a: &[f64], b: &[f64], f: fn(f64, f64) -> f64) -> Vec<f64> {
+    if a.len() != b.len() {
+        panic!("vector lengths differ: {} and {}", a.len(), b.len());
+    }
+    a.iter().zip(b).map(|(&x, &y)| f(x, y)).collect()
+}
+
+pub fn check_same(a: &MatVal, b: &MatVal) {
+    if (a.rows, a.cols) != (b.rows, b.cols) {
+        panic!("matrix sizes differ: {}x{} and {}x{}", a.rows, a.cols, b.rows, b.cols);
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn dot_fixed<const N: usize>(a: &[f64], b: &[f64]) -> f64 {
+    let a: &[f64; N] = a.try_into().unwrap();
+    let b: &[f64; N] = b.try_into().unwrap();
+    let mut sum = 0.0;
+    for i in 0..N {
+        sum += a[i] * b[i];
+    }
+    sum
+}
+
+type DotKernel = fn(&[f64], &[f64]) -> f64;
+
+fn dot_kernel(len: Option<usize>) -> DotKernel {
+    match len {
+        Some(2) => dot_fixed::<2>,
+        Some(3) => dot_fixed::<3>,
+        Some(4) => dot_fixed::<4>,
+        _ => dot,
+    }
+}
+
+fn check_dot(a: &VecVal, b: &VecVal) {
+    if a.v.len() != b.v.len() {
+        panic!("can't take the dot product of vectors of lengths {} and {}", a.v.len(), b.v.len());
+    }
+}
+
+pub fn mat_mul(a: &MatVal, b: &MatVal) -> MatVal {
+    if a.cols != b.rows {
+        panic!("can't multiply a {}x{} matrix by a {}x{} one", a.rows, a.cols, b.rows, b.cols);
+    }
+    let mut v = vec![0.0; a.rows * b.cols];
+    // Row by row, so the inner loop walks both `b` and the result in order.
+    for i in 0..a.rows {
+        for k in 0..a.cols {
+            let x = a.v[i * a.cols + k];
+            for j in 0..b.cols {
+                v[i * b.cols + j] += x * b.v[k * b.cols + j];
+            }
+        }
+    }
+    MatVal {
+        rows: a.rows,
+        cols: b.cols,
+        v
+    }
+}
+
+fn mat_mul_fixed<const N: usize>(a: &MatVal, b: &MatVal) -> MatVal {
+    let mut v = vec![0.0; N * N];
+    for i in 0..N {
+        for k in 0..N {
+            let x = a.v[i * N + k];
+            for j in 0..N {
+                v[i * N + j] += x * b.v[k * N + j];
+            }
+        }
+    }
+    MatVal {
+        rows: N,
+        cols: N,
+        v
+    }
+}
+
+// The fixed kernels only apply when every size is `n`; anything else goes to
+// `mat_mul`, which checks the sizes fit.
+#[derive(Clone, Copy)]
+struct MatMulKernel {
+    n: usize,
+    fixed: fn(&MatVal, &MatVal) -> MatVal,
+}
+
+impl MatMulKernel {
+    fn new(dims: Option<(usize, usize)>) -> Option<MatMulKernel> {
+        let fixed: fn(&MatVal, &MatVal) -> MatVal = match dims {
+            Some((2, 2)) => mat_mul_fixed::<2>,
+            Some((3, 3)) => mat_mul_fixed::<3>,
+            Some((4, 4)) => mat_mul_fixed::<4>,
+            _ => return None,
+        };
+        Some(MatMulKernel {
+            n: dims.unwrap().0,
+            fixed
+        })
+    }
+
+    fn run(kernel: Option<MatMulKernel>, a: &MatVal, b: &MatVal) -> MatVal {
+        match kernel {
+            Some(k) if a.rows == k.n && a.cols == k.n && b.rows == k.n && b.cols == k.n => (k.fixed)(a, b),
+            _ => mat_mul(a, b),
+        }
+    }
+}
+
+fn vec_len(exp: &Exp<Output=VecVal>) -> Option<usize> {
+    exp.constant().and_then(|c| c.downcast_ref::<VecVal>()).map(|c| c.v.len())
+}
+
+fn mat_dims(exp: &Exp<Output=MatVal>) -> Option<(usize, usize)> {
+    exp.constant().and_then(|c| c.downcast_ref::<MatVal>()).map(|c| (c.rows, c.cols))
+}
+
+#[derive(Clone)]
+pub struct DotExp {
+    exp1: Box<Exp<Output=VecVal>>,
+    exp2: Box<Exp<Output=VecVal>>,
+}
+
+pub struct DotStagedExp {
+    staged_exp1: Box<StagedExp<Output=VecVal>>,
+    staged_exp2: Box<StagedExp<Output=VecVal>>,
+    kernel: DotKernel,
+}
+
+impl Exp for DotExp {
+    type Output = FloatVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box DotStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+            kernel: dot_kernel(vec_len(&*self.exp1).or_else(|| vec_len(&*self.exp2))),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let a = self.exp1.interpret();
+        let b = self.exp2.interpret();
+        check_dot(&a, &b);
+        Self::Output {
+            v: dot(&a.v, &b.v)
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("dot", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+}
+
+impl StagedExp for DotStagedExp {
+    type Output = FloatVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let a = self.staged_exp1.run(ctx);
+        let b = self.staged_exp2.run(ctx);
+        check_dot(&a, &b);
+        Self::Output {
+            v: (self.kernel)(&a.v, &b.v)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MatMulExp {
+    exp1: Box<Exp<Output=MatVal>>,
+    exp2: Box<Exp<Output=MatVal>>,
+}
+
+pub struct MatMulStagedExp {
+    staged_exp1: Box<StagedExp<Output=MatVal>>,
+    staged_exp2: Box<StagedExp<Output=MatVal>>,
+    kernel: Option<MatMulKernel>,
+}
+
+impl Exp for MatMulExp {
+    type Output = MatVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box MatMulStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+            kernel: MatMulKernel::new(mat_dims(&*self.exp1).or_else(|| mat_dims(&*self.exp2))),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let a = self.exp1.interpret();
+        mat_mul(&a, &self.exp2.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("mat_mul", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+}
+
+impl StagedExp for MatMulStagedExp {
+    type Output = MatVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let a = self.staged_exp1.run(ctx);
+        MatMulKernel::run(self.kernel, &a, &self.staged_exp2.run(ctx))
+    }
+}
+
+pub fn vec_exp(v: Vec<f64>) -> ConstantExp<VecVal> {
+    unit_exp(VecVal { v })
+}
+
+// Panics unless `v` has `rows * cols` elements.
+pub fn mat_exp(rows: usize, cols: usize, v: Vec<f64>) -> ConstantExp<MatVal> {
+    if v.len() != rows * cols {
+        panic!("a {}x{} matrix needs {} elements, not {}", rows, cols, rows * cols, v.len());
+    }
+    unit_exp(MatVal { rows, cols, v })
+}
+
+pub fn dot_exp(exp1: Box<Exp<Output=VecVal>>, exp2: Box<Exp<Output=VecVal>>) -> DotExp {
+    DotExp {
+        exp1,
+        exp2
+    }
+}
+
+pub fn mat_mul_exp(exp1: Box<Exp<Output=MatVal>>, exp2: Box<Exp<Output=MatVal>>) -> MatMulExp {
+    MatMulExp {
+        exp1,
+        exp2
+    }
+}
+
+impl E<VecVal> {
+    pub fn dot(self, rhs: E<VecVal>) -> E<FloatVal> {
+        E::new(dot_exp(self.0, rhs.0))
+    }
+}
+
+impl E<MatVal> {
+    pub fn mat_mul(self, rhs: E<MatVal>) -> E<MatVal> {
+        E::new(mat_mul_exp(self.0, rhs.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, StagedExp, EvalContext, VariableExp, VecVal, MatVal, add_exp, mul_exp};
+    use super::*;
+
+    fn both<T: 'static+Clone+PartialEq+::std::fmt::Debug>(exp: &Exp<Output=T>) -> T {
+        let v = exp.interpret();
+        assert_eq!(exp.stage().run(&EvalContext::new()), v);
+        v
+    }
+
+    fn seq(n: usize) -> Vec<f64> {
+        (1..n + 1).map(|i| i as f64).collect()
+    }
+
+    // Reference product, an entry at a time.
+    fn product(a: &MatVal, b: &MatVal) -> Vec<f64> {
+        let mut v = Vec::new();
+        for i in 0..a.rows {
+            for j in 0..b.cols {
+                v.push((0..a.cols).map(|k| a.v[i * a.cols + k] * b.v[k * b.cols + j]).sum());
+            }
+        }
+        v
+    }
+
+    #[test]
+    fn dot_kernels_agree_with_the_general_product() {
+        for n in 1..7 {
+            let exp = dot_exp(box vec_exp(seq(n)), box vec_exp(seq(n)));
+            assert_eq!(both(&exp).v, seq(n).iter().map(|x| x * x).sum::<f64>());
+        }
+    }
+
+    #[test]
+    fn mat_mul_kernels_agree_with_the_general_product() {
+        for &(r, k, c) in &[(2, 2, 2), (3, 3, 3), (4, 4, 4), (5, 5, 5), (2, 3, 4), (1, 4, 1)] {
+            let a = MatVal { rows: r, cols: k, v: seq(r * k) };
+            let b = MatVal { rows: k, cols: c, v: seq(k * c).into_iter().rev().collect() };
+            let exp = mat_mul_exp(box mat_exp(r, k, a.v.clone()), box mat_exp(k, c, b.v.clone()));
+            assert_eq!(both(&exp), MatVal { rows: r, cols: c, v: product(&a, &b) });
+        }
+    }
+
+    // The kernel is picked by the constant's size; an operand of another
+    // size falls back to the general product.
+    #[test]
+    fn fixed_kernel_checks_the_other_operand() {
+        let other = VariableExp::fresh_with_val(MatVal { rows: 2, cols: 3, v: seq(6) });
+        let exp = mat_mul_exp(box mat_exp(2, 2, vec![1.0, 0.0, 0.0, 1.0]), box other.clone());
+        assert_eq!(both(&exp), MatVal { rows: 2, cols: 3, v: seq(6) });
+    }
+
+    #[test]
+    #[should_panic(expected = "can't take the dot product of vectors of lengths 3 and 2")]
+    fn staged_dot_checks_lengths() {
+        let other = VariableExp::fresh_with_val(VecVal { v: seq(2) });
+        dot_exp(box vec_exp(seq(3)), box other.clone()).stage().run(&EvalContext::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "can't multiply a 2x3 matrix by a 2x3 one")]
+    fn mat_mul_checks_sizes() {
+        mat_mul_exp(box mat_exp(2, 3, seq(6)), box mat_exp(2, 3, seq(6))).interpret();
+    }
+
+    #[test]
+    fn add_and_mul_are_elementwise() {
+        assert_eq!(both(&add_exp(box vec_exp(seq(3)), box vec_exp(seq(3)))).v, vec![2.0, 4.0, 6.0]);
+        assert_eq!(both(&mul_exp(box vec_exp(seq(3)), box vec_exp(seq(3)))).v, vec![1.0, 4.0, 9.0]);
+        assert_eq!(both(&add_exp(box mat_exp(1, 2, seq(2)), box mat_exp(1, 2, seq(2)))).v, vec![2.0, 4.0]);
+    }
+}
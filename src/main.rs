@@ -1,279 +1,3035 @@
 #![feature(box_syntax)]
 #![feature(box_patterns)]
 #![feature(refcell_replace_swap)]
+#![feature(unboxed_closures)]
+#![feature(fn_traits)]
+#![feature(tuple_trait)]
+
+#[cfg(feature = "gpu")]
+extern crate wgpu;
+#[cfg(feature = "gpu")]
+extern crate pollster;
+#[cfg(feature = "python")]
+extern crate pyo3;
+// pyo3's macros name `::core`, which this edition only finds at the root.
+#[cfg(feature = "python")]
+extern crate core;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+#[cfg(feature = "arrow")]
+extern crate arrow_array;
+#[cfg(feature = "native")]
+extern crate libloading;
 
 use std::collections::HashMap;
-use std::cell::Cell;
+use std::hash::Hash;
+use std::cell::{Cell, UnsafeCell};
 use std::rc::Rc;
 use std::any::Any;
 use std::cell::RefCell;
 use std::default::Default;
 use std::borrow::BorrowMut;
+use std::fmt;
+use std::mem;
+
+use reify::{Expr, node, binder, value_of};
+
+#[cfg(feature = "arena")]
+#[global_allocator]
+static ALLOC: arena::ArenaAlloc = arena::ArenaAlloc;
+
+mod agg;
+mod array;
+mod assert;
+mod atomic;
+mod batch;
+mod bench;
+mod bits;
+mod builder;
+mod cache;
+mod canon;
+mod channel;
+mod check;
+mod cost;
+mod coverage;
+mod deriv;
+mod dict;
+mod diff;
+mod dynamic;
+mod effects;
+mod equivalence;
+mod exhaustive;
+mod externs;
+mod gen;
+mod inline;
+mod journal;
+mod lambda;
+mod licm;
+mod limits;
+mod meta;
+mod memo;
+mod minimize;
+mod observe;
+mod ops;
+mod params;
+mod patterns;
+mod poly;
+mod prelude;
+mod query;
+mod rec;
+mod records;
+mod refs;
+mod registry;
+mod reify;
+mod replay;
+mod resume;
+mod rewrite;
+mod rules;
+mod sandbox;
+mod scope;
+mod score;
+mod shadow;
+mod snapshot;
+mod span;
+mod spawn;
+mod stream;
+mod strength;
+mod strings;
+mod subst;
+mod switch;
+mod thunk;
+mod time;
+mod variants;
+mod watch;
+
+#[cfg(feature = "arena")]
+mod arena;
+#[cfg(feature = "arrow")]
+mod arrow;
+#[cfg(feature = "binary")]
+mod binary;
+#[cfg(feature = "capi")]
+mod capi;
+#[cfg(feature = "complex")]
+mod complex;
+#[cfg(feature = "decimal")]
+mod decimal;
+#[cfg(feature = "fuzzy")]
+mod fuzzy;
+#[cfg(feature = "gpu")]
+mod gpu;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "linalg")]
+mod linalg;
+#[cfg(feature = "native")]
+mod native;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "simd")]
+mod simd;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+trait Val {
+    type Output;
+
+    fn get(&self) -> Self::Output;
+}
+
+#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Hash, Default)]
+struct NumVal {
+    v: i64,
+}
+
+impl Val for NumVal {
+    type Output = i64;
+
+    fn get(&self) -> Self::Output {
+        self.v
+    }
+}
+
+impl std::ops::Add for NumVal {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v + rhs.v
+        }
+    }
+}
+
+impl std::ops::Sub for NumVal {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v - rhs.v
+        }
+    }
+}
+
+impl std::ops::Mul for NumVal {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v * rhs.v
+        }
+    }
+}
+
+impl std::ops::BitAnd for NumVal {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v & rhs.v
+        }
+    }
+}
+
+impl std::ops::BitOr for NumVal {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v | rhs.v
+        }
+    }
+}
+
+impl std::ops::BitXor for NumVal {
+    type Output = Self;
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v ^ rhs.v
+        }
+    }
+}
+
+impl std::ops::Not for NumVal {
+    type Output = Self;
+    fn not(self) -> Self::Output {
+        Self {
+            v: !self.v
+        }
+    }
+}
+
+#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Hash, Default)]
+struct BoolVal {
+    v: bool,
+}
+
+impl Val for BoolVal {
+    type Output = bool;
+
+    fn get(&self) -> Self::Output {
+        self.v
+    }
+}
+
+#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Hash, Default)]
+struct UnitVal;
+
+impl Val for UnitVal {
+    type Output = ();
+
+    fn get(&self) -> Self::Output {
+        ()
+    }
+}
+
+#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Hash, Default)]
+struct StrVal {
+    v: String,
+}
+
+impl Val for StrVal {
+    type Output = String;
+
+    fn get(&self) -> Self::Output {
+        self.v.clone()
+    }
+}
+
+#[derive(Debug,Clone, PartialOrd, PartialEq, Default)]
+struct FloatVal {
+    v: f64,
+}
+
+impl Val for FloatVal {
+    type Output = f64;
+
+    fn get(&self) -> Self::Output {
+        self.v
+    }
+}
+
+impl std::ops::Add for FloatVal {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v + rhs.v
+        }
+    }
+}
+
+impl std::ops::Sub for FloatVal {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v - rhs.v
+        }
+    }
+}
+
+impl std::ops::Mul for FloatVal {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v * rhs.v
+        }
+    }
+}
+
+impl std::ops::Div for FloatVal {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self::Output {
+        Self {
+            v: self.v / rhs.v
+        }
+    }
+}
+
+#[cfg(feature = "complex")]
+#[derive(Debug,Clone, PartialEq, Default)]
+struct ComplexVal {
+    re: f64,
+    im: f64,
+}
+
+#[cfg(feature = "complex")]
+impl Val for ComplexVal {
+    type Output = (f64, f64);
+
+    fn get(&self) -> Self::Output {
+        (self.re, self.im)
+    }
+}
+
+#[cfg(feature = "complex")]
+impl std::ops::Add for ComplexVal {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            re: self.re + rhs.re,
+            im: self.im + rhs.im
+        }
+    }
+}
+
+#[cfg(feature = "complex")]
+impl std::ops::Sub for ComplexVal {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self {
+            re: self.re - rhs.re,
+            im: self.im - rhs.im
+        }
+    }
+}
+
+#[cfg(feature = "complex")]
+impl std::ops::Mul for ComplexVal {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self {
+            re: self.re * rhs.re - self.im * rhs.im,
+            im: self.re * rhs.im + self.im * rhs.re
+        }
+    }
+}
+
+// `v` divided by 10 to the `scale`: exact decimal fractions, e.g. amounts
+// of money, without binary-float rounding. Sums and differences have the
+// larger scale of their operands and products the sum of theirs, so these
+// are exact too; they panic on overflow rather than lose digits. Values
+// compare by what they stand for, so 1.5 equals 1.50.
+#[cfg(feature = "decimal")]
+#[derive(Debug,Clone, Default)]
+struct DecimalVal {
+    v: i128,
+    scale: u32,
+}
+
+#[cfg(feature = "decimal")]
+impl Val for DecimalVal {
+    type Output = (i128, u32);
+
+    fn get(&self) -> Self::Output {
+        (self.v, self.scale)
+    }
+}
+
+#[cfg(feature = "decimal")]
+impl std::ops::Add for DecimalVal {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        let (a, b, scale) = decimal::align(&self, &rhs);
+        Self {
+            v: a.checked_add(b).expect("decimal overflow"),
+            scale
+        }
+    }
+}
+
+#[cfg(feature = "decimal")]
+impl std::ops::Sub for DecimalVal {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        let (a, b, scale) = decimal::align(&self, &rhs);
+        Self {
+            v: a.checked_sub(b).expect("decimal overflow"),
+            scale
+        }
+    }
+}
+
+#[cfg(feature = "decimal")]
+impl std::ops::Mul for DecimalVal {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        let scale = self.scale + rhs.scale;
+        decimal::check_scale(scale);
+        Self {
+            v: self.v.checked_mul(rhs.v).expect("decimal overflow"),
+            scale
+        }
+    }
+}
+
+#[cfg(feature = "linalg")]
+#[derive(Debug,Clone, PartialEq, Default)]
+struct VecVal {
+    v: Vec<f64>,
+}
+
+#[cfg(feature = "linalg")]
+impl Val for VecVal {
+    type Output = Vec<f64>;
+
+    fn get(&self) -> Self::Output {
+        self.v.clone()
+    }
+}
+
+// Elementwise; both must have the same length.
+#[cfg(feature = "linalg")]
+impl std::ops::Add for VecVal {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            v: linalg::zip(&self.v, &rhs.v, |a, b| a + b)
+        }
+    }
+}
+
+#[cfg(feature = "linalg")]
+impl std::ops::Mul for VecVal {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self {
+            v: linalg::zip(&self.v, &rhs.v, |a, b| a * b)
+        }
+    }
+}
+
+// A `rows` by `cols` matrix, stored a row at a time.
+#[cfg(feature = "linalg")]
+#[derive(Debug,Clone, PartialEq, Default)]
+struct MatVal {
+    rows: usize,
+    cols: usize,
+    v: Vec<f64>,
+}
+
+#[cfg(feature = "linalg")]
+impl Val for MatVal {
+    type Output = Vec<f64>;
+
+    fn get(&self) -> Self::Output {
+        self.v.clone()
+    }
+}
+
+// Elementwise, as for VecVal; `linalg::mat_mul` is the matrix product.
+#[cfg(feature = "linalg")]
+impl std::ops::Add for MatVal {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        linalg::check_same(&self, &rhs);
+        Self {
+            v: linalg::zip(&self.v, &rhs.v, |a, b| a + b),
+            ..self
+        }
+    }
+}
+
+#[cfg(feature = "linalg")]
+impl std::ops::Mul for MatVal {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self::Output {
+        linalg::check_same(&self, &rhs);
+        Self {
+            v: linalg::zip(&self.v, &rhs.v, |a, b| a * b),
+            ..self
+        }
+    }
+}
+
+// A span of time in nanoseconds, which may be negative.
+#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Hash, Default)]
+struct DurationVal {
+    nanos: i64,
+}
+
+impl DurationVal {
+    fn from_millis(ms: i64) -> DurationVal {
+        DurationVal {
+            nanos: ms.saturating_mul(1_000_000)
+        }
+    }
+}
+
+impl Val for DurationVal {
+    type Output = i64;
+
+    fn get(&self) -> Self::Output {
+        self.nanos
+    }
+}
+
+impl std::ops::Add for DurationVal {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            nanos: self.nanos + rhs.nanos
+        }
+    }
+}
+
+impl std::ops::Sub for DurationVal {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self {
+            nanos: self.nanos - rhs.nanos
+        }
+    }
+}
+
+// A point in time, in nanoseconds since the clock it was read from started.
+// Only instants from the same clock can be compared.
+#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Hash, Default)]
+struct InstantVal {
+    nanos: i64,
+}
+
+impl Val for InstantVal {
+    type Output = i64;
+
+    fn get(&self) -> Self::Output {
+        self.nanos
+    }
+}
+
+#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Hash, Default)]
+struct ArrayVal<T> {
+    v: Vec<T>,
+}
+
+impl<T: Clone> Val for ArrayVal<T> {
+    type Output = Vec<T>;
+
+    fn get(&self) -> Self::Output {
+        self.v.clone()
+    }
+}
+
+// From `start` towards `end` (exclusive) by a non-zero `step`.
+#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Hash)]
+struct RangeVal {
+    start: i64,
+    end: i64,
+    step: i64,
+}
+
+impl Default for RangeVal {
+    fn default() -> Self {
+        RangeVal {
+            start: 0,
+            end: 0,
+            step: 1,
+        }
+    }
+}
+
+impl Val for RangeVal {
+    type Output = (i64, i64, i64);
+
+    fn get(&self) -> Self::Output {
+        (self.start, self.end, self.step)
+    }
+}
+
+// Values whose elements can be visited in order by ForEachExp and the
+// map/filter/fold nodes.
+trait Iterable {
+    type Elem: 'static+Clone+Default;
+
+    fn each(&self, f: &mut FnMut(Self::Elem));
+}
+
+// A way of combining a collection's elements into one value, by `agg`.
+trait Aggregate<T>: 'static+Clone {
+    type Acc;
+    type Output: 'static+Clone;
+
+    const NAME: &'static str;
+
+    fn start() -> Self::Acc;
+    fn add(acc: &mut Self::Acc, x: T);
+    fn finish(acc: Self::Acc) -> Self::Output;
+}
+
+// Elements that can be averaged.
+trait Average {
+    fn to_f64(&self) -> f64;
+}
+
+impl Iterable for RangeVal {
+    type Elem = NumVal;
+
+    fn each(&self, f: &mut FnMut(Self::Elem)) {
+        for_range(self.start, self.end, self.step, &mut |v| f(NumVal { v }));
+    }
+}
+
+impl<T: 'static+Clone+Default> Iterable for ArrayVal<T> {
+    type Elem = T;
+
+    fn each(&self, f: &mut FnMut(Self::Elem)) {
+        for x in &self.v {
+            sandbox::step();
+            f(x.clone());
+        }
+    }
+}
+
+#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Hash, Default)]
+struct OptionVal<T> {
+    v: Option<T>,
+}
+
+impl<T: Clone> Val for OptionVal<T> {
+    type Output = Option<T>;
+
+    fn get(&self) -> Self::Output {
+        self.v.clone()
+    }
+}
+
+// Shared until modified, so passing a map around doesn't copy it.
+#[derive(Debug,Clone, Eq, PartialEq)]
+struct MapVal<K: Eq+Hash, V> {
+    v: Rc<HashMap<K, V>>,
+}
+
+// Empty, whatever the keys and values are.
+impl<K: Eq+Hash, V> Default for MapVal<K, V> {
+    fn default() -> Self {
+        MapVal {
+            v: Rc::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq+Hash+Clone, V: Clone> Val for MapVal<K, V> {
+    type Output = HashMap<K, V>;
+
+    fn get(&self) -> Self::Output {
+        (*self.v).clone()
+    }
+}
+
+// A mutable cell. Clones refer to the same cell, and two refs are equal
+// only if they are the same cell.
+#[derive(Debug, Default)]
+struct RefVal<T> {
+    v: Rc<RefCell<T>>,
+}
+
+impl<T> Clone for RefVal<T> {
+    fn clone(&self) -> Self {
+        RefVal {
+            v: self.v.clone(),
+        }
+    }
+}
+
+impl<T> PartialEq for RefVal<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.v, &other.v)
+    }
+}
+
+impl<T> Val for RefVal<T> {
+    type Output = Rc<RefCell<T>>;
+
+    fn get(&self) -> Self::Output {
+        self.v.clone()
+    }
+}
+
+// Named fields of any type, kept sorted by name. Fields are shared between
+// copies of a record; updating one makes a new record.
+#[derive(Clone, Default)]
+struct RecordVal {
+    v: Rc<Vec<(String, Rc<Any>)>>,
+}
+
+impl Val for RecordVal {
+    type Output = Vec<(String, Rc<Any>)>;
+
+    fn get(&self) -> Self::Output {
+        (*self.v).clone()
+    }
+}
+
+// A value of one of several cases, told apart by `tag`, each carrying a
+// payload of its own type.
+#[derive(Clone)]
+struct VariantVal {
+    tag: Rc<str>,
+    v: Rc<Any>,
+}
+
+impl Default for VariantVal {
+    fn default() -> Self {
+        VariantVal {
+            tag: Rc::from(""),
+            v: Rc::new(UnitVal),
+        }
+    }
+}
+
+impl Val for VariantVal {
+    type Output = (Rc<str>, Rc<Any>);
+
+    fn get(&self) -> Self::Output {
+        (self.tag.clone(), self.v.clone())
+    }
+}
+
+// A program as a value, so one stage can compute the program run by the
+// next. ProgVal<ProgVal<T>> is a generator of generators, and so on.
+struct ProgVal<T: 'static> {
+    v: Rc<Exp<Output=T>>,
+}
+
+impl<T: 'static> Clone for ProgVal<T> {
+    fn clone(&self) -> Self {
+        ProgVal {
+            v: self.v.clone(),
+        }
+    }
+}
+
+// The program returning T's default, so generated programs can be held in
+// variables.
+impl<T: 'static+Clone+Default> Default for ProgVal<T> {
+    fn default() -> Self {
+        ProgVal {
+            v: Rc::new(unit_exp(T::default())),
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for ProgVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgVal({:p})", &*self.v)
+    }
+}
+
+impl<T: 'static> Val for ProgVal<T> {
+    type Output = Rc<Exp<Output=T>>;
+
+    fn get(&self) -> Self::Output {
+        self.v.clone()
+    }
+}
+
+// An already staged program as a value, built by quoting.
+struct CodeVal<T: 'static> {
+    v: Rc<StagedExp<Output=T>>,
+}
+
+impl<T: 'static> Clone for CodeVal<T> {
+    fn clone(&self) -> Self {
+        CodeVal {
+            v: self.v.clone(),
+        }
+    }
+}
+
+impl<T: 'static+Clone+Default> Default for CodeVal<T> {
+    fn default() -> Self {
+        CodeVal {
+            v: Rc::new(ConstantStagedExp { const_val: Rc::new(T::default()) }),
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for CodeVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CodeVal({:p})", &*self.v)
+    }
+}
+
+impl<T: 'static> Val for CodeVal<T> {
+    type Output = Rc<StagedExp<Output=T>>;
+
+    fn get(&self) -> Self::Output {
+        self.v.clone()
+    }
+}
+
+// A function as a value, made by the lambda nodes and called by ApplyExp.
+// A curried function applied to its first argument keeps that argument's
+// value, so partial applications can be passed around and called later.
+struct FnVal<A: 'static, R: 'static> {
+    f: Rc<Fn(A) -> R>,
+}
+
+impl<A: 'static, R: 'static> Clone for FnVal<A, R> {
+    fn clone(&self) -> Self {
+        FnVal {
+            f: self.f.clone(),
+        }
+    }
+}
+
+// The function returning R's default, so functions can be held in variables.
+impl<A: 'static, R: 'static+Default> Default for FnVal<A, R> {
+    fn default() -> Self {
+        FnVal {
+            f: Rc::new(|_| R::default()),
+        }
+    }
+}
+
+impl<A: 'static, R: 'static> fmt::Debug for FnVal<A, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FnVal({:p})", &*self.f)
+    }
+}
+
+impl<A: 'static, R: 'static> Val for FnVal<A, R> {
+    type Output = Rc<Fn(A) -> R>;
+
+    fn get(&self) -> Self::Output {
+        self.f.clone()
+    }
+}
+
+// A computation put off until it's forced, then kept. Clones share it, so
+// it's computed at most once however many hold it; see `thunk`.
+struct ThunkVal<T: 'static> {
+    state: Rc<RefCell<Thunk<T>>>,
+}
+
+enum Thunk<T: 'static> {
+    Pending(Rc<Fn() -> T>),
+    // Being computed, so forcing it again would never finish.
+    Forcing(Rc<Fn() -> T>),
+    Done(T),
+}
+
+impl<T: 'static> Clone for ThunkVal<T> {
+    fn clone(&self) -> Self {
+        ThunkVal {
+            state: self.state.clone(),
+        }
+    }
+}
+
+// Already forced to T's default, so thunks can be held in variables.
+impl<T: 'static+Default> Default for ThunkVal<T> {
+    fn default() -> Self {
+        ThunkVal {
+            state: Rc::new(RefCell::new(Thunk::Done(T::default()))),
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for ThunkVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ThunkVal({:p})", &*self.state)
+    }
+}
+
+// Elements pulled one at a time from a host iterator, possibly without end.
+// Clones share the iterator, so an element taken through one is gone from
+// all of them; see `stream`.
+struct StreamVal<T: 'static> {
+    iter: Rc<RefCell<Box<Iterator<Item=T>>>>,
+}
+
+impl<T: 'static> Clone for StreamVal<T> {
+    fn clone(&self) -> Self {
+        StreamVal {
+            iter: self.iter.clone(),
+        }
+    }
+}
+
+// An empty stream, so streams can be held in variables.
+impl<T: 'static> Default for StreamVal<T> {
+    fn default() -> Self {
+        StreamVal {
+            iter: Rc::new(RefCell::new(box std::iter::empty())),
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for StreamVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StreamVal({:p})", &*self.iter)
+    }
+}
+
+// A program running on another thread, and then its result; see `spawn`.
+// Clones share the task, so it's joined once and its result kept.
+struct TaskVal<T: 'static> {
+    state: Rc<RefCell<TaskState<T>>>,
+}
+
+enum TaskState<T> {
+    // The program's value, or its panic, once it's done, with what it spent
+    // of a sandbox's budget carried to it.
+    Running(std::sync::mpsc::Receiver<(std::thread::Result<dynamic::DynVal>, sandbox::Spent)>),
+    Done(T),
+}
+
+impl<T: 'static> Clone for TaskVal<T> {
+    fn clone(&self) -> Self {
+        TaskVal {
+            state: self.state.clone(),
+        }
+    }
+}
+
+// A task already done with the default value, so tasks can be held in
+// variables.
+impl<T: 'static+Default> Default for TaskVal<T> {
+    fn default() -> Self {
+        TaskVal {
+            state: Rc::new(RefCell::new(TaskState::Done(T::default()))),
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for TaskVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TaskVal({:p})", &*self.state)
+    }
+}
+
+// A queue of values that programs on any thread can send to and receive
+// from; see `channel`. Clones are the same channel.
+struct ChannelVal<T: 'static> {
+    chan: std::sync::Arc<channel::Channel>,
+    _elem: std::marker::PhantomData<T>,
+}
+
+impl<T: 'static> Clone for ChannelVal<T> {
+    fn clone(&self) -> Self {
+        ChannelVal {
+            chan: self.chan.clone(),
+            _elem: std::marker::PhantomData,
+        }
+    }
+}
+
+// A new, empty channel, so channels can be held in variables.
+impl<T: 'static> Default for ChannelVal<T> {
+    fn default() -> Self {
+        ChannelVal {
+            chan: channel::Channel::new(),
+            _elem: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for ChannelVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChannelVal({})", self.chan.id())
+    }
+}
+
+// The textual form of a value, as written by PrintExp.
+impl fmt::Display for NumVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.v)
+    }
+}
+
+impl fmt::Display for BoolVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.v)
+    }
+}
+
+impl fmt::Display for UnitVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "()")
+    }
+}
+
+impl fmt::Display for StrVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.v)
+    }
+}
+
+impl fmt::Display for FloatVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.v)
+    }
+}
+
+#[cfg(feature = "complex")]
+impl fmt::Display for ComplexVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.im < 0.0 {
+            write!(f, "{}-{}i", self.re, -self.im)
+        } else {
+            write!(f, "{}+{}i", self.re, self.im)
+        }
+    }
+}
+
+#[cfg(feature = "decimal")]
+impl fmt::Display for DecimalVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.v.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (whole, frac) = digits.split_at(digits.len() - scale);
+        let sign = if self.v < 0 { "-" } else { "" };
+        if scale == 0 {
+            write!(f, "{}{}", sign, whole)
+        } else {
+            write!(f, "{}{}.{}", sign, whole, frac)
+        }
+    }
+}
+
+#[cfg(feature = "linalg")]
+impl fmt::Display for VecVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.v)
+    }
+}
+
+#[cfg(feature = "linalg")]
+impl fmt::Display for MatVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, row) in self.v.chunks(self.cols.max(1)).take(self.rows).enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{:?}", row)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl fmt::Display for DurationVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}ms", self.nanos as f64 / 1e6)
+    }
+}
+
+impl fmt::Display for InstantVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "@{}ms", self.nanos as f64 / 1e6)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for ArrayVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, x) in self.v.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", x)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl fmt::Display for RangeVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.step == 1 {
+            write!(f, "{}..{}", self.start, self.end)
+        } else {
+            write!(f, "{}..{} by {}", self.start, self.end, self.step)
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for OptionVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.v {
+            Some(ref x) => write!(f, "Some({})", x),
+            None => write!(f, "None"),
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for RefVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ref({})", self.v.borrow())
+    }
+}
+
+trait Exp {
+    type Output;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>>;
+
+    fn interpret(&self) -> Self::Output;
+
+    // Passes the result to `f` by reference, as `StagedExp::run_with` does,
+    // so reading a constant or variable doesn't copy a large string or array.
+    // A variable stays borrowed while `f` runs, so `f` must not assign it.
+    fn interpret_with(&self, f: &mut FnMut(&Self::Output)) {
+        f(&self.interpret())
+    }
+
+    // A copy of this node and everything under it, so a subtree can be used
+    // in more than one place. Nodes implement it as `box self.clone()`.
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>>;
+
+    // Called instead of stage/interpret when this node is in tail position of
+    // the recursive function `fn_id`, so self-calls there can become jumps.
+    fn stage_tail(&self, _fn_id: i32) -> Box<StagedExp<Output=Self::Output>> {
+        self.stage()
+    }
+
+    fn interpret_tail(&self, _fn_id: i32) -> Self::Output {
+        self.interpret()
+    }
+
+    // Prepares the expression as nested closures rather than a tree of
+    // staged nodes, so running it makes at most one indirect call per
+    // node. Nodes without their own version run their staged form instead.
+    fn stage_compiled(&self) -> Compiled<Self::Output> where Self::Output: 'static {
+        let staged = self.stage();
+        box move || staged.run(&EvalContext::new())
+    }
+
+    // The untyped shape of the tree, for comparing and caching programs.
+    fn reify(&self) -> Expr {
+        Expr::Opaque
+    }
+
+    // The value, if this node is a constant, so nodes above it can
+    // specialize on it when they're staged.
+    fn constant(&self) -> Option<&Any> {
+        None
+    }
+
+    // The variable, as a `VariableExp`, if this node reads one, so nodes
+    // above it can read it themselves when they're staged.
+    fn variable(&self) -> Option<&Any> {
+        None
+    }
+
+    // For nodes that build an array from another collection, a staged loop
+    // over the elements the array would hold, as a boxed
+    // `Box<array::StagedEach>`; nodes above that only go through the
+    // elements once run it rather than build the array. See `agg`.
+    fn stage_each(&self) -> Option<Box<Any>> {
+        None
+    }
+}
+
+impl<T: 'static> Clone for Box<Exp<Output=T>> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+type Compiled<T> = Box<Fn() -> T>;
+
+// Lets a compiled program be used wherever a staged one is expected.
+struct CompiledStagedExp<T: 'static> {
+    compiled: Compiled<T>,
+}
+
+impl<T: 'static> StagedExp for CompiledStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, _ctx: &EvalContext) -> Self::Output {
+        (self.compiled)()
+    }
+}
+
+trait StagedExp {
+    type Output;
+
+    // Runs with the variables the program binds kept in `ctx`; see
+    // EvalContext.
+    fn run(&self, ctx: &EvalContext) -> Self::Output;
+
+    // Passes the result to `f` by reference; nodes that already hold their
+    // value (constants, variables) override this to skip the clone in `run`.
+    // A host variable stays borrowed while `f` runs, so `f` must not assign
+    // it.
+    fn run_with(&self, ctx: &EvalContext, f: &mut FnMut(&Self::Output)) {
+        f(&self.run(ctx))
+    }
+}
+
+// The value is shared, so copying the tree or staging it doesn't copy a large
+// string or array; `run` still returns a copy each time, so nodes that only
+// read an operand use `run_with`, which doesn't.
+#[derive(Clone)]
+struct ConstantExp<T: 'static+Clone> {
+    const_val: Rc<T>,
+}
+
+struct ConstantStagedExp<T: 'static+Clone> {
+    const_val: Rc<T>,
+}
+
+impl<T: 'static+Clone> Exp for ConstantExp<T>{
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ConstantStagedExp {
+            const_val: self.const_val.clone()
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        (*self.const_val).clone()
+    }
+
+    fn interpret_with(&self, f: &mut FnMut(&Self::Output)) {
+        f(&self.const_val)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        Expr::Const(value_of(&*self.const_val))
+    }
+
+    fn constant(&self) -> Option<&Any> {
+        Some(&*self.const_val)
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let const_val = self.const_val.clone();
+        box move || (*const_val).clone()
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for ConstantStagedExp<T>{
+    type Output = T;
+
+    fn run(&self, _ctx: &EvalContext) -> Self::Output {
+        (*self.const_val).clone()
+    }
+
+    fn run_with(&self, _ctx: &EvalContext, f: &mut FnMut(&Self::Output)) {
+        f(&self.const_val)
+    }
+}
+
+static mut var_counter: i32 = 0;
+
+fn fresh_id() -> i32 {
+    unsafe{
+        var_counter += 1;
+        var_counter
+    }
+}
+
+#[derive(Debug,Clone)]
+struct VariableExp<T: 'static+Clone> {
+    id: i32,
+    var_val: Rc<RefCell<T>>,
+}
+
+impl<T: 'static+Clone+Default> VariableExp<T> {
+    fn fresh() -> VariableExp<T> {
+        VariableExp {
+            id: fresh_id(),
+            var_val: Rc::new(RefCell::new(T::default())),
+        }
+    }
+}
+
+impl<T: 'static+Clone> VariableExp<T> {
+    fn fresh_with_val(v: T) -> VariableExp<T> {
+        VariableExp {
+            id: fresh_id(),
+            var_val: Rc::new(RefCell::new(v)),
+        }
+    }
+}
+
+impl<T: 'static+Clone> VariableExp<T> {
+    // Writes the variable and returns what it held. Nodes write their
+    // variables through this so a `journal::Journal` can record it and
+    // `watch` can report it.
+    fn assign(&self, v: T) -> T {
+        if !journal::recording() && !watch::watching() {
+            return self.var_val.replace(v);
+        }
+        let old = self.var_val.replace(v.clone());
+        journal::record_write(self, &old, &v);
+        watch::notify(self, &old, &v);
+        old
+    }
+
+    // Changes the value in place with `f`, rather than reading a copy and
+    // assigning it back, unless a journal or watch needs both. The value is
+    // taken out of the cell while `f` runs, so the cell isn't borrowed then,
+    // and put back after; `f` mustn't read or set the variable, which holds
+    // T's default until it returns.
+    fn update(&self, f: &mut FnMut(&mut T)) where T: Default {
+        if !journal::recording() && !watch::watching() {
+            let mut v = self.var_val.take();
+            f(&mut v);
+            self.var_val.replace(v);
+            return;
+        }
+        let mut v = self.var_val.borrow().clone();
+        f(&mut v);
+        self.assign(v);
+    }
+}
+
+// The state of one run of a staged program. Staged trees don't change when
+// they run: the variables a program binds itself, with let, loops, function
+// arguments and patterns, are kept in the frame of the context the run is
+// given, so two runs of the same tree, one inside the other or taking turns,
+// each see their own. Variables the program doesn't bind are the host's,
+// read and set where they are, so the host still passes inputs and reads
+// results through them. Start each run with a new context:
+//
+//     let v = exp.stage().run(&EvalContext::new());
+//
+// Each bound variable has a slot in the frame, its index, given to it as
+// it's staged (see `SlotScope`), so reading one is an index rather than a
+// lookup or a borrow of its own cell. A slot is shared by variables whose
+// scopes don't overlap; each holds the variable whose value it is, and a
+// read of any other, as by a function called after the let around it has
+// finished, gets the host's cell instead.
+//
+// Clones share the same frame, so a function, stream or thunk a run makes
+// reads it as the run has it when it's called. Writes to frame variables
+// fire watches, but a journal only records writes to the host's: there'd be
+// nothing to step back once the run is over.
+#[derive(Clone, Default)]
+pub struct EvalContext {
+    frame: Rc<Frame>,
+}
+
+// The slots of a frame, each empty or holding a binding. They're read and
+// written on every access to a bound variable, so they aren't behind a
+// RefCell: the methods of EvalContext are the only code that reaches them,
+// through `slots`, and none of them keeps the reference while a program,
+// a watch or a function it was given runs. They do clone and drop values
+// while they hold it, which is fine as no value's Clone or Drop runs a
+// program.
+#[derive(Default)]
+struct Frame {
+    slots: UnsafeCell<Vec<Option<Binding>>>,
+}
+
+// What a slot holds: a variable's value, with the variable's own cell,
+// which says whose value it is and lets code that reads variables there be
+// lent the value; see `lend`. The value is shared only while `get_with`
+// lends it out, so a write overwrites it where it is rather than
+// allocating. The cell's and the value's addresses are kept as well, as
+// finding them through the trait objects looks their layout up each time.
+pub struct Binding {
+    var: *const u8,
+    at: *const u8,
+    value: Rc<Any>,
+    cell: Rc<Lend>,
+}
+
+pub trait Lend {
+    // Puts `v` in the cell and returns what the cell held.
+    fn lend(&self, v: &Any) -> Box<Any>;
+    fn give_back(&self, held: Box<Any>);
+}
+
+impl<T: 'static+Clone> Lend for RefCell<T> {
+    fn lend(&self, v: &Any) -> Box<Any> {
+        box self.replace(v.downcast_ref::<T>().unwrap().clone())
+    }
+
+    fn give_back(&self, held: Box<Any>) {
+        if let Ok(v) = held.downcast::<T>() {
+            self.replace(*v);
+        }
+    }
+}
+
+impl Binding {
+    // The only way a binding is made, so a binding whose cell is a
+    // `RefCell<T>` holds a T; see `binds`.
+    fn new<T: 'static+Clone>(var: &VariableExp<T>, v: T) -> Binding {
+        let value = Rc::new(v);
+        Binding {
+            var: Rc::as_ptr(&var.var_val) as *const u8,
+            at: Rc::as_ptr(&value) as *const u8,
+            value,
+            cell: var.var_val.clone(),
+        }
+    }
+
+    // Whether this is `var`'s. Variables are told apart by their cells, so
+    // the check is a comparison of addresses. The binding keeps its cell
+    // alive, so no other allocation can have the cell's address while it
+    // lives: a binding that passes holds `var`'s own `RefCell<T>`, and so,
+    // made by `new`, a T at `at`. The accessors below cast to T on that
+    // basis rather than downcast, which would look the type up through
+    // `Any` each time.
+    fn binds<T: 'static+Clone>(&self, var: &VariableExp<T>) -> bool {
+        self.var == Rc::as_ptr(&var.var_val) as *const u8
+    }
+
+    fn value_of<T: 'static+Clone>(&self, var: &VariableExp<T>) -> Option<&T> {
+        if !self.binds(var) {
+            return None;
+        }
+        // SAFETY: the value is a T, as `binds` passed, and lives as long as
+        // `self.value` does.
+        Some(unsafe { &*(self.at as *const T) })
+    }
+
+    // `var`'s value, to change in place, if this is `var`'s. It's copied
+    // first if `get_with` has lent it out.
+    fn value_mut_of<T: 'static+Clone>(&mut self, var: &VariableExp<T>) -> Option<&mut T> {
+        if !self.binds(var) {
+            return None;
+        }
+        if !self.owned() {
+            let v = self.value_of(var).unwrap().clone();
+            self.rebind(var, v);
+        }
+        // SAFETY: the value is a T, as `binds` passed, and nothing else
+        // holds it, as `owned` checked.
+        Some(unsafe { &mut *(self.at as *mut T) })
+    }
+
+    // Sets `var` to `v` if this is `var`'s, and otherwise gives `v` back.
+    fn set_of<T: 'static+Clone>(&mut self, var: &VariableExp<T>, v: T) -> Result<(), T> {
+        if !self.binds(var) {
+            return Err(v);
+        }
+        if self.owned() {
+            // SAFETY: the value is a T, as `binds` passed, and nothing else
+            // holds it, as `owned` checked.
+            unsafe { *(self.at as *mut T) = v };
+        } else {
+            self.rebind(var, v);
+        }
+        Ok(())
+    }
+
+    // Gives `var` a value of its own, leaving the one lent out to whatever
+    // has it. Out of line, as it's rare, so the writes above stay cheap.
+    #[cold]
+    #[inline(never)]
+    fn rebind<T: 'static+Clone>(&mut self, var: &VariableExp<T>, v: T) {
+        *self = Binding::new(var, v);
+    }
+
+    // Whether the value isn't lent out, so it may be changed where it is.
+    fn owned(&self) -> bool {
+        Rc::strong_count(&self.value) == 1 && Rc::weak_count(&self.value) == 0
+    }
+}
+
+// A bound variable's place in the frames of the runs of a staged tree.
+#[derive(Clone)]
+struct Slot<T: 'static+Clone> {
+    var: VariableExp<T>,
+    index: usize,
+}
+
+// Where a staged node finds a variable it reads or sets, settled as it's
+// staged: a slot, if a binder around the node binds it, or else the host's
+// cell.
+#[derive(Clone)]
+enum Place<T: 'static+Clone> {
+    Host(VariableExp<T>),
+    Slot(Slot<T>),
+}
+
+thread_local! {
+    // The variables bound around the node being staged, outermost first;
+    // the index of each is its slot.
+    static STAGING: RefCell<Vec<i32>> = const { RefCell::new(Vec::new()) };
+    // While `SlotScope::record` runs, each variable given a slot and the
+    // slot, in the order they're given.
+    static RECORDING: RefCell<Option<Vec<(i32, usize)>>> = const { RefCell::new(None) };
+}
+
+// The variables a binder being staged puts in scope, for as long as the
+// SlotScope lives: stage the children that see them while it does, and the
+// rest before. Slots are the depth of the variable in the scopes around
+// it, so a frame is only as long as binders nest; `scope::stage_slots`
+// reports the slots a staging gave out.
+pub struct SlotScope {
+    depth: usize,
+}
+
+impl SlotScope {
+    fn enter() -> SlotScope {
+        SlotScope {
+            depth: STAGING.with(|s| s.borrow().len()),
+        }
+    }
+
+    fn bind<T: 'static+Clone>(&self, var: &VariableExp<T>) -> Slot<T> {
+        Slot {
+            var: var.clone(),
+            index: self.bind_id(var.id),
+        }
+    }
+
+    fn bind_id(&self, id: i32) -> usize {
+        let index = STAGING.with(|s| {
+            let mut s = s.borrow_mut();
+            s.push(id);
+            s.len() - 1
+        });
+        RECORDING.with(|r| {
+            if let Some(ref mut given) = *(*r).borrow_mut() {
+                given.push((id, index));
+            }
+        });
+        index
+    }
+
+    // Runs `f`, and returns the variables it gave slots to with their
+    // slots, in the order it gave them.
+    fn record<R, F: FnOnce() -> R>(f: F) -> (R, Vec<(i32, usize)>) {
+        struct Outer(Option<Vec<(i32, usize)>>);
+
+        impl Drop for Outer {
+            fn drop(&mut self) {
+                RECORDING.with(|r| r.replace(self.0.take()));
+            }
+        }
+
+        let outer = Outer(RECORDING.with(|r| r.replace(Some(Vec::new()))));
+        let result = f();
+        let given = RECORDING.with(|r| r.replace(None)).unwrap_or_default();
+        drop(outer);
+        (result, given)
+    }
+
+    // The variables in scope now, for staging code later as it would be
+    // staged here; see `resume`.
+    fn current() -> Rc<Vec<i32>> {
+        Rc::new(STAGING.with(|s| s.borrow().clone()))
+    }
+
+    // Runs `f` with `vars` in scope instead of what is, as when a quote's
+    // body is staged by a run.
+    fn resume<R, F: FnOnce() -> R>(vars: &[i32], f: F) -> R {
+        struct Outer(Vec<i32>);
+
+        impl Drop for Outer {
+            fn drop(&mut self) {
+                STAGING.with(|s| s.replace(mem::take(&mut self.0)));
+            }
+        }
+
+        let _outer = Outer(STAGING.with(|s| s.replace(vars.to_vec())));
+        f()
+    }
+
+    fn slot_of(id: i32) -> Option<usize> {
+        STAGING.with(|s| s.borrow().iter().rposition(|&v| v == id))
+    }
+}
+
+impl Drop for SlotScope {
+    fn drop(&mut self) {
+        STAGING.with(|s| s.borrow_mut().truncate(self.depth));
+    }
+}
+
+impl<T: 'static+Clone> Place<T> {
+    fn of(var: &VariableExp<T>) -> Place<T> {
+        match SlotScope::slot_of(var.id) {
+            Some(index) => Place::Slot(Slot {
+                var: var.clone(),
+                index,
+            }),
+            None => Place::Host(var.clone()),
+        }
+    }
+}
+
+impl EvalContext {
+    fn new() -> EvalContext {
+        EvalContext::default()
+    }
+
+    // The frame's slots. Callers keep to what `Frame` says: the reference
+    // is gone before anything that could reach the frame again runs.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slots(&self) -> &mut Vec<Option<Binding>> {
+        &mut *self.frame.slots.get()
+    }
+
+    fn get<T: 'static+Clone>(&self, slot: &Slot<T>) -> T {
+        // SAFETY: held while the value is cloned.
+        if let Some(&Some(ref b)) = unsafe { self.slots() }.get(slot.index) {
+            if let Some(v) = b.value_of(&slot.var) {
+                return v.clone();
+            }
+        }
+        slot.var.var_val.borrow().clone()
+    }
+
+    // The value is shared rather than borrowed while `f` runs, so `f` may
+    // bind or set variables, this one included.
+    fn get_with<T: 'static+Clone>(&self, slot: &Slot<T>, f: &mut FnMut(&T)) {
+        // SAFETY: held while the value's Rc is cloned, and not while `f` runs.
+        let lent = match unsafe { self.slots() }.get(slot.index) {
+            Some(&Some(ref b)) if b.binds(&slot.var) => Some((b.value.clone(), b.at)),
+            _ => None,
+        };
+        match lent {
+            // SAFETY: the value is a T, as `binds` passed, and the clone of
+            // its Rc keeps it alive.
+            Some((_value, at)) => f(unsafe { &*(at as *const T) }),
+            None => f(&*slot.var.var_val.borrow()),
+        }
+    }
+
+    // Takes what the slot holds, for `restore` to put back.
+    fn save(&self, index: usize) -> Option<Binding> {
+        // SAFETY: held while the binding is moved out.
+        match unsafe { self.slots() }.get_mut(index) {
+            Some(b) => b.take(),
+            None => None,
+        }
+    }
+
+    // Sets the slot's variable to `v` if the slot holds it, and otherwise
+    // gives `v` back.
+    fn overwrite<T: 'static+Clone>(&self, slot: &Slot<T>, v: T) -> Result<(), T> {
+        if watch::watching() {
+            return self.overwrite_watched(slot, v);
+        }
+        // SAFETY: held while the value is written over the old one.
+        match unsafe { self.slots() }.get_mut(slot.index) {
+            Some(&mut Some(ref mut b)) => b.set_of(&slot.var, v),
+            _ => Err(v),
+        }
+    }
+
+    fn overwrite_watched<T: 'static+Clone>(&self, slot: &Slot<T>, v: T) -> Result<(), T> {
+        // SAFETY: held while the binding is replaced; watches are told after.
+        let old = match unsafe { self.slots() }.get_mut(slot.index) {
+            Some(&mut Some(ref mut b)) if b.binds(&slot.var) => {
+                mem::replace(b, Binding::new(&slot.var, v.clone()))
+            }
+            _ => return Err(v),
+        };
+        watch::notify(&slot.var, old.value_of(&slot.var).unwrap(), &v);
+        Ok(())
+    }
+
+    // Binds the slot's variable to `v`. A slot that holds it already is
+    // overwritten where it is, so a loop setting it allocates nothing.
+    fn set_local<T: 'static+Clone>(&self, slot: &Slot<T>, v: T) {
+        if let Err(v) = self.overwrite(slot, v) {
+            if watch::watching() {
+                let old = slot.var.var_val.borrow().clone();
+                self.replace(slot.index, Some(Binding::new(&slot.var, v.clone())));
+                watch::notify(&slot.var, &old, &v);
+            } else {
+                self.replace(slot.index, Some(Binding::new(&slot.var, v)));
+            }
+        }
+    }
+
+    // Puts `b` in the slot, until something else binds or restores it, and
+    // returns what the slot held.
+    fn replace(&self, index: usize, b: Option<Binding>) -> Option<Binding> {
+        // SAFETY: held while the binding is moved in.
+        let slots = unsafe { self.slots() };
+        if slots.len() <= index {
+            slots.resize_with(index + 1, || None);
+        }
+        mem::replace(&mut slots[index], b)
+    }
+
+    fn restore<T: 'static+Clone>(&self, slot: &Slot<T>, saved: Option<Binding>) {
+        match saved {
+            Some(ref b) if b.binds(&slot.var) && watch::watching() => {
+                self.set_local(slot, b.value_of(&slot.var).unwrap().clone())
+            }
+            saved => {
+                self.replace(slot.index, saved);
+            }
+        }
+    }
+
+    fn read<T: 'static+Clone>(&self, place: &Place<T>) -> T {
+        match *place {
+            Place::Host(ref var) => var.var_val.borrow().clone(),
+            Place::Slot(ref slot) => self.get(slot),
+        }
+    }
+
+    // Sets the slot's variable where it lives: in the frame if the run has
+    // it bound, or else the host's.
+    fn set<T: 'static+Clone>(&self, slot: &Slot<T>, v: T) {
+        if let Err(v) = self.overwrite(slot, v) {
+            slot.var.assign(v);
+        }
+    }
+
+    fn write<T: 'static+Clone>(&self, place: &Place<T>, v: T) {
+        match *place {
+            Place::Slot(ref slot) => self.set(slot, v),
+            Place::Host(ref var) => {
+                var.assign(v);
+            }
+        }
+    }
+
+    // Changes the variable where it lives with `f`, as `write` of what `f`
+    // makes of its value would, without copying the value if nothing else
+    // holds it. The value is taken out while `f` runs, so neither the frame
+    // nor the variable's cell is borrowed then, and put back after; `f`
+    // mustn't read or set the variable, which it would find unset.
+    fn update<T: 'static+Clone+Default>(&self, place: &Place<T>, f: &mut FnMut(&mut T)) {
+        if watch::watching() {
+            let mut v = self.read(place);
+            f(&mut v);
+            return self.write(place, v);
+        }
+        if let Place::Slot(ref slot) = *place {
+            // SAFETY: held while the binding is moved out; `f` runs after.
+            let taken = match unsafe { self.slots() }.get_mut(slot.index) {
+                Some(b) if b.as_ref().is_some_and(|b| b.binds(&slot.var)) => b.take(),
+                _ => None,
+            };
+            if let Some(mut b) = taken {
+                f(b.value_mut_of(&slot.var).unwrap());
+                self.replace(slot.index, Some(b));
+                return;
+            }
+        }
+        match *place {
+            Place::Host(ref var) | Place::Slot(Slot { ref var, .. }) => var.update(f),
+        }
+    }
+
+    fn bind<'a, T: 'static+Clone>(&'a self, slot: &'a Slot<T>) -> Local<'a, T> {
+        Local {
+            ctx: self,
+            slot,
+            outer: None,
+        }
+    }
+
+    // Runs `f` with the variables the frame binds put in their own cells,
+    // and then puts back what the cells held. For staging in the middle of a
+    // run, as a quote does: the splices it evaluates are interpreted, and
+    // the interpreter reads variables from their cells.
+    fn lend<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        struct GiveBack<'a>(&'a EvalContext, Vec<(usize, Box<Any>)>);
+
+        impl<'a> Drop for GiveBack<'a> {
+            fn drop(&mut self) {
+                // SAFETY: held while the cells are given back what they
+                // held, which only moves and drops values.
+                let slots = unsafe { self.0.slots() };
+                for (index, held) in self.1.drain(..).rev() {
+                    if let Some(&Some(ref b)) = slots.get(index) {
+                        b.cell.give_back(held);
+                    }
+                }
+            }
+        }
+
+        // SAFETY: held while the values are copied into their cells; `f`
+        // runs after.
+        let held = unsafe { self.slots() }.iter().enumerate().filter_map(|(index, b)| {
+            b.as_ref().map(|b| (index, b.cell.lend(&*b.value)))
+        }).collect();
+        let _give_back = GiveBack(self, held);
+        f()
+    }
+}
+
+// Binds a staged binder's variable in the run's frame and, when dropped,
+// puts back what the slot held before. Staged bodies are shared by every
+// run of them, so a body run again while it's already running, as by a
+// recursive call, would otherwise leave its own bindings in the outer
+// run's variables. Loops set it once per iteration.
+struct Local<'a, T: 'static+Clone> {
+    ctx: &'a EvalContext,
+    slot: &'a Slot<T>,
+    outer: Option<Option<Binding>>,
+}
+
+impl<'a, T: 'static+Clone> Local<'a, T> {
+    fn set(&mut self, v: T) {
+        if self.outer.is_none() {
+            self.outer = Some(self.ctx.save(self.slot.index));
+        }
+        self.ctx.set_local(self.slot, v);
+    }
+
+    fn get(&self) -> T {
+        self.ctx.get(self.slot)
+    }
+}
+
+impl<'a, T: 'static+Clone> Drop for Local<'a, T> {
+    fn drop(&mut self) {
+        if let Some(outer) = self.outer.take() {
+            self.ctx.restore(self.slot, outer);
+        }
+    }
+}
+
+// A variable staged outside every binder of it reads the host's cell.
+impl<T: 'static+Clone> StagedExp for VariableExp<T> {
+    type Output = T;
+
+    fn run(&self, _ctx: &EvalContext) -> Self::Output {
+        self.var_val.borrow().clone()
+    }
+
+    fn run_with(&self, _ctx: &EvalContext, f: &mut FnMut(&Self::Output)) {
+        f(&*self.var_val.borrow())
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for Slot<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        ctx.get(self)
+    }
+
+    fn run_with(&self, ctx: &EvalContext, f: &mut FnMut(&Self::Output)) {
+        ctx.get_with(self, f)
+    }
+}
+
+impl<T: 'static+Clone> Exp for VariableExp<T>{
+    type Output = T;
+
+    // Staged as the place it's found, so a run reads it without matching on
+    // where it lives.
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        match Place::of(self) {
+            Place::Host(var) => box var,
+            Place::Slot(slot) => box slot,
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.var_val.borrow().clone()
+    }
+
+    fn interpret_with(&self, f: &mut FnMut(&Self::Output)) {
+        f(&*self.var_val.borrow())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn variable(&self) -> Option<&Any> {
+        Some(self)
+    }
+
+    fn reify(&self) -> Expr {
+        reify::reached(self);
+        Expr::Var(self.id)
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let var_val = self.var_val.clone();
+        box move || var_val.borrow().clone()
+    }
+}
+
+// An operand of a staged arithmetic or comparison node. A constant, or a
+// variable, is kept in the node, so a run takes its value without a call.
+// Variables of either place share a case: with a case each, the match
+// becomes a jump table, which costs about what the call it saves does.
+enum Operand<T: 'static+Clone> {
+    Const(T),
+    Var(Place<T>),
+    Staged(Box<StagedExp<Output=T>>),
+}
+
+impl<T: 'static+Clone> Operand<T> {
+    fn stage(exp: &Exp<Output=T>) -> Operand<T> {
+        if let Some(c) = exp.constant().and_then(|c| c.downcast_ref::<T>()) {
+            return Operand::Const(c.clone());
+        }
+        match exp.variable().and_then(|v| v.downcast_ref::<VariableExp<T>>()) {
+            Some(var) => Operand::Var(Place::of(var)),
+            None => Operand::Staged(exp.stage()),
+        }
+    }
+
+    fn run(&self, ctx: &EvalContext) -> T {
+        match *self {
+            Operand::Const(ref c) => c.clone(),
+            Operand::Var(ref place) => ctx.read(place),
+            Operand::Staged(ref exp) => exp.run(ctx),
+        }
+    }
+}
+
+// An operand of a compiled arithmetic or comparison node. A constant, or a
+// variable's cell, is kept in the closure, as `Operand` keeps it in a staged
+// node, so it's read without a call.
+enum CompiledOperand<T: 'static+Clone> {
+    Const(T),
+    Var(Rc<RefCell<T>>),
+    Compiled(Compiled<T>),
+}
+
+impl<T: 'static+Clone> CompiledOperand<T> {
+    fn compile(exp: &Exp<Output=T>) -> CompiledOperand<T> {
+        if let Some(c) = exp.constant().and_then(|c| c.downcast_ref::<T>()) {
+            return CompiledOperand::Const(c.clone());
+        }
+        match exp.variable().and_then(|v| v.downcast_ref::<VariableExp<T>>()) {
+            Some(var) => CompiledOperand::Var(var.var_val.clone()),
+            None => CompiledOperand::Compiled(exp.stage_compiled()),
+        }
+    }
+
+    fn get(&self) -> T {
+        match *self {
+            CompiledOperand::Const(ref c) => c.clone(),
+            CompiledOperand::Var(ref cell) => cell.borrow().clone(),
+            CompiledOperand::Compiled(ref exp) => exp(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AddExp<T: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=T>>,
+}
+
+struct AddStagedExp<T: 'static+Clone> {
+    staged_exp1: Operand<T>,
+    staged_exp2: Operand<T>,
+}
+
+impl<T: 'static+Clone+Val+std::ops::Add<Output=T>> Exp for AddExp<T>{
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box AddStagedExp {
+            staged_exp1: Operand::stage(&*self.exp1),
+            staged_exp2: Operand::stage(&*self.exp2),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.exp1.interpret() + self.exp2.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("add", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let operand1 = CompiledOperand::compile(&*self.exp1);
+        let operand2 = CompiledOperand::compile(&*self.exp2);
+        box move || operand1.get() + operand2.get()
+    }
+}
+
+impl<T: 'static+Clone+Val+std::ops::Add<Output=T>> StagedExp for AddStagedExp<T>{
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_exp1.run(ctx) + self.staged_exp2.run(ctx)
+    }
+}
+
+#[derive(Clone)]
+struct SubExp<T: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=T>>,
+}
+
+struct SubStagedExp<T: 'static+Clone> {
+    staged_exp1: Operand<T>,
+    staged_exp2: Operand<T>,
+}
+
+impl<T: 'static+Clone+Val+std::ops::Sub<Output=T>> Exp for SubExp<T>{
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box SubStagedExp {
+            staged_exp1: Operand::stage(&*self.exp1),
+            staged_exp2: Operand::stage(&*self.exp2),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.exp1.interpret() - self.exp2.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("sub", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let operand1 = CompiledOperand::compile(&*self.exp1);
+        let operand2 = CompiledOperand::compile(&*self.exp2);
+        box move || operand1.get() - operand2.get()
+    }
+}
+
+impl<T: 'static+Clone+Val+std::ops::Sub<Output=T>> StagedExp for SubStagedExp<T>{
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_exp1.run(ctx) - self.staged_exp2.run(ctx)
+    }
+}
+
+#[derive(Clone)]
+struct MulExp<T: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=T>>,
+}
+
+struct MulStagedExp<T: 'static+Clone> {
+    staged_exp1: Operand<T>,
+    staged_exp2: Operand<T>,
+}
+
+impl<T: 'static+Clone+Val+std::ops::Mul<Output=T>> Exp for MulExp<T>{
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box MulStagedExp {
+            staged_exp1: Operand::stage(&*self.exp1),
+            staged_exp2: Operand::stage(&*self.exp2),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.exp1.interpret() * self.exp2.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("mul", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let operand1 = CompiledOperand::compile(&*self.exp1);
+        let operand2 = CompiledOperand::compile(&*self.exp2);
+        box move || operand1.get() * operand2.get()
+    }
+}
+
+impl<T: 'static+Clone+Val+std::ops::Mul<Output=T>> StagedExp for MulStagedExp<T>{
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_exp1.run(ctx) * self.staged_exp2.run(ctx)
+    }
+}
+
+#[derive(Clone)]
+struct DivExp<T: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=T>>,
+}
+
+struct DivStagedExp<T: 'static+Clone> {
+    staged_exp1: Operand<T>,
+    staged_exp2: Operand<T>,
+}
+
+impl<T: 'static+Clone+Val+std::ops::Div<Output=T>> Exp for DivExp<T>{
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box DivStagedExp {
+            staged_exp1: Operand::stage(&*self.exp1),
+            staged_exp2: Operand::stage(&*self.exp2),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.exp1.interpret() / self.exp2.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("div", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let operand1 = CompiledOperand::compile(&*self.exp1);
+        let operand2 = CompiledOperand::compile(&*self.exp2);
+        box move || operand1.get() / operand2.get()
+    }
+}
+
+impl<T: 'static+Clone+Val+std::ops::Div<Output=T>> StagedExp for DivStagedExp<T>{
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_exp1.run(ctx) / self.staged_exp2.run(ctx)
+    }
+}
+
+// `exp1` to the power `exp2`, as f64::powf.
+#[derive(Clone)]
+struct PowExp {
+    exp1: Box<Exp<Output=FloatVal>>,
+    exp2: Box<Exp<Output=FloatVal>>,
+}
+
+struct PowStagedExp {
+    staged_exp1: Operand<FloatVal>,
+    staged_exp2: Operand<FloatVal>,
+}
+
+impl Exp for PowExp {
+    type Output = FloatVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box PowStagedExp {
+            staged_exp1: Operand::stage(&*self.exp1),
+            staged_exp2: Operand::stage(&*self.exp2),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        FloatVal { v: self.exp1.interpret().v.powf(self.exp2.interpret().v) }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("pow", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let operand1 = CompiledOperand::compile(&*self.exp1);
+        let operand2 = CompiledOperand::compile(&*self.exp2);
+        box move || FloatVal { v: operand1.get().v.powf(operand2.get().v) }
+    }
+}
+
+impl StagedExp for PowStagedExp {
+    type Output = FloatVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        FloatVal { v: self.staged_exp1.run(ctx).v.powf(self.staged_exp2.run(ctx).v) }
+    }
+}
+
+#[derive(Clone)]
+struct LessThanExp<T: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=T>>,
+}
+
+struct LessThanStagedExp<T: 'static+Clone> {
+    staged_exp1: Operand<T>,
+    staged_exp2: Operand<T>,
+}
+
+impl<T: 'static+Clone+Val+Ord> Exp for LessThanExp<T>{
+    type Output = BoolVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box LessThanStagedExp {
+            staged_exp1: Operand::stage(&*self.exp1),
+            staged_exp2: Operand::stage(&*self.exp2),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        Self::Output {
+            v: self.exp1.interpret() < self.exp2.interpret()
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("lt", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let operand1 = CompiledOperand::compile(&*self.exp1);
+        let operand2 = CompiledOperand::compile(&*self.exp2);
+        box move || BoolVal { v: operand1.get() < operand2.get() }
+    }
+}
+
+impl<T: 'static+Clone+Val+Ord> StagedExp for LessThanStagedExp<T>{
+    type Output = BoolVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        Self::Output {
+            v: self.staged_exp1.run(ctx) < self.staged_exp2.run(ctx)
+        }
+    }
+}
+
+// For values without a total order, like floats: unordered operands (NaN)
+// compare as false.
+#[derive(Clone)]
+struct PartialLessThanExp<T: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Box<Exp<Output=T>>,
+}
+
+struct PartialLessThanStagedExp<T: 'static+Clone> {
+    staged_exp1: Operand<T>,
+    staged_exp2: Operand<T>,
+}
+
+impl<T: 'static+Clone+Val+PartialOrd> Exp for PartialLessThanExp<T>{
+    type Output = BoolVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box PartialLessThanStagedExp {
+            staged_exp1: Operand::stage(&*self.exp1),
+            staged_exp2: Operand::stage(&*self.exp2),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        Self::Output {
+            v: self.exp1.interpret() < self.exp2.interpret()
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("partial_lt", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let operand1 = CompiledOperand::compile(&*self.exp1);
+        let operand2 = CompiledOperand::compile(&*self.exp2);
+        box move || BoolVal { v: operand1.get() < operand2.get() }
+    }
+}
+
+impl<T: 'static+Clone+Val+PartialOrd> StagedExp for PartialLessThanStagedExp<T>{
+    type Output = BoolVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        Self::Output {
+            v: self.staged_exp1.run(ctx) < self.staged_exp2.run(ctx)
+        }
+    }
+}
+
+#[derive(Clone)]
+struct LetExp<T: 'static+Clone, U: 'static+Clone> {
+    exp1: Box<Exp<Output=T>>,
+    exp2: Rc<Fn(VariableExp<T>) -> Box<Exp<Output=U>>>
+}
+
+struct LetStagedExp<T: 'static+Clone, U: 'static+Clone> {
+    staged_exp1: Box<StagedExp<Output=T>>,
+    exp1_slot: Slot<T>,
+    staged_exp2: Box<StagedExp<Output=U>>,
+}
+
+impl<T: 'static+Clone+Default, U: 'static+Clone> Exp for LetExp<T,U>{
+    type Output = U;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_exp1 = self.exp1.stage();
+        let exp1_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        let exp1_slot = scope.bind(&exp1_var);
+        box LetStagedExp {
+            staged_exp1,
+            exp1_slot,
+            staged_exp2: (self.exp2)(exp1_var).stage(),
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        let exp1_var = VariableExp::fresh_with_val(self.exp1.interpret());
+        (self.exp2)(exp1_var).interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let exp1_var = VariableExp::<T>::fresh();
+        let body = (self.exp2)(exp1_var.clone()).reify();
+        binder("let", vec![exp1_var.id], vec![self.exp1.reify(), body])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let exp1_var = VariableExp::fresh();
+        let compiled_exp2 = (self.exp2)(exp1_var.clone()).stage_compiled();
+        let compiled_exp1 = self.exp1.stage_compiled();
+        box move || {
+            exp1_var.assign(compiled_exp1());
+            compiled_exp2()
+        }
+    }
+
+    fn stage_tail(&self, fn_id: i32) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_exp1 = self.exp1.stage();
+        let exp1_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        let exp1_slot = scope.bind(&exp1_var);
+        box LetStagedExp {
+            staged_exp1,
+            exp1_slot,
+            staged_exp2: (self.exp2)(exp1_var).stage_tail(fn_id),
+        }
+    }
 
-trait Val {
-    type Output;
+    fn interpret_tail(&self, fn_id: i32) -> Self::Output {
+        let exp1_var = VariableExp::fresh_with_val(self.exp1.interpret());
+        (self.exp2)(exp1_var).interpret_tail(fn_id)
+    }
+}
 
-    fn get(&self) -> Self::Output;
+impl<T: 'static+Clone, U: 'static+Clone> StagedExp for LetStagedExp<T,U>{
+    type Output = U;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut binding = ctx.bind(&self.exp1_slot);
+        binding.set(self.staged_exp1.run(ctx));
+        self.staged_exp2.run(ctx)
+    }
 }
 
-#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Default)]
-struct NumVal {
-    v: i64,
+#[derive(Clone)]
+struct IfExp<T: 'static+Clone> {
+    cond_exp: Box<Exp<Output=BoolVal>>,
+    then_exp: Box<Exp<Output=T>>,
+    else_exp: Box<Exp<Output=T>>,
 }
 
-impl Val for NumVal {
-    type Output = i64;
+struct IfStagedExp<T: 'static+Clone> {
+    staged_cond_exp: Box<StagedExp<Output=BoolVal>>,
+    staged_then_exp: Box<StagedExp<Output=T>>,
+    staged_else_exp: Box<StagedExp<Output=T>>,
+}
 
-    fn get(&self) -> Self::Output {
-        self.v
+impl<T: 'static+Clone> Exp for IfExp<T>{
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box IfStagedExp {
+            staged_cond_exp: self.cond_exp.stage(),
+            staged_then_exp: self.then_exp.stage(),
+            staged_else_exp: self.else_exp.stage(),
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        if self.cond_exp.interpret().v {
+            self.then_exp.interpret()
+        } else {
+            self.else_exp.interpret()
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("if", vec![self.cond_exp.reify(), self.then_exp.reify(), self.else_exp.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_cond_exp = self.cond_exp.stage_compiled();
+        let compiled_then_exp = self.then_exp.stage_compiled();
+        let compiled_else_exp = self.else_exp.stage_compiled();
+        box move || if compiled_cond_exp().v { compiled_then_exp() } else { compiled_else_exp() }
+    }
+
+    fn stage_tail(&self, fn_id: i32) -> Box<StagedExp<Output=Self::Output>> {
+        box IfStagedExp {
+            staged_cond_exp: self.cond_exp.stage(),
+            staged_then_exp: self.then_exp.stage_tail(fn_id),
+            staged_else_exp: self.else_exp.stage_tail(fn_id),
+        }
+    }
+
+    fn interpret_tail(&self, fn_id: i32) -> Self::Output {
+        if self.cond_exp.interpret().v {
+            self.then_exp.interpret_tail(fn_id)
+        } else {
+            self.else_exp.interpret_tail(fn_id)
+        }
     }
 }
 
-impl std::ops::Add for NumVal {
-    type Output = Self;
-    fn add(self, rhs: Self) -> Self::Output {
-        Self {
-            v: self.v + rhs.v
+impl<T: 'static+Clone> StagedExp for IfStagedExp<T>{
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        if self.staged_cond_exp.run(ctx).v {
+            self.staged_then_exp.run(ctx)
+        } else {
+            self.staged_else_exp.run(ctx)
         }
     }
 }
 
-#[derive(Debug,Clone, Eq, Ord, PartialOrd, PartialEq, Default)]
-struct BoolVal {
-    v: bool,
+#[derive(Clone)]
+struct SetExp<T: 'static+Clone> {
+    var: VariableExp<T>,
+    exp: Box<Exp<Output=T>>,
 }
 
-impl Val for BoolVal {
-    type Output = bool;
+// Staged as one of these, by where the variable is found, as a read is.
+struct SetStagedExp<T: 'static+Clone> {
+    var: VariableExp<T>,
+    staged_exp: Box<StagedExp<Output=T>>,
+}
 
-    fn get(&self) -> Self::Output {
-        self.v
-    }
+struct SetSlotStagedExp<T: 'static+Clone> {
+    slot: Slot<T>,
+    staged_exp: Box<StagedExp<Output=T>>,
 }
 
-trait Exp {
-    type Output;
+impl<T: 'static+Clone> Exp for SetExp<T>{
+    type Output = UnitVal;
 
-    fn stage(&self) -> Box<StagedExp<Output=Self::Output>>;
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_exp = self.exp.stage();
+        match Place::of(&self.var) {
+            Place::Host(var) => box SetStagedExp { var, staged_exp },
+            Place::Slot(slot) => box SetSlotStagedExp { slot, staged_exp },
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.var.assign(self.exp.interpret());
+        UnitVal
+    }
 
-    fn interpret(&self) -> Self::Output;
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("set", vec![self.var.reify(), self.exp.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let var = self.var.clone();
+        let compiled_exp = self.exp.stage_compiled();
+        box move || {
+            var.assign(compiled_exp());
+            UnitVal
+        }
+    }
 }
 
-trait StagedExp {
-    type Output;
+impl<T: 'static+Clone> StagedExp for SetStagedExp<T>{
+    type Output = UnitVal;
 
-    fn run(&self) -> Self::Output;
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.var.assign(self.staged_exp.run(ctx));
+        UnitVal
+    }
 }
 
-struct ConstantExp<T: 'static+Clone> {
-    const_val: T,
+impl<T: 'static+Clone> StagedExp for SetSlotStagedExp<T>{
+    type Output = UnitVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        ctx.set(&self.slot, self.staged_exp.run(ctx));
+        UnitVal
+    }
 }
 
-struct ConstantStagedExp<T: 'static+Clone> {
-    const_val: T,
+#[derive(Clone)]
+struct WhileExp {
+    cond_exp: Box<Exp<Output=BoolVal>>,
+    body_exp: Box<Exp<Output=UnitVal>>,
 }
 
-impl<T: 'static+Clone> Exp for ConstantExp<T>{
-    type Output = T;
+struct WhileStagedExp {
+    staged_cond_exp: Box<StagedExp<Output=BoolVal>>,
+    staged_body_exp: Box<StagedExp<Output=UnitVal>>,
+}
+
+impl Exp for WhileExp{
+    type Output = UnitVal;
 
     fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
-        box ConstantStagedExp {
-            const_val: self.const_val.clone()
+        box WhileStagedExp {
+            staged_cond_exp: self.cond_exp.stage(),
+            staged_body_exp: self.body_exp.stage(),
         }
     }
     fn interpret(&self) -> Self::Output {
-        self.const_val.clone()
+        while self.cond_exp.interpret().v {
+            sandbox::step();
+            self.body_exp.interpret();
+        }
+        UnitVal
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("while", vec![self.cond_exp.reify(), self.body_exp.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_cond_exp = self.cond_exp.stage_compiled();
+        let compiled_body_exp = self.body_exp.stage_compiled();
+        box move || {
+            while compiled_cond_exp().v {
+                sandbox::step();
+                compiled_body_exp();
+            }
+            UnitVal
+        }
     }
 }
 
-impl<T: 'static+Clone> StagedExp for ConstantStagedExp<T>{
-    type Output = T;
+impl StagedExp for WhileStagedExp{
+    type Output = UnitVal;
 
-    fn run(&self) -> Self::Output {
-        self.const_val.clone()
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        while self.staged_cond_exp.run(ctx).v {
+            sandbox::step();
+            self.staged_body_exp.run(ctx);
+        }
+        UnitVal
     }
 }
 
-static mut var_counter: i32 = 0;
+// Counts from `start` towards `end` (exclusive) by `step`, which defaults to
+// 1 and may be negative. The bounds and step are evaluated once, before the
+// first iteration. The index is taken from an internal counter each time
+// round, so assigning to it in the body doesn't change the iteration.
+#[derive(Clone)]
+struct ForExp {
+    start_exp: Box<Exp<Output=NumVal>>,
+    end_exp: Box<Exp<Output=NumVal>>,
+    step_exp: Option<Box<Exp<Output=NumVal>>>,
+    body_exp: Rc<Fn(VariableExp<NumVal>) -> Box<Exp<Output=UnitVal>>>,
+}
 
-#[derive(Debug,Clone)]
-struct VariableExp<T: 'static+Clone> {
-    id: i32,
-    var_val: Rc<RefCell<T>>,
+struct ForStagedExp {
+    staged_start_exp: Box<StagedExp<Output=NumVal>>,
+    staged_end_exp: Box<StagedExp<Output=NumVal>>,
+    staged_step_exp: Option<Box<StagedExp<Output=NumVal>>>,
+    index_slot: Slot<NumVal>,
+    staged_body_exp: Box<StagedExp<Output=UnitVal>>,
 }
 
-impl<T: 'static+Clone+Default> VariableExp<T> {
-    fn fresh() -> VariableExp<T> {
-        VariableExp {
-            id: {
-                unsafe{
-                    var_counter += 1;
-                    var_counter
-                }
-            },
-            var_val: Rc::new(RefCell::new(T::default())),
-        }
+fn for_range(start: i64, end: i64, step: i64, f: &mut FnMut(i64)) {
+    assert!(step != 0, "for loop step must be non-zero");
+    let mut i = start;
+    while (step > 0 && i < end) || (step < 0 && i > end) {
+        sandbox::step();
+        f(i);
+        i = match i.checked_add(step) {
+            Some(next) => next,
+            None => break,
+        };
     }
 }
 
-impl<T: 'static+Clone> VariableExp<T> {
-    fn fresh_with_val(v: T) -> VariableExp<T> {
-        VariableExp {
-            id: {
-                unsafe{
-                    var_counter += 1;
-                    var_counter
-                }
-            },
-            var_val: Rc::new(RefCell::new(v)),
-        }
+fn compile_for(compiled_start_exp: Compiled<NumVal>, compiled_end_exp: Compiled<NumVal>,
+               compiled_step_exp: Option<Compiled<NumVal>>, index_var: VariableExp<NumVal>,
+               compiled_body_exp: Compiled<UnitVal>) -> Compiled<UnitVal> {
+    box move || {
+        let start = compiled_start_exp().v;
+        let end = compiled_end_exp().v;
+        let step = compiled_step_exp.as_ref().map_or(1, |e| e().v);
+        for_range(start, end, step, &mut |i| {
+            index_var.assign(NumVal { v: i });
+            compiled_body_exp();
+        });
+        UnitVal
     }
 }
 
-impl<T: 'static+Clone> Exp for VariableExp<T>{
-    type Output = T;
+fn reify_for(start: Expr, end: Expr, step: Option<Expr>, index_id: i32, body: Expr) -> Expr {
+    match step {
+        Some(step) => binder("for_step", vec![index_id], vec![start, end, step, body]),
+        None => binder("for", vec![index_id], vec![start, end, body]),
+    }
+}
+
+impl Exp for ForExp{
+    type Output = UnitVal;
 
     fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
-        box self.clone()
+        let staged_start_exp = self.start_exp.stage();
+        let staged_end_exp = self.end_exp.stage();
+        let staged_step_exp = self.step_exp.as_ref().map(|e| e.stage());
+        let index_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        let index_slot = scope.bind(&index_var);
+        box ForStagedExp {
+            staged_start_exp,
+            staged_end_exp,
+            staged_step_exp,
+            index_slot,
+            staged_body_exp: (self.body_exp)(index_var).stage(),
+        }
     }
     fn interpret(&self) -> Self::Output {
-        self.var_val.borrow().clone()
+        let start = self.start_exp.interpret().v;
+        let end = self.end_exp.interpret().v;
+        let step = self.step_exp.as_ref().map_or(1, |e| e.interpret().v);
+        for_range(start, end, step, &mut |i| {
+            (self.body_exp)(VariableExp::fresh_with_val(NumVal { v: i })).interpret();
+        });
+        UnitVal
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let index_var = VariableExp::fresh();
+        let body = (self.body_exp)(index_var.clone()).reify();
+        reify_for(self.start_exp.reify(), self.end_exp.reify(), self.step_exp.as_ref().map(|e| e.reify()),
+                  index_var.id, body)
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let index_var = VariableExp::fresh();
+        let compiled_body_exp = (self.body_exp)(index_var.clone()).stage_compiled();
+        compile_for(self.start_exp.stage_compiled(), self.end_exp.stage_compiled(),
+                    self.step_exp.as_ref().map(|e| e.stage_compiled()), index_var, compiled_body_exp)
     }
 }
 
-impl<T: 'static+Clone> StagedExp for VariableExp<T>{
-    type Output = T;
+impl StagedExp for ForStagedExp{
+    type Output = UnitVal;
 
-    fn run(&self) -> Self::Output {
-        self.var_val.borrow().clone()
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let start = self.staged_start_exp.run(ctx).v;
+        let end = self.staged_end_exp.run(ctx).v;
+        let step = self.staged_step_exp.as_ref().map_or(1, |e| e.run(ctx).v);
+        let mut index = ctx.bind(&self.index_slot);
+        for_range(start, end, step, &mut |i| {
+            index.set(NumVal { v: i });
+            self.staged_body_exp.run(ctx);
+        });
+        UnitVal
     }
 }
 
-struct AddExp {
-    exp1: Box<Exp<Output=NumVal>>,
-    exp2: Box<Exp<Output=NumVal>>,
+#[derive(Clone)]
+struct ForEachExp<C: 'static+Clone+Iterable> {
+    coll_exp: Box<Exp<Output=C>>,
+    body_exp: Rc<Fn(VariableExp<C::Elem>) -> Box<Exp<Output=UnitVal>>>,
 }
 
-struct AddStagedExp {
-    staged_exp1: Box<StagedExp<Output=NumVal>>,
-    staged_exp2: Box<StagedExp<Output=NumVal>>,
+struct ForEachStagedExp<C: 'static+Clone+Iterable> {
+    staged_coll_exp: Box<StagedExp<Output=C>>,
+    elem_slot: Slot<C::Elem>,
+    staged_body_exp: Box<StagedExp<Output=UnitVal>>,
 }
 
-impl Exp for AddExp{
-    type Output = NumVal;
+impl<C: 'static+Clone+Iterable> Exp for ForEachExp<C>{
+    type Output = UnitVal;
 
     fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
-        box AddStagedExp {
-            staged_exp1: self.exp1.stage(),
-            staged_exp2: self.exp2.stage(),
+        let staged_coll_exp = self.coll_exp.stage();
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        let elem_slot = scope.bind(&elem_var);
+        box ForEachStagedExp {
+            staged_coll_exp,
+            elem_slot,
+            staged_body_exp: (self.body_exp)(elem_var).stage(),
         }
     }
     fn interpret(&self) -> Self::Output {
-        self.exp1.interpret() + self.exp2.interpret()
+        // Not borrowed: the body may assign the variable holding the
+        // collection.
+        self.coll_exp.interpret().each(&mut |x| {
+            (self.body_exp)(VariableExp::fresh_with_val(x)).interpret();
+        });
+        UnitVal
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let elem_var = VariableExp::fresh();
+        let body = (self.body_exp)(elem_var.clone()).reify();
+        binder("for_each", vec![elem_var.id], vec![self.coll_exp.reify(), body])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let elem_var = VariableExp::fresh();
+        let compiled_body_exp = (self.body_exp)(elem_var.clone()).stage_compiled();
+        let compiled_coll_exp = self.coll_exp.stage_compiled();
+        box move || {
+            compiled_coll_exp().each(&mut |x| {
+                elem_var.assign(x);
+                compiled_body_exp();
+            });
+            UnitVal
+        }
     }
 }
 
-impl StagedExp for AddStagedExp{
-    type Output = NumVal;
+impl<C: 'static+Clone+Iterable> StagedExp for ForEachStagedExp<C>{
+    type Output = UnitVal;
 
-    fn run(&self) -> Self::Output {
-        self.staged_exp1.run() + self.staged_exp2.run()
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        // Not borrowed: the body may assign the variable holding the
+        // collection.
+        let coll = self.staged_coll_exp.run(ctx);
+        let mut elem = ctx.bind(&self.elem_slot);
+        coll.each(&mut |x| {
+            elem.set(x);
+            self.staged_body_exp.run(ctx);
+        });
+        UnitVal
     }
 }
 
-struct LessThanExp {
-    exp1: Box<Exp<Output=NumVal>>,
-    exp2: Box<Exp<Output=NumVal>>,
+#[derive(Clone)]
+struct RangeExp {
+    start_exp: Box<Exp<Output=NumVal>>,
+    end_exp: Box<Exp<Output=NumVal>>,
+    step_exp: Option<Box<Exp<Output=NumVal>>>,
 }
 
-struct LessThanStagedExp {
-    staged_exp1: Box<StagedExp<Output=NumVal>>,
-    staged_exp2: Box<StagedExp<Output=NumVal>>,
+struct RangeStagedExp {
+    staged_start_exp: Box<StagedExp<Output=NumVal>>,
+    staged_end_exp: Box<StagedExp<Output=NumVal>>,
+    staged_step_exp: Option<Box<StagedExp<Output=NumVal>>>,
 }
 
-impl Exp for LessThanExp{
-    type Output = BoolVal;
+impl Exp for RangeExp{
+    type Output = RangeVal;
 
     fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
-        box LessThanStagedExp {
-            staged_exp1: self.exp1.stage(),
-            staged_exp2: self.exp2.stage(),
+        box RangeStagedExp {
+            staged_start_exp: self.start_exp.stage(),
+            staged_end_exp: self.end_exp.stage(),
+            staged_step_exp: self.step_exp.as_ref().map(|e| e.stage()),
         }
     }
     fn interpret(&self) -> Self::Output {
-        Self::Output {
-            v: self.exp1.interpret() < self.exp2.interpret()
+        RangeVal {
+            start: self.start_exp.interpret().v,
+            end: self.end_exp.interpret().v,
+            step: self.step_exp.as_ref().map_or(1, |e| e.interpret().v),
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        match self.step_exp {
+            Some(ref step) => node("range_step", vec![self.start_exp.reify(), self.end_exp.reify(), step.reify()]),
+            None => node("range", vec![self.start_exp.reify(), self.end_exp.reify()]),
+        }
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_start_exp = self.start_exp.stage_compiled();
+        let compiled_end_exp = self.end_exp.stage_compiled();
+        let compiled_step_exp = self.step_exp.as_ref().map(|e| e.stage_compiled());
+        box move || RangeVal {
+            start: compiled_start_exp().v,
+            end: compiled_end_exp().v,
+            step: compiled_step_exp.as_ref().map_or(1, |e| e().v),
         }
     }
 }
 
-impl StagedExp for LessThanStagedExp{
-    type Output = BoolVal;
+impl StagedExp for RangeStagedExp{
+    type Output = RangeVal;
 
-    fn run(&self) -> Self::Output {
-        Self::Output {
-            v: self.staged_exp1.run() < self.staged_exp2.run()
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        RangeVal {
+            start: self.staged_start_exp.run(ctx).v,
+            end: self.staged_end_exp.run(ctx).v,
+            step: self.staged_step_exp.as_ref().map_or(1, |e| e.run(ctx).v),
         }
     }
 }
 
-struct LetExp<T: 'static+Clone, U: 'static+Clone> {
+#[derive(Clone)]
+struct SeqExp<T: 'static+Clone, U: 'static+Clone> {
     exp1: Box<Exp<Output=T>>,
-    exp2: Box<Fn(VariableExp<T>) -> Box<Exp<Output=U>>>
+    exp2: Box<Exp<Output=U>>,
 }
 
-struct LetStagedExp<T: 'static+Clone, U: 'static+Clone> {
+struct SeqStagedExp<T: 'static+Clone, U: 'static+Clone> {
     staged_exp1: Box<StagedExp<Output=T>>,
-    staged_exp1_var: VariableExp<T>,
     staged_exp2: Box<StagedExp<Output=U>>,
 }
 
-impl<T: 'static+Clone+Default, U: 'static+Clone> Exp for LetExp<T,U>{
+impl<T: 'static+Clone, U: 'static+Clone> Exp for SeqExp<T,U>{
     type Output = U;
 
     fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
-        let exp1_var = VariableExp::fresh();
-        let staged_exp2 = (self.exp2)(exp1_var.clone()).stage();
-        box LetStagedExp {
+        box SeqStagedExp {
             staged_exp1: self.exp1.stage(),
-            staged_exp1_var: exp1_var,
-            staged_exp2,
+            staged_exp2: self.exp2.stage(),
         }
     }
-
     fn interpret(&self) -> Self::Output {
-        let exp1_var = VariableExp::fresh_with_val(self.exp1.interpret());
-        (self.exp2)(exp1_var).interpret()
+        self.exp1.interpret();
+        self.exp2.interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("seq", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_exp1 = self.exp1.stage_compiled();
+        let compiled_exp2 = self.exp2.stage_compiled();
+        box move || {
+            compiled_exp1();
+            compiled_exp2()
+        }
+    }
+
+    fn stage_tail(&self, fn_id: i32) -> Box<StagedExp<Output=Self::Output>> {
+        box SeqStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage_tail(fn_id),
+        }
+    }
+
+    fn interpret_tail(&self, fn_id: i32) -> Self::Output {
+        self.exp1.interpret();
+        self.exp2.interpret_tail(fn_id)
     }
 }
 
-impl<T: 'static+Clone, U: 'static+Clone> StagedExp for LetStagedExp<T,U>{
+impl<T: 'static+Clone, U: 'static+Clone> StagedExp for SeqStagedExp<T,U>{
     type Output = U;
 
-    fn run(&self) -> Self::Output {
-        self.staged_exp1_var.var_val.replace( self.staged_exp1.run() );
-        self.staged_exp2.run()
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_exp1.run(ctx);
+        self.staged_exp2.run(ctx)
     }
 }
 
 fn unit_exp<T: 'static+Clone>(const_val: T) -> ConstantExp<T> {
     ConstantExp {
-        const_val
+        const_val: Rc::new(const_val)
     }
 }
 
-fn add_exp(exp1: Box<Exp<Output=NumVal>>, exp2: Box<Exp<Output=NumVal>>) -> AddExp {
+fn add_exp<T: 'static+Clone+Val+std::ops::Add<Output=T>>(exp1: Box<Exp<Output=T>>,
+                                                         exp2: Box<Exp<Output=T>>) -> AddExp<T> {
     AddExp {
         exp1,
         exp2
     }
 }
 
+fn sub_exp<T: 'static+Clone+Val+std::ops::Sub<Output=T>>(exp1: Box<Exp<Output=T>>,
+                                                         exp2: Box<Exp<Output=T>>) -> SubExp<T> {
+    SubExp {
+        exp1,
+        exp2
+    }
+}
+
+fn mul_exp<T: 'static+Clone+Val+std::ops::Mul<Output=T>>(exp1: Box<Exp<Output=T>>,
+                                                         exp2: Box<Exp<Output=T>>) -> MulExp<T> {
+    MulExp {
+        exp1,
+        exp2
+    }
+}
+
+fn div_exp<T: 'static+Clone+Val+std::ops::Div<Output=T>>(exp1: Box<Exp<Output=T>>,
+                                                         exp2: Box<Exp<Output=T>>) -> DivExp<T> {
+    DivExp {
+        exp1,
+        exp2
+    }
+}
+
+fn pow_exp(exp1: Box<Exp<Output=FloatVal>>, exp2: Box<Exp<Output=FloatVal>>) -> PowExp {
+    PowExp {
+        exp1,
+        exp2
+    }
+}
+
+fn less_than_exp<T: 'static+Clone+Val+Ord>(exp1: Box<Exp<Output=T>>,
+                                           exp2: Box<Exp<Output=T>>) -> LessThanExp<T> {
+    LessThanExp {
+        exp1,
+        exp2
+    }
+}
+
+fn partial_less_than_exp<T: 'static+Clone+Val+PartialOrd>(exp1: Box<Exp<Output=T>>,
+                                                          exp2: Box<Exp<Output=T>>) -> PartialLessThanExp<T> {
+    PartialLessThanExp {
+        exp1,
+        exp2
+    }
+}
+
+fn if_exp<T: 'static+Clone>(cond_exp: Box<Exp<Output=BoolVal>>,
+                            then_exp: Box<Exp<Output=T>>,
+                            else_exp: Box<Exp<Output=T>>) -> IfExp<T> {
+    IfExp {
+        cond_exp,
+        then_exp,
+        else_exp
+    }
+}
+
+fn set_exp<T: 'static+Clone>(var: VariableExp<T>, exp: Box<Exp<Output=T>>) -> SetExp<T> {
+    SetExp {
+        var,
+        exp
+    }
+}
+
+fn while_exp(cond_exp: Box<Exp<Output=BoolVal>>, body_exp: Box<Exp<Output=UnitVal>>) -> WhileExp {
+    WhileExp {
+        cond_exp,
+        body_exp
+    }
+}
+
+fn for_exp(start_exp: Box<Exp<Output=NumVal>>, end_exp: Box<Exp<Output=NumVal>>,
+           body_exp: Box<Fn(VariableExp<NumVal>) -> Box<Exp<Output=UnitVal>>>) -> ForExp {
+    ForExp {
+        start_exp,
+        end_exp,
+        step_exp: None,
+        body_exp: Rc::from(body_exp)
+    }
+}
+
+fn for_step_exp(start_exp: Box<Exp<Output=NumVal>>, end_exp: Box<Exp<Output=NumVal>>,
+                step_exp: Box<Exp<Output=NumVal>>,
+                body_exp: Box<Fn(VariableExp<NumVal>) -> Box<Exp<Output=UnitVal>>>) -> ForExp {
+    ForExp {
+        start_exp,
+        end_exp,
+        step_exp: Some(step_exp),
+        body_exp: Rc::from(body_exp)
+    }
+}
+
+fn for_each_exp<C: 'static+Clone+Iterable>(coll_exp: Box<Exp<Output=C>>,
+                                           body_exp: Box<Fn(VariableExp<C::Elem>) -> Box<Exp<Output=UnitVal>>>) -> ForEachExp<C> {
+    ForEachExp {
+        coll_exp,
+        body_exp: Rc::from(body_exp)
+    }
+}
+
+fn range_exp(start_exp: Box<Exp<Output=NumVal>>, end_exp: Box<Exp<Output=NumVal>>,
+             step_exp: Option<Box<Exp<Output=NumVal>>>) -> RangeExp {
+    RangeExp {
+        start_exp,
+        end_exp,
+        step_exp
+    }
+}
+
+fn seq_exp<T: 'static+Clone, U: 'static+Clone>(exp1: Box<Exp<Output=T>>, exp2: Box<Exp<Output=U>>) -> SeqExp<T,U> {
+    SeqExp {
+        exp1,
+        exp2
+    }
+}
+
 fn let_exp<T: 'static+Clone+Default, U: 'static+Clone>(exp1: Box<Exp<Output=T>>,
                                                        exp2: Box<Fn(VariableExp<T>) -> Box<Exp<Output=U>>>) -> LetExp<T,U> {
     LetExp {
         exp1,
-        exp2
+        exp2: Rc::from(exp2)
     }
 }
 
@@ -313,5 +3069,95 @@ fn main() {
     println!("{:?}", let_nums.interpret());
 
     let staged_expr = let_nums.stage();
-    println!("{:?}", staged_expr.run());
+    println!("{:?}", staged_expr.run(&EvalContext::new()));
+
+    let b = builder::ExpBuilder::new();
+    let count = b.build(b.let_(b.num(1), |b, i| {
+        b.seq(b.while_(b.get(i).lt(1000), b.set(i, b.get(i) + 1)),
+              b.get(i))
+    }));
+
+    println!("{:?}", count.interpret());
+    #[cfg(feature = "json")]
+    println!("{}", json::to_json(&*count));
+
+    // The benchmarks take a few seconds, so they only run when asked for.
+    if std::env::args().skip(1).any(|arg| arg == "--bench") {
+        print!("{}", bench::bench(&let_nums, 100_000));
+        print!("{}", bench::Bench::new(&*count, 1_000).rounds(15).variant("compiled", bench::compiled).run());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staged_for_each_body_assigns_its_collection() {
+        let arr = VariableExp::fresh_with_val(ArrayVal { v: vec![NumVal { v: 1 }, NumVal { v: 2 }, NumVal { v: 3 }] });
+        let sum = VariableExp::fresh_with_val(NumVal { v: 0 });
+        let (a, s) = (arr.clone(), sum.clone());
+        let exp = for_each_exp(box arr.clone(), box move |x| {
+            box seq_exp(box set_exp(a.clone(), box unit_exp(ArrayVal { v: vec![] })),
+                        box set_exp(s.clone(), box add_exp(box s.clone(), box x)))
+        });
+        exp.stage().run(&EvalContext::new());
+        assert_eq!(sum.var_val.borrow().v, 6);
+        assert!(arr.var_val.borrow().v.is_empty());
+    }
+
+    // The value is taken out while `f` runs, so `f` reading the variable
+    // finds it unset, the host's default or the host's value, rather than
+    // panicking on a borrow.
+    #[test]
+    fn update_holds_no_borrow_while_f_runs() {
+        let var = VariableExp::fresh_with_val(NumVal { v: 1 });
+        let ctx = EvalContext::new();
+        let host = Place::of(&var);
+        ctx.update(&host, &mut |v| v.v += ctx.read(&host).v + 10);
+        assert_eq!(var.var_val.borrow().v, 11);
+
+        let scope = SlotScope::enter();
+        let slot = scope.bind(&var);
+        ctx.set_local(&slot, NumVal { v: 5 });
+        let bound = Place::Slot(slot.clone());
+        ctx.update(&bound, &mut |v| v.v += ctx.read(&bound).v + 10);
+        assert_eq!(ctx.get(&slot).v, 26);
+        assert_eq!(var.var_val.borrow().v, 11);
+    }
+
+    // Constant and variable operands are read by the node that has them,
+    // from the place staging found them, rather than through a call.
+    #[test]
+    fn operands_are_read_where_staging_found_them() {
+        let var = VariableExp::fresh_with_val(NumVal { v: 3 });
+        assert!(matches!(Operand::stage(&unit_exp(NumVal { v: 1 })), Operand::Const(NumVal { v: 1 })));
+        assert!(matches!(Operand::stage(&var), Operand::Var(Place::Host(_))));
+        assert!(matches!(Operand::stage(&add_exp(box var.clone(), box unit_exp(NumVal { v: 1 }))),
+                         Operand::Staged(_)));
+
+        let scope = SlotScope::enter();
+        let slot = scope.bind(&var);
+        let sum = add_exp(box var.clone(), box unit_exp(NumVal { v: 1 }));
+        assert!(matches!(Operand::stage(&var), Operand::Var(Place::Slot(ref s)) if s.index == slot.index));
+        let staged = sum.stage();
+        let ctx = EvalContext::new();
+        ctx.set_local(&slot, NumVal { v: 10 });
+        assert_eq!(staged.run(&ctx).v, 11);
+        assert_eq!(sum.interpret().v, 4);
+    }
+
+    #[test]
+    fn compiled_loops_over_ranges_agree_with_interpret() {
+        let sum = VariableExp::fresh_with_val(NumVal { v: 0 });
+        let s = sum.clone();
+        let range = range_exp(box unit_exp(NumVal { v: 10 }), box unit_exp(NumVal { v: 0 }),
+                              Some(box unit_exp(NumVal { v: -3 })));
+        let exp = seq_exp(box for_each_exp(box range, box move |x| {
+            box set_exp(s.clone(), box add_exp(box s.clone(), box mul_exp(box x, box unit_exp(NumVal { v: 2 }))))
+        }), box sum.clone());
+        assert_eq!(exp.interpret().v, 44);
+        sum.assign(NumVal { v: 0 });
+        assert_eq!((exp.stage_compiled())().v, 44);
+    }
 }
@@ -0,0 +1,141 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, FnVal};
+use ops::E;
+use reify::{Expr, Value, node};
+
+// Memoized functions: `memo` wraps a function so that calling it again with
+// an argument it has seen gives back the earlier result without running the
+// body. Only the most recent `capacity` arguments are kept; when it's full,
+// the oldest is dropped to make room.
+//
+// The cache lives for a run: each evaluation of the memo node starts an
+// empty one. Staged with `across_runs`, every run of the staged program
+// shares one cache instead, which is only right if the function gives the
+// same result for the same argument from one run to the next, e.g. it reads
+// no variables.
+//
+// A recursive function sees the cache only if its recursive calls go
+// through the memoized function, so tie the knot through a cell:
+//
+//     let fib = alloc(default);
+//     assign(fib, memo(lambda(|n| ... deref(fib).apply(n - 1) ...)))
+//
+// A function that raises partway leaves nothing in the cache for the call.
+
+struct Memo<A, R> {
+    results: HashMap<A, R>,
+    // The arguments in `results`, oldest first.
+    order: VecDeque<A>,
+    capacity: usize,
+}
+
+impl<A: Clone+Hash+Eq, R: Clone> Memo<A, R> {
+    fn new(capacity: usize) -> Memo<A, R> {
+        Memo {
+            results: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn insert(&mut self, a: A, r: R) {
+        if self.capacity == 0 || self.results.contains_key(&a) {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.results.remove(&oldest);
+            }
+        }
+        self.order.push_back(a.clone());
+        self.results.insert(a, r);
+    }
+}
+
+fn memoize<A, R>(f: FnVal<A, R>, memo: Rc<RefCell<Memo<A, R>>>) -> FnVal<A, R>
+    where A: 'static+Clone+Hash+Eq, R: 'static+Clone {
+    FnVal {
+        f: Rc::new(move |a: A| {
+            if let Some(r) = memo.borrow().results.get(&a) {
+                return r.clone();
+            }
+            // Not borrowed while the body runs, since it may call back in.
+            let r = (f.f)(a.clone());
+            memo.borrow_mut().insert(a, r.clone());
+            r
+        }),
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoExp<A: 'static+Clone, R: 'static+Clone> {
+    f: Box<Exp<Output=FnVal<A, R>>>,
+    capacity: usize,
+    across_runs: bool,
+}
+
+pub struct MemoStagedExp<A: 'static+Clone, R: 'static+Clone> {
+    staged_f: Box<StagedExp<Output=FnVal<A, R>>>,
+    capacity: usize,
+    // The cache every run shares, when it's kept across runs.
+    shared: Option<Rc<RefCell<Memo<A, R>>>>,
+}
+
+impl<A: 'static+Clone+Hash+Eq, R: 'static+Clone> Exp for MemoExp<A, R> {
+    type Output = FnVal<A, R>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box MemoStagedExp {
+            staged_f: self.f.stage(),
+            capacity: self.capacity,
+            shared: if self.across_runs { Some(Rc::new(RefCell::new(Memo::new(self.capacity)))) } else { None },
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        memoize(self.f.interpret(), Rc::new(RefCell::new(Memo::new(self.capacity))))
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("memo", vec![self.f.reify(), Expr::Const(Value::Num(self.capacity as i64)),
+                          Expr::Const(Value::Bool(self.across_runs))])
+    }
+}
+
+impl<A: 'static+Clone+Hash+Eq, R: 'static+Clone> StagedExp for MemoStagedExp<A, R> {
+    type Output = FnVal<A, R>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let memo = match self.shared {
+            Some(ref memo) => memo.clone(),
+            None => Rc::new(RefCell::new(Memo::new(self.capacity))),
+        };
+        memoize(self.staged_f.run(ctx), memo)
+    }
+}
+
+pub fn memo_exp<A: 'static+Clone+Hash+Eq, R: 'static+Clone>(f: Box<Exp<Output=FnVal<A, R>>>, capacity: usize,
+                                                           across_runs: bool) -> MemoExp<A, R> {
+    MemoExp {
+        f,
+        capacity,
+        across_runs,
+    }
+}
+
+impl<A: 'static+Clone+Hash+Eq, R: 'static+Clone> E<FnVal<A, R>> {
+    pub fn memo(self, capacity: usize) -> E<FnVal<A, R>> {
+        E::new(memo_exp(self.0, capacity, false))
+    }
+
+    pub fn memo_across_runs(self, capacity: usize) -> E<FnVal<A, R>> {
+        E::new(memo_exp(self.0, capacity, true))
+    }
+}
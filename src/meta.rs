@@ -0,0 +1,441 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, SlotScope, Compiled, ConstantExp, ProgVal, CodeVal, unit_exp};
+use ops::E;
+use reify::Expr;
+
+// A program shared between several places in a larger one, e.g. a generated
+// fragment spliced into two branches.
+pub struct SharedExp<T: 'static>(pub Rc<Exp<Output=T>>);
+
+impl<T: 'static> Clone for SharedExp<T> {
+    fn clone(&self) -> Self {
+        SharedExp(self.0.clone())
+    }
+}
+
+impl<T: 'static> Exp for SharedExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        self.0.stage()
+    }
+
+    fn interpret(&self) -> Self::Output {
+        self.0.interpret()
+    }
+
+    fn interpret_with(&self, f: &mut FnMut(&Self::Output)) {
+        self.0.interpret_with(f)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        self.0.reify()
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        self.0.stage_compiled()
+    }
+
+    fn stage_tail(&self, fn_id: i32) -> Box<StagedExp<Output=Self::Output>> {
+        self.0.stage_tail(fn_id)
+    }
+
+    fn interpret_tail(&self, fn_id: i32) -> Self::Output {
+        self.0.interpret_tail(fn_id)
+    }
+}
+
+impl<T: 'static> ProgVal<T> {
+    pub fn new(exp: Box<Exp<Output=T>>) -> ProgVal<T> {
+        ProgVal {
+            v: Rc::from(exp),
+        }
+    }
+
+    pub fn stage(&self) -> Box<StagedExp<Output=T>> {
+        self.v.stage()
+    }
+
+    pub fn interpret(&self) -> T {
+        self.v.interpret()
+    }
+}
+
+// A program literal: `exp` itself, not its value.
+pub fn prog_exp<T: 'static>(exp: Box<Exp<Output=T>>) -> ConstantExp<ProgVal<T>> {
+    unit_exp(ProgVal::new(exp))
+}
+
+// Evaluates `exp` now and produces a program returning that value, carrying
+// it over into the next stage.
+#[derive(Clone)]
+pub struct LiftExp<T: 'static+Clone> {
+    exp: Box<Exp<Output=T>>,
+}
+
+pub struct LiftStagedExp<T: 'static+Clone> {
+    staged_exp: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone> Exp for LiftExp<T> {
+    type Output = ProgVal<T>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box LiftStagedExp {
+            staged_exp: self.exp.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        ProgVal::new(box unit_exp(self.exp.interpret()))
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for LiftStagedExp<T> {
+    type Output = ProgVal<T>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        ProgVal::new(box unit_exp(self.staged_exp.run(ctx)))
+    }
+}
+
+// Builds a program out of two generated ones; `f` gets them as expressions
+// and combines them with any of the ordinary nodes.
+pub struct ProgZipExp<A: 'static, B: 'static, R: 'static> {
+    prog1: Box<Exp<Output=ProgVal<A>>>,
+    prog2: Box<Exp<Output=ProgVal<B>>>,
+    f: Rc<Fn(Box<Exp<Output=A>>, Box<Exp<Output=B>>) -> Box<Exp<Output=R>>>,
+}
+
+impl<A: 'static, B: 'static, R: 'static> Clone for ProgZipExp<A, B, R> {
+    fn clone(&self) -> Self {
+        ProgZipExp {
+            prog1: self.prog1.clone(),
+            prog2: self.prog2.clone(),
+            f: self.f.clone(),
+        }
+    }
+}
+
+pub struct ProgZipStagedExp<A: 'static, B: 'static, R: 'static> {
+    staged_prog1: Box<StagedExp<Output=ProgVal<A>>>,
+    staged_prog2: Box<StagedExp<Output=ProgVal<B>>>,
+    f: Rc<Fn(Box<Exp<Output=A>>, Box<Exp<Output=B>>) -> Box<Exp<Output=R>>>,
+}
+
+fn zip<A: 'static, B: 'static, R: 'static>(f: &Fn(Box<Exp<Output=A>>, Box<Exp<Output=B>>) -> Box<Exp<Output=R>>,
+                                           prog1: ProgVal<A>, prog2: ProgVal<B>) -> ProgVal<R> {
+    ProgVal::new(f(box SharedExp(prog1.v), box SharedExp(prog2.v)))
+}
+
+impl<A: 'static, B: 'static, R: 'static> Exp for ProgZipExp<A, B, R> {
+    type Output = ProgVal<R>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ProgZipStagedExp {
+            staged_prog1: self.prog1.stage(),
+            staged_prog2: self.prog2.stage(),
+            f: self.f.clone(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let prog1 = self.prog1.interpret();
+        zip(&*self.f, prog1, self.prog2.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl<A: 'static, B: 'static, R: 'static> StagedExp for ProgZipStagedExp<A, B, R> {
+    type Output = ProgVal<R>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let prog1 = self.staged_prog1.run(ctx);
+        zip(&*self.f, prog1, self.staged_prog2.run(ctx))
+    }
+}
+
+// Runs a generated program: the next stage. The staged form stages what it
+// is given and keeps the result, so a program that comes back unchanged on
+// the next run is not staged again.
+pub struct RunProgExp<T: 'static> {
+    prog: Box<Exp<Output=ProgVal<T>>>,
+}
+
+impl<T: 'static> Clone for RunProgExp<T> {
+    fn clone(&self) -> Self {
+        RunProgExp {
+            prog: self.prog.clone(),
+        }
+    }
+}
+
+pub struct RunProgStagedExp<T: 'static> {
+    staged_prog: Box<StagedExp<Output=ProgVal<T>>>,
+    // The variables in scope here, which programs are staged in.
+    scope: Rc<Vec<i32>>,
+    last: RefCell<Option<(ProgVal<T>, Rc<StagedExp<Output=T>>)>>,
+}
+
+impl<T: 'static> Exp for RunProgExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box RunProgStagedExp {
+            staged_prog: self.prog.stage(),
+            scope: SlotScope::current(),
+            last: RefCell::new(None),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.prog.interpret().interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl<T: 'static> StagedExp for RunProgStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let prog = self.staged_prog.run(ctx);
+        let cached = match *self.last.borrow() {
+            Some((ref last, ref staged)) if Rc::ptr_eq(&last.v, &prog.v) => Some(staged.clone()),
+            _ => None,
+        };
+        let staged = match cached {
+            Some(staged) => staged,
+            None => {
+                let staged: Rc<StagedExp<Output=T>> = Rc::from(SlotScope::resume(&self.scope, || ctx.lend(|| prog.stage())));
+                self.last.replace(Some((prog, staged.clone())));
+                staged
+            }
+        };
+        staged.run(ctx)
+    }
+}
+
+pub struct SharedStagedExp<T: 'static>(pub Rc<StagedExp<Output=T>>);
+
+impl<T: 'static> StagedExp for SharedStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.0.run(ctx)
+    }
+
+    fn run_with(&self, ctx: &EvalContext, f: &mut FnMut(&Self::Output)) {
+        self.0.run_with(ctx, f)
+    }
+}
+
+// Stages `body` each time the quote is evaluated, and produces the staged
+// program as a value. Splices in `body` are evaluated at that point, so the
+// code they produce is built into the result.
+pub struct QuoteExp<T: 'static> {
+    body: Rc<Exp<Output=T>>,
+}
+
+impl<T: 'static> Clone for QuoteExp<T> {
+    fn clone(&self) -> Self {
+        QuoteExp {
+            body: self.body.clone(),
+        }
+    }
+}
+
+pub struct QuoteStagedExp<T: 'static> {
+    body: Rc<Exp<Output=T>>,
+    // The variables in scope at the quote, which the body is staged in.
+    scope: Rc<Vec<i32>>,
+}
+
+impl<T: 'static> Exp for QuoteExp<T> {
+    type Output = CodeVal<T>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box QuoteStagedExp {
+            body: self.body.clone(),
+            scope: SlotScope::current(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        CodeVal {
+            v: Rc::from(self.body.stage())
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl<T: 'static> StagedExp for QuoteStagedExp<T> {
+    type Output = CodeVal<T>;
+
+    // Staging evaluates the body's splices, which read the run's variables
+    // from their cells.
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        CodeVal {
+            v: Rc::from(SlotScope::resume(&self.scope, || ctx.lend(|| self.body.stage())))
+        }
+    }
+}
+
+// Inserts the code computed by `code` in place of this node. The code is
+// computed when this node is staged, i.e. when the enclosing quote is
+// evaluated, or when the whole program is staged if there is no quote.
+pub struct SpliceExp<T: 'static> {
+    code: Box<Exp<Output=CodeVal<T>>>,
+}
+
+impl<T: 'static> Clone for SpliceExp<T> {
+    fn clone(&self) -> Self {
+        SpliceExp {
+            code: self.code.clone(),
+        }
+    }
+}
+
+impl<T: 'static> Exp for SpliceExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box SharedStagedExp(self.code.interpret().v)
+    }
+    fn interpret(&self) -> Self::Output {
+        self.code.interpret().v.run(&EvalContext::new())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+// Runs a code value.
+pub struct RunCodeExp<T: 'static> {
+    code: Box<Exp<Output=CodeVal<T>>>,
+}
+
+impl<T: 'static> Clone for RunCodeExp<T> {
+    fn clone(&self) -> Self {
+        RunCodeExp {
+            code: self.code.clone(),
+        }
+    }
+}
+
+pub struct RunCodeStagedExp<T: 'static> {
+    staged_code: Box<StagedExp<Output=CodeVal<T>>>,
+}
+
+impl<T: 'static> Exp for RunCodeExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box RunCodeStagedExp {
+            staged_code: self.code.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.code.interpret().v.run(&EvalContext::new())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl<T: 'static> StagedExp for RunCodeStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_code.run(ctx).v.run(ctx)
+    }
+}
+
+pub fn lift_exp<T: 'static+Clone>(exp: Box<Exp<Output=T>>) -> LiftExp<T> {
+    LiftExp {
+        exp
+    }
+}
+
+pub fn prog_zip_exp<A: 'static, B: 'static, R: 'static>(prog1: Box<Exp<Output=ProgVal<A>>>,
+                                                       prog2: Box<Exp<Output=ProgVal<B>>>,
+                                                       f: Rc<Fn(Box<Exp<Output=A>>, Box<Exp<Output=B>>) -> Box<Exp<Output=R>>>) -> ProgZipExp<A, B, R> {
+    ProgZipExp {
+        prog1,
+        prog2,
+        f
+    }
+}
+
+pub fn run_prog_exp<T: 'static>(prog: Box<Exp<Output=ProgVal<T>>>) -> RunProgExp<T> {
+    RunProgExp {
+        prog
+    }
+}
+
+pub fn quote_exp<T: 'static>(body: Box<Exp<Output=T>>) -> QuoteExp<T> {
+    QuoteExp {
+        body: Rc::from(body)
+    }
+}
+
+pub fn splice_exp<T: 'static>(code: Box<Exp<Output=CodeVal<T>>>) -> SpliceExp<T> {
+    SpliceExp {
+        code
+    }
+}
+
+pub fn run_code_exp<T: 'static>(code: Box<Exp<Output=CodeVal<T>>>) -> RunCodeExp<T> {
+    RunCodeExp {
+        code
+    }
+}
+
+impl<T: 'static> E<T> {
+    pub fn quote(self) -> E<CodeVal<T>> {
+        E::new(quote_exp(self.0))
+    }
+}
+
+impl<T: 'static> E<CodeVal<T>> {
+    pub fn splice(self) -> E<T> {
+        E::new(splice_exp(self.0))
+    }
+
+    pub fn run(self) -> E<T> {
+        E::new(run_code_exp(self.0))
+    }
+}
+
+impl<T: 'static+Clone> E<T> {
+    pub fn lift(self) -> E<ProgVal<T>> {
+        E::new(lift_exp(self.0))
+    }
+}
+
+impl<T: 'static> E<ProgVal<T>> {
+    pub fn zip<B: 'static, R: 'static, F>(self, prog2: E<ProgVal<B>>, f: F) -> E<ProgVal<R>>
+        where F: Fn(E<T>, E<B>) -> E<R> + 'static {
+        E::new(prog_zip_exp(self.0, prog2.0, Rc::new(move |a, b| f(E(a), E(b)).0)))
+    }
+
+    pub fn run(self) -> E<T> {
+        E::new(run_prog_exp(self.0))
+    }
+}
@@ -0,0 +1,92 @@
+use Exp;
+use reify::{Expr, Value};
+
+// Shrinking a program while it keeps some property, to turn a failure found
+// on a big generated program into a small one to debug. The property is
+// the caller's, usually that the program still fails the same way:
+//
+//     minimize(&program, |e| match check(e) {
+//         Ok(typed) => typed.eval() != typed.eval_interpreted(),
+//         Err(_) => false,
+//     })
+//
+// Subtrees are replaced by one of their children or by a constant, larger
+// subtrees first, for as long as the smaller program keeps the property.
+// Most candidates don't check, a child not being of its parent's type, so
+// the property should check the program first and reject those that
+// don't. What's returned keeps the property, and replacing any one of its
+// nodes by a child or a constant wouldn't. Each step makes the program
+// smaller or a constant in it simpler, so shrinking stops, but it calls
+// the property up to a few times per node for each step it takes.
+
+// What a node might be replaced by to make its program smaller.
+fn smaller(expr: &Expr) -> Vec<Expr> {
+    let mut out = Vec::new();
+    if let Expr::Node { ref children, .. } = *expr {
+        out.extend(children.iter().cloned());
+    }
+    // Only ever towards these, so shrinking stops.
+    let simplest: Vec<Expr> = vec![Value::Num(0), Value::Bool(false), Value::Unit, Value::Str(String::new())]
+        .into_iter().map(Expr::Const).collect();
+    if !simplest.contains(expr) {
+        out.extend(simplest);
+    }
+    out
+}
+
+// `expr` with the node at `path` replaced by `by`.
+fn replace(expr: &Expr, path: &[usize], by: &Expr) -> Expr {
+    match (path.split_first(), expr) {
+        (None, _) => by.clone(),
+        (Some((&i, rest)), &Expr::Node { ref kind, ref binds, ref children }) => Expr::Node {
+            kind: kind.clone(),
+            binds: binds.clone(),
+            children: children.iter().enumerate()
+                .map(|(j, c)| if j == i { replace(c, rest, by) } else { c.clone() }).collect(),
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn paths(expr: &Expr, path: &mut Vec<usize>, out: &mut Vec<Vec<usize>>) {
+    out.push(path.clone());
+    if let Expr::Node { ref children, .. } = *expr {
+        for (i, c) in children.iter().enumerate() {
+            path.push(i);
+            paths(c, path, out);
+            path.pop();
+        }
+    }
+}
+
+fn at<'a>(expr: &'a Expr, path: &[usize]) -> &'a Expr {
+    match (path.split_first(), expr) {
+        (None, _) => expr,
+        (Some((&i, rest)), &Expr::Node { ref children, .. }) => at(&children[i], rest),
+        _ => unreachable!(),
+    }
+}
+
+// `expr`, which should keep `property`, shrunk as far as it can be while it
+// does. `expr` itself is returned if it doesn't.
+pub fn minimize<F: FnMut(&Expr) -> bool>(expr: &Expr, mut property: F) -> Expr {
+    let mut expr = expr.clone();
+    'shrink: loop {
+        let mut all = Vec::new();
+        paths(&expr, &mut Vec::new(), &mut all);
+        for path in all {
+            for candidate in smaller(at(&expr, &path)) {
+                let shrunk = replace(&expr, &path, &candidate);
+                if property(&shrunk) {
+                    expr = shrunk;
+                    continue 'shrink;
+                }
+            }
+        }
+        return expr;
+    }
+}
+
+pub fn minimize_exp<T, F: FnMut(&Expr) -> bool>(exp: &Exp<Output=T>, property: F) -> Expr {
+    minimize(&exp.reify(), property)
+}
@@ -0,0 +1,244 @@
+use std::any::{Any, TypeId};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::rc::Rc;
+
+use libloading::Library;
+
+use {Exp, StagedExp, EvalContext, Place, VariableExp, NumVal, BoolVal};
+use reify::{Expr, Value, node};
+use strength::reduce;
+
+// Native code for long-lived programs: the program is written out as Rust,
+// built by rustc into a shared library with optimizations on, and loaded,
+// so running it is a call into machine code rather than a walk over staged
+// nodes. Building takes seconds, so libraries are kept in a directory,
+// named by a hash of the program, and a program staged again, in this
+// process or a later one, loads the one built before.
+//
+// Only programs of numbers and bools are written out: arithmetic,
+// comparison, if, let, set, seq and while, over constants and the input
+// variables the host names, each a NumVal. Inputs are read when the program
+// starts and those it sets are written back when it finishes. Native code
+// doesn't count steps, so a sandbox can't stop a loop in it, and arithmetic
+// wraps on overflow, as NumVal's does in release builds.
+
+#[derive(Debug)]
+pub enum NativeError {
+    // The program uses something the backend can't write out.
+    Unsupported(String),
+    Io(String),
+    // rustc's errors.
+    Compile(String),
+    Load(String),
+}
+
+impl fmt::Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NativeError::Unsupported(ref msg) => write!(f, "can't compile natively: {}", msg),
+            NativeError::Io(ref msg) => write!(f, "couldn't write the library: {}", msg),
+            NativeError::Compile(ref msg) => write!(f, "rustc failed: {}", msg),
+            NativeError::Load(ref msg) => write!(f, "couldn't load the library: {}", msg),
+        }
+    }
+}
+
+fn unsupported<T>(msg: String) -> Result<T, NativeError> {
+    Err(NativeError::Unsupported(msg))
+}
+
+struct Writer<'a> {
+    inputs: &'a [i32],
+    // Variables bound by lets around the expression being written.
+    bound: Vec<i32>,
+    // Which inputs the program sets.
+    written: Vec<bool>,
+}
+
+impl<'a> Writer<'a> {
+    fn var(&self, id: i32) -> Result<String, NativeError> {
+        if self.bound.contains(&id) {
+            Ok(format!("b{}", id))
+        } else if let Some(i) = self.inputs.iter().position(|&input| input == id) {
+            Ok(format!("v{}", i))
+        } else {
+            unsupported(format!("variable {} isn't an input", id))
+        }
+    }
+
+    // `expr` as a Rust expression.
+    fn expr(&mut self, expr: &Expr) -> Result<String, NativeError> {
+        let (kind, binds, children) = match *expr {
+            Expr::Const(Value::Num(v)) => return Ok(format!("({}i64)", v)),
+            Expr::Const(Value::Bool(v)) => return Ok(v.to_string()),
+            Expr::Const(Value::Unit) => return Ok("()".to_string()),
+            Expr::Var(id) => return self.var(id),
+            Expr::Node { ref kind, ref binds, ref children } => (kind, binds, children),
+            Expr::Const(_) => return unsupported("a value that isn't a number or bool".to_string()),
+            Expr::Bound(_) | Expr::Opaque => return unsupported("a node that can't be reified".to_string()),
+        };
+        if &kind[..] == "let" && binds.len() == 1 && children.len() == 2 {
+            let init = self.expr(&children[0])?;
+            self.bound.push(binds[0]);
+            let body = self.expr(&children[1]);
+            self.bound.pop();
+            return Ok(format!("{{ let mut b{} = {}; {} }}", binds[0], init, body?));
+        }
+        if !binds.is_empty() {
+            return unsupported(format!("the {} node", kind));
+        }
+        if &kind[..] == "set" && children.len() == 2 {
+            let id = match children[0] {
+                Expr::Var(id) => id,
+                _ => return unsupported("a set of something other than a variable".to_string()),
+            };
+            if let Some(i) = self.inputs.iter().position(|&input| input == id) {
+                if !self.bound.contains(&id) {
+                    self.written[i] = true;
+                }
+            }
+            let var = self.var(id)?;
+            return Ok(format!("{{ {} = {}; }}", var, self.expr(&children[1])?));
+        }
+        let args = children.iter().map(|c| self.expr(c)).collect::<Result<Vec<_>, _>>()?;
+        Ok(match (&kind[..], &args[..]) {
+            ("add", &[ref a, ref b]) => format!("{}.wrapping_add({})", a, b),
+            ("sub", &[ref a, ref b]) => format!("{}.wrapping_sub({})", a, b),
+            ("mul", &[ref a, ref b]) => format!("{}.wrapping_mul({})", a, b),
+            ("lt", &[ref a, ref b]) => format!("({} < {})", a, b),
+            ("if", &[ref c, ref a, ref b]) => format!("(if {} {{ {} }} else {{ {} }})", c, a, b),
+            ("seq", &[ref a, ref b]) => format!("{{ {}; {} }}", a, b),
+            ("while", &[ref c, ref body]) => format!("{{ while {} {{ {}; }} }}", c, body),
+            _ => return unsupported(format!("the {} node", kind)),
+        })
+    }
+}
+
+// The library's source: `tagless_run` takes the inputs' values, runs the
+// program, writes back the inputs it set and gives the result as an i64.
+fn source(body: &Expr, inputs: &[i32], result: &str) -> Result<String, NativeError> {
+    let mut w = Writer {
+        inputs,
+        bound: Vec::new(),
+        written: vec![false; inputs.len()],
+    };
+    let body = w.expr(body)?;
+    let mut src = String::from("#![allow(unused)]\n\n#[no_mangle]\npub unsafe extern \"C\" fn tagless_run(inputs: *mut i64) -> i64 {\n");
+    for i in 0..inputs.len() {
+        src.push_str(&format!("    let mut v{} = *inputs.add({});\n", i, i));
+    }
+    src.push_str(&format!("    let result: {} = {};\n", result, body));
+    for (i, &written) in w.written.iter().enumerate() {
+        if written {
+            src.push_str(&format!("    *inputs.add({}) = v{};\n", i, i));
+        }
+    }
+    src.push_str("    result as i64\n}\n");
+    Ok(src)
+}
+
+// Where libraries are built and kept.
+pub struct Native {
+    dir: PathBuf,
+    rustc: String,
+}
+
+impl Native {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Native {
+        Native {
+            dir: dir.into(),
+            rustc: env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()),
+        }
+    }
+
+    pub fn rustc(mut self, rustc: &str) -> Self {
+        self.rustc = rustc.to_string();
+        self
+    }
+
+    // The library for `src`, built unless it's in the directory already.
+    fn library(&self, key: u64, src: &str) -> Result<Library, NativeError> {
+        let io = |e: ::std::io::Error| NativeError::Io(e.to_string());
+        let name = format!("{}tagless_{:016x}{}", env::consts::DLL_PREFIX, key, env::consts::DLL_SUFFIX);
+        let path = self.dir.join(&name);
+        if !path.exists() {
+            fs::create_dir_all(&self.dir).map_err(io)?;
+            let src_path = self.dir.join(format!("tagless_{:016x}.rs", key));
+            fs::write(&src_path, src).map_err(io)?;
+            // Built under another name and moved into place, so another
+            // process never loads a half-written library.
+            let tmp = self.dir.join(format!("{}.{}.tmp", name, ::std::process::id()));
+            let out = Command::new(&self.rustc)
+                .args(&["--crate-type", "cdylib", "--edition", "2021", "-C", "opt-level=3", "-o"])
+                .arg(&tmp)
+                .arg(&src_path)
+                .output()
+                .map_err(|e| NativeError::Compile(e.to_string()))?;
+            if !out.status.success() {
+                let _ = fs::remove_file(&tmp);
+                return Err(NativeError::Compile(String::from_utf8_lossy(&out.stderr).into_owned()));
+            }
+            fs::rename(&tmp, &path).map_err(io)?;
+        }
+        unsafe { Library::new(&path) }.map_err(|e| NativeError::Load(e.to_string()))
+    }
+
+    // `exp` compiled to native code, reading and setting the variables
+    // `inputs`. The program's result must be a NumVal or BoolVal.
+    pub fn stage<T: 'static+Clone>(&self, exp: &Exp<Output=T>, inputs: &[VariableExp<NumVal>])
+        -> Result<Box<StagedExp<Output=T>>, NativeError> {
+        let (result, wrap): (&str, Box<Any>) = if TypeId::of::<T>() == TypeId::of::<NumVal>() {
+            let wrap: Rc<Fn(i64) -> NumVal> = Rc::new(|v| NumVal { v });
+            ("i64", box wrap)
+        } else if TypeId::of::<T>() == TypeId::of::<BoolVal>() {
+            let wrap: Rc<Fn(i64) -> BoolVal> = Rc::new(|v| BoolVal { v: v != 0 });
+            ("bool", box wrap)
+        } else {
+            return unsupported("a result that isn't a number or bool".to_string());
+        };
+        let body = reduce(&exp.reify());
+        let ids: Vec<i32> = inputs.iter().map(|input| input.id).collect();
+        let src = source(&body, &ids, result)?;
+        // The inputs are part of the key, in order, so the same shape over
+        // inputs given in another order is another library.
+        let key = node(result, vec![body, node("inputs", ids.iter().map(|&id| Expr::Var(id)).collect())]).stable_hash();
+        let lib = self.library(key, &src)?;
+        let run = unsafe {
+            *lib.get::<unsafe extern "C" fn(*mut i64) -> i64>(b"tagless_run").map_err(|e| NativeError::Load(e.to_string()))?
+        };
+        Ok(box NativeStagedExp {
+            _lib: lib,
+            run,
+            inputs: inputs.iter().map(Place::of).collect(),
+            wrap: *wrap.downcast::<Rc<Fn(i64) -> T>>().unwrap(),
+        })
+    }
+}
+
+pub struct NativeStagedExp<T: 'static> {
+    // Kept loaded as long as `run` may be called.
+    _lib: Library,
+    run: unsafe extern "C" fn(*mut i64) -> i64,
+    inputs: Vec<Place<NumVal>>,
+    wrap: Rc<Fn(i64) -> T>,
+}
+
+impl<T: 'static> StagedExp for NativeStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut vals: Vec<i64> = self.inputs.iter().map(|input| ctx.read(input).v).collect();
+        let before = vals.clone();
+        let result = unsafe { (self.run)(vals.as_mut_ptr()) };
+        for (i, input) in self.inputs.iter().enumerate() {
+            if vals[i] != before[i] {
+                ctx.write(input, NumVal { v: vals[i] });
+            }
+        }
+        (self.wrap)(result)
+    }
+}
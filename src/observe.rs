@@ -0,0 +1,129 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext};
+use ops::E;
+use reify::Expr;
+
+// Hooks for watching a program evaluate, for logging, coverage and metrics.
+// Only observed nodes report: wrap a subtree with `observed_exp`, or load a
+// whole program with `check::check_observed` to have every node reported.
+// Reports go to the observer `observing` has installed on the thread, so the
+// same program can run watched or not; without one an observed node costs
+// a thread-local read.
+//
+// Observed nodes don't expose their constants, so nodes above them aren't
+// specialized on them when staged and every observed node runs.
+
+pub trait EvalObserver {
+    fn on_enter(&self, node: &Expr);
+    // `value` is the node's result, of its output type.
+    fn on_exit(&self, node: &Expr, value: &Any);
+}
+
+thread_local! {
+    static OBSERVER: RefCell<Option<Rc<EvalObserver>>> = const { RefCell::new(None) };
+}
+
+// Runs `f` with observed nodes reporting to `observer`, then puts back
+// whichever observer was installed before.
+pub fn observing<R, F: FnOnce() -> R>(observer: Rc<EvalObserver>, f: F) -> R {
+    struct Restore(Option<Rc<EvalObserver>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            OBSERVER.with(|o| *o.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(OBSERVER.with(|o| o.borrow_mut().replace(observer)));
+    f()
+}
+
+fn current() -> Option<Rc<EvalObserver>> {
+    OBSERVER.with(|o| o.borrow().clone())
+}
+
+// Evaluates `eval`, reporting it as `node` if an observer is installed.
+fn observe<T: 'static, F: FnOnce() -> T>(node: &Expr, eval: F) -> T {
+    match current() {
+        Some(observer) => {
+            observer.on_enter(node);
+            let v = eval();
+            observer.on_exit(node, &v);
+            v
+        }
+        None => eval(),
+    }
+}
+
+pub struct ObservedExp<T: 'static> {
+    exp: Box<Exp<Output=T>>,
+    // What's reported for it: the subtree's own shape.
+    node: Rc<Expr>,
+}
+
+impl<T: 'static> Clone for ObservedExp<T> {
+    fn clone(&self) -> Self {
+        ObservedExp {
+            exp: self.exp.clone(),
+            node: self.node.clone(),
+        }
+    }
+}
+
+pub struct ObservedStagedExp<T: 'static> {
+    staged_exp: Box<StagedExp<Output=T>>,
+    node: Rc<Expr>,
+}
+
+impl<T: 'static> Exp for ObservedExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ObservedStagedExp {
+            staged_exp: self.exp.stage(),
+            node: self.node.clone(),
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        observe(&self.node, || self.exp.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        self.exp.reify()
+    }
+}
+
+impl<T: 'static> StagedExp for ObservedStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        observe(&self.node, || self.staged_exp.run(ctx))
+    }
+}
+
+pub fn observed_exp<T: 'static>(exp: Box<Exp<Output=T>>) -> ObservedExp<T> {
+    let node = Rc::new(exp.reify());
+    observed_exp_as(exp, node)
+}
+
+// Reports `exp` as `node`, for callers that already have its shape.
+pub fn observed_exp_as<T: 'static>(exp: Box<Exp<Output=T>>, node: Rc<Expr>) -> ObservedExp<T> {
+    ObservedExp {
+        exp,
+        node,
+    }
+}
+
+impl<T: 'static> E<T> {
+    pub fn observed(self) -> E<T> {
+        E::new(observed_exp(self.0))
+    }
+}
@@ -0,0 +1,159 @@
+use std::any::Any;
+use std::ops::{Add, Sub, Mul, Div};
+
+use {Exp, StagedExp, Compiled, Val, NumVal, BoolVal, FloatVal, VariableExp};
+use {unit_exp, add_exp, sub_exp, mul_exp, div_exp, pow_exp, less_than_exp, partial_less_than_exp, if_exp};
+use reify::Expr;
+
+// Wraps an expression so it can be built with operators: `(a + b).lt(c)`
+// builds the same tree as `less_than_exp(box add_exp(box a, box b), box c)`.
+pub struct E<T: 'static>(pub Box<Exp<Output=T>>);
+
+impl<T: 'static> Clone for E<T> {
+    fn clone(&self) -> Self {
+        E(self.0.clone_box())
+    }
+}
+
+impl<T: 'static> E<T> {
+    pub fn new<X: Exp<Output=T> + 'static>(exp: X) -> E<T> {
+        E(box exp)
+    }
+
+    pub fn into_exp(self) -> Box<Exp<Output=T>> {
+        self.0
+    }
+}
+
+pub fn lit(v: i64) -> E<NumVal> {
+    E::new(unit_exp(NumVal { v }))
+}
+
+impl From<i64> for E<NumVal> {
+    fn from(v: i64) -> E<NumVal> {
+        lit(v)
+    }
+}
+
+impl From<f64> for E<FloatVal> {
+    fn from(v: f64) -> E<FloatVal> {
+        E::new(unit_exp(FloatVal { v }))
+    }
+}
+
+impl<T: 'static+Clone> From<VariableExp<T>> for E<T> {
+    fn from(var: VariableExp<T>) -> E<T> {
+        E::new(var)
+    }
+}
+
+impl<'a, T: 'static+Clone> From<&'a VariableExp<T>> for E<T> {
+    fn from(var: &'a VariableExp<T>) -> E<T> {
+        E::new(var.clone())
+    }
+}
+
+impl<T, R> Add<R> for E<T> where T: 'static+Clone+Val+Add<Output=T>, R: Into<E<T>> {
+    type Output = E<T>;
+
+    fn add(self, rhs: R) -> E<T> {
+        E::new(add_exp(self.0, rhs.into().0))
+    }
+}
+
+impl<T, R> Sub<R> for E<T> where T: 'static+Clone+Val+Sub<Output=T>, R: Into<E<T>> {
+    type Output = E<T>;
+
+    fn sub(self, rhs: R) -> E<T> {
+        E::new(sub_exp(self.0, rhs.into().0))
+    }
+}
+
+impl<T, R> Mul<R> for E<T> where T: 'static+Clone+Val+Mul<Output=T>, R: Into<E<T>> {
+    type Output = E<T>;
+
+    fn mul(self, rhs: R) -> E<T> {
+        E::new(mul_exp(self.0, rhs.into().0))
+    }
+}
+
+impl<T, R> Div<R> for E<T> where T: 'static+Clone+Val+Div<Output=T>, R: Into<E<T>> {
+    type Output = E<T>;
+
+    fn div(self, rhs: R) -> E<T> {
+        E::new(div_exp(self.0, rhs.into().0))
+    }
+}
+
+impl<T: 'static+Clone+Val+Ord> E<T> {
+    pub fn lt<R: Into<E<T>>>(self, rhs: R) -> E<BoolVal> {
+        E::new(less_than_exp(self.0, rhs.into().0))
+    }
+
+    // Note this evaluates `rhs` before `self`.
+    pub fn gt<R: Into<E<T>>>(self, rhs: R) -> E<BoolVal> {
+        E::new(less_than_exp(rhs.into().0, self.0))
+    }
+}
+
+impl<T: 'static+Clone+Val+PartialOrd> E<T> {
+    pub fn partial_lt<R: Into<E<T>>>(self, rhs: R) -> E<BoolVal> {
+        E::new(partial_less_than_exp(self.0, rhs.into().0))
+    }
+
+    pub fn partial_gt<R: Into<E<T>>>(self, rhs: R) -> E<BoolVal> {
+        E::new(partial_less_than_exp(rhs.into().0, self.0))
+    }
+}
+
+impl E<FloatVal> {
+    pub fn pow<R: Into<E<FloatVal>>>(self, rhs: R) -> E<FloatVal> {
+        E::new(pow_exp(self.0, rhs.into().0))
+    }
+}
+
+impl E<BoolVal> {
+    pub fn select<T: 'static+Clone>(self, then_exp: E<T>, else_exp: E<T>) -> E<T> {
+        E::new(if_exp(self.0, then_exp.0, else_exp.0))
+    }
+}
+
+impl<T: 'static> Exp for E<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        self.0.stage()
+    }
+
+    fn interpret(&self) -> Self::Output {
+        self.0.interpret()
+    }
+
+    fn interpret_with(&self, f: &mut FnMut(&Self::Output)) {
+        self.0.interpret_with(f)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        self.0.reify()
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        self.0.stage_compiled()
+    }
+
+    fn stage_tail(&self, fn_id: i32) -> Box<StagedExp<Output=Self::Output>> {
+        self.0.stage_tail(fn_id)
+    }
+
+    fn stage_each(&self) -> Option<Box<Any>> {
+        self.0.stage_each()
+    }
+
+    fn interpret_tail(&self, fn_id: i32) -> Self::Output {
+        self.0.interpret_tail(fn_id)
+    }
+}
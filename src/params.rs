@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::marker::Tuple;
+use std::rc::Rc;
+
+use {Exp, EvalContext, SlotScope, Slot, VariableExp, Val};
+use {NumVal, BoolVal, UnitVal, StrVal, FloatVal, ArrayVal, OptionVal, MapVal};
+
+// Staged programs called as functions. The variables a program reads its
+// inputs from are declared once, as a tuple, along with the expression
+// whose value is its result, and the program is staged with them as its
+// parameters:
+//
+//     let f = stage_fn((x.clone(), y.clone()), &body);
+//     let v: i64 = f(3, 7);
+//
+// Each call binds the inputs to its arguments in a context of its own, so
+// the host's cells aren't read or set, and calls may nest: a call made
+// while another is running, as from an extern the program calls, sees its
+// own arguments. Arguments and results are passed as the Rust types the
+// values hold (`Val::Output`), i64 for NumVal and so on, so a program of
+// two NumVal inputs and a BoolVal result is an `Fn(i64, i64) -> bool`, and
+// can be passed wherever one is expected; `call` takes the arguments as a
+// tuple instead. Programs take up to eight inputs. An input the program
+// sets only changes the call's copy.
+
+// Values a staged function can take, built from what they hold.
+pub trait FromHost: Val {
+    fn from_host(v: Self::Output) -> Self;
+}
+
+impl FromHost for NumVal {
+    fn from_host(v: i64) -> NumVal {
+        NumVal { v }
+    }
+}
+
+impl FromHost for BoolVal {
+    fn from_host(v: bool) -> BoolVal {
+        BoolVal { v }
+    }
+}
+
+impl FromHost for UnitVal {
+    fn from_host(_: ()) -> UnitVal {
+        UnitVal
+    }
+}
+
+impl FromHost for StrVal {
+    fn from_host(v: String) -> StrVal {
+        StrVal { v }
+    }
+}
+
+impl FromHost for FloatVal {
+    fn from_host(v: f64) -> FloatVal {
+        FloatVal { v }
+    }
+}
+
+impl<T: Clone> FromHost for ArrayVal<T> {
+    fn from_host(v: Vec<T>) -> ArrayVal<T> {
+        ArrayVal { v }
+    }
+}
+
+impl<T: Clone> FromHost for OptionVal<T> {
+    fn from_host(v: Option<T>) -> OptionVal<T> {
+        OptionVal { v }
+    }
+}
+
+impl<K: Eq+Hash+Clone, V: Clone> FromHost for MapVal<K, V> {
+    fn from_host(v: HashMap<K, V>) -> MapVal<K, V> {
+        MapVal { v: Rc::new(v) }
+    }
+}
+
+// A tuple of the variables a program takes its inputs from, in the order
+// a call gives their values.
+pub trait Params {
+    // The tuple of values a call takes.
+    type Args;
+    type Slots;
+
+    fn bind(&self, scope: &SlotScope) -> Self::Slots;
+
+    fn set(ctx: &EvalContext, slots: &Self::Slots, args: Self::Args);
+}
+
+impl Params for () {
+    type Args = ();
+    type Slots = ();
+
+    fn bind(&self, _scope: &SlotScope) {}
+
+    fn set(_ctx: &EvalContext, _slots: &(), _args: ()) {}
+}
+
+// The Params of a tuple of variables, with the type of each variable's
+// value and its place in the tuple.
+macro_rules! params {
+    ($($t:ident $i:tt),+) => {
+        impl<$($t: 'static+Clone+FromHost),+> Params for ($(VariableExp<$t>,)+) {
+            type Args = ($($t::Output,)+);
+            type Slots = ($(Slot<$t>,)+);
+
+            fn bind(&self, scope: &SlotScope) -> Self::Slots {
+                ($(scope.bind(&self.$i),)+)
+            }
+
+            fn set(ctx: &EvalContext, slots: &Self::Slots, args: Self::Args) {
+                $(ctx.set_local(&slots.$i, $t::from_host(args.$i));)+
+            }
+        }
+    }
+}
+
+params!(A 0);
+params!(A 0, B 1);
+params!(A 0, B 1, C 2);
+params!(A 0, B 1, C 2, D 3);
+params!(A 0, B 1, C 2, D 3, E 4);
+params!(A 0, B 1, C 2, D 3, E 4, F 5);
+params!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+params!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+// A program staged as a function of `Args`, the tuple of values its inputs
+// are bound to, returning `Ret`.
+pub struct StagedFn<Args, Ret> {
+    f: Box<Fn(Args) -> Ret>,
+}
+
+// Stages `output`, with the variables `inputs` its parameters.
+pub fn stage_fn<P, R>(inputs: P, output: &Exp<Output=R>) -> StagedFn<P::Args, R::Output>
+    where P: Params+'static, R: 'static+Clone+Val {
+    let scope = SlotScope::enter();
+    let slots = inputs.bind(&scope);
+    let staged = output.stage();
+    StagedFn {
+        f: box move |args| {
+            let ctx = EvalContext::new();
+            P::set(&ctx, &slots, args);
+            staged.run(&ctx).get()
+        },
+    }
+}
+
+impl<Args, Ret> StagedFn<Args, Ret> {
+    pub fn call(&self, args: Args) -> Ret {
+        (self.f)(args)
+    }
+}
+
+// So a staged function can be called with its arguments, `f(3, 7)`, and
+// passed where a closure taking them is.
+impl<Args: Tuple, Ret> FnOnce<Args> for StagedFn<Args, Ret> {
+    type Output = Ret;
+
+    extern "rust-call" fn call_once(self, args: Args) -> Ret {
+        (self.f)(args)
+    }
+}
+
+impl<Args: Tuple, Ret> FnMut<Args> for StagedFn<Args, Ret> {
+    extern "rust-call" fn call_mut(&mut self, args: Args) -> Ret {
+        (self.f)(args)
+    }
+}
+
+impl<Args: Tuple, Ret> Fn<Args> for StagedFn<Args, Ret> {
+    extern "rust-call" fn call(&self, args: Args) -> Ret {
+        (self.f)(args)
+    }
+}
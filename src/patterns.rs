@@ -0,0 +1,443 @@
+use std::any::Any;
+
+use {Exp, StagedExp, EvalContext, Binding, SlotScope, Slot, VariableExp, BoolVal, RecordVal, VariantVal};
+use reify::{Expr, Value, node, binder, value_of};
+
+// Where a pattern puts the part of the value it binds, with the type erased
+// so patterns can bind variables of different types.
+pub trait Binder {
+    fn bind(&self, v: &Any);
+    fn id(&self) -> i32;
+    // The variable's value now, to put back with `restore`.
+    fn save(&self) -> Box<Any>;
+    fn restore(&self, v: Box<Any>);
+    // Gives the variable a slot in `scope`, for a staged match.
+    fn stage_slot(&self, scope: &SlotScope) -> Box<SlotBinder>;
+    fn clone_binder(&self) -> Box<Binder>;
+}
+
+// A pattern variable's slot, where a staged match binds it in a run's frame.
+pub trait SlotBinder {
+    fn id(&self) -> i32;
+    fn index(&self) -> usize;
+    fn bind_in(&self, ctx: &EvalContext, v: &Any);
+    fn restore_in(&self, ctx: &EvalContext, saved: Option<Binding>);
+}
+
+impl<T: 'static+Clone> Binder for VariableExp<T> {
+    fn bind(&self, v: &Any) {
+        match v.downcast_ref::<T>() {
+            Some(v) => {
+                self.assign(v.clone());
+            }
+            None => panic!("pattern variable bound to a value of the wrong type"),
+        }
+    }
+
+    fn id(&self) -> i32 {
+        self.id
+    }
+
+    fn save(&self) -> Box<Any> {
+        box self.var_val.borrow().clone()
+    }
+
+    fn restore(&self, v: Box<Any>) {
+        if let Ok(v) = v.downcast::<T>() {
+            self.assign(*v);
+        }
+    }
+
+    fn stage_slot(&self, scope: &SlotScope) -> Box<SlotBinder> {
+        box scope.bind(self)
+    }
+
+    fn clone_binder(&self) -> Box<Binder> {
+        box self.clone()
+    }
+}
+
+impl<T: 'static+Clone> SlotBinder for Slot<T> {
+    fn id(&self) -> i32 {
+        self.var.id
+    }
+
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn bind_in(&self, ctx: &EvalContext, v: &Any) {
+        match v.downcast_ref::<T>() {
+            Some(v) => ctx.set_local(self, v.clone()),
+            None => panic!("pattern variable bound to a value of the wrong type"),
+        }
+    }
+
+    fn restore_in(&self, ctx: &EvalContext, saved: Option<Binding>) {
+        ctx.restore(self, saved);
+    }
+}
+
+impl Clone for Box<Binder> {
+    fn clone(&self) -> Self {
+        self.clone_binder()
+    }
+}
+
+// Tuples are records with fields named "0", "1", ...; see `tuple`. A record
+// pattern need only name the fields it looks at.
+#[derive(Clone)]
+pub enum Pattern {
+    Wild,
+    // Binds the whole value, if it also matches the inner pattern.
+    Bind(Box<Binder>, Box<Pattern>),
+    Lit(Value),
+    Variant(String, Box<Pattern>),
+    Record(Vec<(String, Pattern)>),
+}
+
+pub fn wild() -> Pattern {
+    Pattern::Wild
+}
+
+pub fn bind<T: 'static+Clone>(var: &VariableExp<T>) -> Pattern {
+    Pattern::Bind(box var.clone(), box Pattern::Wild)
+}
+
+pub fn bind_as<T: 'static+Clone>(var: &VariableExp<T>, p: Pattern) -> Pattern {
+    Pattern::Bind(box var.clone(), box p)
+}
+
+// Takes a num, bool, unit, str or float.
+pub fn lit(v: &Any) -> Pattern {
+    match value_of(v) {
+        Value::Opaque => panic!("only scalar values can be literal patterns"),
+        v => Pattern::Lit(v),
+    }
+}
+
+pub fn variant(tag: &str, payload: Pattern) -> Pattern {
+    Pattern::Variant(tag.to_string(), box payload)
+}
+
+pub fn record(fields: Vec<(&str, Pattern)>) -> Pattern {
+    Pattern::Record(fields.into_iter().map(|(name, p)| (name.to_string(), p)).collect())
+}
+
+pub fn tuple(items: Vec<Pattern>) -> Pattern {
+    Pattern::Record(items.into_iter().enumerate().map(|(i, p)| (i.to_string(), p)).collect())
+}
+
+impl Pattern {
+    // Binds the pattern's variables as it goes, so on a failed match some of
+    // them may have been set.
+    pub fn matches(&self, v: &Any) -> bool {
+        self.matches_by(v, &mut |var, v| var.bind(v))
+    }
+
+    // As `matches`, binding the variables in a staged run's frame, at the
+    // slots given.
+    fn matches_in(&self, ctx: &EvalContext, slots: &[Box<SlotBinder>], v: &Any) -> bool {
+        self.matches_by(v, &mut |var, v| {
+            let slot = slots.iter().find(|s| s.id() == var.id()).expect("pattern variable without a slot");
+            slot.bind_in(ctx, v)
+        })
+    }
+
+    fn matches_by(&self, v: &Any, bind: &mut FnMut(&Binder, &Any)) -> bool {
+        match *self {
+            Pattern::Wild => true,
+            Pattern::Bind(ref var, ref p) => {
+                if p.matches_by(v, bind) {
+                    bind(&**var, v);
+                    true
+                } else {
+                    false
+                }
+            }
+            Pattern::Lit(ref lit) => value_of(v) == *lit,
+            Pattern::Variant(ref tag, ref p) => match v.downcast_ref::<VariantVal>() {
+                Some(v) => &*v.tag == tag && p.matches_by(&*v.v, bind),
+                None => false,
+            },
+            Pattern::Record(ref fields) => match v.downcast_ref::<RecordVal>() {
+                Some(r) => fields.iter().all(|&(ref name, ref p)| {
+                    match r.v.binary_search_by(|f| f.0[..].cmp(name)) {
+                        Ok(i) => p.matches_by(&*r.v[i].1, bind),
+                        Err(_) => false,
+                    }
+                }),
+                None => false,
+            },
+        }
+    }
+
+    // The variables the pattern binds, in order.
+    pub fn binds(&self, out: &mut Vec<i32>) {
+        let mut binders = Vec::new();
+        self.binders(&mut binders);
+        out.extend(binders.iter().map(|b| b.id()));
+    }
+
+    fn binders(&self, out: &mut Vec<Box<Binder>>) {
+        match *self {
+            Pattern::Bind(ref var, ref p) => {
+                out.push(var.clone());
+                p.binders(out);
+            }
+            Pattern::Variant(_, ref p) => p.binders(out),
+            Pattern::Record(ref fields) => {
+                for f in fields {
+                    f.1.binders(out);
+                }
+            }
+            Pattern::Wild | Pattern::Lit(_) => {}
+        }
+    }
+
+    pub fn reify(&self) -> Expr {
+        match *self {
+            Pattern::Wild => node("pat_wild", vec![]),
+            Pattern::Bind(ref var, ref p) => node("pat_bind", vec![Expr::Var(var.id()), p.reify()]),
+            Pattern::Lit(ref v) => node("pat_lit", vec![Expr::Const(v.clone())]),
+            Pattern::Variant(ref tag, ref p) => node("pat_variant", vec![Expr::Const(Value::Str(tag.clone())), p.reify()]),
+            Pattern::Record(ref fields) => {
+                let mut children = Vec::new();
+                for &(ref name, ref p) in fields {
+                    children.push(Expr::Const(Value::Str(name.clone())));
+                    children.push(p.reify());
+                }
+                node("pat_record", children)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Case<R: 'static+Clone> {
+    pattern: Pattern,
+    binders: Vec<Box<Binder>>,
+    guard: Option<Box<Exp<Output=BoolVal>>>,
+    body: Box<Exp<Output=R>>,
+}
+
+struct StagedCase<R: 'static+Clone> {
+    pattern: Pattern,
+    slots: Vec<Box<SlotBinder>>,
+    staged_guard: Option<Box<StagedExp<Output=BoolVal>>>,
+    staged_body: Box<StagedExp<Output=R>>,
+}
+
+// Tries the cases in order and runs the body of the first whose pattern
+// matches and whose guard, if any, is true. The pattern's variables are
+// bound for the guard and the body. No case matching panics; programs loaded
+// through `check` are checked to cover every value.
+#[derive(Clone)]
+pub struct PatternMatchExp<S: 'static+Clone, R: 'static+Clone> {
+    scrutinee: Box<Exp<Output=S>>,
+    cases: Vec<Case<R>>,
+}
+
+pub struct PatternMatchStagedExp<S: 'static+Clone, R: 'static+Clone> {
+    staged_scrutinee: Box<StagedExp<Output=S>>,
+    staged_cases: Vec<StagedCase<R>>,
+}
+
+fn no_case() -> ! {
+    panic!("no pattern matched")
+}
+
+// Tries a case and then puts back the values its variables had before, as
+// staged binders do, so a case re-entered by a recursive call from its guard
+// or body leaves the outer run's bindings as they were.
+fn try_case<R>(binders: &[Box<Binder>], f: &mut FnMut() -> Option<R>) -> Option<R> {
+    let outer: Vec<Box<Any>> = binders.iter().map(|b| b.save()).collect();
+    let result = f();
+    for (b, v) in binders.iter().zip(outer) {
+        b.restore(v);
+    }
+    result
+}
+
+// As `try_case`, for a staged run, whose variables are kept in its frame.
+fn try_case_in<R>(ctx: &EvalContext, slots: &[Box<SlotBinder>], f: &mut FnMut() -> Option<R>) -> Option<R> {
+    let outer: Vec<Option<Binding>> = slots.iter().map(|s| ctx.save(s.index())).collect();
+    let result = f();
+    for (s, saved) in slots.iter().zip(outer) {
+        s.restore_in(ctx, saved);
+    }
+    result
+}
+
+impl<S: 'static+Clone, R: 'static+Clone> PatternMatchExp<S, R> {
+    pub fn case(mut self, pattern: Pattern, guard: Option<Box<Exp<Output=BoolVal>>>, body: Box<Exp<Output=R>>) -> PatternMatchExp<S, R> {
+        let mut binders = Vec::new();
+        pattern.binders(&mut binders);
+        self.cases.push(Case {
+            pattern,
+            binders,
+            guard,
+            body,
+        });
+        self
+    }
+}
+
+impl<S: 'static+Clone, R: 'static+Clone> Exp for PatternMatchExp<S, R> {
+    type Output = R;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box PatternMatchStagedExp {
+            staged_scrutinee: self.scrutinee.stage(),
+            staged_cases: self.cases.iter().map(|c| {
+                let scope = SlotScope::enter();
+                StagedCase {
+                    pattern: c.pattern.clone(),
+                    slots: c.binders.iter().map(|b| b.stage_slot(&scope)).collect(),
+                    staged_guard: c.guard.as_ref().map(|g| g.stage()),
+                    staged_body: c.body.stage(),
+                }
+            }).collect(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let v = self.scrutinee.interpret();
+        for c in &self.cases {
+            let result = try_case(&c.binders, &mut || {
+                if c.pattern.matches(&v) && c.guard.as_ref().map_or(true, |g| g.interpret().v) {
+                    Some(c.body.interpret())
+                } else {
+                    None
+                }
+            });
+            if let Some(result) = result {
+                return result;
+            }
+        }
+        no_case()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    // Binds every pattern variable. The children are the scrutinee and then
+    // a "case" node per case: [pattern, body] or [pattern, guard, body].
+    fn reify(&self) -> Expr {
+        let mut binds = Vec::new();
+        let mut children = vec![self.scrutinee.reify()];
+        for c in &self.cases {
+            c.pattern.binds(&mut binds);
+            let mut case = vec![c.pattern.reify()];
+            if let Some(ref g) = c.guard {
+                case.push(g.reify());
+            }
+            case.push(c.body.reify());
+            children.push(node("case", case));
+        }
+        binder("pmatch", binds, children)
+    }
+}
+
+impl<S: 'static+Clone, R: 'static+Clone> StagedExp for PatternMatchStagedExp<S, R> {
+    type Output = R;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let v = self.staged_scrutinee.run(ctx);
+        for c in &self.staged_cases {
+            let result = try_case_in(ctx, &c.slots, &mut || {
+                if c.pattern.matches_in(ctx, &c.slots, &v) && c.staged_guard.as_ref().map_or(true, |g| g.run(ctx).v) {
+                    Some(c.staged_body.run(ctx))
+                } else {
+                    None
+                }
+            });
+            if let Some(result) = result {
+                return result;
+            }
+        }
+        no_case()
+    }
+}
+
+// A match with no cases yet; add them with `case`.
+pub fn pattern_match_exp<S: 'static+Clone, R: 'static+Clone>(scrutinee: Box<Exp<Output=S>>) -> PatternMatchExp<S, R> {
+    PatternMatchExp {
+        scrutinee,
+        cases: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, StagedExp, EvalContext, VariableExp, BoolVal, NumVal, VariantVal, unit_exp, add_exp, less_than_exp};
+    use check::check;
+    use dynamic::DynVal;
+    use records::record_exp;
+    use super::*;
+
+    fn num(v: i64) -> Box<Exp<Output=NumVal>> {
+        box unit_exp(NumVal { v })
+    }
+
+    fn point(x: i64, y: i64) -> VariantVal {
+        VariantVal::new("some", record_exp().field("x", num(x)).field("y", num(y)).interpret())
+    }
+
+    fn classify(v: &VariableExp<VariantVal>, a: &VariableExp<NumVal>, b: &VariableExp<NumVal>) -> PatternMatchExp<VariantVal, NumVal> {
+        pattern_match_exp(box v.clone())
+            .case(variant("none", wild()), None, num(0))
+            .case(variant("some", record(vec![("x", lit(&NumVal { v: 0 }))])), None, num(-1))
+            .case(variant("some", record(vec![("x", bind(a)), ("y", bind(b))])),
+                  Some(box less_than_exp(box a.clone(), box b.clone())),
+                  box add_exp(box a.clone(), box b.clone()))
+            .case(wild(), None, num(100))
+    }
+
+    #[test]
+    fn cases_are_tried_in_order_with_guards() {
+        let v = VariableExp::fresh_with_val(VariantVal::new("none", NumVal { v: 0 }));
+        let (a, b) = (VariableExp::fresh_with_val(NumVal { v: 0 }), VariableExp::fresh_with_val(NumVal { v: 0 }));
+        let exp = classify(&v, &a, &b);
+        let staged = exp.stage();
+        for &(value, expected) in &[(None, 0), (Some((0, 5)), -1), (Some((2, 5)), 7), (Some((5, 2)), 100)] {
+            v.assign(match value {
+                Some((x, y)) => point(x, y),
+                None => VariantVal::new("none", NumVal { v: 0 }),
+            });
+            assert_eq!(exp.interpret().v, expected);
+            assert_eq!(staged.run(&EvalContext::new()).v, expected);
+        }
+    }
+
+    // The interpreter binds pattern variables in place, and puts back what
+    // they held once the match is done.
+    #[test]
+    fn pattern_variables_are_restored_after_the_match() {
+        let v = VariableExp::fresh_with_val(point(2, 5));
+        let (a, b) = (VariableExp::fresh_with_val(NumVal { v: 42 }), VariableExp::fresh_with_val(NumVal { v: 43 }));
+        assert_eq!(classify(&v, &a, &b).interpret().v, 7);
+        assert_eq!((a.var_val.borrow().v, b.var_val.borrow().v), (42, 43));
+    }
+
+    #[test]
+    fn checked_matches_must_cover_every_value_once() {
+        let n = VariableExp::fresh_with_val(NumVal { v: 0 });
+        let redundant = pattern_match_exp::<NumVal, NumVal>(num(3))
+            .case(wild(), None, num(1))
+            .case(lit(&NumVal { v: 3 }), None, num(2));
+        assert_eq!(check(&redundant.reify()).err().unwrap().to_string(),
+                   "match case 2 is redundant: the cases before it match everything it does");
+
+        let guarded = pattern_match_exp::<NumVal, NumVal>(num(3))
+            .case(bind(&n), Some(box less_than_exp(box n.clone(), num(0))), num(1));
+        assert!(check(&guarded.reify()).err().unwrap().to_string().starts_with("match doesn't cover"));
+
+        let both = pattern_match_exp::<BoolVal, NumVal>(box unit_exp(BoolVal { v: false }))
+            .case(lit(&BoolVal { v: true }), None, num(1))
+            .case(lit(&BoolVal { v: false }), None, num(2));
+        let typed = check(&both.reify()).ok().unwrap();
+        assert_eq!(typed.eval_interpreted(), DynVal::Num(2));
+        assert_eq!(typed.eval(), DynVal::Num(2));
+    }
+}
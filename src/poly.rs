@@ -0,0 +1,103 @@
+use std::ops::{Add, Mul};
+
+use {Val, VariableExp, NumVal, FloatVal};
+use {unit_exp, let_exp};
+use ops::E;
+use params::{FromHost, StagedFn, stage_fn};
+
+// Polynomials of one variable, built from their coefficients, lowest
+// degree first, in Horner form, so a polynomial of degree n takes n
+// multiplications and at most n additions:
+//
+//     horner(&[1, -3, 0, 2], x)  =>  1 + x * (-3 + x * (x * 2))
+//
+// The tree is specialized to the coefficients as it's built: a coefficient
+// that adds nothing is left out rather than added, a top coefficient of 1
+// isn't multiplied by, and integer zeros above the highest non-zero
+// coefficient don't raise the degree. Each is left out only where that
+// gives exactly what keeping it would; for floats, `x * 0.0` isn't 0.0 for
+// every x, nor is `y + 0.0` y, so only -0.0 is dropped from a sum. Floats
+// are rounded as Horner's rule rounds them, which isn't how a sum of
+// powers would be.
+//
+// `stage_poly` stages the polynomial as a function of x, for evaluating it
+// at many points; `bench::bench` on `horner` of a variable compares its
+// backends on it.
+
+// Values polynomials can have as coefficients.
+pub trait Coefficient: 'static+Clone+Default+FromHost+Add<Output=Self>+Mul<Output=Self> {
+    // Whether `y + self` is y for every y.
+    fn adds_nothing(&self) -> bool;
+
+    // Whether `y * self` is self for every y.
+    fn absorbs(&self) -> bool;
+
+    fn is_one(&self) -> bool;
+}
+
+impl Coefficient for NumVal {
+    fn adds_nothing(&self) -> bool {
+        self.v == 0
+    }
+
+    fn absorbs(&self) -> bool {
+        self.v == 0
+    }
+
+    fn is_one(&self) -> bool {
+        self.v == 1
+    }
+}
+
+impl Coefficient for FloatVal {
+    fn adds_nothing(&self) -> bool {
+        self.v == 0.0 && self.v.is_sign_negative()
+    }
+
+    fn absorbs(&self) -> bool {
+        false
+    }
+
+    fn is_one(&self) -> bool {
+        self.v == 1.0
+    }
+}
+
+// What a coefficient of type T is given as, i64 for NumVal and f64 for
+// FloatVal.
+type Host<T> = <T as Val>::Output;
+
+// The polynomial of `coeffs` at `x`, which is read once per multiplication.
+fn build<T: Coefficient>(coeffs: &[T], x: &VariableExp<T>) -> E<T> {
+    let degree = match coeffs.iter().rposition(|c| !c.absorbs()) {
+        Some(degree) => degree,
+        None => return E::new(unit_exp(T::default())),
+    };
+    let top = &coeffs[degree];
+    let mut acc = E::new(unit_exp(top.clone()));
+    let mut one = top.is_one();
+    for c in coeffs[..degree].iter().rev() {
+        let term = if one { E::from(x) } else { E::from(x) * acc };
+        acc = if c.adds_nothing() { term } else { E::new(unit_exp(c.clone())) + term };
+        one = false;
+    }
+    acc
+}
+
+fn coefficients<T: Coefficient>(coeffs: &[Host<T>]) -> Vec<T> where Host<T>: Clone {
+    coeffs.iter().cloned().map(T::from_host).collect()
+}
+
+// The polynomial with coefficients `coeffs`, lowest degree first, at `x`,
+// which is evaluated once.
+pub fn horner<T: Coefficient>(coeffs: &[Host<T>], x: E<T>) -> E<T> where Host<T>: Clone {
+    let coeffs = coefficients::<T>(coeffs);
+    E::new(let_exp(x.0, box move |x| build(&coeffs, &x).0))
+}
+
+// The polynomial with coefficients `coeffs`, staged as a function of x.
+pub fn stage_poly<T: Coefficient>(coeffs: &[Host<T>]) -> StagedFn<(Host<T>,), Host<T>>
+    where Host<T>: Clone {
+    let x = VariableExp::fresh();
+    stage_fn((x.clone(),), &*build(&coefficients::<T>(coeffs), &x).0)
+}
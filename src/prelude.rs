@@ -0,0 +1,71 @@
+use std::ops::Mul;
+
+use {Val, VariableExp, NumVal, FloatVal};
+use {unit_exp, let_exp};
+use ops::E;
+
+// Common numeric operations, built from let, if and the arithmetic nodes
+// rather than nodes of their own, so they stage, reify and load like any
+// other program. Each argument is evaluated once, in order.
+
+fn with<T, U>(a: E<T>, body: Box<Fn(VariableExp<T>) -> E<U>>) -> E<U>
+    where T: 'static+Clone+Default, U: 'static+Clone {
+    E::new(let_exp(a.0, box move |x| body(x).0))
+}
+
+pub fn min<T: 'static+Clone+Default+Val+Ord>(a: E<T>, b: E<T>) -> E<T> {
+    with(a, box move |x| with(b.clone(), box move |y| E::from(&y).lt(&x).select(E::from(&y), E::from(&x))))
+}
+
+pub fn max<T: 'static+Clone+Default+Val+Ord>(a: E<T>, b: E<T>) -> E<T> {
+    with(a, box move |x| with(b.clone(), box move |y| E::from(&x).lt(&y).select(E::from(&y), E::from(&x))))
+}
+
+// `x` limited to between `lo` and `hi`. If `hi` is below `lo`, the result is
+// `lo` for x below it and `hi` otherwise.
+pub fn clamp<T: 'static+Clone+Default+Val+Ord>(x: E<T>, lo: E<T>, hi: E<T>) -> E<T> {
+    with(x, box move |x| {
+        let hi = hi.clone();
+        with(lo.clone(), box move |lo| {
+            let x = x.clone();
+            with(hi.clone(), box move |hi| {
+                E::from(&x).lt(&lo).select(E::from(&lo),
+                    E::from(&hi).lt(&x).select(E::from(&hi), E::from(&x)))
+            })
+        })
+    })
+}
+
+pub fn abs(x: E<NumVal>) -> E<NumVal> {
+    with(x, box |x| E::from(&x).lt(0i64).select(E::new(unit_exp(NumVal { v: 0 })) - &x, E::from(&x)))
+}
+
+// `x` to the power `n`, by squaring: the exponent is known when the program
+// is built, so this unrolls into about 2 log n multiplications.
+pub fn pow(x: E<NumVal>, n: u32) -> E<NumVal> {
+    pow_of(x, n, NumVal { v: 1 })
+}
+
+pub fn powf(x: E<FloatVal>, n: u32) -> E<FloatVal> {
+    pow_of(x, n, FloatVal { v: 1.0 })
+}
+
+fn pow_of<T: 'static+Clone+Default+Val+Mul<Output=T>>(x: E<T>, n: u32, one: T) -> E<T> {
+    with(x, box move |x| {
+        if n == 0 {
+            E::new(unit_exp(one.clone()))
+        } else {
+            squarings(x, n)
+        }
+    })
+}
+
+fn squarings<T: 'static+Clone+Default+Val+Mul<Output=T>>(x: VariableExp<T>, n: u32) -> E<T> {
+    if n == 1 {
+        E::from(x)
+    } else if n % 2 == 0 {
+        with(E::from(&x) * &x, box move |y| squarings(y, n / 2))
+    } else {
+        squarings(x.clone(), n - 1) * x
+    }
+}
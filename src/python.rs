@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+
+use pyo3::prelude::*;
+use pyo3::exceptions::{PyKeyError, PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::types::{PyBool, PyDict, PyFloat, PyLong, PyString};
+
+use {NumVal, BoolVal, UnitVal, StrVal, FloatVal};
+use check::{Ty, Typed, check_open};
+use dynamic::DynVal;
+use patterns::Binder;
+use reify::{Expr, Value, node};
+
+// Python bindings, as a `tagless` module. Programs are written as Expr trees,
+// with Python's arithmetic operators building the matching nodes, and checked
+// into a Program whose inputs are set from Python values before each run:
+//
+//     x = Expr.var(1)
+//     p = Program(x * x + 1, {1: "num"})
+//     p.set(1, 7)
+//     p.run()        # 50, through the staged form
+//
+// Results come back as Python values: records as dicts and variants as
+// (tag, value) tuples. A run that fails raises RuntimeError rather than
+// taking the interpreter down. Programs hold closures and shared cells, so
+// they stay on the thread that made them.
+//
+// The crate builds as a binary, so for now the module is loaded from a build
+// of it as a library, e.g. with maturin.
+
+#[pyclass(name = "Expr")]
+#[derive(Clone)]
+pub struct PyExpr {
+    expr: Expr,
+}
+
+// Python ints, floats, bools, strings and None as constants, for operands
+// like the 1 in `x + 1`.
+fn operand(v: &Bound<PyAny>) -> PyResult<Expr> {
+    if let Ok(e) = v.extract::<PyExpr>() {
+        return Ok(e.expr);
+    }
+    let value = if v.is_none() {
+        Value::Unit
+    } else if v.is_instance_of::<PyBool>() {
+        Value::Bool(v.extract()?)
+    } else if v.is_instance_of::<PyLong>() {
+        Value::Num(v.extract()?)
+    } else if v.is_instance_of::<PyFloat>() {
+        Value::Float(v.extract::<f64>()?.to_bits())
+    } else if v.is_instance_of::<PyString>() {
+        Value::Str(v.extract()?)
+    } else {
+        return Err(PyTypeError::new_err(format!("can't make an Expr of {}", v.get_type().name()?)));
+    };
+    Ok(Expr::Const(value))
+}
+
+fn binary(kind: &str, a: Expr, b: &Bound<PyAny>) -> PyResult<PyExpr> {
+    Ok(PyExpr { expr: node(kind, vec![a, operand(b)?]) })
+}
+
+fn reversed(kind: &str, a: &Bound<PyAny>, b: Expr) -> PyResult<PyExpr> {
+    Ok(PyExpr { expr: node(kind, vec![operand(a)?, b]) })
+}
+
+#[pymethods]
+impl PyExpr {
+    #[staticmethod]
+    fn num(v: i64) -> PyExpr {
+        PyExpr { expr: Expr::Const(Value::Num(v)) }
+    }
+
+    #[staticmethod]
+    fn boolean(v: bool) -> PyExpr {
+        PyExpr { expr: Expr::Const(Value::Bool(v)) }
+    }
+
+    #[staticmethod]
+    fn unit() -> PyExpr {
+        PyExpr { expr: Expr::Const(Value::Unit) }
+    }
+
+    #[staticmethod]
+    fn float(v: f64) -> PyExpr {
+        PyExpr { expr: Expr::Const(Value::Float(v.to_bits())) }
+    }
+
+    #[staticmethod]
+    fn string(v: String) -> PyExpr {
+        PyExpr { expr: Expr::Const(Value::Str(v)) }
+    }
+
+    #[staticmethod]
+    fn var(id: i32) -> PyExpr {
+        PyExpr { expr: Expr::Var(id) }
+    }
+
+    // A node of any kind `check` knows, e.g.
+    // `Expr.node("let", [Expr.num(1), body], binds=[2])`.
+    #[staticmethod]
+    #[pyo3(signature = (kind, children, binds = Vec::new()))]
+    fn node(kind: &str, children: Vec<Bound<PyAny>>, binds: Vec<i32>) -> PyResult<PyExpr> {
+        let children = children.iter().map(operand).collect::<PyResult<Vec<Expr>>>()?;
+        Ok(PyExpr {
+            expr: Expr::Node {
+                kind: kind.to_string(),
+                binds,
+                children,
+            },
+        })
+    }
+
+    fn __add__(&self, other: &Bound<PyAny>) -> PyResult<PyExpr> {
+        binary("add", self.expr.clone(), other)
+    }
+
+    fn __radd__(&self, other: &Bound<PyAny>) -> PyResult<PyExpr> {
+        reversed("add", other, self.expr.clone())
+    }
+
+    fn __sub__(&self, other: &Bound<PyAny>) -> PyResult<PyExpr> {
+        binary("sub", self.expr.clone(), other)
+    }
+
+    fn __rsub__(&self, other: &Bound<PyAny>) -> PyResult<PyExpr> {
+        reversed("sub", other, self.expr.clone())
+    }
+
+    fn __mul__(&self, other: &Bound<PyAny>) -> PyResult<PyExpr> {
+        binary("mul", self.expr.clone(), other)
+    }
+
+    fn __rmul__(&self, other: &Bound<PyAny>) -> PyResult<PyExpr> {
+        reversed("mul", other, self.expr.clone())
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.expr)
+    }
+}
+
+fn ty_named(name: &str) -> PyResult<Ty> {
+    match name {
+        "num" => Ok(Ty::Num),
+        "bool" => Ok(Ty::Bool),
+        "unit" => Ok(Ty::Unit),
+        "str" => Ok(Ty::Str),
+        "float" => Ok(Ty::Float),
+        _ => Err(PyValueError::new_err(format!("inputs can't be {}", name))),
+    }
+}
+
+fn to_py(py: Python, v: DynVal) -> PyResult<PyObject> {
+    Ok(match v {
+        DynVal::Num(n) => n.into_py(py),
+        DynVal::Bool(b) => b.into_py(py),
+        DynVal::Unit => py.None(),
+        DynVal::Str(s) => s.into_py(py),
+        DynVal::Float(x) => x.into_py(py),
+        DynVal::Record(fields) => {
+            let dict = PyDict::new_bound(py);
+            for (name, v) in fields {
+                dict.set_item(name, to_py(py, v)?)?;
+            }
+            dict.into_py(py)
+        }
+        DynVal::Variant(tag, v) => (tag, to_py(py, *v)?).into_py(py),
+    })
+}
+
+type Eval = Box<Fn() -> DynVal>;
+
+#[pyclass(name = "Program", unsendable)]
+pub struct PyProgram {
+    typed: Typed,
+    inputs: Vec<(i32, Ty, Box<Binder>)>,
+    staged: Option<Eval>,
+    compiled: Option<Eval>,
+}
+
+impl PyProgram {
+    fn eval(&self, py: Python, f: &Fn() -> DynVal) -> PyResult<PyObject> {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(v) => to_py(py, v),
+            Err(err) => Err(PyRuntimeError::new_err(err.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| err.downcast_ref::<String>().cloned())
+                .unwrap_or_default())),
+        }
+    }
+}
+
+#[pymethods]
+impl PyProgram {
+    // Checks `expr`, whose free variables are the inputs, given as a dict of
+    // id to type name: "num", "bool", "unit", "str" or "float". Inputs start
+    // as their type's default. Raises TypeError if it doesn't check.
+    #[new]
+    #[pyo3(signature = (expr, inputs = HashMap::new()))]
+    fn new(expr: PyRef<PyExpr>, inputs: HashMap<i32, String>) -> PyResult<PyProgram> {
+        let mut inputs = inputs.into_iter()
+            .map(|(id, ty)| Ok((id, ty_named(&ty)?)))
+            .collect::<PyResult<Vec<(i32, Ty)>>>()?;
+        inputs.sort_by_key(|input| input.0);
+        let (typed, binders) = check_open(&expr.expr, &inputs)
+            .map_err(|err| PyTypeError::new_err(err.to_string()))?;
+        Ok(PyProgram {
+            typed,
+            inputs: inputs.into_iter().zip(binders).map(|((id, ty), b)| (id, ty, b)).collect(),
+            staged: None,
+            compiled: None,
+        })
+    }
+
+    fn set(&self, id: i32, value: &Bound<PyAny>) -> PyResult<()> {
+        let &(_, ref ty, ref binder) = self.inputs.iter().find(|input| input.0 == id)
+            .ok_or_else(|| PyKeyError::new_err(format!("the program has no input {}", id)))?;
+        match *ty {
+            Ty::Num => binder.bind(&NumVal { v: value.extract()? }),
+            Ty::Bool => binder.bind(&BoolVal { v: value.extract()? }),
+            Ty::Unit => binder.bind(&UnitVal),
+            Ty::Str => binder.bind(&StrVal { v: value.extract()? }),
+            Ty::Float => binder.bind(&FloatVal { v: value.extract()? }),
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn interpret(&self, py: Python) -> PyResult<PyObject> {
+        let typed = &self.typed;
+        self.eval(py, &|| typed.eval_interpreted())
+    }
+
+    // Runs the staged form, staging the program on the first run.
+    fn run(&mut self, py: Python) -> PyResult<PyObject> {
+        if self.staged.is_none() {
+            self.staged = Some(self.typed.stage_eval());
+        }
+        self.eval(py, self.staged.as_ref().unwrap())
+    }
+
+    // Runs the form compiled to closures, compiling on the first run.
+    fn run_compiled(&mut self, py: Python) -> PyResult<PyObject> {
+        if self.compiled.is_none() {
+            self.compiled = Some(self.typed.compile_eval());
+        }
+        self.eval(py, self.compiled.as_ref().unwrap())
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Program({:?})", self.typed.reify())
+    }
+}
+
+#[pymodule]
+fn tagless(m: &Bound<PyModule>) -> PyResult<()> {
+    m.add_class::<PyExpr>()?;
+    m.add_class::<PyProgram>()?;
+    Ok(())
+}
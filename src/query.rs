@@ -0,0 +1,313 @@
+use std::fmt;
+
+use {VariableExp, NumVal, BoolVal, unit_exp};
+use ops::E;
+use batch::{StagedBatch, stage_batch};
+
+// A small SQL-like frontend for predicates and projections over named
+// columns of numbers, so a data tool can hand over `a + 1 < b AND c = 3` as
+// text rather than build the tree itself:
+//
+//     condition := or
+//     or        := and ("OR" and)*
+//     and       := not ("AND" not)*
+//     not       := "NOT" not | compare
+//     compare   := sum (("<" | "<=" | ">" | ">=" | "=" | "!=" | "<>") sum)?
+//     sum       := product (("+" | "-") product)*
+//     product   := unary ("*" unary)*
+//     unary     := "-" unary | number | column | "TRUE" | "FALSE" | "(" or ")"
+//
+// Keywords are any case; column names are case-sensitive. Each column named
+// becomes an input variable of the query. There are no boolean or equality
+// nodes, so AND, OR, NOT and the other comparisons are written with ifs and
+// `lt`, with an operand compared twice where needed; since what a query
+// computes is only arithmetic, that costs time but changes nothing, and it
+// keeps queries to the nodes that `batch` runs on columns.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    // Byte offset into the query.
+    pub pos: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at {}: {}", self.pos, self.msg)
+    }
+}
+
+fn err<T>(pos: usize, msg: &str) -> Result<T, QueryError> {
+    Err(QueryError {
+        pos,
+        msg: msg.to_string(),
+    })
+}
+
+// A parsed query and the columns it reads, in the order they first appear.
+pub struct Query<T: 'static> {
+    pub exp: E<T>,
+    pub columns: Vec<(String, VariableExp<NumVal>)>,
+}
+
+impl<T: 'static+Clone> Query<T> {
+    pub fn column(&self, name: &str) -> Option<&VariableExp<NumVal>> {
+        self.columns.iter().find(|c| c.0 == name).map(|c| &c.1)
+    }
+
+    // Stages the query for `run_batch`, taking one column of values for
+    // each of `columns`, in order.
+    pub fn stage_batch(&self) -> StagedBatch<T> {
+        let inputs: Vec<VariableExp<NumVal>> = self.columns.iter().map(|c| c.1.clone()).collect();
+        stage_batch(&self.exp, &inputs)
+    }
+}
+
+enum Term {
+    Num(E<NumVal>),
+    Bool(E<BoolVal>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+fn constant(v: bool) -> E<BoolVal> {
+    E::new(unit_exp(BoolVal { v }))
+}
+
+fn not(a: E<BoolVal>) -> E<BoolVal> {
+    a.select(constant(false), constant(true))
+}
+
+fn compare(cmp: Cmp, a: E<NumVal>, b: E<NumVal>) -> E<BoolVal> {
+    match cmp {
+        Cmp::Lt => a.lt(b),
+        Cmp::Le => not(b.lt(a)),
+        Cmp::Gt => b.lt(a),
+        Cmp::Ge => not(a.lt(b)),
+        Cmp::Eq => a.clone().lt(b.clone()).select(constant(false), not(b.lt(a))),
+        Cmp::Ne => a.clone().lt(b.clone()).select(constant(true), b.lt(a)),
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    columns: Vec<(String, VariableExp<NumVal>)>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_ws(&mut self) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn rest(&mut self) -> &'a str {
+        self.skip_ws();
+        &self.src[self.pos..]
+    }
+
+    // Takes `sym` if it's next.
+    fn symbol(&mut self, sym: &str) -> bool {
+        if self.rest().starts_with(sym) {
+            self.pos += sym.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn word(&mut self) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+        &rest[..len]
+    }
+
+    // Takes the keyword `kw`, in any case, if it's next.
+    fn keyword(&mut self, kw: &str) -> bool {
+        let word = self.word();
+        if word.eq_ignore_ascii_case(kw) {
+            self.pos += word.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn num(&self, pos: usize, t: Term) -> Result<E<NumVal>, QueryError> {
+        match t {
+            Term::Num(e) => Ok(e),
+            Term::Bool(_) => err(pos, "expected a number, found a condition"),
+        }
+    }
+
+    fn cond(&self, pos: usize, t: Term) -> Result<E<BoolVal>, QueryError> {
+        match t {
+            Term::Bool(e) => Ok(e),
+            Term::Num(_) => err(pos, "expected a condition, found a number"),
+        }
+    }
+
+    fn or(&mut self) -> Result<Term, QueryError> {
+        let pos = self.pos;
+        let mut t = self.and()?;
+        while self.keyword("or") {
+            let a = self.cond(pos, t)?;
+            let pos = self.pos;
+            let b = self.and()?;
+            let b = self.cond(pos, b)?;
+            t = Term::Bool(a.select(constant(true), b));
+        }
+        Ok(t)
+    }
+
+    fn and(&mut self) -> Result<Term, QueryError> {
+        let pos = self.pos;
+        let mut t = self.not()?;
+        while self.keyword("and") {
+            let a = self.cond(pos, t)?;
+            let pos = self.pos;
+            let b = self.not()?;
+            let b = self.cond(pos, b)?;
+            t = Term::Bool(a.select(b, constant(false)));
+        }
+        Ok(t)
+    }
+
+    fn not(&mut self) -> Result<Term, QueryError> {
+        if self.keyword("not") {
+            let pos = self.pos;
+            let t = self.not()?;
+            return Ok(Term::Bool(not(self.cond(pos, t)?)));
+        }
+        self.compare()
+    }
+
+    fn compare(&mut self) -> Result<Term, QueryError> {
+        let pos = self.pos;
+        let t = self.sum()?;
+        // Longest first, so `<=` isn't taken as `<`.
+        let ops = [("<=", Cmp::Le), (">=", Cmp::Ge), ("<>", Cmp::Ne), ("!=", Cmp::Ne),
+                   ("<", Cmp::Lt), (">", Cmp::Gt), ("=", Cmp::Eq)];
+        let cmp = match ops.iter().find(|op| self.symbol(op.0)) {
+            Some(op) => op.1,
+            None => return Ok(t),
+        };
+        let a = self.num(pos, t)?;
+        let pos = self.pos;
+        let b = self.sum()?;
+        let b = self.num(pos, b)?;
+        Ok(Term::Bool(compare(cmp, a, b)))
+    }
+
+    fn sum(&mut self) -> Result<Term, QueryError> {
+        let pos = self.pos;
+        let mut t = self.product()?;
+        loop {
+            let add = if self.symbol("+") {
+                true
+            } else if self.symbol("-") {
+                false
+            } else {
+                return Ok(t);
+            };
+            let a = self.num(pos, t)?;
+            let pos = self.pos;
+            let b = self.product()?;
+            let b = self.num(pos, b)?;
+            t = Term::Num(if add { a + b } else { a - b });
+        }
+    }
+
+    fn product(&mut self) -> Result<Term, QueryError> {
+        let pos = self.pos;
+        let mut t = self.unary()?;
+        while self.symbol("*") {
+            let a = self.num(pos, t)?;
+            let pos = self.pos;
+            let b = self.unary()?;
+            let b = self.num(pos, b)?;
+            t = Term::Num(a * b);
+        }
+        Ok(t)
+    }
+
+    fn unary(&mut self) -> Result<Term, QueryError> {
+        if self.symbol("-") {
+            let pos = self.pos;
+            let t = self.unary()?;
+            let a = self.num(pos, t)?;
+            return Ok(Term::Num(E::from(0) - a));
+        }
+        if self.symbol("(") {
+            let t = self.or()?;
+            if !self.symbol(")") {
+                return err(self.pos, "expected ')'");
+            }
+            return Ok(t);
+        }
+        let pos = self.pos;
+        let word = self.word();
+        if word.is_empty() {
+            return err(pos, if self.rest().is_empty() { "unexpected end of query" } else { "unexpected character" });
+        }
+        self.pos += word.len();
+        if word.as_bytes()[0].is_ascii_digit() {
+            return match word.parse::<i64>() {
+                Ok(v) => Ok(Term::Num(E::from(v))),
+                Err(_) => err(pos, "bad number"),
+            };
+        }
+        if word.eq_ignore_ascii_case("true") || word.eq_ignore_ascii_case("false") {
+            return Ok(Term::Bool(constant(word.eq_ignore_ascii_case("true"))));
+        }
+        if ["and", "or", "not"].iter().any(|kw| word.eq_ignore_ascii_case(kw)) {
+            return err(pos, "expected an operand");
+        }
+        let var = match self.columns.iter().find(|c| c.0 == word) {
+            Some(c) => c.1.clone(),
+            None => {
+                let var = VariableExp::fresh();
+                self.columns.push((word.to_string(), var.clone()));
+                var
+            }
+        };
+        Ok(Term::Num(E::from(var)))
+    }
+}
+
+fn parse(src: &str) -> Result<(Term, Vec<(String, VariableExp<NumVal>)>), QueryError> {
+    let mut p = Parser {
+        src,
+        pos: 0,
+        columns: Vec::new(),
+    };
+    let t = p.or()?;
+    if !p.rest().is_empty() {
+        return err(p.pos, "unexpected text after the query");
+    }
+    Ok((t, p.columns))
+}
+
+// A condition over columns, like `a + 1 < b AND c = 3`.
+pub fn parse_filter(src: &str) -> Result<Query<BoolVal>, QueryError> {
+    match parse(src)? {
+        (Term::Bool(exp), columns) => Ok(Query { exp, columns }),
+        (Term::Num(_), _) => err(0, "expected a condition, found a number"),
+    }
+}
+
+// A number computed from columns, like `price * quantity - discount`.
+pub fn parse_projection(src: &str) -> Result<Query<NumVal>, QueryError> {
+    match parse(src)? {
+        (Term::Num(exp), columns) => Ok(Query { exp, columns }),
+        (Term::Bool(_), _) => err(0, "expected a number, found a condition"),
+    }
+}
@@ -0,0 +1,370 @@
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+use {Exp, StagedExp, EvalContext, SlotScope, Slot, VariableExp, fresh_id};
+use sandbox;
+
+type RecBody<A, R> = Fn(RecFn<A, R>, VariableExp<A>) -> Box<Exp<Output=R>>;
+
+// The body of one of a group of functions, given handles to all of them.
+type GroupBody<A, R> = Fn(&[RecFn<A, R>], VariableExp<A>) -> Box<Exp<Output=R>>;
+
+// Handle to one of a group of recursive functions, passed to the bodies so
+// they can call themselves and each other.
+#[derive(Clone)]
+pub struct RecFn<A: 'static+Clone, R: 'static+Clone> {
+    // The group's.
+    id: i32,
+    index: usize,
+    shared: Weak<RecShared<A, R>>,
+    // Set by a call in tail position to a function of the same group, with
+    // which one; the caller loops instead of recursing.
+    pending: Rc<RefCell<Option<(usize, A)>>>,
+}
+
+// A function's body, staged once and shared by every call to it. The
+// argument is passed to the call, which binds it in the run's context the
+// way staged binders bind their variables (see `Local`), so a recursive
+// call leaves the caller's argument and locals as they were when it
+// returns.
+struct StagedFn<A: 'static+Clone, R: 'static+Clone> {
+    arg: Slot<A>,
+    body: Box<StagedExp<Output=R>>,
+    pending: Rc<RefCell<Option<(usize, A)>>>,
+}
+
+pub struct RecShared<A: 'static+Clone, R: 'static+Clone> {
+    id: i32,
+    bodies: Vec<Rc<GroupBody<A, R>>>,
+    // The variables in scope where the group was staged, which the bodies
+    // are staged in.
+    scope: Rc<Vec<i32>>,
+    // Staged together by the first staged call.
+    staged: RefCell<Option<Rc<Vec<StagedFn<A, R>>>>>,
+}
+
+fn new_shared<A: 'static+Clone, R: 'static+Clone>(bodies: &[Rc<GroupBody<A, R>>]) -> Rc<RecShared<A, R>> {
+    Rc::new(RecShared {
+        id: fresh_id(),
+        bodies: bodies.to_vec(),
+        scope: SlotScope::current(),
+        staged: RefCell::new(None),
+    })
+}
+
+fn handles<A: 'static+Clone, R: 'static+Clone>(shared: &Rc<RecShared<A, R>>,
+                                                pending: Rc<RefCell<Option<(usize, A)>>>) -> Vec<RecFn<A, R>> {
+    (0..shared.bodies.len()).map(|index| RecFn {
+        id: shared.id,
+        index,
+        shared: Rc::downgrade(shared),
+        pending: pending.clone(),
+    }).collect()
+}
+
+fn staged_fns<A: 'static+Clone+Default, R: 'static+Clone+Default>(shared: &Rc<RecShared<A, R>>) -> Rc<Vec<StagedFn<A, R>>> {
+    if let Some(ref fns) = *shared.staged.borrow() {
+        return fns.clone();
+    }
+    let fns = Rc::new(shared.bodies.iter().map(|body| SlotScope::resume(&shared.scope, || {
+        let pending = Rc::new(RefCell::new(None));
+        let arg = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        StagedFn {
+            arg: scope.bind(&arg),
+            body: body(&handles(shared, pending.clone()), arg).stage_tail(shared.id),
+            pending,
+        }
+    })).collect::<Vec<_>>());
+    *shared.staged.borrow_mut() = Some(fns.clone());
+    fns
+}
+
+fn run_call<A: 'static+Clone+Default, R: 'static+Clone+Default>(ctx: &EvalContext, shared: &Rc<RecShared<A, R>>,
+                                                                 index: usize, arg: A) -> R {
+    let fns = staged_fns(shared);
+    let mut index = index;
+    let mut arg = arg;
+    loop {
+        sandbox::step();
+        let f = &fns[index];
+        let result = {
+            let mut binding = ctx.bind(&f.arg);
+            binding.set(arg);
+            f.body.run(ctx)
+        };
+        let next = f.pending.borrow_mut().take();
+        match next {
+            Some((next_index, next)) => {
+                index = next_index;
+                arg = next;
+            }
+            None => return result,
+        }
+    }
+}
+
+fn interpret_call<A: 'static+Clone+Default, R: 'static+Clone+Default>(shared: &Rc<RecShared<A, R>>, index: usize, arg: A) -> R {
+    let mut index = index;
+    let mut arg = arg;
+    loop {
+        sandbox::step();
+        let pending = Rc::new(RefCell::new(None));
+        let body = (shared.bodies[index])(&handles(shared, pending.clone()), VariableExp::fresh_with_val(arg));
+        let result = body.interpret_tail(shared.id);
+        let next = pending.borrow_mut().take();
+        match next {
+            Some((next_index, next)) => {
+                index = next_index;
+                arg = next;
+            }
+            None => return result,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RecExp<A: 'static+Clone, R: 'static+Clone> {
+    arg: Box<Exp<Output=A>>,
+    // A group of one.
+    bodies: Vec<Rc<GroupBody<A, R>>>,
+}
+
+pub struct RecStagedExp<A: 'static+Clone, R: 'static+Clone> {
+    staged_arg: Box<StagedExp<Output=A>>,
+    shared: Rc<RecShared<A, R>>,
+}
+
+impl<A: 'static+Clone+Default, R: 'static+Clone+Default> Exp for RecExp<A, R> {
+    type Output = R;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box RecStagedExp {
+            staged_arg: self.arg.stage(),
+            shared: new_shared(&self.bodies),
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        interpret_call(&new_shared(&self.bodies), 0, self.arg.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl<A: 'static+Clone+Default, R: 'static+Clone+Default> StagedExp for RecStagedExp<A, R> {
+    type Output = R;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        run_call(ctx, &self.shared, 0, self.staged_arg.run(ctx))
+    }
+}
+
+#[derive(Clone)]
+pub struct CallExp<A: 'static+Clone, R: 'static+Clone> {
+    f: RecFn<A, R>,
+    arg: Box<Exp<Output=A>>,
+}
+
+pub struct CallStagedExp<A: 'static+Clone, R: 'static+Clone> {
+    staged_arg: Box<StagedExp<Output=A>>,
+    index: usize,
+    shared: Weak<RecShared<A, R>>,
+}
+
+pub struct TailCallStagedExp<A: 'static+Clone, R: 'static+Clone> {
+    staged_arg: Box<StagedExp<Output=A>>,
+    index: usize,
+    pending: Rc<RefCell<Option<(usize, A)>>>,
+    result: R,
+}
+
+impl<A: 'static+Clone+Default, R: 'static+Clone+Default> Exp for CallExp<A, R> {
+    type Output = R;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box CallStagedExp {
+            staged_arg: self.arg.stage(),
+            index: self.f.index,
+            shared: self.f.shared.clone(),
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        let shared = self.f.shared.upgrade().expect("call outside of its recursive function");
+        interpret_call(&shared, self.f.index, self.arg.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn stage_tail(&self, fn_id: i32) -> Box<StagedExp<Output=Self::Output>> {
+        if fn_id != self.f.id {
+            return self.stage();
+        }
+        box TailCallStagedExp {
+            staged_arg: self.arg.stage(),
+            index: self.f.index,
+            pending: self.f.pending.clone(),
+            result: R::default(),
+        }
+    }
+
+    fn interpret_tail(&self, fn_id: i32) -> Self::Output {
+        if fn_id != self.f.id {
+            return self.interpret();
+        }
+        *self.f.pending.borrow_mut() = Some((self.f.index, self.arg.interpret()));
+        R::default()
+    }
+}
+
+impl<A: 'static+Clone+Default, R: 'static+Clone+Default> StagedExp for CallStagedExp<A, R> {
+    type Output = R;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let shared = self.shared.upgrade().expect("call outside of its recursive function");
+        run_call(ctx, &shared, self.index, self.staged_arg.run(ctx))
+    }
+}
+
+impl<A: 'static+Clone, R: 'static+Clone> StagedExp for TailCallStagedExp<A, R> {
+    type Output = R;
+
+    // The value is discarded: the enclosing call loops with the pending argument.
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        *self.pending.borrow_mut() = Some((self.index, self.staged_arg.run(ctx)));
+        self.result.clone()
+    }
+}
+
+// Binds a group of functions from A to R whose bodies may call each other,
+// numbered in the order they're added, for the body of the let. Calls in
+// tail position to any function of the group loop rather than recurse, so
+// e.g. even and odd run in constant stack.
+#[derive(Clone)]
+pub struct LetRecExp<A: 'static+Clone, R: 'static+Clone, T: 'static+Clone> {
+    bodies: Vec<Rc<GroupBody<A, R>>>,
+    body: Rc<Fn(&[RecFn<A, R>]) -> Box<Exp<Output=T>>>,
+}
+
+pub struct LetRecStagedExp<A: 'static+Clone, R: 'static+Clone, T: 'static+Clone> {
+    // Keeps the group alive for the handles in the staged body.
+    _shared: Rc<RecShared<A, R>>,
+    staged_body: Box<StagedExp<Output=T>>,
+}
+
+impl<A: 'static+Clone, R: 'static+Clone, T: 'static+Clone> LetRecExp<A, R, T> {
+    pub fn func(mut self, body: Box<GroupBody<A, R>>) -> LetRecExp<A, R, T> {
+        self.bodies.push(Rc::from(body));
+        self
+    }
+
+    // The handles the let's body gets. Their pending cell is never read,
+    // since the let's body isn't a function of the group.
+    fn handles(&self, shared: &Rc<RecShared<A, R>>) -> Vec<RecFn<A, R>> {
+        handles(shared, Rc::new(RefCell::new(None)))
+    }
+}
+
+impl<A: 'static+Clone+Default, R: 'static+Clone+Default, T: 'static+Clone> Exp for LetRecExp<A, R, T> {
+    type Output = T;
+
+    // Every function gets its handle before any body is staged; the bodies
+    // themselves are staged on the first call that reaches them.
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let shared = new_shared(&self.bodies);
+        let staged_body = (self.body)(&self.handles(&shared)).stage();
+        box LetRecStagedExp {
+            _shared: shared,
+            staged_body,
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        let shared = new_shared(&self.bodies);
+        (self.body)(&self.handles(&shared)).interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl<A: 'static+Clone, R: 'static+Clone, T: 'static+Clone> StagedExp for LetRecStagedExp<A, R, T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_body.run(ctx)
+    }
+}
+
+pub fn rec_exp<A: 'static+Clone+Default, R: 'static+Clone+Default>(arg: Box<Exp<Output=A>>,
+                                                                   body: Box<RecBody<A, R>>) -> RecExp<A, R> {
+    let body: Rc<RecBody<A, R>> = Rc::from(body);
+    RecExp {
+        arg,
+        bodies: vec![Rc::new(move |fs: &[RecFn<A, R>], x| body(fs[0].clone(), x))],
+    }
+}
+
+pub fn call_exp<A: 'static+Clone+Default, R: 'static+Clone+Default>(f: RecFn<A, R>,
+                                                                    arg: Box<Exp<Output=A>>) -> CallExp<A, R> {
+    CallExp {
+        f,
+        arg
+    }
+}
+
+// A group with no functions yet; add them with `func`.
+pub fn let_rec_exp<A: 'static+Clone+Default, R: 'static+Clone+Default, T: 'static+Clone>(
+    body: Box<Fn(&[RecFn<A, R>]) -> Box<Exp<Output=T>>>) -> LetRecExp<A, R, T> {
+    LetRecExp {
+        bodies: vec![],
+        body: Rc::from(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, EvalContext, NumVal, VariableExp};
+    use {unit_exp, add_exp, sub_exp, mul_exp, less_than_exp, if_exp, let_exp};
+    use super::{RecFn, rec_exp, call_exp};
+
+    fn num(v: i64) -> Box<Exp<Output=NumVal>> {
+        box unit_exp(NumVal { v })
+    }
+
+    // f(n) = if n < 1 then 0 else let y = n * 10 in f(n - 1) + (n + y),
+    // which reads its argument and a local after the call it makes returns.
+    fn sum_after_call(n: i64) -> Box<Exp<Output=NumVal>> {
+        box rec_exp(num(n), box |f: RecFn<NumVal, NumVal>, n: VariableExp<NumVal>| -> Box<Exp<Output=NumVal>> {
+            let arg = n.clone();
+            let rest = move |y: VariableExp<NumVal>| -> Box<Exp<Output=NumVal>> {
+                let call = call_exp(f.clone(), box sub_exp(box arg.clone(), num(1)));
+                box add_exp(box call, box add_exp(box arg.clone(), box y))
+            };
+            box if_exp(box less_than_exp(box n.clone(), num(1)),
+                       num(0),
+                       box let_exp(box mul_exp(box n.clone(), num(10)), box rest))
+        })
+    }
+
+    // Each call of the staged body binds its own argument and local, so the
+    // caller's are as they were when the call it made returns.
+    #[test]
+    fn recursive_calls_leave_the_callers_bindings() {
+        for n in 0..20 {
+            let want = (1..n + 1).map(|i| i * 11).sum::<i64>();
+            let exp = sum_after_call(n);
+            assert_eq!(exp.interpret().v, want);
+            let staged = exp.stage();
+            assert_eq!(staged.run(&EvalContext::new()).v, want);
+            // The same staged function runs again from the top.
+            assert_eq!(staged.run(&EvalContext::new()).v, want);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, RecordVal, VariantVal};
+use ops::E;
+use reify::{Expr, Value, node, value_of};
+use sandbox;
+
+// A field's expression with its type erased, so fields of different types
+// can be held together.
+pub trait FieldExp {
+    fn interpret_field(&self) -> Rc<Any>;
+    fn stage_field(&self) -> Box<StagedFieldExp>;
+    fn reify_field(&self) -> Expr;
+    fn clone_field(&self) -> Box<FieldExp>;
+}
+
+pub trait StagedFieldExp {
+    fn run_field(&self, ctx: &EvalContext) -> Rc<Any>;
+}
+
+impl<T: 'static+Clone> FieldExp for Box<Exp<Output=T>> {
+    fn interpret_field(&self) -> Rc<Any> {
+        Rc::new(self.interpret())
+    }
+
+    fn stage_field(&self) -> Box<StagedFieldExp> {
+        box self.stage()
+    }
+
+    fn reify_field(&self) -> Expr {
+        self.reify()
+    }
+
+    fn clone_field(&self) -> Box<FieldExp> {
+        box self.clone()
+    }
+}
+
+impl<T: 'static> StagedFieldExp for Box<StagedExp<Output=T>> {
+    fn run_field(&self, ctx: &EvalContext) -> Rc<Any> {
+        Rc::new(self.run(ctx))
+    }
+}
+
+impl Clone for Box<FieldExp> {
+    fn clone(&self) -> Self {
+        self.clone_field()
+    }
+}
+
+impl RecordVal {
+    pub fn field<T: 'static+Clone>(&self, name: &str) -> Option<T> {
+        match self.v.binary_search_by(|f| f.0[..].cmp(name)) {
+            Ok(i) => self.v[i].1.downcast_ref::<T>().cloned(),
+            Err(_) => None,
+        }
+    }
+
+    // A copy of the record with `name` set to `v`, added if it's new.
+    pub fn with(&self, name: &str, v: Rc<Any>) -> RecordVal {
+        let mut fields = (*self.v).clone();
+        match fields.binary_search_by(|f| f.0[..].cmp(name)) {
+            Ok(i) => fields[i].1 = v,
+            Err(i) => fields.insert(i, (name.to_string(), v)),
+        }
+        sandbox::alloc(fields.len() * mem::size_of::<(String, Rc<Any>)>());
+        RecordVal {
+            v: Rc::new(fields),
+        }
+    }
+}
+
+// Writes a value held as Any, e.g. a field or a variant's payload.
+pub fn fmt_any(f: &mut fmt::Formatter, v: &Any) -> fmt::Result {
+    if let Some(r) = v.downcast_ref::<RecordVal>() {
+        return write!(f, "{}", r);
+    }
+    if let Some(r) = v.downcast_ref::<VariantVal>() {
+        return write!(f, "{}", r);
+    }
+    match value_of(v) {
+        Value::Num(n) => write!(f, "{}", n),
+        Value::Bool(b) => write!(f, "{}", b),
+        Value::Unit => write!(f, "()"),
+        Value::Str(s) => write!(f, "{}", s),
+        Value::Float(bits) => write!(f, "{}", f64::from_bits(bits)),
+        Value::Opaque => write!(f, "_"),
+    }
+}
+
+impl fmt::Display for RecordVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, &(ref name, ref v)) in self.v.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: ", name)?;
+            fmt_any(f, &**v)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl fmt::Debug for RecordVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RecordVal({})", self)
+    }
+}
+
+// Builds a record from its fields' expressions, evaluated in name order.
+#[derive(Clone)]
+pub struct RecordExp {
+    fields: Vec<(String, Box<FieldExp>)>,
+}
+
+pub struct RecordStagedExp {
+    staged_fields: Vec<(String, Box<StagedFieldExp>)>,
+}
+
+impl RecordExp {
+    // Adds a field, replacing any earlier one of the same name.
+    pub fn field<T: 'static+Clone>(mut self, name: &str, exp: Box<Exp<Output=T>>) -> RecordExp {
+        let exp: Box<FieldExp> = box exp;
+        match self.fields.binary_search_by(|f| f.0[..].cmp(name)) {
+            Ok(i) => self.fields[i].1 = exp,
+            Err(i) => self.fields.insert(i, (name.to_string(), exp)),
+        }
+        self
+    }
+}
+
+fn record_val(fields: Vec<(String, Rc<Any>)>) -> RecordVal {
+    sandbox::alloc(fields.len() * mem::size_of::<(String, Rc<Any>)>());
+    RecordVal {
+        v: Rc::new(fields),
+    }
+}
+
+impl Exp for RecordExp {
+    type Output = RecordVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box RecordStagedExp {
+            staged_fields: self.fields.iter().map(|f| (f.0.clone(), f.1.stage_field())).collect(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        record_val(self.fields.iter().map(|f| (f.0.clone(), f.1.interpret_field())).collect())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    // Field names and values alternate: [name, value, name, value, ...].
+    fn reify(&self) -> Expr {
+        let mut children = Vec::new();
+        for &(ref name, ref exp) in &self.fields {
+            children.push(Expr::Const(Value::Str(name.clone())));
+            children.push(exp.reify_field());
+        }
+        node("record", children)
+    }
+}
+
+impl StagedExp for RecordStagedExp {
+    type Output = RecordVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        record_val(self.staged_fields.iter().map(|f| (f.0.clone(), f.1.run_field(ctx))).collect())
+    }
+}
+
+// Reading a field the record doesn't have, or with a different type, panics.
+// Programs loaded through `check` are checked not to.
+#[derive(Clone)]
+pub struct FieldGetExp<T: 'static+Clone> {
+    record: Box<Exp<Output=RecordVal>>,
+    name: String,
+    _t: PhantomData<T>,
+}
+
+pub struct FieldGetStagedExp<T: 'static+Clone> {
+    staged_record: Box<StagedExp<Output=RecordVal>>,
+    name: String,
+    _t: PhantomData<T>,
+}
+
+fn get_field<T: 'static+Clone>(record: &RecordVal, name: &str) -> T {
+    match record.field(name) {
+        Some(v) => v,
+        None => panic!("record {} has no field {:?} of the expected type", record, name),
+    }
+}
+
+impl<T: 'static+Clone> Exp for FieldGetExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box FieldGetStagedExp {
+            staged_record: self.record.stage(),
+            name: self.name.clone(),
+            _t: PhantomData,
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut v = None;
+        self.record.interpret_with(&mut |r: &RecordVal| v = Some(get_field(r, &self.name)));
+        v.unwrap()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("field", vec![self.record.reify(), Expr::Const(Value::Str(self.name.clone()))])
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for FieldGetStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut v = None;
+        self.staged_record.run_with(ctx, &mut |r: &RecordVal| v = Some(get_field(r, &self.name)));
+        v.unwrap()
+    }
+}
+
+// Functional update: a copy of the record with one field set, or added.
+#[derive(Clone)]
+pub struct WithExp<T: 'static+Clone> {
+    record: Box<Exp<Output=RecordVal>>,
+    name: String,
+    val: Box<Exp<Output=T>>,
+}
+
+pub struct WithStagedExp<T: 'static+Clone> {
+    staged_record: Box<StagedExp<Output=RecordVal>>,
+    name: String,
+    staged_val: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone> Exp for WithExp<T> {
+    type Output = RecordVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box WithStagedExp {
+            staged_record: self.record.stage(),
+            name: self.name.clone(),
+            staged_val: self.val.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let record = self.record.interpret();
+        record.with(&self.name, Rc::new(self.val.interpret()))
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("with", vec![self.record.reify(), Expr::Const(Value::Str(self.name.clone())), self.val.reify()])
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for WithStagedExp<T> {
+    type Output = RecordVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let record = self.staged_record.run(ctx);
+        record.with(&self.name, Rc::new(self.staged_val.run(ctx)))
+    }
+}
+
+// An empty record; add fields with `RecordExp::field`.
+pub fn record_exp() -> RecordExp {
+    RecordExp {
+        fields: vec![],
+    }
+}
+
+pub fn field_get_exp<T: 'static+Clone>(record: Box<Exp<Output=RecordVal>>, name: &str) -> FieldGetExp<T> {
+    FieldGetExp {
+        record,
+        name: name.to_string(),
+        _t: PhantomData,
+    }
+}
+
+pub fn with_exp<T: 'static+Clone>(record: Box<Exp<Output=RecordVal>>, name: &str, val: Box<Exp<Output=T>>) -> WithExp<T> {
+    WithExp {
+        record,
+        name: name.to_string(),
+        val
+    }
+}
+
+impl E<RecordVal> {
+    pub fn field<T: 'static+Clone>(self, name: &str) -> E<T> {
+        E::new(field_get_exp(self.0, name))
+    }
+
+    pub fn with<T: 'static+Clone>(self, name: &str, val: E<T>) -> E<RecordVal> {
+        E::new(with_exp(self.0, name, val.0))
+    }
+}
@@ -0,0 +1,181 @@
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, RefVal, UnitVal};
+use ops::E;
+use reify::{Expr, node};
+use sandbox;
+
+// ML-style references: `alloc` makes a new cell holding the value, `deref`
+// reads it and `assign` replaces it. Unlike a let-bound variable, a cell is
+// a value, so it can be stored in arrays and maps, returned, and shared
+// between parts of a program. Each evaluation of an alloc makes a new cell.
+#[derive(Clone)]
+pub struct AllocExp<T: 'static+Clone> {
+    init: Box<Exp<Output=T>>,
+}
+
+pub struct AllocStagedExp<T: 'static+Clone> {
+    staged_init: Box<StagedExp<Output=T>>,
+}
+
+fn new_ref<T>(v: T) -> RefVal<T> {
+    sandbox::alloc(mem::size_of::<T>());
+    RefVal {
+        v: Rc::new(RefCell::new(v)),
+    }
+}
+
+impl<T: 'static+Clone> Exp for AllocExp<T> {
+    type Output = RefVal<T>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box AllocStagedExp {
+            staged_init: self.init.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        new_ref(self.init.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("alloc", vec![self.init.reify()])
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for AllocStagedExp<T> {
+    type Output = RefVal<T>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        new_ref(self.staged_init.run(ctx))
+    }
+}
+
+#[derive(Clone)]
+pub struct DerefExp<T: 'static+Clone> {
+    r: Box<Exp<Output=RefVal<T>>>,
+}
+
+pub struct DerefStagedExp<T: 'static+Clone> {
+    staged_r: Box<StagedExp<Output=RefVal<T>>>,
+}
+
+impl<T: 'static+Clone> Exp for DerefExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box DerefStagedExp {
+            staged_r: self.r.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let r = self.r.interpret();
+        let v = r.v.borrow().clone();
+        v
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("deref", vec![self.r.reify()])
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for DerefStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let r = self.staged_r.run(ctx);
+        let v = r.v.borrow().clone();
+        v
+    }
+}
+
+// The cell is evaluated before the new value, and the value in full before
+// the cell is written, so the value may read the cell it replaces.
+#[derive(Clone)]
+pub struct AssignExp<T: 'static+Clone> {
+    r: Box<Exp<Output=RefVal<T>>>,
+    val: Box<Exp<Output=T>>,
+}
+
+pub struct AssignStagedExp<T: 'static+Clone> {
+    staged_r: Box<StagedExp<Output=RefVal<T>>>,
+    staged_val: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone> Exp for AssignExp<T> {
+    type Output = UnitVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box AssignStagedExp {
+            staged_r: self.r.stage(),
+            staged_val: self.val.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let r = self.r.interpret();
+        r.v.replace(self.val.interpret());
+        UnitVal
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("assign", vec![self.r.reify(), self.val.reify()])
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for AssignStagedExp<T> {
+    type Output = UnitVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let r = self.staged_r.run(ctx);
+        r.v.replace(self.staged_val.run(ctx));
+        UnitVal
+    }
+}
+
+pub fn alloc_exp<T: 'static+Clone>(init: Box<Exp<Output=T>>) -> AllocExp<T> {
+    AllocExp {
+        init
+    }
+}
+
+pub fn deref_exp<T: 'static+Clone>(r: Box<Exp<Output=RefVal<T>>>) -> DerefExp<T> {
+    DerefExp {
+        r
+    }
+}
+
+pub fn assign_exp<T: 'static+Clone>(r: Box<Exp<Output=RefVal<T>>>, val: Box<Exp<Output=T>>) -> AssignExp<T> {
+    AssignExp {
+        r,
+        val
+    }
+}
+
+impl<T: 'static+Clone> E<T> {
+    pub fn alloc(self) -> E<RefVal<T>> {
+        E::new(alloc_exp(self.0))
+    }
+}
+
+impl<T: 'static+Clone> E<RefVal<T>> {
+    pub fn deref(self) -> E<T> {
+        E::new(deref_exp(self.0))
+    }
+
+    pub fn assign(self, val: E<T>) -> E<UnitVal> {
+        E::new(assign_exp(self.0, val.0))
+    }
+}
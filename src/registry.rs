@@ -0,0 +1,65 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use check::{CheckError, Typed};
+
+// Node kinds defined outside the crate. A new operation is a node pair like
+// any other, implementing Exp and StagedExp, with `reify` giving
+// `node(kind, children)` so it prints and serializes with the rest of the
+// tree. Registering an Extension for its kind lets `check` build it too, so
+// programs using it can be loaded back from json or binary.
+//
+// Registrations are per thread, like the effects context. A kind the crate
+// has itself is never looked up here, and extension nodes don't bind
+// variables.
+
+pub trait Extension {
+    fn kind(&self) -> &str;
+    // Builds the node from its arguments, already checked, or says why it
+    // can't, e.g. for arguments of the wrong type.
+    fn check(&self, args: Vec<Typed>) -> Result<Typed, CheckError>;
+}
+
+struct FnExtension<F: Fn(Vec<Typed>) -> Result<Typed, CheckError>> {
+    kind: String,
+    f: F,
+}
+
+impl<F: Fn(Vec<Typed>) -> Result<Typed, CheckError>> Extension for FnExtension<F> {
+    fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    fn check(&self, args: Vec<Typed>) -> Result<Typed, CheckError> {
+        (self.f)(args)
+    }
+}
+
+// An extension that checks its nodes with `f`.
+pub fn fn_extension<F>(kind: &str, f: F) -> Rc<Extension>
+    where F: Fn(Vec<Typed>) -> Result<Typed, CheckError> + 'static {
+    Rc::new(FnExtension {
+        kind: kind.to_string(),
+        f,
+    })
+}
+
+thread_local! {
+    static EXTENSIONS: RefCell<HashMap<String, Rc<Extension>>> = RefCell::new(HashMap::new());
+}
+
+// Registers `extension` on this thread, returning the one it replaces for
+// the same kind, if any.
+pub fn register(extension: Rc<Extension>) -> Option<Rc<Extension>> {
+    let kind = extension.kind().to_string();
+    EXTENSIONS.with(|e| e.borrow_mut().insert(kind, extension))
+}
+
+pub fn unregister(kind: &str) -> Option<Rc<Extension>> {
+    EXTENSIONS.with(|e| e.borrow_mut().remove(kind))
+}
+
+pub fn lookup(kind: &str) -> Option<Rc<Extension>> {
+    EXTENSIONS.with(|e| e.borrow().get(kind).cloned())
+}
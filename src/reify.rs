@@ -0,0 +1,329 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+use {Exp, NumVal, BoolVal, UnitVal, StrVal, FloatVal};
+use patterns::Binder;
+
+// Constants as they appear in an Expr. Floats are kept as their bits so
+// Expr can be hashed and compared exactly.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Value {
+    Num(i64),
+    Bool(bool),
+    Unit,
+    Str(String),
+    Float(u64),
+    // A constant of a type Value doesn't cover.
+    Opaque,
+}
+
+// The untyped shape of an expression tree, produced by `Exp::reify`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Expr {
+    Const(Value),
+    Var(i32),
+    // A variable bound by an enclosing node, numbered in the order binders
+    // are met; only in alpha-normalized trees.
+    Bound(i32),
+    // `binds` are the variables the node introduces for its children, e.g.
+    // the variable of a let.
+    Node { kind: String, binds: Vec<i32>, children: Vec<Expr> },
+    // A node that doesn't describe itself.
+    Opaque,
+}
+
+pub fn value_of(v: &Any) -> Value {
+    if let Some(n) = v.downcast_ref::<NumVal>() {
+        Value::Num(n.v)
+    } else if let Some(b) = v.downcast_ref::<BoolVal>() {
+        Value::Bool(b.v)
+    } else if v.is::<UnitVal>() {
+        Value::Unit
+    } else if let Some(s) = v.downcast_ref::<StrVal>() {
+        Value::Str(s.v.clone())
+    } else if let Some(f) = v.downcast_ref::<FloatVal>() {
+        Value::Float(f.v.to_bits())
+    } else {
+        Value::Opaque
+    }
+}
+
+pub fn node(kind: &str, children: Vec<Expr>) -> Expr {
+    Expr::Node {
+        kind: kind.to_string(),
+        binds: vec![],
+        children,
+    }
+}
+
+pub fn binder(kind: &str, binds: Vec<i32>, children: Vec<Expr>) -> Expr {
+    Expr::Node {
+        kind: kind.to_string(),
+        binds,
+        children,
+    }
+}
+
+impl Expr {
+    // True if some part of the tree couldn't be described, in which case two
+    // trees with equal Exprs may still differ.
+    pub fn is_opaque(&self) -> bool {
+        match *self {
+            Expr::Opaque | Expr::Const(Value::Opaque) => true,
+            Expr::Node { ref children, .. } => children.iter().any(|c| c.is_opaque()),
+            _ => false,
+        }
+    }
+
+    // The variables read in the tree that it doesn't bind itself, in the
+    // order they're first read.
+    pub fn free_vars(&self) -> Vec<i32> {
+        let mut free = Vec::new();
+        self.collect_free(&mut Vec::new(), &mut free);
+        free
+    }
+
+    fn collect_free(&self, bound: &mut Vec<i32>, free: &mut Vec<i32>) {
+        match *self {
+            Expr::Var(id) if !bound.contains(&id) && !free.contains(&id) => free.push(id),
+            Expr::Node { ref binds, ref children, .. } => {
+                bound.extend(binds);
+                for c in children {
+                    c.collect_free(bound, free);
+                }
+                bound.truncate(bound.len() - binds.len());
+            }
+            _ => {}
+        }
+    }
+
+    // Renames bound variables to Bound(0), Bound(1), ... in the order their
+    // binders appear, so trees that differ only in variable ids come out
+    // equal. Free variables keep their ids.
+    pub fn alpha_normalized(&self) -> Expr {
+        let mut names = HashMap::new();
+        let mut next = 0;
+        self.normalize(&mut names, &mut next)
+    }
+
+    fn normalize(&self, names: &mut HashMap<i32, i32>, next: &mut i32) -> Expr {
+        match *self {
+            Expr::Var(id) => match names.get(&id) {
+                Some(&n) => Expr::Bound(n),
+                None => Expr::Var(id),
+            },
+            Expr::Node { ref kind, ref binds, ref children } => {
+                let mut shadowed = Vec::new();
+                let mut new_binds = Vec::new();
+                for &id in binds {
+                    shadowed.push((id, names.insert(id, *next)));
+                    new_binds.push(*next);
+                    *next += 1;
+                }
+                let children = children.iter().map(|c| c.normalize(names, next)).collect();
+                for (id, prev) in shadowed.into_iter().rev() {
+                    match prev {
+                        Some(n) => names.insert(id, n),
+                        None => names.remove(&id),
+                    };
+                }
+                Expr::Node {
+                    kind: kind.clone(),
+                    binds: new_binds,
+                    children,
+                }
+            }
+            ref e => e.clone(),
+        }
+    }
+}
+
+// FNV-1a with a final mix. Written out rather than using std's hashers so a
+// hash is the same across runs, platforms and compiler versions.
+struct StableHasher {
+    h: u64,
+}
+
+impl StableHasher {
+    fn new() -> StableHasher {
+        StableHasher {
+            h: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.h = (self.h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    fn u64(&mut self, v: u64) {
+        for i in 0..8 {
+            self.byte((v >> (i * 8)) as u8);
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u64(s.len() as u64);
+        for &b in s.as_bytes() {
+            self.byte(b);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        let mut z = self.h;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Value {
+    fn stable_hash_into(&self, h: &mut StableHasher) {
+        match *self {
+            Value::Num(v) => {
+                h.byte(0);
+                h.u64(v as u64);
+            }
+            Value::Bool(v) => {
+                h.byte(1);
+                h.byte(v as u8);
+            }
+            Value::Unit => h.byte(2),
+            Value::Str(ref v) => {
+                h.byte(3);
+                h.str(v);
+            }
+            Value::Float(bits) => {
+                h.byte(4);
+                h.u64(bits);
+            }
+            Value::Opaque => h.byte(5),
+        }
+    }
+}
+
+impl Expr {
+    // A hash of the alpha-normalized tree that doesn't depend on variable
+    // ids: free variables are numbered in the order they first appear. Equal
+    // trees (as by exp_eq) hash equal; the converse only holds with high
+    // probability, so compare the trees too before relying on a match.
+    pub fn stable_hash(&self) -> u64 {
+        let mut h = StableHasher::new();
+        let mut free = HashMap::new();
+        self.alpha_normalized().stable_hash_into(&mut h, &mut free);
+        h.finish()
+    }
+
+    fn stable_hash_into(&self, h: &mut StableHasher, free: &mut HashMap<i32, u64>) {
+        match *self {
+            Expr::Const(ref v) => {
+                h.byte(0);
+                v.stable_hash_into(h);
+            }
+            Expr::Var(id) => {
+                let n = free.len() as u64;
+                let n = *free.entry(id).or_insert(n);
+                h.byte(1);
+                h.u64(n);
+            }
+            Expr::Bound(n) => {
+                h.byte(2);
+                h.u64(n as u64);
+            }
+            Expr::Node { ref kind, ref binds, ref children } => {
+                h.byte(3);
+                h.str(kind);
+                h.u64(binds.len() as u64);
+                for &b in binds {
+                    h.u64(b as u64);
+                }
+                h.u64(children.len() as u64);
+                for c in children {
+                    c.stable_hash_into(h, free);
+                }
+            }
+            Expr::Opaque => h.byte(4),
+        }
+    }
+}
+
+pub fn hash_exp<T>(exp: &Exp<Output=T>) -> u64 {
+    exp.reify().stable_hash()
+}
+
+// Structural equality up to renaming of bound variables: `let x = 1 in x`
+// equals `let y = 1 in y`. Free variables must be the same variable. Trees
+// with parts that can't be reified are never equal, even to themselves.
+pub fn exp_eq<T>(a: &Exp<Output=T>, b: &Exp<Output=T>) -> bool {
+    let a = a.reify();
+    let b = b.reify();
+    !a.is_opaque() && !b.is_opaque() && a.alpha_normalized() == b.alpha_normalized()
+}
+
+// Trees print in the syntax rewrite rules are parsed from, `kind(a, b)`,
+// with a binding node's variables in brackets after its kind, as in
+// `let[v3](1, add(v3, v3))`, free and bound variables alike as `v<id>`, and
+// Bound variables as `b<n>`. Floats always have a point, so they read back
+// as floats.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Num(v) => write!(f, "{}", v),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Unit => write!(f, "()"),
+            Value::Str(ref v) => write!(f, "{:?}", v),
+            Value::Float(bits) => write!(f, "{:?}", f64::from_bits(bits)),
+            Value::Opaque => write!(f, "<opaque>"),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Const(ref v) => write!(f, "{}", v),
+            Expr::Var(id) => write!(f, "v{}", id),
+            Expr::Bound(n) => write!(f, "b{}", n),
+            Expr::Node { ref kind, ref binds, ref children } => {
+                write!(f, "{}", kind)?;
+                if !binds.is_empty() {
+                    let binds: Vec<String> = binds.iter().map(|b| format!("v{}", b)).collect();
+                    write!(f, "[{}]", binds.join(", "))?;
+                }
+                let children: Vec<String> = children.iter().map(|c| c.to_string()).collect();
+                write!(f, "({})", children.join(", "))
+            }
+            Expr::Opaque => write!(f, "<opaque>"),
+        }
+    }
+}
+
+thread_local! {
+    static REACHED: RefCell<Option<Vec<Box<Binder>>>> = const { RefCell::new(None) };
+}
+
+// Called by variables as they're reified, so `reify_with_vars` can find
+// them.
+pub fn reached(var: &Binder) {
+    REACHED.with(|r| {
+        if let Some(ref mut vars) = *r.borrow_mut() {
+            if !vars.iter().any(|v| v.id() == var.id()) {
+                vars.push(var.clone_binder());
+            }
+        }
+    });
+}
+
+// The tree of `exp` and the variables it reads that it doesn't bind
+// itself, in the order they're first read, so their values can be looked
+// at or saved.
+pub fn reify_with_vars<T>(exp: &Exp<Output=T>) -> (Expr, Vec<Box<Binder>>) {
+    let prev = REACHED.with(|r| r.replace(Some(Vec::new())));
+    let expr = exp.reify();
+    let reached = REACHED.with(|r| r.replace(prev)).unwrap_or_default();
+    let free = expr.free_vars();
+    let mut vars: Vec<Box<Binder>> = reached.into_iter().filter(|v| free.contains(&v.id())).collect();
+    vars.sort_by_key(|v| free.iter().position(|&id| id == v.id()));
+    (expr, vars)
+}
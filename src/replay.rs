@@ -0,0 +1,243 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+
+use {StagedExp, EvalContext, NumVal, BoolVal, UnitVal, StrVal, FloatVal, InstantVal};
+use effects::{InputSource, RandomSource, Clock, current, with_effects};
+use patterns::Binder;
+use reify::{Value, value_of};
+
+// Recording a run's inputs so it can be run again exactly as it was, for
+// debugging. A recording has the values the host's variables had when the
+// run started and, in order, every number read, drawn at random and read
+// from the clock. Replaying sets the variables back and feeds the run the
+// same numbers in place of the current context's input, generator and
+// clock; what it prints still goes to the current output.
+//
+// Variables are matched up by id, so replay the same staged program, or one
+// built with the same variables. Variables of types `Value` can't hold are
+// recorded as opaque and left as they are on replay.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub vars: Vec<(i32, Value)>,
+    pub reads: Vec<i64>,
+    pub draws: Vec<i64>,
+    // In nanoseconds, as InstantVal has them.
+    pub times: Vec<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayError {
+    pub msg: String,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "malformed recording: {}", self.msg)
+    }
+}
+
+// The recording as text, one entry per line: `var <id> <type> <value>`,
+// `read <n>`, `rand <n>` or `now <nanos>`. A string is written as its
+// length in bytes, a colon and the bytes, so it may hold anything.
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(id, ref v) in &self.vars {
+            write!(f, "var {} ", id)?;
+            match *v {
+                Value::Num(n) => writeln!(f, "num {}", n)?,
+                Value::Bool(b) => writeln!(f, "bool {}", b)?,
+                Value::Unit => writeln!(f, "unit")?,
+                Value::Str(ref s) => writeln!(f, "str {}:{}", s.len(), s)?,
+                Value::Float(bits) => writeln!(f, "float {}", bits)?,
+                Value::Opaque => writeln!(f, "opaque")?,
+            }
+        }
+        for n in &self.reads {
+            writeln!(f, "read {}", n)?;
+        }
+        for n in &self.draws {
+            writeln!(f, "rand {}", n)?;
+        }
+        for n in &self.times {
+            writeln!(f, "now {}", n)?;
+        }
+        Ok(())
+    }
+}
+
+fn err<T>(msg: String) -> Result<T, ReplayError> {
+    Err(ReplayError {
+        msg,
+    })
+}
+
+struct Parser<'a> {
+    src: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_space(&mut self) {
+        self.src = self.src.trim_start();
+    }
+
+    fn word(&mut self) -> Option<&'a str> {
+        self.skip_space();
+        if self.src.is_empty() {
+            return None;
+        }
+        let end = self.src.find(char::is_whitespace).unwrap_or(self.src.len());
+        let (word, rest) = self.src.split_at(end);
+        self.src = rest;
+        Some(word)
+    }
+
+    fn num<T: ::std::str::FromStr>(&mut self) -> Result<T, ReplayError> {
+        match self.word() {
+            Some(w) => w.parse().or_else(|_| err(format!("expected a value, found {:?}", w))),
+            None => err("unexpected end".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ReplayError> {
+        self.skip_space();
+        let colon = match self.src.find(':') {
+            Some(i) => i,
+            None => return err("expected a string".to_string()),
+        };
+        let len: usize = match self.src[..colon].parse() {
+            Ok(len) => len,
+            Err(_) => return err(format!("bad string length {:?}", &self.src[..colon])),
+        };
+        let rest = &self.src[colon + 1..];
+        match rest.get(..len) {
+            Some(s) => {
+                self.src = &rest[len..];
+                Ok(s.to_string())
+            }
+            None => err("string runs past the end".to_string()),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ReplayError> {
+        match self.word() {
+            Some("num") => Ok(Value::Num(self.num()?)),
+            Some("bool") => Ok(Value::Bool(self.num()?)),
+            Some("unit") => Ok(Value::Unit),
+            Some("str") => Ok(Value::Str(self.string()?)),
+            Some("float") => Ok(Value::Float(self.num()?)),
+            Some("opaque") => Ok(Value::Opaque),
+            Some(w) => err(format!("unknown type {:?}", w)),
+            None => err("unexpected end".to_string()),
+        }
+    }
+}
+
+impl Recording {
+    // Reads a recording written by its Display.
+    pub fn parse(src: &str) -> Result<Recording, ReplayError> {
+        let mut p = Parser { src };
+        let mut recording = Recording::default();
+        while let Some(w) = p.word() {
+            match w {
+                "var" => {
+                    let id = p.num()?;
+                    let v = p.value()?;
+                    recording.vars.push((id, v));
+                }
+                "read" => recording.reads.push(p.num()?),
+                "rand" => recording.draws.push(p.num()?),
+                "now" => recording.times.push(p.num()?),
+                _ => return err(format!("unknown entry {:?}", w)),
+            }
+        }
+        Ok(recording)
+    }
+}
+
+// Runs `f` in the current context, recording the values `vars` start with
+// and the numbers `f` takes from the context.
+pub fn record<R, F: FnOnce() -> R>(vars: &[&Binder], f: F) -> (R, Recording) {
+    let tape = Rc::new(RefCell::new(Recording {
+        vars: vars.iter().map(|var| (var.id(), value_of(&*var.save()))).collect(),
+        ..Recording::default()
+    }));
+    let result = with_effects(current().recording(tape.clone()), f);
+    let recording = tape.borrow().clone();
+    (result, recording)
+}
+
+pub fn record_run<T>(staged_exp: &StagedExp<Output=T>, vars: &[&Binder]) -> (T, Recording) {
+    record(vars, || staged_exp.run(&EvalContext::new()))
+}
+
+fn any_of(v: &Value) -> Option<Box<Any>> {
+    match *v {
+        Value::Num(v) => Some(box NumVal { v }),
+        Value::Bool(v) => Some(box BoolVal { v }),
+        Value::Unit => Some(box UnitVal),
+        Value::Str(ref v) => Some(box StrVal { v: v.clone() }),
+        Value::Float(bits) => Some(box FloatVal { v: f64::from_bits(bits) }),
+        Value::Opaque => None,
+    }
+}
+
+// Hands out recorded numbers in order. Asking for more than were recorded
+// means the run went differently from the one recorded.
+struct Tape(RefCell<VecDeque<i64>>, &'static str);
+
+impl Tape {
+    fn new(ns: &[i64], what: &'static str) -> Tape {
+        Tape(RefCell::new(ns.iter().cloned().collect()), what)
+    }
+
+    fn next(&self) -> i64 {
+        match self.0.borrow_mut().pop_front() {
+            Some(n) => n,
+            None => panic!("replay ran past the recorded {}", self.1),
+        }
+    }
+}
+
+impl InputSource for Tape {
+    fn next_num(&self) -> Option<i64> {
+        self.0.borrow_mut().pop_front()
+    }
+}
+
+impl RandomSource for Tape {
+    fn in_range(&self, _lo: i64, _hi: i64) -> i64 {
+        self.next()
+    }
+}
+
+impl Clock for Tape {
+    fn now(&self) -> InstantVal {
+        InstantVal {
+            nanos: self.next(),
+        }
+    }
+}
+
+// Runs `f` as the recorded run went: with `vars` set to the values recorded
+// for them and the recorded numbers in place of the context's.
+pub fn replay<R, F: FnOnce() -> R>(recording: &Recording, vars: &[&Binder], f: F) -> R {
+    for var in vars {
+        let recorded = recording.vars.iter().find(|v| v.0 == var.id()).and_then(|v| any_of(&v.1));
+        if let Some(v) = recorded {
+            var.bind(&*v);
+        }
+    }
+    let effects = current()
+        .input(Rc::new(Tape::new(&recording.reads, "reads")))
+        .random(Rc::new(Tape::new(&recording.draws, "random numbers")))
+        .clock(Rc::new(Tape::new(&recording.times, "clock readings")));
+    with_effects(effects, f)
+}
+
+pub fn replay_run<T>(staged_exp: &StagedExp<Output=T>, recording: &Recording, vars: &[&Binder]) -> T {
+    replay(recording, vars, || staged_exp.run(&EvalContext::new()))
+}
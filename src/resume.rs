@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+
+use Exp;
+use check::Scalar;
+use dynamic::DynVal;
+use patterns::Binder;
+use reify::{Expr, Value, reify_with_vars, value_of};
+use sandbox;
+
+// Programs run a slice at a time, for running many of them on one thread
+// by turns: `run_resumable(exp, budget)` runs `exp` for at most `budget`
+// steps and gives either its value or a Paused run, which `resume` carries
+// on from where it stopped for another budget, as often as it takes.
+//
+//     let mut runs: Vec<_> = programs.iter().map(|p| run_resumable(p, 0)).collect()?;
+//     // ... resume each Paused one in turn, dropping them as they're Done
+//
+// A step is the evaluation of one node, so a slice's length doesn't depend
+// on how much work the nodes under a loop do. Runs are of the program's
+// tree, on a machine whose state is kept in the Paused run rather than on
+// the stack, so they don't hold up the thread between slices, and a paused
+// run that's dropped is simply abandoned.
+//
+// The tree may use numbers, bools, strings and floats, with add, sub, mul,
+// div, pow, lt, partial_lt, the bit operators, str_eq, str_len and concat,
+// if, let, seq, set, while, for and for_step. The variables it reads but
+// doesn't bind are the host's: each read sees what the variable holds at
+// the time and each set changes it, so the host can feed a run new inputs
+// between slices and read its progress. Loops charge a sandbox around a
+// slice a step per iteration, as they do when interpreted.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeError {
+    pub msg: String,
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+fn err<T>(msg: String) -> Result<T, ResumeError> {
+    Err(ResumeError {
+        msg,
+    })
+}
+
+const OPS: &[&str] = &["add", "sub", "mul", "div", "pow", "lt", "partial_lt", "bit_and", "bit_or", "bit_xor", "bit_not",
+                       "str_eq", "str_len", "concat", "if", "let", "seq", "set", "while", "for", "for_step"];
+
+enum Node {
+    Const(DynVal),
+    Var(i32),
+    Op { kind: String, bind: Option<i32>, children: Vec<usize> },
+}
+
+// A node being evaluated, with the values of the children it's had so far.
+struct Frame {
+    node: usize,
+    vals: Vec<DynVal>,
+    // The index of a for loop, once its bounds are known.
+    index: Option<i64>,
+}
+
+struct Machine {
+    // The tree, each node's children before it.
+    nodes: Vec<Node>,
+    // The host's variables, by id.
+    vars: HashMap<i32, Box<Binder>>,
+    // The values of the tree's own variables.
+    env: HashMap<i32, DynVal>,
+    frames: Vec<Frame>,
+    // The value of the node just finished, for the frame under it.
+    ret: Option<DynVal>,
+    steps: u64,
+}
+
+fn dyn_val(v: Value) -> Option<DynVal> {
+    match v {
+        Value::Num(v) => Some(DynVal::Num(v)),
+        Value::Bool(v) => Some(DynVal::Bool(v)),
+        Value::Unit => Some(DynVal::Unit),
+        Value::Str(v) => Some(DynVal::Str(v)),
+        Value::Float(bits) => Some(DynVal::Float(f64::from_bits(bits))),
+        Value::Opaque => None,
+    }
+}
+
+// Adds `expr`'s nodes to `nodes`, giving the index of its root.
+fn flatten(expr: &Expr, nodes: &mut Vec<Node>) -> Result<usize, ResumeError> {
+    let node = match *expr {
+        Expr::Const(ref v) => match dyn_val(v.clone()) {
+            Some(v) => Node::Const(v),
+            None => return err("a constant of a type runs can't hold".to_string()),
+        },
+        Expr::Var(id) => Node::Var(id),
+        Expr::Node { ref kind, ref binds, ref children } if OPS.contains(&&kind[..]) && binds.len() <= 1 => {
+            if kind == "set" && !matches!(children.first(), Some(&Expr::Var(_))) {
+                return err("a set of something other than a variable".to_string());
+            }
+            let children = children.iter().map(|c| flatten(c, nodes)).collect::<Result<_, _>>()?;
+            Node::Op {
+                kind: kind.clone(),
+                bind: binds.first().cloned(),
+                children,
+            }
+        }
+        Expr::Node { ref kind, .. } => return err(format!("can't run the {} node resumably", kind)),
+        Expr::Bound(_) | Expr::Opaque => return err("a node that can't be reified".to_string()),
+    };
+    nodes.push(node);
+    Ok(nodes.len() - 1)
+}
+
+fn num(v: &DynVal) -> i64 {
+    match *v {
+        DynVal::Num(v) => v,
+        _ => panic!("expected a number, not {}", v),
+    }
+}
+
+fn boolean(v: &DynVal) -> bool {
+    match *v {
+        DynVal::Bool(v) => v,
+        _ => panic!("expected a bool, not {}", v),
+    }
+}
+
+// Applies the operator `kind` to `vals`, as its node does.
+fn apply(kind: &str, vals: &[DynVal]) -> DynVal {
+    use dynamic::DynVal::*;
+    match (kind, vals) {
+        ("add", &[Num(a), Num(b)]) => Num(a + b),
+        ("add", &[Float(a), Float(b)]) => Float(a + b),
+        ("sub", &[Num(a), Num(b)]) => Num(a - b),
+        ("sub", &[Float(a), Float(b)]) => Float(a - b),
+        ("mul", &[Num(a), Num(b)]) => Num(a * b),
+        ("mul", &[Float(a), Float(b)]) => Float(a * b),
+        ("div", &[Float(a), Float(b)]) => Float(a / b),
+        ("pow", &[Float(a), Float(b)]) => Float(a.powf(b)),
+        ("lt", &[Num(a), Num(b)]) => Bool(a < b),
+        ("lt", &[Bool(a), Bool(b)]) => Bool(!a & b),
+        ("lt", &[Str(ref a), Str(ref b)]) => Bool(a < b),
+        ("partial_lt", &[Num(a), Num(b)]) => Bool(a < b),
+        ("partial_lt", &[Float(a), Float(b)]) => Bool(a < b),
+        ("bit_and", &[Num(a), Num(b)]) => Num(a & b),
+        ("bit_or", &[Num(a), Num(b)]) => Num(a | b),
+        ("bit_xor", &[Num(a), Num(b)]) => Num(a ^ b),
+        ("bit_not", &[Num(a)]) => Num(!a),
+        ("str_eq", &[Str(ref a), Str(ref b)]) => Bool(a == b),
+        ("str_len", &[Str(ref a)]) => Num(a.chars().count() as i64),
+        ("concat", &[Str(ref a), Str(ref b)]) => {
+            sandbox::alloc(a.len() + b.len());
+            Str(format!("{}{}", a, b))
+        }
+        _ => panic!("can't {} {} values", kind, vals.len()),
+    }
+}
+
+impl Machine {
+    fn read(&self, id: i32) -> DynVal {
+        match self.vars.get(&id) {
+            Some(var) => dyn_val(value_of(&*var.save())).expect("a variable of a run holds a value it can't"),
+            None => self.env[&id].clone(),
+        }
+    }
+
+    fn write(&mut self, id: i32, v: DynVal) {
+        match self.vars.get(&id) {
+            Some(var) => var.bind(&*v.to_any()),
+            None => {
+                self.env.insert(id, v);
+            }
+        }
+    }
+
+    fn push(&mut self, node: usize) {
+        self.steps += 1;
+        self.frames.push(Frame {
+            node,
+            vals: Vec::new(),
+            index: None,
+        });
+    }
+
+    fn finish(&mut self, v: DynVal) {
+        self.frames.pop();
+        self.ret = Some(v);
+    }
+
+    // Evaluates the top frame's node until it needs a child's value or is
+    // done.
+    fn step(&mut self) {
+        let ret = self.ret.take();
+        let frame = self.frames.last_mut().unwrap();
+        if let Some(v) = ret {
+            frame.vals.push(v);
+        }
+        let (kind, bind, children) = match self.nodes[frame.node] {
+            Node::Const(ref v) => {
+                let v = v.clone();
+                return self.finish(v);
+            }
+            Node::Var(id) => {
+                let v = self.read(id);
+                return self.finish(v);
+            }
+            Node::Op { ref kind, bind, ref children } => (&kind[..], bind, children),
+        };
+        let k = frame.vals.len();
+        match kind {
+            "if" if k == 0 => {
+                let c = children[0];
+                self.push(c)
+            }
+            "if" if k == 1 => {
+                let c = if boolean(&frame.vals[0]) { children[1] } else { children[2] };
+                self.push(c)
+            }
+            "let" if k == 0 => {
+                let c = children[0];
+                self.push(c)
+            }
+            "let" if k == 1 => {
+                let (id, init, body) = (bind.unwrap(), frame.vals[0].clone(), children[1]);
+                self.env.insert(id, init);
+                self.push(body)
+            }
+            "if" | "let" => {
+                let v = frame.vals.pop().unwrap();
+                self.finish(v)
+            }
+            "set" if k == 0 => {
+                let c = children[1];
+                self.push(c)
+            }
+            "set" => {
+                let id = match self.nodes[children[0]] {
+                    Node::Var(id) => id,
+                    _ => unreachable!(),
+                };
+                let v = frame.vals.pop().unwrap();
+                self.write(id, v);
+                self.finish(DynVal::Unit)
+            }
+            "while" if k == 1 && boolean(&frame.vals[0]) => {
+                sandbox::step();
+                let c = children[1];
+                self.push(c)
+            }
+            "while" if k == 1 => self.finish(DynVal::Unit),
+            "while" => {
+                frame.vals.clear();
+                let c = children[0];
+                self.push(c)
+            }
+            "for" | "for_step" if k + 1 < children.len() => {
+                let c = children[k];
+                self.push(c)
+            }
+            "for" | "for_step" => {
+                let bounds = children.len() - 1;
+                let step = if bounds == 3 { num(&frame.vals[2]) } else { 1 };
+                let i = match frame.index {
+                    None => {
+                        assert!(step != 0, "for loop step must be non-zero");
+                        Some(num(&frame.vals[0]))
+                    }
+                    Some(i) => {
+                        frame.vals.truncate(bounds);
+                        i.checked_add(step)
+                    }
+                };
+                let end = num(&frame.vals[1]);
+                match i {
+                    Some(i) if (step > 0 && i < end) || (step < 0 && i > end) => {
+                        frame.index = Some(i);
+                        sandbox::step();
+                        let (id, body) = (bind.unwrap(), children[bounds]);
+                        self.env.insert(id, DynVal::Num(i));
+                        self.push(body)
+                    }
+                    _ => self.finish(DynVal::Unit),
+                }
+            }
+            _ if k < children.len() => {
+                let c = children[k];
+                self.push(c)
+            }
+            "seq" => {
+                let v = frame.vals.pop().unwrap();
+                self.finish(v)
+            }
+            _ => {
+                let v = apply(kind, &frame.vals);
+                self.finish(v)
+            }
+        }
+    }
+
+    // Runs for at most `budget` more steps, giving the value if it's done.
+    fn run(&mut self, budget: u64) -> Option<DynVal> {
+        let limit = self.steps.saturating_add(budget);
+        loop {
+            if self.frames.is_empty() {
+                return self.ret.take();
+            }
+            if self.steps >= limit {
+                return None;
+            }
+            self.step();
+        }
+    }
+}
+
+// A run that's used up its budget, to carry on with `resume`.
+pub struct Paused<T> {
+    machine: Machine,
+    _result: PhantomData<T>,
+}
+
+pub enum Resumed<T> {
+    Done(T),
+    Paused(Paused<T>),
+}
+
+impl<T: Scalar> Paused<T> {
+    // Runs for at most another `budget` steps.
+    pub fn resume(mut self, budget: u64) -> Resumed<T> {
+        match self.machine.run(budget) {
+            Some(v) => Resumed::Done(v.downcast::<T>().expect("a run gave a value of another type")),
+            None => Resumed::Paused(self),
+        }
+    }
+
+    // The steps taken so far, over all slices.
+    pub fn steps(&self) -> u64 {
+        self.machine.steps
+    }
+}
+
+// Runs `exp` for at most `budget` steps. An error if it has nodes runs
+// can't have, or reads a variable that doesn't hold a number, bool, string,
+// float or unit.
+pub fn run_resumable<T: Scalar>(exp: &Exp<Output=T>, budget: u64) -> Result<Resumed<T>, ResumeError> {
+    let (expr, vars) = reify_with_vars(exp);
+    let mut nodes = Vec::new();
+    let root = flatten(&expr, &mut nodes)?;
+    if let Some(var) = vars.iter().find(|var| dyn_val(value_of(&*var.save())).is_none()) {
+        return err(format!("variable {} doesn't hold a value runs can have", var.id()));
+    }
+    let mut machine = Machine {
+        nodes,
+        vars: vars.into_iter().map(|var| (var.id(), var)).collect(),
+        env: HashMap::new(),
+        frames: Vec::new(),
+        ret: None,
+        steps: 0,
+    };
+    machine.push(root);
+    let paused = Paused {
+        machine,
+        _result: PhantomData,
+    };
+    Ok(paused.resume(budget))
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use reify::{Expr, Value, node};
+
+// Rewrite rules over untyped trees, for teaching the optimizer identities of
+// a DSL without writing a visitor: `add(?x, 0) => ?x` replaces any `add`
+// whose second argument is the constant 0 with its first. A metavariable
+// matches any subtree, and one used twice in a pattern only matches equal
+// subtrees. Rewriting works on Exprs, so check the result to run it; a rule
+// that changes a program's type is caught there.
+//
+// Rules are tried in the order they were added, on children before their
+// parents, and a rewritten node is rewritten again until no rule matches,
+// so rules that undo each other would loop: rewriting stops after
+// `max_steps` rewrites.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RewriteError {
+    pub msg: String,
+}
+
+impl fmt::Display for RewriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+fn err<T>(msg: String) -> Result<T, RewriteError> {
+    Err(RewriteError {
+        msg,
+    })
+}
+
+// A metavariable, for patterns and templates built in code rather than
+// parsed.
+pub fn meta(name: &str) -> Expr {
+    node("?", vec![Expr::Const(Value::Str(name.to_string()))])
+}
+
+fn meta_name(expr: &Expr) -> Option<&str> {
+    match *expr {
+        Expr::Node { ref kind, ref children, .. } if kind == "?" => match children.first() {
+            Some(&Expr::Const(Value::Str(ref name))) => Some(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn metas<'a>(expr: &'a Expr, out: &mut Vec<&'a str>) {
+    if let Some(name) = meta_name(expr) {
+        out.push(name);
+    } else if let Expr::Node { ref children, .. } = *expr {
+        for c in children {
+            metas(c, out);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RewriteRule {
+    pub name: String,
+    pattern: Expr,
+    template: Expr,
+}
+
+impl RewriteRule {
+    // Errors if the template uses a metavariable the pattern doesn't bind.
+    pub fn new(name: &str, pattern: Expr, template: Expr) -> Result<RewriteRule, RewriteError> {
+        let (mut bound, mut used) = (Vec::new(), Vec::new());
+        metas(&pattern, &mut bound);
+        metas(&template, &mut used);
+        if let Some(name) = used.iter().find(|m| !bound.contains(m)) {
+            return err(format!("?{} isn't bound by the pattern", name));
+        }
+        Ok(RewriteRule {
+            name: name.to_string(),
+            pattern,
+            template,
+        })
+    }
+
+    // Reads a rule written `pattern => template`. Nodes are written
+    // `kind(arg, ...)`, with the parentheses needed even when there are no
+    // arguments; constants as numbers, floats with a decimal point, quoted
+    // strings, `true`, `false` and `()`; metavariables as `?name`.
+    pub fn parse(name: &str, src: &str) -> Result<RewriteRule, RewriteError> {
+        let mut p = Parser { src: src.as_bytes(), pos: 0 };
+        let pattern = p.term()?;
+        p.expect("=>")?;
+        let template = p.term()?;
+        p.skip_space();
+        if p.pos != p.src.len() {
+            return p.error("the end of the rule");
+        }
+        RewriteRule::new(name, pattern, template)
+    }
+
+    // The template with this rule's metavariables filled in, if `expr`
+    // matches the pattern.
+    fn apply(&self, expr: &Expr) -> Option<Expr> {
+        let mut bindings = HashMap::new();
+        if matches(&self.pattern, expr, &mut bindings) {
+            Some(instantiate(&self.template, &bindings))
+        } else {
+            None
+        }
+    }
+}
+
+fn matches<'a>(pattern: &'a Expr, expr: &Expr, bindings: &mut HashMap<&'a str, Expr>) -> bool {
+    if let Some(name) = meta_name(pattern) {
+        if let Some(bound) = bindings.get(name) {
+            return *bound == *expr;
+        }
+        bindings.insert(name, expr.clone());
+        return true;
+    }
+    match (pattern, expr) {
+        (&Expr::Node { kind: ref pk, binds: ref pb, children: ref pc },
+         &Expr::Node { ref kind, ref binds, ref children }) => {
+            pk == kind && pb == binds && pc.len() == children.len()
+                && pc.iter().zip(children).all(|(p, c)| matches(p, c, bindings))
+        }
+        _ => pattern == expr,
+    }
+}
+
+fn instantiate(template: &Expr, bindings: &HashMap<&str, Expr>) -> Expr {
+    if let Some(name) = meta_name(template) {
+        return bindings[name].clone();
+    }
+    match *template {
+        Expr::Node { ref kind, ref binds, ref children } => Expr::Node {
+            kind: kind.clone(),
+            binds: binds.clone(),
+            children: children.iter().map(|c| instantiate(c, bindings)).collect(),
+        },
+        ref e => e.clone(),
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, expected: &str) -> Result<T, RewriteError> {
+        err(format!("expected {} at byte {}", expected, self.pos))
+    }
+
+    fn skip_space(&mut self) {
+        while self.pos < self.src.len() && (self.src[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        self.skip_space();
+        if self.src[self.pos..].starts_with(s.as_bytes()) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), RewriteError> {
+        if self.eat(s) {
+            Ok(())
+        } else {
+            self.error(&format!("{:?}", s))
+        }
+    }
+
+    fn ident(&mut self) -> &'a str {
+        let start = self.pos;
+        while self.pos < self.src.len() && (self.src[self.pos] == b'_' || (self.src[self.pos] as char).is_alphanumeric()) {
+            self.pos += 1;
+        }
+        ::std::str::from_utf8(&self.src[start..self.pos]).unwrap()
+    }
+
+    fn term(&mut self) -> Result<Expr, RewriteError> {
+        self.skip_space();
+        if self.eat("?") {
+            let name = self.ident();
+            if name.is_empty() {
+                return self.error("a metavariable's name");
+            }
+            return Ok(meta(name));
+        }
+        if self.eat("()") {
+            return Ok(Expr::Const(Value::Unit));
+        }
+        if self.eat("\"") {
+            let start = self.pos;
+            while self.pos < self.src.len() && self.src[self.pos] != b'"' {
+                self.pos += 1;
+            }
+            let s = String::from_utf8_lossy(&self.src[start..self.pos]).into_owned();
+            self.expect("\"")?;
+            return Ok(Expr::Const(Value::Str(s)));
+        }
+        let start = self.pos;
+        if self.pos < self.src.len() && self.src[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        let word = self.ident();
+        let word = if self.src.get(self.pos) == Some(&b'.') && !word.is_empty() {
+            self.pos += 1;
+            self.ident();
+            ::std::str::from_utf8(&self.src[start..self.pos]).unwrap()
+        } else {
+            ::std::str::from_utf8(&self.src[start..self.pos]).unwrap()
+        };
+        if let Ok(n) = word.parse() {
+            return Ok(Expr::Const(Value::Num(n)));
+        }
+        if let Ok(x) = word.parse::<f64>() {
+            return Ok(Expr::Const(Value::Float(x.to_bits())));
+        }
+        match word {
+            "true" => return Ok(Expr::Const(Value::Bool(true))),
+            "false" => return Ok(Expr::Const(Value::Bool(false))),
+            "" | "-" => return self.error("a term"),
+            _ => {}
+        }
+        self.expect("(")?;
+        let mut children = Vec::new();
+        if !self.eat(")") {
+            loop {
+                children.push(self.term()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(node(word, children))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rewriter {
+    rules: Vec<RewriteRule>,
+    max_steps: usize,
+}
+
+impl Rewriter {
+    pub fn new() -> Rewriter {
+        Rewriter {
+            rules: Vec::new(),
+            max_steps: 10_000,
+        }
+    }
+
+    pub fn rule(mut self, rule: RewriteRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    // Adds a rule written as `RewriteRule::parse` reads them.
+    pub fn parse_rule(self, name: &str, src: &str) -> Result<Self, RewriteError> {
+        Ok(self.rule(RewriteRule::parse(name, src)?))
+    }
+
+    pub fn max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    // `expr` with the rules applied wherever they match, and the names of
+    // the rules applied, in order.
+    pub fn rewrite(&self, expr: &Expr) -> (Expr, Vec<String>) {
+        let mut applied = Vec::new();
+        let result = self.walk(expr, &mut applied);
+        (result, applied)
+    }
+
+    fn walk(&self, expr: &Expr, applied: &mut Vec<String>) -> Expr {
+        let mut expr = match *expr {
+            Expr::Node { ref kind, ref binds, ref children } => Expr::Node {
+                kind: kind.clone(),
+                binds: binds.clone(),
+                children: children.iter().map(|c| self.walk(c, applied)).collect(),
+            },
+            ref e => e.clone(),
+        };
+        while applied.len() < self.max_steps {
+            match self.rules.iter().filter_map(|r| r.apply(&expr).map(|e| (r, e))).next() {
+                Some((rule, rewritten)) => {
+                    applied.push(rule.name.clone());
+                    // Its new children may match rules too.
+                    expr = match rewritten {
+                        Expr::Node { kind, binds, children } => Expr::Node {
+                            kind,
+                            binds,
+                            children: children.iter().map(|c| self.walk(c, applied)).collect(),
+                        },
+                        e => e,
+                    };
+                }
+                None => break,
+            }
+        }
+        expr
+    }
+}
+
+impl Default for Rewriter {
+    fn default() -> Rewriter {
+        Rewriter::new()
+    }
+}
@@ -0,0 +1,233 @@
+use std::cmp::Reverse;
+use std::fmt;
+
+use {Exp, StagedExp, EvalContext, BoolVal};
+
+#[derive(Debug,Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    // Highest priority wins; ties go to the rule added first.
+    Priority,
+    // The first rule (in insertion order) that matches wins; later rules aren't evaluated.
+    FirstMatch,
+    // The matching rule with the most conditions wins; ties fall back to priority.
+    MostSpecific,
+}
+
+#[derive(Debug,Clone, Copy, PartialEq, Eq)]
+pub enum RuleStatus {
+    Matched,
+    NotMatched,
+    // Not evaluated because the outcome was already decided.
+    Skipped,
+}
+
+#[derive(Debug,Clone, PartialEq, Eq)]
+pub struct RuleTrace {
+    pub rule: String,
+    pub priority: i32,
+    pub specificity: usize,
+    pub status: RuleStatus,
+}
+
+#[derive(Debug,Clone, PartialEq, Eq, Default)]
+pub struct Outcome<D> {
+    pub decision: Option<D>,
+    pub winner: Option<String>,
+    pub reason: String,
+    pub audit: Vec<RuleTrace>,
+}
+
+impl<D: fmt::Debug> fmt::Display for Outcome<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.winner {
+            Some(ref winner) => writeln!(f, "{:?} from {}: {}", self.decision.as_ref().unwrap(), winner, self.reason)?,
+            None => writeln!(f, "no decision: {}", self.reason)?,
+        }
+        for t in &self.audit {
+            writeln!(f, "  {:<12} {} (priority {}, {} conditions)", format!("{:?}", t.status), t.rule, t.priority, t.specificity)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Rule<D> {
+    name: String,
+    priority: i32,
+    conditions: Vec<Box<Exp<Output=BoolVal>>>,
+    decision: D,
+}
+
+struct StagedRule<D> {
+    name: String,
+    priority: i32,
+    staged_conditions: Vec<Box<StagedExp<Output=BoolVal>>>,
+    decision: D,
+}
+
+#[derive(Clone)]
+pub struct RuleSetExp<D: 'static+Clone> {
+    resolution: Resolution,
+    rules: Vec<Rule<D>>,
+}
+
+pub struct RuleSetStagedExp<D: 'static+Clone> {
+    resolution: Resolution,
+    // Kept in evaluation order: by priority for `Priority`, insertion order otherwise.
+    rules: Vec<StagedRule<D>>,
+    // Insertion-order indices in evaluation order, and the inverse mapping.
+    // Audit entries are reported in insertion order.
+    order: Vec<usize>,
+    position: Vec<usize>,
+}
+
+impl<D: 'static+Clone> RuleSetExp<D> {
+    // A rule matches when all of its conditions hold.
+    pub fn rule(mut self, name: &str, priority: i32, conditions: Vec<Box<Exp<Output=BoolVal>>>, decision: D) -> Self {
+        self.rules.push(Rule {
+            name: name.to_string(),
+            priority,
+            conditions,
+            decision,
+        });
+        self
+    }
+
+    fn evaluation_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.rules.len()).collect();
+        if self.resolution == Resolution::Priority {
+            // Stable, so equal priorities keep insertion order.
+            order.sort_by_key(|&i| Reverse(self.rules[i].priority));
+        }
+        order
+    }
+}
+
+// Runs the rules in `order` and resolves conflicts between the matches.
+// `matches(i)` evaluates the conditions of rule `i`.
+fn resolve<D: Clone, F>(resolution: Resolution, order: &[usize], names: &[(&str, i32, usize)],
+                        decisions: &[&D], mut matches: F) -> Outcome<D>
+    where F: FnMut(usize) -> bool {
+    let mut status = vec![RuleStatus::Skipped; names.len()];
+    let mut matched = Vec::new();
+    for &i in order {
+        if matches(i) {
+            status[i] = RuleStatus::Matched;
+            matched.push(i);
+            if resolution != Resolution::MostSpecific {
+                break;
+            }
+        } else {
+            status[i] = RuleStatus::NotMatched;
+        }
+    }
+
+    let winner = match resolution {
+        Resolution::MostSpecific => matched.iter().cloned().fold(None, |best: Option<usize>, i| match best {
+            Some(b) if (names[b].2, names[b].1) >= (names[i].2, names[i].1) => Some(b),
+            _ => Some(i),
+        }),
+        _ => matched.first().cloned(),
+    };
+
+    let reason = match winner {
+        None => format!("none of {} rules matched", names.len()),
+        Some(w) => match resolution {
+            Resolution::Priority => format!("highest priority ({}) among matching rules", names[w].1),
+            Resolution::FirstMatch => "first matching rule".to_string(),
+            Resolution::MostSpecific => format!("most specific ({} conditions, priority {}) of {} matching rules",
+                                                names[w].2, names[w].1, matched.len()),
+        },
+    };
+
+    Outcome {
+        decision: winner.map(|w| decisions[w].clone()),
+        winner: winner.map(|w| names[w].0.to_string()),
+        reason,
+        audit: names.iter().zip(status).map(|(&(name, priority, specificity), status)| RuleTrace {
+            rule: name.to_string(),
+            priority,
+            specificity,
+            status,
+        }).collect(),
+    }
+}
+
+impl<D: 'static+Clone> Exp for RuleSetExp<D> {
+    type Output = Outcome<D>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let order = self.evaluation_order();
+        let mut position = vec![0; order.len()];
+        for (staged, &i) in order.iter().enumerate() {
+            position[i] = staged;
+        }
+        box RuleSetStagedExp {
+            resolution: self.resolution,
+            rules: order.iter().map(|&i| {
+                let rule = &self.rules[i];
+                StagedRule {
+                    name: rule.name.clone(),
+                    priority: rule.priority,
+                    staged_conditions: rule.conditions.iter().map(|c| c.stage()).collect(),
+                    decision: rule.decision.clone(),
+                }
+            }).collect(),
+            order,
+            position,
+        }
+    }
+
+    fn interpret(&self) -> Self::Output {
+        let names: Vec<_> = self.rules.iter().map(|r| (&r.name[..], r.priority, r.conditions.len())).collect();
+        let decisions: Vec<_> = self.rules.iter().map(|r| &r.decision).collect();
+        resolve(self.resolution, &self.evaluation_order(), &names, &decisions,
+                |i| self.rules[i].conditions.iter().all(|c| c.interpret().v))
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl<D: 'static+Clone> StagedExp for RuleSetStagedExp<D> {
+    type Output = Outcome<D>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let position = &self.position;
+        let names: Vec<_> = position.iter().map(|&p| {
+            let r = &self.rules[p];
+            (&r.name[..], r.priority, r.staged_conditions.len())
+        }).collect();
+        let decisions: Vec<_> = position.iter().map(|&p| &self.rules[p].decision).collect();
+        resolve(self.resolution, &self.order, &names, &decisions,
+                |i| self.rules[position[i]].staged_conditions.iter().all(|c| c.run(ctx).v))
+    }
+}
+
+pub fn rule_set_exp<D: 'static+Clone>(resolution: Resolution) -> RuleSetExp<D> {
+    RuleSetExp {
+        resolution,
+        rules: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, EvalContext, BoolVal, unit_exp};
+    use super::{Resolution, rule_set_exp};
+
+    fn yes() -> Vec<Box<Exp<Output=BoolVal>>> {
+        vec![box unit_exp(BoolVal { v: true })]
+    }
+
+    // i32::MIN can't be negated.
+    #[test]
+    fn priorities_at_the_ends_of_the_range() {
+        let rules = rule_set_exp(Resolution::Priority)
+            .rule("lowest", i32::MIN, yes(), "lowest")
+            .rule("highest", i32::MAX, yes(), "highest");
+        assert_eq!(rules.interpret().winner, Some("highest".to_string()));
+        assert_eq!(rules.stage().run(&EvalContext::new()).decision, Some("highest"));
+    }
+}
@@ -0,0 +1,296 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {Exp, StagedExp, EvalContext};
+
+// Budgets for running untrusted programs. Loops and recursive calls charge a
+// step per iteration or call, nodes blocked on another thread a step per
+// wait, and the nodes that build strings and arrays charge the bytes they
+// produce. Nothing is freed back, so the memory budget
+// bounds the total produced over the run rather than what's live at once.
+//
+// Work a program hands to another thread, as `spawn` does, runs under what
+// the budget has left, and what it spends is charged to the budget when
+// it's joined.
+//
+// Running out aborts the run by unwinding to `Sandbox::run`, so a staged
+// program stopped part way may be left mid-run (a loop variable half way,
+// say); stage it afresh rather than running it again.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Memory,
+    Steps,
+    Time,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceExhausted {
+    pub resource: Resource,
+    // In bytes, steps or milliseconds.
+    pub limit: u64,
+}
+
+impl fmt::Display for ResourceExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.resource {
+            Resource::Memory => write!(f, "memory budget of {} bytes exhausted", self.limit),
+            Resource::Steps => write!(f, "step budget of {} exhausted", self.limit),
+            Resource::Time => write!(f, "timed out after {} ms", self.limit),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sandbox {
+    max_memory: Option<u64>,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+}
+
+// The clock is read every this many steps rather than on each one.
+const CLOCK_EVERY: u64 = 256;
+
+struct Meter {
+    limits: Sandbox,
+    memory: u64,
+    steps: u64,
+    deadline: Option<Instant>,
+}
+
+impl Meter {
+    fn step(&mut self) -> Result<(), ResourceExhausted> {
+        self.steps += 1;
+        if let Some(max) = self.limits.max_steps {
+            if self.steps > max {
+                return Err(ResourceExhausted { resource: Resource::Steps, limit: max });
+            }
+        }
+        if self.steps % CLOCK_EVERY == 0 {
+            return self.clock();
+        }
+        Ok(())
+    }
+
+    fn clock(&self) -> Result<(), ResourceExhausted> {
+        if let (Some(deadline), Some(timeout)) = (self.deadline, self.limits.timeout) {
+            if Instant::now() > deadline {
+                let ms = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000;
+                return Err(ResourceExhausted { resource: Resource::Time, limit: ms });
+            }
+        }
+        Ok(())
+    }
+
+    // A wait by a blocked node: a step, with the clock read every time, as
+    // waits are long.
+    fn wait(&mut self) -> Result<(), ResourceExhausted> {
+        self.step()?;
+        self.clock()
+    }
+
+    fn alloc(&mut self, bytes: u64) -> Result<(), ResourceExhausted> {
+        self.memory = self.memory.saturating_add(bytes);
+        match self.limits.max_memory {
+            Some(max) if self.memory > max => Err(ResourceExhausted { resource: Resource::Memory, limit: max }),
+            _ => Ok(()),
+        }
+    }
+
+    // What another thread spent under a budget carried from this one.
+    fn spend(&mut self, spent: Spent) -> Result<(), ResourceExhausted> {
+        self.steps = self.steps.saturating_add(spent.steps);
+        if let Some(max) = self.limits.max_steps {
+            if self.steps > max {
+                return Err(ResourceExhausted { resource: Resource::Steps, limit: max });
+            }
+        }
+        self.alloc(spent.memory)?;
+        self.clock()
+    }
+}
+
+thread_local! {
+    static METER: RefCell<Option<Meter>> = RefCell::new(None);
+}
+
+// Sandboxes running on any thread. Lets step and alloc return straight away
+// in the usual case of no sandbox, which matters in tight staged loops.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+fn charge(f: &Fn(&mut Meter) -> Result<(), ResourceExhausted>) {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let result = METER.with(|m| match *m.borrow_mut() {
+        Some(ref mut meter) => f(meter),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        panic::resume_unwind(Box::new(e));
+    }
+}
+
+// Called by nodes once per loop iteration or call.
+#[inline]
+pub fn step() {
+    charge(&|m| m.step());
+}
+
+// Called by kernels that run `n` iterations at once.
+#[inline]
+pub fn steps(n: usize) {
+    charge(&|m| {
+        for _ in 0..n {
+            m.step()?;
+        }
+        Ok(())
+    });
+}
+
+// Called by nodes blocked waiting on another thread, between waits of at
+// most WAIT, so a sandbox's budgets stop a program blocked for good.
+#[inline]
+pub fn wait() {
+    charge(&|m| m.wait());
+}
+
+// The longest a blocked node waits between calls to `wait`.
+pub const WAIT: Duration = Duration::from_millis(10);
+
+// Called by nodes that produce a string or array, with its size in bytes.
+#[inline]
+pub fn alloc(bytes: usize) {
+    charge(&|m| m.alloc(bytes as u64));
+}
+
+// Called when another thread's work under a budget carried from this one is
+// taken in, as by a join, with what it spent.
+pub fn spend(spent: Spent) {
+    charge(&|m| m.spend(spent));
+}
+
+// What a sandbox running on one thread has left, for work it hands to
+// another; see `carry`.
+pub struct Carried {
+    limits: Sandbox,
+    memory: u64,
+    steps: u64,
+    deadline: Option<Instant>,
+}
+
+// What was spent under a carried budget.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spent {
+    memory: u64,
+    steps: u64,
+}
+
+// The budget of the sandbox around the caller, if there is one, for work
+// run on another thread with `Carried::run`. What that spends counts
+// towards the budget there, starting from what's been spent here, but only
+// comes off the budget here once it's passed to `spend`.
+pub fn carry() -> Option<Carried> {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    METER.with(|m| m.borrow().as_ref().map(|meter| Carried {
+        limits: meter.limits,
+        memory: meter.memory,
+        steps: meter.steps,
+        deadline: meter.deadline,
+    }))
+}
+
+impl Carried {
+    // Runs `f` on this thread under the carried budget, and gives its value,
+    // or its panic, which is a `ResourceExhausted` if it ran out, with what
+    // it spent.
+    pub fn run<R, F: FnOnce() -> R>(self, f: F) -> (thread::Result<R>, Spent) {
+        let meter = Meter {
+            limits: self.limits,
+            memory: self.memory,
+            steps: self.steps,
+            deadline: self.deadline,
+        };
+        let prev = METER.with(|m| m.replace(Some(meter)));
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        let _restore = Restore(Some(prev));
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let spent = METER.with(|m| match *m.borrow() {
+            Some(ref meter) => Spent {
+                memory: meter.memory - self.memory,
+                steps: meter.steps - self.steps,
+            },
+            None => Spent::default(),
+        });
+        (result, spent)
+    }
+}
+
+// Puts the outer meter back even if the run unwinds.
+struct Restore(Option<Option<Meter>>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        if let Some(prev) = self.0.take() {
+            METER.with(|m| *m.borrow_mut() = prev);
+            ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Sandbox {
+    pub fn new() -> Sandbox {
+        Sandbox::default()
+    }
+
+    pub fn memory(mut self, bytes: u64) -> Sandbox {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn steps(mut self, steps: u64) -> Sandbox {
+        self.max_steps = Some(steps);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Sandbox {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Runs `f` under this sandbox's budgets, starting from zero. Sandboxes
+    // nest, and what's spent in an inner one isn't charged to the outer.
+    // Panics other than running out pass through.
+    pub fn run<R, F: FnOnce() -> R>(&self, f: F) -> Result<R, ResourceExhausted> {
+        let meter = Meter {
+            limits: *self,
+            memory: 0,
+            steps: 0,
+            deadline: self.timeout.map(|t| Instant::now() + t),
+        };
+        let prev = METER.with(|m| m.replace(Some(meter)));
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        let _restore = Restore(Some(prev));
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(v) => Ok(v),
+            Err(payload) => match payload.downcast::<ResourceExhausted>() {
+                Ok(e) => Err(*e),
+                Err(payload) => panic::resume_unwind(payload),
+            },
+        }
+    }
+
+    pub fn interpret<T>(&self, exp: &Exp<Output=T>) -> Result<T, ResourceExhausted> {
+        self.run(|| exp.interpret())
+    }
+
+    pub fn run_staged<T>(&self, staged_exp: &StagedExp<Output=T>) -> Result<T, ResourceExhausted> {
+        self.run(|| staged_exp.run(&EvalContext::new()))
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use {Exp, StagedExp, SlotScope};
+use reify::Expr;
+
+// Finds variables used where no enclosing node binds them. Such a variable
+// doesn't fail when run: it reads whatever it last held, or its type's
+// default, so a tree put together by hand or read from outside can be
+// wrong without anything noticing. `check::check` rejects these too, but
+// only for the types it covers, and it stops at the first.
+//
+// A node's variables are only in scope in the children that see them: a
+// let's in its body but not its initializer, a loop's in its body, a match
+// arm's in that arm. Nodes this pass doesn't know are taken to bind their
+// variables in all their children.
+//
+// Staging gives each bound variable its slot, the index where a staged
+// run keeps it in its frame: the number of variables in scope where it's
+// bound. `stage_slots` reports the slots a program's staging gave out, so
+// a backend can keep its variables in an array laid out as the staged
+// runs' frames are.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScopeError {
+    pub var: i32,
+    // From the root: the kind of each node passed through and which of its
+    // children was taken.
+    pub path: Vec<(String, usize)>,
+}
+
+impl fmt::Display for ScopeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "variable {} is used outside its binding at ", self.var)?;
+        if self.path.is_empty() {
+            return write!(f, "the root");
+        }
+        for (i, &(ref kind, child)) in self.path.iter().enumerate() {
+            if i > 0 {
+                write!(f, " > ")?;
+            }
+            write!(f, "{}[{}]", kind, child)?;
+        }
+        Ok(())
+    }
+}
+
+// The variables of `binds` in scope in child `i` of a `kind` node.
+fn scoped(kind: &str, binds: &[i32], children: &[Expr], i: usize) -> Vec<i32> {
+    let last = i + 1 == children.len();
+    match kind {
+        "let" => if i == 1 { binds.to_vec() } else { vec![] },
+        "for" | "for_step" | "for_each" | "map" | "filter" | "fold" | "gpu_map" | "stream_map" | "stream_filter"
+        | "stream_fold" => {
+            if last { binds.to_vec() } else { vec![] }
+        }
+        _ if kind.starts_with("group_") => if last { binds.to_vec() } else { vec![] },
+        // Arm k's tag is child 2k+1 and its body 2k+2.
+        "match" => {
+            if i >= 2 && i % 2 == 0 {
+                binds.get(i / 2 - 1).into_iter().cloned().collect()
+            } else {
+                vec![]
+            }
+        }
+        // A case sees the variables its own pattern binds.
+        "pmatch" => {
+            let mut vars = Vec::new();
+            if let Expr::Node { ref kind, ref children, .. } = children[i] {
+                if kind == "case" && !children.is_empty() {
+                    pattern_vars(&children[0], &mut vars);
+                }
+            }
+            vars
+        }
+        _ => binds.to_vec(),
+    }
+}
+
+fn pattern_vars(pattern: &Expr, out: &mut Vec<i32>) {
+    if let Expr::Node { ref kind, ref children, .. } = *pattern {
+        for (i, c) in children.iter().enumerate() {
+            match *c {
+                Expr::Var(id) if kind == "pat_bind" && i == 0 => out.push(id),
+                _ => pattern_vars(c, out),
+            }
+        }
+    }
+}
+
+fn walk(expr: &Expr, in_scope: &mut Vec<i32>, host: &HashSet<i32>, path: &mut Vec<(String, usize)>,
+        errors: &mut Vec<ScopeError>) {
+    match *expr {
+        Expr::Var(id) => {
+            if !in_scope.contains(&id) && !host.contains(&id) {
+                errors.push(ScopeError {
+                    var: id,
+                    path: path.clone(),
+                });
+            }
+        }
+        Expr::Node { ref kind, ref binds, ref children } => {
+            for (i, c) in children.iter().enumerate() {
+                // A pattern's variables are where it binds them, not uses.
+                if kind == "pat_bind" && i == 0 {
+                    continue;
+                }
+                let vars = scoped(kind, binds, children, i);
+                let depth = in_scope.len();
+                in_scope.extend(vars);
+                path.push((kind.clone(), i));
+                walk(c, in_scope, host, path, errors);
+                path.pop();
+                in_scope.truncate(depth);
+            }
+        }
+        Expr::Const(_) | Expr::Bound(_) | Expr::Opaque => {}
+    }
+}
+
+// Every use in `expr` of a variable not bound where it's used, in the order
+// they appear. `host` are variables the caller gives values to itself, which
+// may be used anywhere.
+pub fn check_scopes(expr: &Expr, host: &[i32]) -> Result<(), Vec<ScopeError>> {
+    let mut errors = Vec::new();
+    walk(expr, &mut Vec::new(), &host.iter().cloned().collect(), &mut Vec::new(), &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn check_exp_scopes<T>(exp: &Exp<Output=T>, host: &[i32]) -> Result<(), Vec<ScopeError>> {
+    check_scopes(&exp.reify(), host)
+}
+
+// The slots of the variables a program binds, and how long a frame holding
+// them must be. Variables whose scopes don't overlap share a slot.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Slots {
+    slots: HashMap<i32, usize>,
+    size: usize,
+}
+
+impl Slots {
+    pub fn slot(&self, var: i32) -> Option<usize> {
+        self.slots.get(&var).cloned()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // The variables and their slots, by slot and then id.
+    pub fn vars(&self) -> Vec<(i32, usize)> {
+        let mut vars: Vec<(i32, usize)> = self.slots.iter().map(|(&var, &slot)| (var, slot)).collect();
+        vars.sort_by_key(|&(var, slot)| (slot, var));
+        vars
+    }
+}
+
+// Stages `exp` with `inputs` bound around it in the first slots, as
+// `stage_batch` binds its inputs, and returns the staged tree with the
+// slots its staging gave the variables. They're the variables that tree
+// uses: a binder built from a closure makes a new variable each time it's
+// staged or reified, so the ids of another staging, or of `reify`, aren't
+// these. A variable bound in more than one place keeps the slot of the
+// first.
+pub fn stage_slots<T: 'static+Clone>(exp: &Exp<Output=T>, inputs: &[i32])
+                                     -> (Box<StagedExp<Output=T>>, Slots) {
+    let scope = SlotScope::enter();
+    let (staged, given) = SlotScope::record(|| {
+        for &var in inputs {
+            scope.bind_id(var);
+        }
+        exp.stage()
+    });
+    let mut out = Slots::default();
+    for (var, slot) in given {
+        out.slots.entry(var).or_insert(slot);
+        out.size = out.size.max(slot + 1);
+    }
+    (staged, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use {Exp, EvalContext, VariableExp, NumVal, add_exp, let_exp, unit_exp};
+    use super::stage_slots;
+
+    // The let makes its variable as it's staged, so only the staging's own
+    // record has it.
+    #[test]
+    fn stage_slots_gives_the_staged_variables() {
+        let input: VariableExp<NumVal> = VariableExp::fresh();
+        let bound = Rc::new(Cell::new(0));
+        let seen = bound.clone();
+        let x = input.clone();
+        let exp = let_exp(box unit_exp(NumVal { v: 2 }), box move |y: VariableExp<NumVal>| {
+            seen.set(y.id);
+            box add_exp(box x.clone(), box y)
+        });
+        let (staged, slots) = stage_slots(&exp, &[input.id]);
+        assert_eq!(slots.slot(input.id), Some(0));
+        assert_eq!(slots.slot(bound.get()), Some(1));
+        assert_eq!(slots.size(), 2);
+        assert_eq!(staged.run(&EvalContext::new()).v, 2);
+
+        exp.reify();
+        assert_eq!(slots.slot(bound.get()), None);
+    }
+}
@@ -0,0 +1,332 @@
+use std::fmt;
+
+use {Exp, StagedExp, EvalContext, BoolVal, NumVal, FloatVal};
+
+#[derive(Clone)]
+enum Factor {
+    When(Box<Exp<Output=BoolVal>>),
+    Per(Box<Exp<Output=FloatVal>>),
+    PerNum(Box<Exp<Output=NumVal>>),
+}
+
+enum StagedFactor {
+    When(Box<StagedExp<Output=BoolVal>>),
+    Per(Box<StagedExp<Output=FloatVal>>),
+    PerNum(Box<StagedExp<Output=NumVal>>),
+}
+
+#[derive(Clone)]
+struct Bounds {
+    floor: Option<f64>,
+    cap: Option<f64>,
+}
+
+impl Bounds {
+    fn apply(&self, v: f64) -> f64 {
+        let v = self.floor.map_or(v, |floor| v.max(floor));
+        self.cap.map_or(v, |cap| v.min(cap))
+    }
+}
+
+#[derive(Clone)]
+struct ScoreTerm {
+    name: String,
+    factor: Factor,
+    weight: f64,
+    bounds: Bounds,
+}
+
+struct StagedScoreTerm {
+    name: String,
+    factor: StagedFactor,
+    weight: f64,
+    bounds: Bounds,
+}
+
+#[derive(Debug,Clone, PartialEq, Default)]
+pub struct Contribution {
+    pub name: String,
+    // Whether a `when` condition held; always true for `per` terms.
+    pub fired: bool,
+    pub points: f64,
+}
+
+#[derive(Debug,Clone, PartialEq, Default)]
+pub struct ScoreExplanation {
+    pub base: f64,
+    pub contributions: Vec<Contribution>,
+    // Sum before the overall floor/cap.
+    pub raw: f64,
+    pub total: f64,
+}
+
+impl fmt::Display for ScoreExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>10.2}  base", self.base)?;
+        for c in &self.contributions {
+            if c.fired {
+                writeln!(f, "{:>+10.2}  {}", c.points, c.name)?;
+            } else {
+                writeln!(f, "{:>10}  {} (not met)", "-", c.name)?;
+            }
+        }
+        if self.raw != self.total {
+            writeln!(f, "{:>10.2}  raw, clamped", self.raw)?;
+        }
+        write!(f, "{:>10.2}  total", self.total)
+    }
+}
+
+// A weighted sum of term contributions, each optionally bounded, with the
+// overall score bounded by `floor`/`cap`. Built up with the chained methods.
+#[derive(Clone)]
+pub struct ScoreExp {
+    base: f64,
+    terms: Vec<ScoreTerm>,
+    bounds: Bounds,
+}
+
+pub struct ScoreStagedExp {
+    base: f64,
+    terms: Vec<StagedScoreTerm>,
+    bounds: Bounds,
+}
+
+impl ScoreExp {
+    fn push(mut self, name: &str, factor: Factor, weight: f64) -> Self {
+        self.terms.push(ScoreTerm {
+            name: name.to_string(),
+            factor,
+            weight,
+            bounds: Bounds { floor: None, cap: None },
+        });
+        self
+    }
+
+    // Adds `weight` when `cond` holds.
+    pub fn when(self, name: &str, cond: Box<Exp<Output=BoolVal>>, weight: f64) -> Self {
+        self.push(name, Factor::When(cond), weight)
+    }
+
+    // Adds `weight * value`.
+    pub fn per(self, name: &str, value: Box<Exp<Output=FloatVal>>, weight: f64) -> Self {
+        self.push(name, Factor::Per(value), weight)
+    }
+
+    pub fn per_num(self, name: &str, value: Box<Exp<Output=NumVal>>, weight: f64) -> Self {
+        self.push(name, Factor::PerNum(value), weight)
+    }
+
+    // Bounds the contribution of the most recently added term.
+    pub fn term_floor(mut self, floor: f64) -> Self {
+        self.terms.last_mut().expect("no term to bound").bounds.floor = Some(floor);
+        self
+    }
+
+    pub fn term_cap(mut self, cap: f64) -> Self {
+        self.terms.last_mut().expect("no term to bound").bounds.cap = Some(cap);
+        self
+    }
+
+    pub fn floor(mut self, floor: f64) -> Self {
+        self.bounds.floor = Some(floor);
+        self
+    }
+
+    pub fn cap(mut self, cap: f64) -> Self {
+        self.bounds.cap = Some(cap);
+        self
+    }
+
+    fn stage_score(&self) -> ScoreStagedExp {
+        ScoreStagedExp {
+            base: self.base,
+            terms: self.terms.iter().map(|t| StagedScoreTerm {
+                name: t.name.clone(),
+                factor: match t.factor {
+                    Factor::When(ref e) => StagedFactor::When(e.stage()),
+                    Factor::Per(ref e) => StagedFactor::Per(e.stage()),
+                    Factor::PerNum(ref e) => StagedFactor::PerNum(e.stage()),
+                },
+                weight: t.weight,
+                bounds: Bounds { floor: t.bounds.floor, cap: t.bounds.cap },
+            }).collect(),
+            bounds: Bounds { floor: self.bounds.floor, cap: self.bounds.cap },
+        }
+    }
+
+    fn interpret_explained(&self) -> ScoreExplanation {
+        let contributions = self.terms.iter().map(|t| {
+            let (fired, factor) = match t.factor {
+                Factor::When(ref e) => (e.interpret().v, 1.0),
+                Factor::Per(ref e) => (true, e.interpret().v),
+                Factor::PerNum(ref e) => (true, e.interpret().v as f64),
+            };
+            Contribution {
+                name: t.name.clone(),
+                fired,
+                points: if fired { t.bounds.apply(t.weight * factor) } else { 0.0 },
+            }
+        }).collect();
+        explanation(self.base, contributions, &self.bounds)
+    }
+}
+
+fn explanation(base: f64, contributions: Vec<Contribution>, bounds: &Bounds) -> ScoreExplanation {
+    let raw = contributions.iter().fold(base, |sum, c| sum + c.points);
+    ScoreExplanation {
+        base,
+        contributions,
+        raw,
+        total: bounds.apply(raw),
+    }
+}
+
+impl StagedScoreTerm {
+    fn points(&self, ctx: &EvalContext) -> Option<f64> {
+        let factor = match self.factor {
+            StagedFactor::When(ref e) => if e.run(ctx).v { 1.0 } else { return None },
+            StagedFactor::Per(ref e) => e.run(ctx).v,
+            StagedFactor::PerNum(ref e) => e.run(ctx).v as f64,
+        };
+        Some(self.bounds.apply(self.weight * factor))
+    }
+}
+
+impl ScoreStagedExp {
+    fn explain(&self, ctx: &EvalContext) -> ScoreExplanation {
+        let contributions = self.terms.iter().map(|t| {
+            let points = t.points(ctx);
+            Contribution {
+                name: t.name.clone(),
+                fired: points.is_some(),
+                points: points.unwrap_or(0.0),
+            }
+        }).collect();
+        explanation(self.base, contributions, &self.bounds)
+    }
+}
+
+impl Exp for ScoreExp {
+    type Output = FloatVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box self.stage_score()
+    }
+    fn interpret(&self) -> Self::Output {
+        Self::Output {
+            v: self.interpret_explained().total
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl StagedExp for ScoreStagedExp {
+    type Output = FloatVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let raw = self.terms.iter().fold(self.base, |sum, t| sum + t.points(ctx).unwrap_or(0.0));
+        Self::Output {
+            v: self.bounds.apply(raw)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ExplainScoreExp {
+    score: ScoreExp,
+}
+
+pub struct ExplainScoreStagedExp {
+    staged_score: ScoreStagedExp,
+}
+
+impl Exp for ExplainScoreExp {
+    type Output = ScoreExplanation;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ExplainScoreStagedExp {
+            staged_score: self.score.stage_score(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.score.interpret_explained()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl StagedExp for ExplainScoreStagedExp {
+    type Output = ScoreExplanation;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_score.explain(ctx)
+    }
+}
+
+pub fn score_exp(base: f64) -> ScoreExp {
+    ScoreExp {
+        base,
+        terms: Vec::new(),
+        bounds: Bounds { floor: None, cap: None },
+    }
+}
+
+pub fn explain_score_exp(score: ScoreExp) -> ExplainScoreExp {
+    ExplainScoreExp {
+        score
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, StagedExp, EvalContext, VariableExp, BoolVal, NumVal, FloatVal, unit_exp};
+    use super::{ScoreExp, score_exp, explain_score_exp};
+
+    fn score(premium: &VariableExp<BoolVal>, visits: &VariableExp<NumVal>) -> ScoreExp {
+        score_exp(10.0)
+            .when("premium", box premium.clone(), 5.0)
+            .per("rating", box unit_exp(FloatVal { v: 4.5 }), 2.0)
+            .per_num("visits", box visits.clone(), 0.5).term_cap(3.0)
+            .floor(0.0).cap(20.0)
+    }
+
+    #[test]
+    fn terms_are_weighted_bounded_and_summed() {
+        let premium = VariableExp::fresh_with_val(BoolVal { v: false });
+        let visits = VariableExp::fresh_with_val(NumVal { v: 2 });
+        let exp = score(&premium, &visits);
+        let staged = exp.stage();
+        assert_eq!(exp.interpret().v, 10.0 + 9.0 + 1.0);
+        assert_eq!(staged.run(&EvalContext::new()).v, 10.0 + 9.0 + 1.0);
+
+        premium.assign(BoolVal { v: true });
+        visits.assign(NumVal { v: 100 });
+        assert_eq!(exp.interpret().v, 20.0);
+        assert_eq!(staged.run(&EvalContext::new()).v, 20.0);
+    }
+
+    #[test]
+    fn explanation_lists_each_contribution() {
+        let premium = VariableExp::fresh_with_val(BoolVal { v: false });
+        let visits = VariableExp::fresh_with_val(NumVal { v: 100 });
+        let exp = explain_score_exp(score(&premium, &visits));
+        let explained = exp.interpret();
+        assert_eq!(explained, exp.stage().run(&EvalContext::new()));
+        let points: Vec<_> = explained.contributions.iter().map(|c| (&c.name[..], c.fired, c.points)).collect();
+        assert_eq!(points, vec![("premium", false, 0.0), ("rating", true, 9.0), ("visits", true, 3.0)]);
+        assert_eq!((explained.raw, explained.total), (22.0, 20.0));
+        let lines = ["     10.00  base",
+                     "         -  premium (not met)",
+                     "     +9.00  rating",
+                     "     +3.00  visits",
+                     "     22.00  raw, clamped",
+                     "     20.00  total"];
+        assert_eq!(explained.to_string(), lines.join("\n"));
+    }
+}
@@ -0,0 +1,202 @@
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use {Exp, StagedExp, EvalContext};
+use sandbox::ResourceExhausted;
+
+#[derive(Debug,Clone, PartialEq)]
+pub enum DivergenceKind {
+    Value { active: String, candidate: String },
+    // The candidate took more than `latency_ratio` times as long as the active program.
+    Latency,
+    Panicked { message: String },
+}
+
+#[derive(Debug,Clone, PartialEq)]
+pub struct Divergence {
+    pub label: String,
+    pub kind: DivergenceKind,
+    pub active_time: Duration,
+    pub candidate_time: Duration,
+}
+
+pub trait AuditSink {
+    fn record(&self, divergence: Divergence);
+}
+
+// Collects divergences in memory; clones share the same log.
+#[derive(Clone, Default)]
+pub struct VecSink {
+    pub log: Rc<RefCell<Vec<Divergence>>>,
+}
+
+impl AuditSink for VecSink {
+    fn record(&self, divergence: Divergence) {
+        self.log.borrow_mut().push(divergence);
+    }
+}
+
+pub struct StderrSink;
+
+impl AuditSink for StderrSink {
+    fn record(&self, divergence: Divergence) {
+        eprintln!("shadow divergence: {:?}", divergence);
+    }
+}
+
+#[derive(Clone)]
+struct ShadowConfig {
+    label: String,
+    latency_ratio: f64,
+    sink: Rc<AuditSink>,
+}
+
+impl ShadowConfig {
+    // Runs the candidate after the active program and reports how they differ.
+    // The candidate can't change the result: its value is dropped and a panic
+    // in it is caught and recorded, but for a sandbox running out, which
+    // stops the run as it would anywhere else. It does share any variables the two
+    // programs both refer to, so it should be free of writes.
+    fn compare<T: PartialEq + Debug, A, C>(&self, active: A, candidate: C) -> T
+        where A: FnOnce() -> T, C: FnOnce() -> T {
+        let start = Instant::now();
+        let active_val = active();
+        let active_time = start.elapsed();
+
+        let start = Instant::now();
+        let candidate_val = panic::catch_unwind(AssertUnwindSafe(candidate));
+        let candidate_time = start.elapsed();
+
+        let kind = match candidate_val {
+            // Running out of a sandbox's budget isn't the candidate's fault,
+            // and the sandbox must still stop the run.
+            Err(err) if err.is::<ResourceExhausted>() => panic::resume_unwind(err),
+            Err(err) => Some(DivergenceKind::Panicked {
+                message: err.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| err.downcast_ref::<String>().cloned())
+                    .unwrap_or_default(),
+            }),
+            Ok(ref v) if *v != active_val => Some(DivergenceKind::Value {
+                active: format!("{:?}", active_val),
+                candidate: format!("{:?}", v),
+            }),
+            Ok(_) if secs(candidate_time) > secs(active_time) * self.latency_ratio => Some(DivergenceKind::Latency),
+            Ok(_) => None,
+        };
+        if let Some(kind) = kind {
+            self.sink.record(Divergence {
+                label: self.label.clone(),
+                kind,
+                active_time,
+                candidate_time,
+            });
+        }
+        active_val
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+pub struct ShadowExp<T: 'static> {
+    active: Box<Exp<Output=T>>,
+    candidate: Box<Exp<Output=T>>,
+    config: ShadowConfig,
+}
+
+impl<T: 'static> Clone for ShadowExp<T> {
+    fn clone(&self) -> Self {
+        ShadowExp {
+            active: self.active.clone(),
+            candidate: self.candidate.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+pub struct ShadowStagedExp<T: 'static> {
+    staged_active: Box<StagedExp<Output=T>>,
+    staged_candidate: Box<StagedExp<Output=T>>,
+    config: ShadowConfig,
+}
+
+impl<T: 'static> ShadowExp<T> {
+    pub fn label(mut self, label: &str) -> Self {
+        self.config.label = label.to_string();
+        self
+    }
+
+    pub fn latency_ratio(mut self, ratio: f64) -> Self {
+        self.config.latency_ratio = ratio;
+        self
+    }
+}
+
+impl<T: 'static+PartialEq+Debug> Exp for ShadowExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ShadowStagedExp {
+            staged_active: self.active.stage(),
+            staged_candidate: self.candidate.stage(),
+            config: self.config.clone(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.config.compare(|| self.active.interpret(), || self.candidate.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+}
+
+impl<T: 'static+PartialEq+Debug> StagedExp for ShadowStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.config.compare(|| self.staged_active.run(ctx), || self.staged_candidate.run(ctx))
+    }
+}
+
+pub fn shadow_exp<T: 'static+PartialEq+Debug>(active: Box<Exp<Output=T>>, candidate: Box<Exp<Output=T>>,
+                                              sink: Rc<AuditSink>) -> ShadowExp<T> {
+    ShadowExp {
+        active,
+        candidate,
+        config: ShadowConfig {
+            label: String::new(),
+            latency_ratio: 2.0,
+            sink,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use {Exp, NumVal, BoolVal, UnitVal, unit_exp, seq_exp, while_exp};
+    use sandbox::{Sandbox, Resource};
+    use super::{VecSink, shadow_exp};
+
+    // A candidate that never finishes is stopped by the sandbox, not
+    // recorded as having panicked.
+    #[test]
+    fn sandbox_stops_a_candidate_that_runs_out() {
+        let forever = while_exp(box unit_exp(BoolVal { v: true }), box unit_exp(UnitVal));
+        let sink = VecSink::default();
+        let exp = shadow_exp(box unit_exp(NumVal { v: 1 }),
+                             box seq_exp(box forever, box unit_exp(NumVal { v: 1 })),
+                             Rc::new(sink.clone()));
+        let err = Sandbox::new().steps(100).interpret(&exp).unwrap_err();
+        assert_eq!(err.resource, Resource::Steps);
+        let err = Sandbox::new().steps(100).run_staged(&*exp.stage()).unwrap_err();
+        assert_eq!(err.resource, Resource::Steps);
+        assert!(sink.log.borrow().is_empty());
+    }
+}
@@ -0,0 +1,368 @@
+use std::any::{Any, TypeId};
+use std::marker::PhantomData;
+use std::mem;
+
+use {Exp, StagedExp, EvalContext, VariableExp, ArrayVal, NumVal, FloatVal, Iterable};
+use reify::{Expr, Value};
+use sandbox;
+
+// Staged kernels for maps and folds over arrays of numbers. A body that is
+// only arithmetic on the element and constants is compiled to a short list
+// of operations, each run over a chunk of LANES elements at a time, which
+// the compiler turns into vector instructions. Bodies that do anything else,
+// or read any other variable, stage as usual.
+//
+// Integer arithmetic wraps, as NumVal's does in release builds. Folds are
+// only run this way for integer sums, where adding in a different order
+// gives the same result; float sums could round differently.
+
+const LANES: usize = 8;
+
+trait Lane: Copy+Default+'static {
+    type Val: 'static+Clone;
+
+    fn constant(v: &Value) -> Option<Self>;
+    fn of(v: &Self::Val) -> Self;
+    fn val(self) -> Self::Val;
+    fn add(self, rhs: Self) -> Self;
+    fn sub(self, rhs: Self) -> Self;
+    fn mul(self, rhs: Self) -> Self;
+}
+
+impl Lane for i64 {
+    type Val = NumVal;
+
+    fn constant(v: &Value) -> Option<i64> {
+        match *v {
+            Value::Num(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    fn of(v: &NumVal) -> i64 {
+        v.v
+    }
+
+    fn val(self) -> NumVal {
+        NumVal { v: self }
+    }
+
+    fn add(self, rhs: i64) -> i64 {
+        self.wrapping_add(rhs)
+    }
+
+    fn sub(self, rhs: i64) -> i64 {
+        self.wrapping_sub(rhs)
+    }
+
+    fn mul(self, rhs: i64) -> i64 {
+        self.wrapping_mul(rhs)
+    }
+}
+
+impl Lane for f64 {
+    type Val = FloatVal;
+
+    fn constant(v: &Value) -> Option<f64> {
+        match *v {
+            Value::Float(bits) => Some(f64::from_bits(bits)),
+            _ => None,
+        }
+    }
+
+    fn of(v: &FloatVal) -> f64 {
+        v.v
+    }
+
+    fn val(self) -> FloatVal {
+        FloatVal { v: self }
+    }
+
+    fn add(self, rhs: f64) -> f64 {
+        self + rhs
+    }
+
+    fn sub(self, rhs: f64) -> f64 {
+        self - rhs
+    }
+
+    fn mul(self, rhs: f64) -> f64 {
+        self * rhs
+    }
+}
+
+// Each operation writes the register of its own index; the result is in
+// the last.
+#[derive(Clone, Debug)]
+enum Op<L> {
+    Elem,
+    Const(L),
+    Add(usize, usize),
+    Sub(usize, usize),
+    Mul(usize, usize),
+}
+
+#[derive(Clone, Debug)]
+struct Kernel<L> {
+    ops: Vec<Op<L>>,
+}
+
+impl<L: Lane> Kernel<L> {
+    // The kernel for `body`, a function of the variable `elem`, if it's
+    // one the kernels can run.
+    fn compile(body: &Expr, elem: i32) -> Option<Kernel<L>> {
+        let mut ops = Vec::new();
+        Kernel::emit(body, elem, &mut ops)?;
+        Some(Kernel { ops })
+    }
+
+    fn emit(expr: &Expr, elem: i32, ops: &mut Vec<Op<L>>) -> Option<usize> {
+        let op = match *expr {
+            Expr::Var(id) if id == elem => Op::Elem,
+            Expr::Const(ref v) => Op::Const(L::constant(v)?),
+            Expr::Node { ref kind, ref binds, ref children } if binds.is_empty() && children.len() == 2 => {
+                let a = Kernel::emit(&children[0], elem, ops)?;
+                let b = Kernel::emit(&children[1], elem, ops)?;
+                match &kind[..] {
+                    "add" => Op::Add(a, b),
+                    "sub" => Op::Sub(a, b),
+                    "mul" => Op::Mul(a, b),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        ops.push(op);
+        Some(ops.len() - 1)
+    }
+
+    // Runs the kernel on every element of `xs`, passing each chunk of
+    // results to `out` along with how many of them are real: the last chunk
+    // is padded.
+    fn each_chunk(&self, xs: &[L], out: &mut FnMut(&[L; LANES], usize)) {
+        let mut regs = vec![[L::default(); LANES]; self.ops.len()];
+        for chunk in xs.chunks(LANES) {
+            let mut x = [L::default(); LANES];
+            x[..chunk.len()].copy_from_slice(chunk);
+            for i in 0..self.ops.len() {
+                let r = match self.ops[i] {
+                    Op::Elem => x,
+                    Op::Const(c) => [c; LANES],
+                    Op::Add(a, b) => lanes(&regs[a], &regs[b], L::add),
+                    Op::Sub(a, b) => lanes(&regs[a], &regs[b], L::sub),
+                    Op::Mul(a, b) => lanes(&regs[a], &regs[b], L::mul),
+                };
+                regs[i] = r;
+            }
+            out(&regs[self.ops.len() - 1], chunk.len());
+        }
+    }
+
+    fn map(&self, xs: &[L]) -> Vec<L> {
+        let mut v = Vec::with_capacity(xs.len());
+        self.each_chunk(xs, &mut |r, n| v.extend_from_slice(&r[..n]));
+        v
+    }
+
+    fn sum(&self, xs: &[L]) -> L {
+        let mut sums = [L::default(); LANES];
+        self.each_chunk(xs, &mut |r, n| {
+            for i in 0..n {
+                sums[i] = sums[i].add(r[i]);
+            }
+        });
+        sums.iter().fold(L::default(), |a, &b| a.add(b))
+    }
+}
+
+#[inline]
+fn lanes<L: Copy+Default>(a: &[L; LANES], b: &[L; LANES], f: fn(L, L) -> L) -> [L; LANES] {
+    let mut r = [L::default(); LANES];
+    for i in 0..LANES {
+        r[i] = f(a[i], b[i]);
+    }
+    r
+}
+
+fn is<A: 'static, B: 'static>() -> bool {
+    TypeId::of::<A>() == TypeId::of::<B>()
+}
+
+fn elems<L: Lane>(items: &Any) -> Vec<L> {
+    let items = items.downcast_ref::<ArrayVal<L::Val>>().unwrap();
+    sandbox::steps(items.v.len());
+    items.v.iter().map(L::of).collect()
+}
+
+// `v` as the type it's known to be.
+fn cast<A: 'static, B: 'static>(v: A) -> B {
+    let v: Box<Any> = box v;
+    *v.downcast::<B>().unwrap()
+}
+
+struct SimdMapStagedExp<C: 'static+Clone, U: 'static+Clone, L: Lane> {
+    staged_items: Box<StagedExp<Output=C>>,
+    kernel: Kernel<L>,
+    _out: PhantomData<U>,
+}
+
+impl<C: 'static+Clone, U: 'static+Clone, L: Lane> StagedExp for SimdMapStagedExp<C, U, L> {
+    type Output = ArrayVal<U>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut v = Vec::new();
+        self.staged_items.run_with(ctx, &mut |items: &C| {
+            v = self.kernel.map(&elems::<L>(items));
+        });
+        sandbox::alloc(v.len() * mem::size_of::<U>());
+        cast(ArrayVal { v: v.into_iter().map(L::val).collect::<Vec<_>>() })
+    }
+}
+
+fn map_kernel<C, U, L>(items: &Exp<Output=C>, body: &Expr, elem: i32) -> Option<Box<StagedExp<Output=ArrayVal<U>>>>
+    where C: 'static+Clone, U: 'static+Clone, L: Lane {
+    if !is::<C, ArrayVal<L::Val>>() || !is::<U, L::Val>() {
+        return None;
+    }
+    let kernel = Kernel::<L>::compile(body, elem)?;
+    Some(box SimdMapStagedExp {
+        staged_items: items.stage(),
+        kernel,
+        _out: PhantomData,
+    })
+}
+
+// The staged form of a map of `f` over `items`, if it can run as a kernel.
+pub fn stage_map<C, U>(items: &Exp<Output=C>, f: &Fn(VariableExp<C::Elem>) -> Box<Exp<Output=U>>)
+    -> Option<Box<StagedExp<Output=ArrayVal<U>>>>
+    where C: 'static+Clone+Iterable, U: 'static+Clone {
+    if !is::<C::Elem, NumVal>() && !is::<C::Elem, FloatVal>() {
+        return None;
+    }
+    let elem = VariableExp::<C::Elem>::fresh();
+    let body = f(elem.clone()).reify();
+    map_kernel::<C, U, i64>(items, &body, elem.id).or_else(|| map_kernel::<C, U, f64>(items, &body, elem.id))
+}
+
+struct SimdSumStagedExp<C: 'static+Clone> {
+    staged_items: Box<StagedExp<Output=C>>,
+    staged_init: Box<StagedExp<Output=NumVal>>,
+    kernel: Kernel<i64>,
+}
+
+impl<C: 'static+Clone> StagedExp for SimdSumStagedExp<C> {
+    type Output = NumVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut sum = 0;
+        self.staged_items.run_with(ctx, &mut |items: &C| {
+            let init = self.staged_init.run(ctx).v;
+            sum = init.wrapping_add(self.kernel.sum(&elems::<i64>(items)));
+        });
+        NumVal { v: sum }
+    }
+}
+
+// The staged form of a fold of `f` over `items` from `init`, if `f` adds
+// something computed from the element alone to the accumulator.
+pub fn stage_fold<C, A>(items: &Exp<Output=C>, init: &Exp<Output=A>,
+                        f: &Fn(VariableExp<A>, VariableExp<C::Elem>) -> Box<Exp<Output=A>>)
+    -> Option<Box<StagedExp<Output=A>>>
+    where C: 'static+Clone+Iterable, A: 'static+Clone+Default {
+    if !is::<C, ArrayVal<NumVal>>() || !is::<A, NumVal>() {
+        return None;
+    }
+    let acc = VariableExp::<A>::fresh();
+    let elem = VariableExp::<C::Elem>::fresh();
+    let term = match f(acc.clone(), elem.clone()).reify() {
+        Expr::Node { ref kind, ref binds, ref children } if kind == "add" && binds.is_empty() && children.len() == 2 => {
+            match (&children[0], &children[1]) {
+                (&Expr::Var(id), term) | (term, &Expr::Var(id)) if id == acc.id => term.clone(),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let kernel = Kernel::<i64>::compile(&term, elem.id)?;
+    let staged: Box<StagedExp<Output=NumVal>> = box SimdSumStagedExp {
+        staged_items: items.stage(),
+        staged_init: cast(init.stage()),
+        kernel,
+    };
+    Some(cast(staged))
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, EvalContext, VariableExp, ArrayVal, NumVal, FloatVal};
+    use ops::E;
+    use array::{map_exp, fold_exp};
+    use super::{LANES, stage_map, stage_fold};
+
+    fn array<T: 'static+Clone>(v: Vec<T>) -> E<ArrayVal<T>> {
+        E::from(VariableExp::fresh_with_val(ArrayVal { v }))
+    }
+
+    // Up to three chunks and a bit, so most lengths leave the last chunk
+    // padded.
+    fn lengths() -> ::std::ops::Range<i64> {
+        0..3 * LANES as i64 + 2
+    }
+
+    fn int_body(x: VariableExp<NumVal>) -> Box<Exp<Output=NumVal>> {
+        (E::from(&x) * 3i64 - (E::from(&x) - 7i64) * &x).0
+    }
+
+    fn float_body(x: VariableExp<FloatVal>) -> Box<Exp<Output=FloatVal>> {
+        (E::from(&x) * 1.5 + 0.25 - E::from(&x) * &x).0
+    }
+
+    #[test]
+    fn int_map_kernel_matches_the_scalar_map() {
+        for n in lengths() {
+            let items = array((0..n).map(|v| NumVal { v: v - 5 }).collect());
+            let kernel = stage_map(&*items.0, &int_body).expect("int map runs as a kernel");
+            let scalar = map_exp(items.0, box int_body).interpret();
+            assert_eq!(kernel.run(&EvalContext::new()).v, scalar.v, "{} elements", n);
+        }
+    }
+
+    #[test]
+    fn float_map_kernel_matches_the_scalar_map() {
+        for n in lengths() {
+            let items = array((0..n).map(|v| FloatVal { v: v as f64 / 3.0 - 2.0 }).collect());
+            let kernel = stage_map(&*items.0, &float_body).expect("float map runs as a kernel");
+            let scalar = map_exp(items.0, box float_body).interpret();
+            let bits = |v: ArrayVal<FloatVal>| v.v.iter().map(|x| x.v.to_bits()).collect::<Vec<_>>();
+            assert_eq!(bits(kernel.run(&EvalContext::new())), bits(scalar), "{} elements", n);
+        }
+    }
+
+    #[test]
+    fn sum_kernel_matches_the_scalar_fold() {
+        let f = |acc: VariableExp<NumVal>, x: VariableExp<NumVal>| -> Box<Exp<Output=NumVal>> {
+            (E::from(&acc) + E::from(&x) * &x).0
+        };
+        for n in lengths() {
+            let items = array((0..n).map(|v| NumVal { v: 3 - v }).collect());
+            let init = E::from(11i64);
+            let kernel = stage_fold(&*items.0, &*init.0, &f).expect("sum runs as a kernel");
+            let scalar = fold_exp(items.0, init.0, box f).interpret();
+            assert_eq!(kernel.run(&EvalContext::new()).v, scalar.v, "{} elements", n);
+        }
+    }
+
+    // A body reading a variable other than the element stages as usual,
+    // and gives what the interpreter does.
+    #[test]
+    fn other_bodies_fall_back_to_the_scalar_map() {
+        let k = VariableExp::fresh_with_val(NumVal { v: 4 });
+        let body = move |x: VariableExp<NumVal>| -> Box<Exp<Output=NumVal>> { (E::from(&x) * &k).0 };
+        for n in lengths() {
+            let items = array((0..n).map(|v| NumVal { v }).collect());
+            assert!(stage_map(&*items.0, &body).is_none());
+            let exp = map_exp(items.0, box body.clone());
+            assert_eq!(exp.stage().run(&EvalContext::new()).v, exp.interpret().v, "{} elements", n);
+        }
+    }
+}
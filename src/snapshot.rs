@@ -0,0 +1,70 @@
+use std::any::Any;
+use std::rc::Rc;
+
+use patterns::Binder;
+use reify::{Value, value_of};
+
+// Checkpoints of a program's state between runs. A snapshot holds the
+// values a set of variables had when it was taken, of any type, and puts
+// them all back when restored, as often as needed: take one before a run
+// that may go wrong and restore it to roll the run back.
+//
+// Staged programs don't list the variables they use, so an Env names them:
+// the host's own variables, or the inputs `check::check_open` hands back.
+// Variables a program binds itself, with let, for or match, are set afresh
+// each run and needn't be saved. What a snapshot can't reach isn't rolled
+// back, like cells made with `refs` or what was printed.
+
+// The variables a host keeps a program's state in.
+#[derive(Clone, Default)]
+pub struct Env {
+    vars: Vec<Box<Binder>>,
+}
+
+impl Env {
+    pub fn new() -> Env {
+        Env::default()
+    }
+
+    pub fn var(mut self, var: &Binder) -> Self {
+        self.vars.push(var.clone_binder());
+        self
+    }
+
+    pub fn vars(mut self, vars: Vec<Box<Binder>>) -> Self {
+        self.vars.extend(vars);
+        self
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            vars: Rc::new(self.vars.iter().map(|var| (var.clone(), Rc::from(var.save()))).collect()),
+        }
+    }
+}
+
+// Cheap to clone; clones share the saved values.
+#[derive(Clone)]
+pub struct Snapshot {
+    vars: Rc<Vec<(Box<Binder>, Rc<Any>)>>,
+}
+
+impl Snapshot {
+    pub fn take(vars: &[&Binder]) -> Snapshot {
+        vars.iter().fold(Env::new(), |env, &var| env.var(var)).snapshot()
+    }
+
+    // Sets every variable back to the value it had when the snapshot was
+    // taken. Each write goes through the variable, so a journal recording
+    // at the time sees the restore as writes it can undo.
+    pub fn restore(&self) {
+        for &(ref var, ref v) in self.vars.iter() {
+            var.bind(&**v);
+        }
+    }
+
+    // The saved values by variable id, as far as `Value` can show them.
+    pub fn values(&self) -> Vec<(i32, Value)> {
+        self.vars.iter().map(|&(ref var, ref v)| (var.id(), value_of(&**v))).collect()
+    }
+}
@@ -0,0 +1,204 @@
+#[cfg(feature = "json")]
+use std::any::Any;
+#[cfg(feature = "json")]
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "json")]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "json")]
+use std::rc::Rc;
+
+#[cfg(feature = "json")]
+use check::{Typed, check, check_located};
+#[cfg(feature = "json")]
+use dynamic::DynVal;
+#[cfg(feature = "json")]
+use json::from_json_spanned;
+#[cfg(feature = "json")]
+use observe::{EvalObserver, observing};
+use reify::Expr;
+
+// Where a program's nodes were in the text it was read from, for errors that
+// point at the part of the program they're about. Nodes are named by their
+// path, the child indexes leading to them from the root, which is how
+// `CheckError` names the node it's about; `Spans` maps paths to byte ranges
+// of the source.
+//
+// The JSON reader fills in spans; programs built in code or rewritten have
+// none, so their errors come without a snippet.
+
+// Bytes `start` up to `end` of the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Spans {
+    spans: HashMap<Vec<usize>, Span>,
+}
+
+impl Spans {
+    pub fn new() -> Spans {
+        Spans::default()
+    }
+
+    pub fn insert(&mut self, path: Vec<usize>, span: Span) {
+        self.spans.insert(path, span);
+    }
+
+    pub fn get(&self, path: &[usize]) -> Option<Span> {
+        self.spans.get(path).cloned()
+    }
+}
+
+// Calls `f` with each node of `expr` and its path, parents before their
+// children.
+pub fn each_node<F: FnMut(&[usize], &Expr)>(expr: &Expr, f: &mut F) {
+    fn walk<F: FnMut(&[usize], &Expr)>(expr: &Expr, path: &mut Vec<usize>, f: &mut F) {
+        f(path, expr);
+        if let Expr::Node { ref children, .. } = *expr {
+            for (i, c) in children.iter().enumerate() {
+                path.push(i);
+                walk(c, path, f);
+                path.pop();
+            }
+        }
+    }
+    walk(expr, &mut Vec::new(), f)
+}
+
+// The path of each node of `expr`, by its address.
+pub fn node_paths(expr: &Expr) -> HashMap<*const Expr, Vec<usize>> {
+    let mut paths = HashMap::new();
+    each_node(expr, &mut |path, node| {
+        paths.insert(node as *const Expr, path.to_vec());
+    });
+    paths
+}
+
+// Where in the program each node an observer is shown came from, for a
+// program loaded with `check::check_located`. Only good while the program
+// it came with is.
+pub struct Locator {
+    paths: HashMap<*const Expr, Vec<usize>>,
+}
+
+impl Locator {
+    pub fn new(paths: HashMap<*const Expr, Vec<usize>>) -> Locator {
+        Locator {
+            paths,
+        }
+    }
+
+    pub fn path(&self, node: &Expr) -> Option<&[usize]> {
+        self.paths.get(&(node as *const Expr)).map(|p| &p[..])
+    }
+
+    // The path of each node that reports, in no particular order.
+    pub fn paths(&self) -> Vec<&[usize]> {
+        self.paths.values().map(|p| &p[..]).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub msg: String,
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Diagnostic {
+    // The error with the line of `src` it's about and the span underlined,
+    // like:
+    //
+    //   error: can't add num and bool
+    //    --> 1:1
+    //     |
+    //   1 | {"node": "add", "args": [{"num": 1}, {"bool": true}]}
+    //     | ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
+    //
+    // A span over several lines is underlined to the end of its first.
+    pub fn render(&self, src: &str) -> String {
+        let span = match self.span {
+            Some(span) if span.start <= src.len() => span,
+            _ => return format!("error: {}\n", self.msg),
+        };
+        let line_start = src[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = src[span.start..].find('\n').map(|i| span.start + i).unwrap_or(src.len());
+        let line = &src[line_start..line_end];
+        let number = src[..line_start].matches('\n').count() + 1;
+        let column = src[line_start..span.start].chars().count();
+        let width = src[span.start..span.end.min(line_end).max(span.start)].chars().count().max(1);
+        let gutter = " ".repeat(number.to_string().len());
+        format!("error: {}\n{} --> {}:{}\n{} |\n{} | {}\n{} | {}{}\n", self.msg, gutter, number, column + 1, gutter,
+                number, line, gutter, " ".repeat(column), "^".repeat(width))
+    }
+}
+
+#[cfg(feature = "json")]
+fn located(msg: String, path: Option<&[usize]>, spans: &Spans) -> Diagnostic {
+    Diagnostic {
+        msg,
+        span: path.and_then(|p| spans.get(p)),
+    }
+}
+
+// Reads and checks the JSON program in `src`, pointing at what's wrong with
+// it if that fails.
+#[cfg(feature = "json")]
+pub fn check_source(src: &str) -> Result<Typed, Diagnostic> {
+    let (expr, spans) = load(src)?;
+    check(&expr).map_err(|e| located(e.msg, e.path.as_ref().map(|p| &p[..]), &spans))
+}
+
+#[cfg(feature = "json")]
+fn load(src: &str) -> Result<(Expr, Spans), Diagnostic> {
+    // JsonError gives errors in the program's shape, rather than its
+    // syntax, at 0.
+    from_json_spanned(src).map_err(|e| Diagnostic {
+        span: if e.pos > 0 { Some(Span { start: e.pos, end: e.pos + 1 }) } else { None },
+        msg: e.msg,
+    })
+}
+
+// The nodes being evaluated, innermost last.
+#[cfg(feature = "json")]
+struct Stack(RefCell<Vec<*const Expr>>);
+
+#[cfg(feature = "json")]
+impl EvalObserver for Stack {
+    fn on_enter(&self, node: &Expr) {
+        self.0.borrow_mut().push(node);
+    }
+
+    fn on_exit(&self, _node: &Expr, _value: &Any) {
+        self.0.borrow_mut().pop();
+    }
+}
+
+// Reads, checks and runs the JSON program in `src`. If the run fails, the
+// error points at the innermost node that was running; it runs observed to
+// know which, so slower than a plain run.
+#[cfg(feature = "json")]
+pub fn run_source(src: &str) -> Result<DynVal, Diagnostic> {
+    let (expr, spans) = load(src)?;
+    let (typed, locator) = check_located(&expr)
+        .map_err(|e| located(e.msg, e.path.as_ref().map(|p| &p[..]), &spans))?;
+    let stack = Rc::new(Stack(RefCell::new(Vec::new())));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| observing(stack.clone(), || typed.eval_interpreted())));
+    result.map_err(|err| {
+        let msg = err.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| err.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        let path = stack.0.borrow().last().and_then(|node| locator.paths.get(node)).map(|p| &p[..]);
+        located(msg, path, &spans)
+    })
+}
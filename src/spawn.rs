@@ -0,0 +1,308 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+
+use {Exp, StagedExp, EvalContext, TaskVal, TaskState};
+use canon::pure_kind;
+use channel::{Channel, channel_of};
+use check::{Scalar, check};
+use ops::E;
+use patterns::Binder;
+use reify::{Expr, Value, node, value_of};
+use sandbox::{self, Spent};
+
+// Parallel parts of a program: `spawn` starts an expression on another
+// thread and gives a task, and `join` waits for the task and gives the
+// expression's value. Between the two the program carries on, so
+//
+//     let a = f.spawn(inputs)?; let b = g.spawn(inputs)?;
+//     a.join() + b.join()
+//
+// runs f and g at once.
+//
+// Expressions and values are tied to the thread that made them, so what
+// goes to the other thread is the expression's tree, with the values its
+// variables have when it's spawned written in as constants, and it's
+// loaded there by `check` and run. That's only the same as running it here
+// if it has no effects, so a spawned expression must be pure but for sends
+// and receives on channels and operations on atomics, must read no
+// variables but the `inputs` it's given, each holding a number, bool,
+// string, float, unit or channel, and its value must be one of those but a
+// channel. Loading it costs about as much as staging it, so spawn
+// expressions that do more work than that.
+//
+// A spawned expression runs under what's left of the budget of a sandbox
+// around the spawn, with its deadline, and what it spends is charged to
+// that sandbox when it's joined; if it runs out, or panics, its join
+// panics the same way. A join waits for the task to finish, charging a
+// sandbox around it a step at least every `sandbox::WAIT`, as a blocked
+// recv does, so a task that never finishes can't hold up a sandboxed
+// program past its budget.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpawnError {
+    pub msg: String,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+fn err<T>(msg: String) -> Result<T, SpawnError> {
+    Err(SpawnError {
+        msg,
+    })
+}
+
+// `expr` with what the inputs hold now in place of their variables.
+fn closed(expr: &Expr, inputs: &[(i32, Expr)]) -> Expr {
+    match *expr {
+        Expr::Var(id) => match inputs.iter().find(|&&(input, _)| input == id) {
+            Some(&(_, ref v)) => v.clone(),
+            None => Expr::Var(id),
+        },
+        Expr::Node { ref kind, ref binds, ref children } => Expr::Node {
+            kind: kind.clone(),
+            binds: binds.clone(),
+            children: children.iter().map(|c| closed(c, inputs)).collect(),
+        },
+        ref e => e.clone(),
+    }
+}
+
+// What the inputs hold, as trees, and the channels among them, which must
+// be kept until the other thread has loaded the program.
+fn values(inputs: &[Box<Binder>]) -> Result<(Vec<(i32, Expr)>, Vec<Arc<Channel>>), SpawnError> {
+    let mut values = Vec::new();
+    let mut channels = Vec::new();
+    for input in inputs {
+        let v = input.save();
+        if let Some((expr, chan)) = channel_of(&*v) {
+            values.push((input.id(), expr));
+            channels.push(chan);
+            continue;
+        }
+        match value_of(&*v) {
+            Value::Opaque => return err(format!("variable {} doesn't hold a value that can be sent to another thread", input.id())),
+            v => values.push((input.id(), Expr::Const(v))),
+        }
+    }
+    Ok((values, channels))
+}
+
+// Whether `expr` can run on another thread: its only effects are on
+// channels and atomics.
+fn spawnable(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Const(_) | Expr::Var(_) | Expr::Bound(_) => true,
+        Expr::Node { ref kind, ref children, .. } => {
+            (pure_kind(kind) || matches!(&kind[..], "channel" | "send" | "recv" | "atomic" | "fetch_add" | "cas")) && children.iter().all(spawnable)
+        }
+        Expr::Opaque => false,
+    }
+}
+
+fn start<T: Scalar>(expr: &Expr, inputs: &[Box<Binder>]) -> TaskVal<T> {
+    let (values, channels) = values(inputs).unwrap_or_else(|e| panic!("{}", e));
+    let program = closed(expr, &values);
+    let (done, result) = mpsc::channel();
+    let carried = sandbox::carry();
+    thread::spawn(move || {
+        let run = move || {
+            let run = check(&program).expect("the spawned expression didn't load").stage_eval();
+            drop(channels);
+            run()
+        };
+        let _ = done.send(match carried {
+            Some(carried) => carried.run(run),
+            None => (panic::catch_unwind(AssertUnwindSafe(run)), Spent::default()),
+        });
+    });
+    TaskVal {
+        state: Rc::new(RefCell::new(TaskState::Running(result))),
+    }
+}
+
+#[derive(Clone)]
+pub struct SpawnExp<T: 'static> {
+    exp: Box<Exp<Output=T>>,
+    inputs: Vec<Box<Binder>>,
+}
+
+pub struct SpawnStagedExp<T: 'static> {
+    expr: Expr,
+    // Keeps the channels and atomics `expr` names alive.
+    _exp: Box<Exp<Output=T>>,
+    inputs: Vec<Box<Binder>>,
+}
+
+impl<T: Scalar> Exp for SpawnExp<T> {
+    type Output = TaskVal<T>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged: SpawnStagedExp<T> = SpawnStagedExp {
+            expr: self.exp.reify(),
+            _exp: self.exp.clone_box(),
+            inputs: self.inputs.clone(),
+        };
+        box staged
+    }
+    fn interpret(&self) -> Self::Output {
+        start(&self.exp.reify(), &self.inputs)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let inputs = self.inputs.iter().map(|input| Expr::Var(input.id())).collect();
+        node("spawn", vec![self.exp.reify(), node("inputs", inputs)])
+    }
+}
+
+impl<T: Scalar> StagedExp for SpawnStagedExp<T> {
+    type Output = TaskVal<T>;
+
+    fn run(&self, _ctx: &EvalContext) -> Self::Output {
+        start(&self.expr, &self.inputs)
+    }
+}
+
+#[derive(Clone)]
+pub struct JoinExp<T: 'static> {
+    task: Box<Exp<Output=TaskVal<T>>>,
+}
+
+pub struct JoinStagedExp<T: 'static> {
+    staged_task: Box<StagedExp<Output=TaskVal<T>>>,
+}
+
+fn join<T: Scalar>(task: TaskVal<T>) -> T {
+    let mut state = task.state.borrow_mut();
+    let v = match *state {
+        TaskState::Done(ref v) => return v.clone(),
+        TaskState::Running(ref result) => loop {
+            match result.recv_timeout(sandbox::WAIT) {
+                Ok((result, spent)) => {
+                    sandbox::spend(spent);
+                    match result {
+                        Ok(v) => break v.downcast::<T>().expect("the spawned expression gave a value of another type"),
+                        Err(panic) => panic::resume_unwind(panic),
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => sandbox::wait(),
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            }
+        },
+    };
+    *state = TaskState::Done(v.clone());
+    v
+}
+
+impl<T: Scalar> Exp for JoinExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box JoinStagedExp {
+            staged_task: self.task.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        join(self.task.interpret())
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("join", vec![self.task.reify()])
+    }
+}
+
+impl<T: Scalar> StagedExp for JoinStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        join(self.staged_task.run(ctx))
+    }
+}
+
+// `exp` to run on another thread, reading `inputs`. An error if it can't
+// be: if it has effects or parts that can't be reified, reads another
+// variable, or doesn't load with the values the inputs hold now.
+pub fn spawn_exp<T: Scalar>(exp: Box<Exp<Output=T>>, inputs: Vec<Box<Binder>>) -> Result<SpawnExp<T>, SpawnError> {
+    let expr = exp.reify();
+    if expr.is_opaque() {
+        return err("the expression has parts that can't be sent to another thread".to_string());
+    }
+    if !spawnable(&expr) {
+        return err("the expression has effects other than on channels and atomics".to_string());
+    }
+    if let Some(id) = expr.free_vars().into_iter().find(|&id| !inputs.iter().any(|input| input.id() == id)) {
+        return err(format!("the expression reads variable {}, which isn't an input", id));
+    }
+    let typed = check(&closed(&expr, &values(&inputs)?.0)).or_else(|e| err(e.to_string()))?;
+    if let Err(e) = typed.downcast::<T>() {
+        return err(e.to_string());
+    }
+    Ok(SpawnExp {
+        exp,
+        inputs,
+    })
+}
+
+pub fn join_exp<T: Scalar>(task: Box<Exp<Output=TaskVal<T>>>) -> JoinExp<T> {
+    JoinExp {
+        task,
+    }
+}
+
+impl<T: Scalar> E<T> {
+    pub fn spawn(self, inputs: Vec<Box<Binder>>) -> Result<E<TaskVal<T>>, SpawnError> {
+        Ok(E::new(spawn_exp(self.0, inputs)?))
+    }
+}
+
+impl<T: Scalar> E<TaskVal<T>> {
+    pub fn join(self) -> E<T> {
+        E::new(join_exp(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {NumVal, StrVal, unit_exp};
+    use ops::E;
+    use sandbox::{Sandbox, Resource};
+
+    // Builds a string of 2000 bytes on the task's thread.
+    fn task() -> E<NumVal> {
+        let s = || E::new(unit_exp(StrVal { v: "x".repeat(1000) }));
+        s().concat(s()).len()
+    }
+
+    #[test]
+    fn spawned_task_runs_under_the_sandbox_around_it() {
+        let join = task().spawn(vec![]).unwrap().join();
+        let err = Sandbox::new().memory(1000).interpret(&*join.0).unwrap_err();
+        assert_eq!(err.resource, Resource::Memory);
+        assert_eq!(err.limit, 1000);
+        assert_eq!(Sandbox::new().memory(2000).interpret(&*join.0).unwrap().v, 2000);
+    }
+
+    #[test]
+    fn joined_tasks_are_charged_to_the_sandbox() {
+        let both = task().spawn(vec![]).unwrap().join() + task().spawn(vec![]).unwrap().join();
+        let err = Sandbox::new().memory(3000).interpret(&*both.0).unwrap_err();
+        assert_eq!(err.resource, Resource::Memory);
+        assert_eq!(Sandbox::new().memory(4000).interpret(&*both.0).unwrap().v, 4000);
+    }
+}
@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, SlotScope, Slot, VariableExp, StreamVal, BoolVal};
+use ops::E;
+use reify::{Expr, binder};
+use sandbox;
+
+// Streams: a host hands over an iterator as a StreamVal, and a program maps
+// and filters it lazily, a record at a time, without collecting it into an
+// array first. Mapping or filtering a stream makes another stream, which
+// pulls from the one under it only when something pulls from it, so the
+// host can feed in an unbounded source and take results as it needs them
+// with `next`. Folding a stream pulls everything left in it, and doesn't
+// finish on one without end.
+//
+// Like a lambda's body, a map or filter body runs when its element is
+// pulled, not when the stream is made, and reads the variables bound around
+// it then.
+
+impl<T: 'static> StreamVal<T> {
+    pub fn new<I: Iterator<Item=T> + 'static>(iter: I) -> StreamVal<T> {
+        StreamVal {
+            iter: Rc::new(RefCell::new(box iter)),
+        }
+    }
+
+    // Takes the next element, or None once the stream is used up.
+    pub fn next(&self) -> Option<T> {
+        sandbox::step();
+        self.iter.borrow_mut().next()
+    }
+}
+
+// A stream pulling from `stream` until it's used up.
+fn pull<T: 'static>(stream: StreamVal<T>) -> impl Iterator<Item=T> {
+    std::iter::from_fn(move || stream.next())
+}
+
+#[derive(Clone)]
+pub struct StreamMapExp<T: 'static+Clone+Default, U: 'static+Clone> {
+    stream: Box<Exp<Output=StreamVal<T>>>,
+    f: Rc<Fn(VariableExp<T>) -> Box<Exp<Output=U>>>,
+}
+
+// The body is staged once, and binds its element the way staged binders do
+// each time one is pulled.
+pub struct StreamMapStagedExp<T: 'static+Clone+Default, U: 'static+Clone> {
+    staged_stream: Box<StagedExp<Output=StreamVal<T>>>,
+    elem_slot: Slot<T>,
+    staged_f: Rc<StagedExp<Output=U>>,
+}
+
+impl<T: 'static+Clone+Default, U: 'static+Clone> Exp for StreamMapExp<T, U> {
+    type Output = StreamVal<U>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_stream = self.stream.stage();
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        box StreamMapStagedExp {
+            staged_stream,
+            elem_slot: scope.bind(&elem_var),
+            staged_f: Rc::from((self.f)(elem_var).stage()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let f = self.f.clone();
+        StreamVal::new(pull(self.stream.interpret()).map(move |x| f(VariableExp::fresh_with_val(x)).interpret()))
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let elem_var = VariableExp::fresh();
+        let f = (self.f)(elem_var.clone()).reify();
+        binder("stream_map", vec![elem_var.id], vec![self.stream.reify(), f])
+    }
+}
+
+impl<T: 'static+Clone+Default, U: 'static+Clone> StagedExp for StreamMapStagedExp<T, U> {
+    type Output = StreamVal<U>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let elem_slot = self.elem_slot.clone();
+        let staged_f = self.staged_f.clone();
+        let ctx = ctx.clone();
+        StreamVal::new(pull(self.staged_stream.run(&ctx)).map(move |x| {
+            let mut elem = ctx.bind(&elem_slot);
+            elem.set(x);
+            staged_f.run(&ctx)
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamFilterExp<T: 'static+Clone+Default> {
+    stream: Box<Exp<Output=StreamVal<T>>>,
+    pred: Rc<Fn(VariableExp<T>) -> Box<Exp<Output=BoolVal>>>,
+}
+
+pub struct StreamFilterStagedExp<T: 'static+Clone+Default> {
+    staged_stream: Box<StagedExp<Output=StreamVal<T>>>,
+    elem_slot: Slot<T>,
+    staged_pred: Rc<StagedExp<Output=BoolVal>>,
+}
+
+impl<T: 'static+Clone+Default> Exp for StreamFilterExp<T> {
+    type Output = StreamVal<T>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_stream = self.stream.stage();
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        box StreamFilterStagedExp {
+            staged_stream,
+            elem_slot: scope.bind(&elem_var),
+            staged_pred: Rc::from((self.pred)(elem_var).stage()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let pred = self.pred.clone();
+        StreamVal::new(pull(self.stream.interpret()).filter(move |x| pred(VariableExp::fresh_with_val(x.clone())).interpret().v))
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let elem_var = VariableExp::fresh();
+        let pred = (self.pred)(elem_var.clone()).reify();
+        binder("stream_filter", vec![elem_var.id], vec![self.stream.reify(), pred])
+    }
+}
+
+impl<T: 'static+Clone+Default> StagedExp for StreamFilterStagedExp<T> {
+    type Output = StreamVal<T>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let elem_slot = self.elem_slot.clone();
+        let staged_pred = self.staged_pred.clone();
+        let ctx = ctx.clone();
+        StreamVal::new(pull(self.staged_stream.run(&ctx)).filter(move |x| {
+            let mut elem = ctx.bind(&elem_slot);
+            elem.set(x.clone());
+            staged_pred.run(&ctx).v
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct StreamFoldExp<T: 'static+Clone+Default, A: 'static+Clone> {
+    stream: Box<Exp<Output=StreamVal<T>>>,
+    init: Box<Exp<Output=A>>,
+    f: Rc<Fn(VariableExp<A>, VariableExp<T>) -> Box<Exp<Output=A>>>,
+}
+
+pub struct StreamFoldStagedExp<T: 'static+Clone+Default, A: 'static+Clone> {
+    staged_stream: Box<StagedExp<Output=StreamVal<T>>>,
+    staged_init: Box<StagedExp<Output=A>>,
+    acc_slot: Slot<A>,
+    elem_slot: Slot<T>,
+    staged_f: Box<StagedExp<Output=A>>,
+}
+
+impl<T: 'static+Clone+Default, A: 'static+Clone+Default> Exp for StreamFoldExp<T, A> {
+    type Output = A;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        let staged_stream = self.stream.stage();
+        let staged_init = self.init.stage();
+        let acc_var = VariableExp::fresh();
+        let elem_var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        box StreamFoldStagedExp {
+            staged_stream,
+            staged_init,
+            acc_slot: scope.bind(&acc_var),
+            elem_slot: scope.bind(&elem_var),
+            staged_f: (self.f)(acc_var, elem_var).stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let stream = self.stream.interpret();
+        let mut acc = self.init.interpret();
+        while let Some(x) = stream.next() {
+            acc = (self.f)(VariableExp::fresh_with_val(acc), VariableExp::fresh_with_val(x)).interpret();
+        }
+        acc
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        let acc_var = VariableExp::fresh();
+        let elem_var = VariableExp::fresh();
+        let f = (self.f)(acc_var.clone(), elem_var.clone()).reify();
+        binder("stream_fold", vec![acc_var.id, elem_var.id], vec![self.stream.reify(), self.init.reify(), f])
+    }
+}
+
+impl<T: 'static+Clone+Default, A: 'static+Clone> StagedExp for StreamFoldStagedExp<T, A> {
+    type Output = A;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let stream = self.staged_stream.run(ctx);
+        let mut acc = ctx.bind(&self.acc_slot);
+        let mut elem = ctx.bind(&self.elem_slot);
+        acc.set(self.staged_init.run(ctx));
+        while let Some(x) = stream.next() {
+            elem.set(x);
+            let next = self.staged_f.run(ctx);
+            acc.set(next);
+        }
+        // Read before the bindings put the outer values back.
+        acc.get()
+    }
+}
+
+pub fn stream_map_exp<T: 'static+Clone+Default, U: 'static+Clone>(stream: Box<Exp<Output=StreamVal<T>>>,
+                                                                  f: Box<Fn(VariableExp<T>) -> Box<Exp<Output=U>>>) -> StreamMapExp<T, U> {
+    StreamMapExp {
+        stream,
+        f: Rc::from(f)
+    }
+}
+
+pub fn stream_filter_exp<T: 'static+Clone+Default>(stream: Box<Exp<Output=StreamVal<T>>>,
+                                                   pred: Box<Fn(VariableExp<T>) -> Box<Exp<Output=BoolVal>>>) -> StreamFilterExp<T> {
+    StreamFilterExp {
+        stream,
+        pred: Rc::from(pred)
+    }
+}
+
+pub fn stream_fold_exp<T: 'static+Clone+Default, A: 'static+Clone+Default>(stream: Box<Exp<Output=StreamVal<T>>>,
+                                                                           init: Box<Exp<Output=A>>,
+                                                                           f: Box<Fn(VariableExp<A>, VariableExp<T>) -> Box<Exp<Output=A>>>) -> StreamFoldExp<T, A> {
+    StreamFoldExp {
+        stream,
+        init,
+        f: Rc::from(f)
+    }
+}
+
+impl<T: 'static+Clone+Default> E<StreamVal<T>> {
+    pub fn stream_map<U: 'static+Clone, F>(self, f: F) -> E<StreamVal<U>>
+        where F: Fn(VariableExp<T>) -> E<U> + 'static {
+        E::new(stream_map_exp(self.0, box move |x| f(x).0))
+    }
+
+    pub fn stream_filter<F>(self, pred: F) -> E<StreamVal<T>>
+        where F: Fn(VariableExp<T>) -> E<BoolVal> + 'static {
+        E::new(stream_filter_exp(self.0, box move |x| pred(x).0))
+    }
+
+    pub fn stream_fold<A: 'static+Clone+Default, F>(self, init: E<A>, f: F) -> E<A>
+        where F: Fn(VariableExp<A>, VariableExp<T>) -> E<A> + 'static {
+        E::new(stream_fold_exp(self.0, init.0, box move |acc, x| f(acc, x).0))
+    }
+}
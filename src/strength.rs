@@ -0,0 +1,91 @@
+use Exp;
+use reify::{Expr, Value, node};
+
+// Strength reduction: operations replaced by cheaper ones that give the
+// same value, for the tree walkers and code generators to run.
+//
+//     mul(x, 2), mul(2, x)     =>  add(x, x)
+//     mul(x, 1), mul(1, x)     =>  x
+//     mul(x, 0), mul(0, x)     =>  0
+//     pow(x, 2.0)              =>  mul(x, x)
+//     pow(x, 1.0)              =>  x
+//
+// with x a variable, so reading it twice or not at all has no effect and
+// costs next to nothing. Multiplying by 2.0 or 1.0 is reduced as by 2 or
+// 1, but not by 0.0, as 0 * x isn't 0 for every float. Each
+// replacement gives exactly what it replaces, overflow included: multiplying
+// by a larger power of two isn't made a shift, as mul panics on overflow in
+// debug builds where shl wraps. The crate has no remainder node, so there's
+// no `x % 2^k` to turn into a mask. pow(x, 2.0) and mul(x, x) agree where
+// the platform's pow is correctly rounded, as it is for squares on the
+// usual libms; the equivalence harness runs reduced programs as one of its
+// pipelines, so a platform where they don't shows up there.
+
+fn num(expr: &Expr) -> Option<i64> {
+    match *expr {
+        Expr::Const(Value::Num(v)) => Some(v),
+        _ => None,
+    }
+}
+
+fn float(expr: &Expr) -> Option<f64> {
+    match *expr {
+        Expr::Const(Value::Float(bits)) => Some(f64::from_bits(bits)),
+        _ => None,
+    }
+}
+
+fn var(expr: &Expr) -> bool {
+    matches!(*expr, Expr::Var(_))
+}
+
+// `kind` applied to `a` and `b`, reduced if a rule applies.
+fn reduce_node(kind: &str, a: &Expr, b: &Expr) -> Option<Expr> {
+    match kind {
+        "mul" => {
+            let constant = |k: &Expr| num(k).is_some() || float(k).is_some();
+            let (k, x) = if constant(a) && var(b) {
+                (a, b)
+            } else if var(a) && constant(b) {
+                (b, a)
+            } else {
+                return None;
+            };
+            match (num(k), float(k)) {
+                (Some(0), _) => Some(Expr::Const(Value::Num(0))),
+                (Some(1), _) | (_, Some(1.0)) => Some(x.clone()),
+                (Some(2), _) | (_, Some(2.0)) => Some(node("add", vec![x.clone(), x.clone()])),
+                _ => None,
+            }
+        }
+        "pow" if var(a) => match float(b) {
+            Some(2.0) => Some(node("mul", vec![a.clone(), a.clone()])),
+            Some(1.0) => Some(a.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn reduce(expr: &Expr) -> Expr {
+    match *expr {
+        Expr::Node { ref kind, ref binds, ref children } => {
+            let children: Vec<Expr> = children.iter().map(reduce).collect();
+            if let (true, &[ref a, ref b]) = (binds.is_empty(), &children[..]) {
+                if let Some(reduced) = reduce_node(kind, a, b) {
+                    return reduced;
+                }
+            }
+            Expr::Node {
+                kind: kind.clone(),
+                binds: binds.clone(),
+                children,
+            }
+        }
+        ref e => e.clone(),
+    }
+}
+
+pub fn reduce_exp<T>(exp: &Exp<Output=T>) -> Expr {
+    reduce(&exp.reify())
+}
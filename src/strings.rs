@@ -0,0 +1,612 @@
+use std::cell::Cell;
+use std::fmt::Display;
+
+use {Exp, StagedExp, EvalContext, ConstantExp, StrVal, NumVal, BoolVal, unit_exp};
+use canon::pure;
+use ops::E;
+use reify::{Expr, Value, node};
+use sandbox;
+
+// Evaluates `exp1` first. Both are borrowed rather than copied, but for
+// `exp1` when `exp2` might assign a variable: `exp1`'s value might be that
+// variable, which `exp2` can't assign while it's borrowed.
+fn interpret_both<R>(exp1: &Exp<Output=StrVal>, exp2: &Exp<Output=StrVal>, borrow_first: bool,
+                     f: &Fn(&str, &str) -> R) -> R {
+    let mut result = None;
+    if borrow_first {
+        exp1.interpret_with(&mut |a: &StrVal| {
+            exp2.interpret_with(&mut |b: &StrVal| result = Some(f(&a.v, &b.v)))
+        });
+    } else {
+        let a = exp1.interpret();
+        exp2.interpret_with(&mut |b: &StrVal| result = Some(f(&a.v, &b.v)));
+    }
+    result.unwrap()
+}
+
+fn with_both<R>(ctx: &EvalContext, staged_exp1: &StagedExp<Output=StrVal>, staged_exp2: &StagedExp<Output=StrVal>,
+                borrow_first: bool, f: &Fn(&str, &str) -> R) -> R {
+    let mut result = None;
+    if borrow_first {
+        staged_exp1.run_with(ctx, &mut |a: &StrVal| {
+            staged_exp2.run_with(ctx, &mut |b: &StrVal| result = Some(f(&a.v, &b.v)))
+        });
+    } else {
+        let a = staged_exp1.run(ctx);
+        staged_exp2.run_with(ctx, &mut |b: &StrVal| result = Some(f(&a.v, &b.v)));
+    }
+    result.unwrap()
+}
+
+// Whether the first operand can stay borrowed while `exp2` is evaluated:
+// whether `exp2` assigns nothing. Worked out the first time it's asked and
+// kept in `known`.
+fn borrow_first(exp2: &Exp<Output=StrVal>, known: &Cell<Option<bool>>) -> bool {
+    known.get().unwrap_or_else(|| {
+        let pure = pure(&exp2.reify());
+        known.set(Some(pure));
+        pure
+    })
+}
+
+fn substring(s: &str, start: i64, len: i64) -> String {
+    let v: String = s.chars().skip(start.max(0) as usize).take(len.max(0) as usize).collect();
+    sandbox::alloc(v.len());
+    v
+}
+
+#[derive(Clone)]
+pub struct ConcatExp {
+    exp1: Box<Exp<Output=StrVal>>,
+    exp2: Box<Exp<Output=StrVal>>,
+    borrow_first: Cell<Option<bool>>,
+}
+
+pub struct ConcatStagedExp {
+    staged_exp1: Box<StagedExp<Output=StrVal>>,
+    staged_exp2: Box<StagedExp<Output=StrVal>>,
+    borrow_first: bool,
+}
+
+impl Exp for ConcatExp {
+    type Output = StrVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ConcatStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+            borrow_first: borrow_first(&*self.exp2, &self.borrow_first),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut v = self.exp1.interpret().v;
+        let v2 = self.exp2.interpret().v;
+        sandbox::alloc(v.len() + v2.len());
+        v.push_str(&v2);
+        Self::Output {
+            v
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("concat", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+}
+
+impl StagedExp for ConcatStagedExp {
+    type Output = StrVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        Self::Output {
+            v: with_both(ctx, &*self.staged_exp1, &*self.staged_exp2, self.borrow_first, &|a, b| {
+                sandbox::alloc(a.len() + b.len());
+                let mut v = String::with_capacity(a.len() + b.len());
+                v.push_str(a);
+                v.push_str(b);
+                v
+            })
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StrEqExp {
+    exp1: Box<Exp<Output=StrVal>>,
+    exp2: Box<Exp<Output=StrVal>>,
+    borrow_first: Cell<Option<bool>>,
+}
+
+pub struct StrEqStagedExp {
+    staged_exp1: Box<StagedExp<Output=StrVal>>,
+    staged_exp2: Box<StagedExp<Output=StrVal>>,
+    borrow_first: bool,
+}
+
+impl Exp for StrEqExp {
+    type Output = BoolVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box StrEqStagedExp {
+            staged_exp1: self.exp1.stage(),
+            staged_exp2: self.exp2.stage(),
+            borrow_first: borrow_first(&*self.exp2, &self.borrow_first),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let borrow = borrow_first(&*self.exp2, &self.borrow_first);
+        Self::Output {
+            v: interpret_both(&*self.exp1, &*self.exp2, borrow, &|a, b| a == b)
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("str_eq", vec![self.exp1.reify(), self.exp2.reify()])
+    }
+}
+
+impl StagedExp for StrEqStagedExp {
+    type Output = BoolVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        Self::Output {
+            v: with_both(ctx, &*self.staged_exp1, &*self.staged_exp2, self.borrow_first, &|a, b| a == b)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ContainsExp {
+    haystack: Box<Exp<Output=StrVal>>,
+    needle: Box<Exp<Output=StrVal>>,
+    borrow_first: Cell<Option<bool>>,
+}
+
+pub struct ContainsStagedExp {
+    staged_haystack: Box<StagedExp<Output=StrVal>>,
+    staged_needle: Box<StagedExp<Output=StrVal>>,
+    borrow_first: bool,
+}
+
+impl Exp for ContainsExp {
+    type Output = BoolVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ContainsStagedExp {
+            staged_haystack: self.haystack.stage(),
+            staged_needle: self.needle.stage(),
+            borrow_first: borrow_first(&*self.needle, &self.borrow_first),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let borrow = borrow_first(&*self.needle, &self.borrow_first);
+        Self::Output {
+            v: interpret_both(&*self.haystack, &*self.needle, borrow, &|h, n| h.contains(n))
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("contains", vec![self.haystack.reify(), self.needle.reify()])
+    }
+}
+
+impl StagedExp for ContainsStagedExp {
+    type Output = BoolVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        Self::Output {
+            v: with_both(ctx, &*self.staged_haystack, &*self.staged_needle, self.borrow_first, &|h, n| h.contains(n))
+        }
+    }
+}
+
+// Character (not byte) length.
+#[derive(Clone)]
+pub struct StrLenExp {
+    exp: Box<Exp<Output=StrVal>>,
+}
+
+pub struct StrLenStagedExp {
+    staged_exp: Box<StagedExp<Output=StrVal>>,
+}
+
+impl Exp for StrLenExp {
+    type Output = NumVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box StrLenStagedExp {
+            staged_exp: self.exp.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let mut v = 0;
+        self.exp.interpret_with(&mut |s: &StrVal| v = s.v.chars().count() as i64);
+        Self::Output {
+            v
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("str_len", vec![self.exp.reify()])
+    }
+}
+
+impl StagedExp for StrLenStagedExp {
+    type Output = NumVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut v = 0;
+        self.staged_exp.run_with(ctx, &mut |s: &StrVal| v = s.v.chars().count() as i64);
+        Self::Output {
+            v
+        }
+    }
+}
+
+// `len` characters starting at character `start`, clamped to the string.
+#[derive(Clone)]
+pub struct SubstringExp {
+    exp: Box<Exp<Output=StrVal>>,
+    start: Box<Exp<Output=NumVal>>,
+    len: Box<Exp<Output=NumVal>>,
+}
+
+pub struct SubstringStagedExp {
+    staged_exp: Box<StagedExp<Output=StrVal>>,
+    staged_start: Box<StagedExp<Output=NumVal>>,
+    staged_len: Box<StagedExp<Output=NumVal>>,
+}
+
+impl Exp for SubstringExp {
+    type Output = StrVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box SubstringStagedExp {
+            staged_exp: self.exp.stage(),
+            staged_start: self.start.stage(),
+            staged_len: self.len.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let s = self.exp.interpret();
+        Self::Output {
+            v: substring(&s.v, self.start.interpret().v, self.len.interpret().v)
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("substring", vec![self.exp.reify(), self.start.reify(), self.len.reify()])
+    }
+}
+
+impl StagedExp for SubstringStagedExp {
+    type Output = StrVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        // Not borrowed, since the bounds are evaluated after it.
+        let s = self.staged_exp.run(ctx);
+        Self::Output {
+            v: substring(&s.v, self.staged_start.run(ctx).v, self.staged_len.run(ctx).v)
+        }
+    }
+}
+
+// The text between a template's `{}` holes, one more than there are holes.
+// `{{` and `}}` stand for `{` and `}`; any other brace is an error.
+pub fn parse_template(template: &str) -> Result<Vec<String>, String> {
+    let mut texts = vec![String::new()];
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().cloned()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                texts.last_mut().unwrap().push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                texts.push(String::new());
+            }
+            ('{', _) | ('}', _) => return Err(format!("unmatched {:?} in format template {:?}", c, template)),
+            _ => texts.last_mut().unwrap().push(c),
+        }
+    }
+    Ok(texts)
+}
+
+// A value of any type that can be shown, to fill a hole with.
+trait Piece {
+    fn stage(&self) -> Box<StagedExp<Output=StrVal>>;
+    fn interpret(&self) -> String;
+    fn reify(&self) -> Expr;
+    fn clone_piece(&self) -> Box<Piece>;
+}
+
+struct ShowStagedExp<T: 'static+Clone+Display> {
+    staged_exp: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone+Display> StagedExp for ShowStagedExp<T> {
+    type Output = StrVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let mut v = String::new();
+        self.staged_exp.run_with(ctx, &mut |x: &T| v = x.to_string());
+        Self::Output {
+            v
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Shown<T: 'static+Clone+Display> {
+    exp: Box<Exp<Output=T>>,
+}
+
+impl<T: 'static+Clone+Display> Piece for Shown<T> {
+    fn stage(&self) -> Box<StagedExp<Output=StrVal>> {
+        box ShowStagedExp {
+            staged_exp: self.exp.stage(),
+        }
+    }
+
+    fn interpret(&self) -> String {
+        let mut v = String::new();
+        self.exp.interpret_with(&mut |x: &T| v = x.to_string());
+        v
+    }
+
+    fn reify(&self) -> Expr {
+        self.exp.reify()
+    }
+
+    fn clone_piece(&self) -> Box<Piece> {
+        box self.clone()
+    }
+}
+
+// A template with each `{}` hole filled with the next argument, shown the
+// way `print` shows it. Arguments are evaluated in order.
+pub struct FormatExp {
+    template: String,
+    texts: Vec<String>,
+    args: Vec<Box<Piece>>,
+}
+
+pub struct FormatStagedExp {
+    texts: Vec<String>,
+    staged_args: Vec<Box<StagedExp<Output=StrVal>>>,
+}
+
+impl Clone for FormatExp {
+    fn clone(&self) -> FormatExp {
+        FormatExp {
+            template: self.template.clone(),
+            texts: self.texts.clone(),
+            args: self.args.iter().map(|a| a.clone_piece()).collect(),
+        }
+    }
+}
+
+impl FormatExp {
+    pub fn arg<T: 'static+Clone+Display>(mut self, exp: Box<Exp<Output=T>>) -> FormatExp {
+        assert!(self.args.len() + 1 < self.texts.len(), "more arguments than holes in format template {:?}", self.template);
+        self.args.push(box Shown { exp });
+        self
+    }
+
+    fn check_args(&self) {
+        assert!(self.args.len() + 1 == self.texts.len(), "format template {:?} has {} holes but {} arguments",
+                self.template, self.texts.len() - 1, self.args.len());
+    }
+}
+
+fn fill(texts: &[String], args: Vec<String>) -> String {
+    let len = texts.iter().chain(&args).map(|s| s.len()).sum();
+    sandbox::alloc(len);
+    let mut v = String::with_capacity(len);
+    v.push_str(&texts[0]);
+    for (arg, text) in args.iter().zip(&texts[1..]) {
+        v.push_str(arg);
+        v.push_str(text);
+    }
+    v
+}
+
+impl Exp for FormatExp {
+    type Output = StrVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        self.check_args();
+        box FormatStagedExp {
+            texts: self.texts.clone(),
+            staged_args: self.args.iter().map(|a| a.stage()).collect(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.check_args();
+        Self::Output {
+            v: fill(&self.texts, self.args.iter().map(|a| a.interpret()).collect())
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    // The template, then the arguments.
+    fn reify(&self) -> Expr {
+        let mut children = vec![Expr::Const(Value::Str(self.template.clone()))];
+        children.extend(self.args.iter().map(|a| a.reify()));
+        node("format", children)
+    }
+}
+
+impl StagedExp for FormatStagedExp {
+    type Output = StrVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        Self::Output {
+            v: fill(&self.texts, self.staged_args.iter().map(|a| a.run(ctx).v).collect())
+        }
+    }
+}
+
+pub fn str_exp(v: &str) -> ConstantExp<StrVal> {
+    unit_exp(StrVal { v: v.to_string() })
+}
+
+pub fn concat_exp(exp1: Box<Exp<Output=StrVal>>, exp2: Box<Exp<Output=StrVal>>) -> ConcatExp {
+    ConcatExp {
+        exp1,
+        exp2,
+        borrow_first: Cell::new(None),
+    }
+}
+
+pub fn str_eq_exp(exp1: Box<Exp<Output=StrVal>>, exp2: Box<Exp<Output=StrVal>>) -> StrEqExp {
+    StrEqExp {
+        exp1,
+        exp2,
+        borrow_first: Cell::new(None),
+    }
+}
+
+pub fn contains_exp(haystack: Box<Exp<Output=StrVal>>, needle: Box<Exp<Output=StrVal>>) -> ContainsExp {
+    ContainsExp {
+        haystack,
+        needle,
+        borrow_first: Cell::new(None),
+    }
+}
+
+pub fn str_len_exp(exp: Box<Exp<Output=StrVal>>) -> StrLenExp {
+    StrLenExp {
+        exp
+    }
+}
+
+pub fn substring_exp(exp: Box<Exp<Output=StrVal>>, start: Box<Exp<Output=NumVal>>,
+                     len: Box<Exp<Output=NumVal>>) -> SubstringExp {
+    SubstringExp {
+        exp,
+        start,
+        len
+    }
+}
+
+// Panics if the template has an unmatched brace; add an argument for each
+// hole with `arg`.
+pub fn format_exp(template: &str) -> FormatExp {
+    FormatExp {
+        template: template.to_string(),
+        texts: parse_template(template).unwrap_or_else(|e| panic!("{}", e)),
+        args: vec![],
+    }
+}
+
+impl E<StrVal> {
+    pub fn concat(self, rhs: E<StrVal>) -> E<StrVal> {
+        E::new(concat_exp(self.0, rhs.0))
+    }
+
+    pub fn str_eq(self, rhs: E<StrVal>) -> E<BoolVal> {
+        E::new(str_eq_exp(self.0, rhs.0))
+    }
+
+    pub fn contains(self, needle: E<StrVal>) -> E<BoolVal> {
+        E::new(contains_exp(self.0, needle.0))
+    }
+
+    pub fn len(self) -> E<NumVal> {
+        E::new(str_len_exp(self.0))
+    }
+
+    pub fn substring(self, start: E<NumVal>, len: E<NumVal>) -> E<StrVal> {
+        E::new(substring_exp(self.0, start.0, len.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Exp, StagedExp, EvalContext, VariableExp, StrVal, seq_exp, set_exp};
+    use super::{str_exp, str_eq_exp, contains_exp};
+
+    // A string that can only be lent, to show where an operand is copied.
+    #[derive(Clone)]
+    struct Lent(StrVal);
+
+    impl Exp for Lent {
+        type Output = StrVal;
+
+        fn stage(&self) -> Box<StagedExp<Output=StrVal>> {
+            box self.clone()
+        }
+        fn interpret(&self) -> StrVal {
+            panic!("copied")
+        }
+
+        fn interpret_with(&self, f: &mut FnMut(&StrVal)) {
+            f(&self.0)
+        }
+
+        fn clone_box(&self) -> Box<Exp<Output=StrVal>> {
+            box self.clone()
+        }
+    }
+
+    impl StagedExp for Lent {
+        type Output = StrVal;
+
+        fn run(&self, _ctx: &EvalContext) -> StrVal {
+            panic!("copied")
+        }
+
+        fn run_with(&self, _ctx: &EvalContext, f: &mut FnMut(&StrVal)) {
+            f(&self.0)
+        }
+    }
+
+    fn lent(s: &str) -> Box<Lent> {
+        box Lent(StrVal { v: s.to_string() })
+    }
+
+    #[test]
+    fn both_operands_are_borrowed() {
+        let eq = str_eq_exp(lent("ab"), box str_exp("ab"));
+        assert!(eq.interpret().v);
+        assert!(eq.stage().run(&EvalContext::new()).v);
+        let contains = contains_exp(lent("abc"), box str_exp("b"));
+        assert!(contains.interpret().v);
+        assert!(contains.stage().run(&EvalContext::new()).v);
+    }
+
+    // The second operand assigns the variable the first reads, so the
+    // first is copied rather than kept borrowed.
+    #[test]
+    fn second_operand_may_assign_the_first() {
+        let x = VariableExp::fresh_with_val(StrVal { v: "a".to_string() });
+        let eq = str_eq_exp(box x.clone(), box seq_exp(box set_exp(x.clone(), box str_exp("b")), box x.clone()));
+        assert!(!eq.interpret().v);
+        x.assign(StrVal { v: "a".to_string() });
+        assert!(!eq.stage().run(&EvalContext::new()).v);
+    }
+}
@@ -0,0 +1,90 @@
+use {Exp, VariableExp, fresh_id};
+use reify::Expr;
+
+// Putting one tree in place of a variable in another, for passes that
+// splice trees together, like inlining a let or a function's body, and for
+// rewrites written by hand. Substitution works on Exprs, as rewriting does,
+// so check the result to run it.
+//
+// It's capture-avoiding: a node in `expr` that binds a variable read in
+// the replacement has its variable renamed before the replacement goes
+// under it, so the replacement still reads what it did. A node that binds
+// `var` itself hides it, and nothing under the node is replaced. Each copy
+// of the replacement has its own variables freshened too, so a tree that
+// gets it twice doesn't bind one id in two places, and passes that assume
+// each binder has its own id keep working.
+
+// `expr` with each of its variables renamed to fresh ids.
+pub fn freshen(expr: &Expr) -> Expr {
+    match *expr {
+        Expr::Node { ref kind, ref binds, ref children } if !binds.is_empty() => {
+            let mut children = children.clone();
+            let mut new_binds = Vec::new();
+            for &b in binds {
+                let new = fresh_id();
+                children = children.iter().map(|c| rename(c, b, new)).collect();
+                new_binds.push(new);
+            }
+            Expr::Node {
+                kind: kind.clone(),
+                binds: new_binds,
+                children: children.iter().map(freshen).collect(),
+            }
+        }
+        Expr::Node { ref kind, ref binds, ref children } => Expr::Node {
+            kind: kind.clone(),
+            binds: binds.clone(),
+            children: children.iter().map(freshen).collect(),
+        },
+        ref e => e.clone(),
+    }
+}
+
+// `expr` with `to`, a fresh id, for the variable `from`.
+fn rename(expr: &Expr, from: i32, to: i32) -> Expr {
+    match *expr {
+        Expr::Var(id) if id == from => Expr::Var(to),
+        Expr::Node { ref binds, .. } if binds.contains(&from) => expr.clone(),
+        Expr::Node { ref kind, ref binds, ref children } => Expr::Node {
+            kind: kind.clone(),
+            binds: binds.clone(),
+            children: children.iter().map(|c| rename(c, from, to)).collect(),
+        },
+        ref e => e.clone(),
+    }
+}
+
+fn subst(expr: &Expr, var: i32, replacement: &Expr, free: &[i32]) -> Expr {
+    match *expr {
+        Expr::Var(id) if id == var => freshen(replacement),
+        Expr::Node { ref binds, .. } if binds.contains(&var) => expr.clone(),
+        Expr::Node { ref kind, ref binds, ref children } => {
+            let mut binds = binds.clone();
+            let mut children = children.clone();
+            if children.iter().any(|c| c.free_vars().contains(&var)) {
+                for b in binds.iter_mut().filter(|b| free.contains(b)) {
+                    let new = fresh_id();
+                    children = children.iter().map(|c| rename(c, *b, new)).collect();
+                    *b = new;
+                }
+            }
+            Expr::Node {
+                kind: kind.clone(),
+                binds,
+                children: children.iter().map(|c| subst(c, var, replacement, free)).collect(),
+            }
+        }
+        ref e => e.clone(),
+    }
+}
+
+// `expr` with `replacement` for each read of the variable `var` that isn't
+// bound in `expr`.
+pub fn substitute(expr: &Expr, var: i32, replacement: &Expr) -> Expr {
+    subst(expr, var, replacement, &replacement.free_vars())
+}
+
+// The tree of `exp` with `replacement`'s for each read of `var`.
+pub fn substitute_exp<T, U: 'static+Clone>(exp: &Exp<Output=T>, var: &VariableExp<U>, replacement: &Exp<Output=U>) -> Expr {
+    substitute(&exp.reify(), var.id, &replacement.reify())
+}
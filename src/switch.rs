@@ -0,0 +1,159 @@
+use {Exp, StagedExp, EvalContext, Compiled, NumVal};
+use reify::{Expr, Value, node};
+
+// Runs the case whose constant equals the scrutinee, or the default. Cases
+// are kept sorted, so interpreting one is a binary search rather than a test
+// of each case in turn; staging and compiling build a jump table when the
+// constants are dense enough.
+#[derive(Clone)]
+pub struct SwitchExp<T: 'static+Clone> {
+    scrutinee: Box<Exp<Output=NumVal>>,
+    cases: Vec<(i64, Box<Exp<Output=T>>)>,
+    default: Box<Exp<Output=T>>,
+}
+
+pub struct SwitchStagedExp<T: 'static+Clone> {
+    staged_scrutinee: Box<StagedExp<Output=NumVal>>,
+    table: Table,
+    staged_cases: Vec<Box<StagedExp<Output=T>>>,
+    staged_default: Box<StagedExp<Output=T>>,
+}
+
+// Which case to run for a value, by its index among the cases.
+#[derive(Clone)]
+enum Table {
+    // Indexed by the value less `min`.
+    Dense { min: i64, slots: Vec<Option<usize>> },
+    Sparse(Vec<i64>),
+}
+
+impl Table {
+    // Dense if at least half the slots would hold a case.
+    fn new(keys: &[i64]) -> Table {
+        if let (Some(&min), Some(&max)) = (keys.first(), keys.last()) {
+            let span = (max as i128 - min as i128 + 1) as u128;
+            if span <= 2 * keys.len() as u128 {
+                let mut slots = vec![None; span as usize];
+                for (i, &k) in keys.iter().enumerate() {
+                    slots[(k - min) as usize] = Some(i);
+                }
+                return Table::Dense { min, slots };
+            }
+        }
+        Table::Sparse(keys.to_vec())
+    }
+
+    fn find(&self, v: i64) -> Option<usize> {
+        match *self {
+            Table::Dense { min, ref slots } => {
+                let i = (v as i128 - min as i128) as u128;
+                if i < slots.len() as u128 {
+                    slots[i as usize]
+                } else {
+                    None
+                }
+            }
+            Table::Sparse(ref keys) => keys.binary_search(&v).ok(),
+        }
+    }
+}
+
+impl<T: 'static+Clone> SwitchExp<T> {
+    // Replaces any earlier case for the same constant.
+    pub fn case(mut self, k: i64, exp: Box<Exp<Output=T>>) -> SwitchExp<T> {
+        match self.cases.binary_search_by_key(&k, |c| c.0) {
+            Ok(i) => self.cases[i].1 = exp,
+            Err(i) => self.cases.insert(i, (k, exp)),
+        }
+        self
+    }
+
+    fn keys(&self) -> Vec<i64> {
+        self.cases.iter().map(|c| c.0).collect()
+    }
+
+    fn staged(&self, stage: &Fn(&Exp<Output=T>) -> Box<StagedExp<Output=T>>) -> Box<StagedExp<Output=T>> {
+        box SwitchStagedExp {
+            staged_scrutinee: self.scrutinee.stage(),
+            table: Table::new(&self.keys()),
+            staged_cases: self.cases.iter().map(|c| stage(&*c.1)).collect(),
+            staged_default: stage(&*self.default),
+        }
+    }
+
+    fn find(&self, v: i64) -> &Exp<Output=T> {
+        match self.cases.binary_search_by_key(&v, |c| c.0) {
+            Ok(i) => &*self.cases[i].1,
+            Err(_) => &*self.default,
+        }
+    }
+}
+
+impl<T: 'static+Clone> Exp for SwitchExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        self.staged(&|e| e.stage())
+    }
+
+    fn interpret(&self) -> Self::Output {
+        let v = self.scrutinee.interpret().v;
+        self.find(v).interpret()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    // The scrutinee, then each case's constant and body in order, then the
+    // default.
+    fn reify(&self) -> Expr {
+        let mut children = vec![self.scrutinee.reify()];
+        for &(k, ref exp) in &self.cases {
+            children.push(Expr::Const(Value::Num(k)));
+            children.push(exp.reify());
+        }
+        children.push(self.default.reify());
+        node("switch", children)
+    }
+
+    fn stage_compiled(&self) -> Compiled<Self::Output> {
+        let compiled_scrutinee = self.scrutinee.stage_compiled();
+        let table = Table::new(&self.keys());
+        let compiled_cases: Vec<Compiled<T>> = self.cases.iter().map(|c| c.1.stage_compiled()).collect();
+        let compiled_default = self.default.stage_compiled();
+        box move || match table.find(compiled_scrutinee().v) {
+            Some(i) => compiled_cases[i](),
+            None => compiled_default(),
+        }
+    }
+
+    fn stage_tail(&self, fn_id: i32) -> Box<StagedExp<Output=Self::Output>> {
+        self.staged(&|e| e.stage_tail(fn_id))
+    }
+
+    fn interpret_tail(&self, fn_id: i32) -> Self::Output {
+        let v = self.scrutinee.interpret().v;
+        self.find(v).interpret_tail(fn_id)
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for SwitchStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        match self.table.find(self.staged_scrutinee.run(ctx).v) {
+            Some(i) => self.staged_cases[i].run(ctx),
+            None => self.staged_default.run(ctx),
+        }
+    }
+}
+
+// A switch with only the default; add cases with `case`.
+pub fn switch_exp<T: 'static+Clone>(scrutinee: Box<Exp<Output=NumVal>>, default: Box<Exp<Output=T>>) -> SwitchExp<T> {
+    SwitchExp {
+        scrutinee,
+        cases: vec![],
+        default,
+    }
+}
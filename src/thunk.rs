@@ -0,0 +1,161 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use {Exp, StagedExp, EvalContext, ThunkVal, Thunk};
+use ops::E;
+use reify::{Expr, node};
+
+// Call-by-need: `delay` makes a thunk of its body without evaluating it,
+// and `force` evaluates a thunk's body the first time and gives back the
+// same value every time after, so an expensive part is only paid for when
+// something needs it, and then once. Like a lambda's body, a thunk's body
+// reads the variables bound around it when it's forced, not when it's
+// made.
+//
+// A thunk whose body forces the thunk itself can't finish, and forcing it
+// panics. If forcing is cut short, by a panic or a sandbox running out, the
+// thunk is left as it was, to be computed again by the next force.
+
+impl<T: 'static+Clone> ThunkVal<T> {
+    fn pending(f: Rc<Fn() -> T>) -> ThunkVal<T> {
+        ThunkVal {
+            state: Rc::new(RefCell::new(Thunk::Pending(f))),
+        }
+    }
+
+    pub fn force(&self) -> T {
+        let f = match *self.state.borrow() {
+            Thunk::Done(ref v) => return v.clone(),
+            Thunk::Forcing(_) => panic!("thunk forced while it's being forced"),
+            Thunk::Pending(ref f) => f.clone(),
+        };
+        // Puts the thunk back as it was if the body doesn't finish.
+        struct Unforced<'a, T: 'static>(&'a RefCell<Thunk<T>>);
+
+        impl<'a, T: 'static> Drop for Unforced<'a, T> {
+            fn drop(&mut self) {
+                let mut state = self.0.borrow_mut();
+                let f = match *state {
+                    Thunk::Forcing(ref f) => f.clone(),
+                    _ => return,
+                };
+                *state = Thunk::Pending(f);
+            }
+        }
+
+        *self.state.borrow_mut() = Thunk::Forcing(f.clone());
+        let unforced = Unforced(&self.state);
+        let v = f();
+        *self.state.borrow_mut() = Thunk::Done(v.clone());
+        drop(unforced);
+        v
+    }
+
+    pub fn is_forced(&self) -> bool {
+        matches!(*self.state.borrow(), Thunk::Done(_))
+    }
+}
+
+#[derive(Clone)]
+pub struct DelayExp<T: 'static+Clone> {
+    body: Box<Exp<Output=T>>,
+}
+
+// The body is staged once; each run makes a new thunk of it.
+pub struct DelayStagedExp<T: 'static+Clone> {
+    staged_body: Rc<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone> Exp for DelayExp<T> {
+    type Output = ThunkVal<T>;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box DelayStagedExp {
+            staged_body: Rc::from(self.body.stage()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let body = self.body.clone();
+        ThunkVal::pending(Rc::new(move || body.interpret()))
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("delay", vec![self.body.reify()])
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for DelayStagedExp<T> {
+    type Output = ThunkVal<T>;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let staged_body = self.staged_body.clone();
+        let ctx = ctx.clone();
+        ThunkVal::pending(Rc::new(move || staged_body.run(&ctx)))
+    }
+}
+
+#[derive(Clone)]
+pub struct ForceExp<T: 'static+Clone> {
+    thunk: Box<Exp<Output=ThunkVal<T>>>,
+}
+
+pub struct ForceStagedExp<T: 'static+Clone> {
+    staged_thunk: Box<StagedExp<Output=ThunkVal<T>>>,
+}
+
+impl<T: 'static+Clone> Exp for ForceExp<T> {
+    type Output = T;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ForceStagedExp {
+            staged_thunk: self.thunk.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        self.thunk.interpret().force()
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("force", vec![self.thunk.reify()])
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for ForceStagedExp<T> {
+    type Output = T;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        self.staged_thunk.run(ctx).force()
+    }
+}
+
+pub fn delay_exp<T: 'static+Clone>(body: Box<Exp<Output=T>>) -> DelayExp<T> {
+    DelayExp {
+        body
+    }
+}
+
+pub fn force_exp<T: 'static+Clone>(thunk: Box<Exp<Output=ThunkVal<T>>>) -> ForceExp<T> {
+    ForceExp {
+        thunk
+    }
+}
+
+impl<T: 'static+Clone> E<T> {
+    pub fn delay(self) -> E<ThunkVal<T>> {
+        E::new(delay_exp(self.0))
+    }
+}
+
+impl<T: 'static+Clone> E<ThunkVal<T>> {
+    pub fn force(self) -> E<T> {
+        E::new(force_exp(self.0))
+    }
+}
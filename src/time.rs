@@ -0,0 +1,238 @@
+use {Exp, StagedExp, EvalContext, NumVal, DurationVal, InstantVal};
+use effects::now_exp;
+use ops::E;
+use reify::{Expr, node};
+
+// Durations add, subtract and compare with the generic nodes; these convert
+// between durations and numbers and do the arithmetic that involves
+// instants. Read the time with `effects::now_exp`.
+
+// The time from `earlier` to `later`, negative if `later` is the earlier.
+#[derive(Clone)]
+pub struct SinceExp {
+    later: Box<Exp<Output=InstantVal>>,
+    earlier: Box<Exp<Output=InstantVal>>,
+}
+
+pub struct SinceStagedExp {
+    staged_later: Box<StagedExp<Output=InstantVal>>,
+    staged_earlier: Box<StagedExp<Output=InstantVal>>,
+}
+
+impl Exp for SinceExp {
+    type Output = DurationVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box SinceStagedExp {
+            staged_later: self.later.stage(),
+            staged_earlier: self.earlier.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let later = self.later.interpret();
+        Self::Output {
+            nanos: later.nanos - self.earlier.interpret().nanos
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("since", vec![self.later.reify(), self.earlier.reify()])
+    }
+}
+
+impl StagedExp for SinceStagedExp {
+    type Output = DurationVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let later = self.staged_later.run(ctx);
+        Self::Output {
+            nanos: later.nanos - self.staged_earlier.run(ctx).nanos
+        }
+    }
+}
+
+// The instant `duration` after `instant`.
+#[derive(Clone)]
+pub struct AfterExp {
+    instant: Box<Exp<Output=InstantVal>>,
+    duration: Box<Exp<Output=DurationVal>>,
+}
+
+pub struct AfterStagedExp {
+    staged_instant: Box<StagedExp<Output=InstantVal>>,
+    staged_duration: Box<StagedExp<Output=DurationVal>>,
+}
+
+impl Exp for AfterExp {
+    type Output = InstantVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box AfterStagedExp {
+            staged_instant: self.instant.stage(),
+            staged_duration: self.duration.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let instant = self.instant.interpret();
+        Self::Output {
+            nanos: instant.nanos + self.duration.interpret().nanos
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("after", vec![self.instant.reify(), self.duration.reify()])
+    }
+}
+
+impl StagedExp for AfterStagedExp {
+    type Output = InstantVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let instant = self.staged_instant.run(ctx);
+        Self::Output {
+            nanos: instant.nanos + self.staged_duration.run(ctx).nanos
+        }
+    }
+}
+
+// A number of milliseconds as a duration.
+#[derive(Clone)]
+pub struct MillisExp {
+    exp: Box<Exp<Output=NumVal>>,
+}
+
+pub struct MillisStagedExp {
+    staged_exp: Box<StagedExp<Output=NumVal>>,
+}
+
+impl Exp for MillisExp {
+    type Output = DurationVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box MillisStagedExp {
+            staged_exp: self.exp.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        DurationVal::from_millis(self.exp.interpret().v)
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("millis", vec![self.exp.reify()])
+    }
+}
+
+impl StagedExp for MillisStagedExp {
+    type Output = DurationVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        DurationVal::from_millis(self.staged_exp.run(ctx).v)
+    }
+}
+
+// Whole milliseconds in a duration, rounded toward zero.
+#[derive(Clone)]
+pub struct ToMillisExp {
+    exp: Box<Exp<Output=DurationVal>>,
+}
+
+pub struct ToMillisStagedExp {
+    staged_exp: Box<StagedExp<Output=DurationVal>>,
+}
+
+impl Exp for ToMillisExp {
+    type Output = NumVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box ToMillisStagedExp {
+            staged_exp: self.exp.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        Self::Output {
+            v: self.exp.interpret().nanos / 1_000_000
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("to_millis", vec![self.exp.reify()])
+    }
+}
+
+impl StagedExp for ToMillisStagedExp {
+    type Output = NumVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        Self::Output {
+            v: self.staged_exp.run(ctx).nanos / 1_000_000
+        }
+    }
+}
+
+pub fn since_exp(later: Box<Exp<Output=InstantVal>>, earlier: Box<Exp<Output=InstantVal>>) -> SinceExp {
+    SinceExp {
+        later,
+        earlier
+    }
+}
+
+pub fn after_exp(instant: Box<Exp<Output=InstantVal>>, duration: Box<Exp<Output=DurationVal>>) -> AfterExp {
+    AfterExp {
+        instant,
+        duration
+    }
+}
+
+pub fn millis_exp(exp: Box<Exp<Output=NumVal>>) -> MillisExp {
+    MillisExp {
+        exp
+    }
+}
+
+pub fn to_millis_exp(exp: Box<Exp<Output=DurationVal>>) -> ToMillisExp {
+    ToMillisExp {
+        exp
+    }
+}
+
+pub fn now() -> E<InstantVal> {
+    E::new(now_exp())
+}
+
+impl E<InstantVal> {
+    pub fn since(self, earlier: E<InstantVal>) -> E<DurationVal> {
+        E::new(since_exp(self.0, earlier.0))
+    }
+
+    pub fn after(self, duration: E<DurationVal>) -> E<InstantVal> {
+        E::new(after_exp(self.0, duration.0))
+    }
+}
+
+impl E<NumVal> {
+    pub fn millis(self) -> E<DurationVal> {
+        E::new(millis_exp(self.0))
+    }
+}
+
+impl E<DurationVal> {
+    pub fn to_millis(self) -> E<NumVal> {
+        E::new(to_millis_exp(self.0))
+    }
+}
@@ -0,0 +1,315 @@
+use std::any::Any;
+use std::fmt;
+use std::rc::Rc;
+
+use {Exp, StagedExp, EvalContext, SlotScope, Slot, VariableExp, VariantVal};
+use ops::E;
+use records::fmt_any;
+use reify::{Expr, Value, node, binder};
+
+impl VariantVal {
+    pub fn new<T: 'static>(tag: &str, v: T) -> VariantVal {
+        VariantVal {
+            tag: Rc::from(tag),
+            v: Rc::new(v),
+        }
+    }
+
+    pub fn payload<T: 'static+Clone>(&self) -> Option<T> {
+        self.v.downcast_ref::<T>().cloned()
+    }
+}
+
+impl fmt::Display for VariantVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}(", self.tag)?;
+        fmt_any(f, &*self.v)?;
+        write!(f, ")")
+    }
+}
+
+impl fmt::Debug for VariantVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VariantVal({})", self)
+    }
+}
+
+#[derive(Clone)]
+pub struct VariantExp<T: 'static+Clone> {
+    tag: Rc<str>,
+    payload: Box<Exp<Output=T>>,
+}
+
+pub struct VariantStagedExp<T: 'static+Clone> {
+    tag: Rc<str>,
+    staged_payload: Box<StagedExp<Output=T>>,
+}
+
+impl<T: 'static+Clone> Exp for VariantExp<T> {
+    type Output = VariantVal;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box VariantStagedExp {
+            tag: self.tag.clone(),
+            staged_payload: self.payload.stage(),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        VariantVal {
+            tag: self.tag.clone(),
+            v: Rc::new(self.payload.interpret()),
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    fn reify(&self) -> Expr {
+        node("variant", vec![Expr::Const(Value::Str(self.tag.to_string())), self.payload.reify()])
+    }
+}
+
+impl<T: 'static+Clone> StagedExp for VariantStagedExp<T> {
+    type Output = VariantVal;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        VariantVal {
+            tag: self.tag.clone(),
+            v: Rc::new(self.staged_payload.run(ctx)),
+        }
+    }
+}
+
+// One arm of a match, with the type of its payload erased so arms for
+// different cases can be held together.
+pub trait MatchArm<R> {
+    fn interpret_arm(&self, payload: &Any) -> R;
+    fn stage_arm(&self) -> Box<StagedMatchArm<R>>;
+    // The variable the payload is bound to, and the body.
+    fn reify_arm(&self) -> (i32, Expr);
+    fn clone_arm(&self) -> Box<MatchArm<R>>;
+}
+
+pub trait StagedMatchArm<R> {
+    fn run_arm(&self, ctx: &EvalContext, payload: &Any) -> R;
+}
+
+impl<R: 'static> Clone for Box<MatchArm<R>> {
+    fn clone(&self) -> Self {
+        self.clone_arm()
+    }
+}
+
+fn payload<T: 'static+Clone>(payload: &Any) -> T {
+    match payload.downcast_ref::<T>() {
+        Some(v) => v.clone(),
+        None => panic!("variant payload is not of the type its match arm expects"),
+    }
+}
+
+// The body is built from a function of the payload's variable, as for
+// LetExp, and interpreted with a fresh variable each time.
+pub struct FnArm<T: 'static+Clone, R: 'static+Clone> {
+    body: Rc<Fn(VariableExp<T>) -> Box<Exp<Output=R>>>,
+}
+
+// The body is already built against `var`, as by the checker.
+pub struct BoundArm<T: 'static+Clone, R: 'static+Clone> {
+    var: VariableExp<T>,
+    body: Box<Exp<Output=R>>,
+}
+
+pub struct StagedArm<T: 'static+Clone, R: 'static+Clone> {
+    slot: Slot<T>,
+    staged_body: Box<StagedExp<Output=R>>,
+}
+
+impl<T: 'static+Clone+Default, R: 'static+Clone> MatchArm<R> for FnArm<T, R> {
+    fn interpret_arm(&self, v: &Any) -> R {
+        (self.body)(VariableExp::fresh_with_val(payload(v))).interpret()
+    }
+
+    fn stage_arm(&self) -> Box<StagedMatchArm<R>> {
+        let var = VariableExp::fresh();
+        let scope = SlotScope::enter();
+        box StagedArm {
+            slot: scope.bind(&var),
+            staged_body: (self.body)(var).stage(),
+        }
+    }
+
+    fn reify_arm(&self) -> (i32, Expr) {
+        let var = VariableExp::fresh();
+        (var.id, (self.body)(var.clone()).reify())
+    }
+
+    fn clone_arm(&self) -> Box<MatchArm<R>> {
+        box FnArm {
+            body: self.body.clone(),
+        }
+    }
+}
+
+impl<T: 'static+Clone, R: 'static+Clone> MatchArm<R> for BoundArm<T, R> {
+    fn interpret_arm(&self, v: &Any) -> R {
+        self.var.assign(payload(v));
+        self.body.interpret()
+    }
+
+    fn stage_arm(&self) -> Box<StagedMatchArm<R>> {
+        let scope = SlotScope::enter();
+        box StagedArm {
+            slot: scope.bind(&self.var),
+            staged_body: self.body.stage(),
+        }
+    }
+
+    fn reify_arm(&self) -> (i32, Expr) {
+        (self.var.id, self.body.reify())
+    }
+
+    fn clone_arm(&self) -> Box<MatchArm<R>> {
+        box BoundArm {
+            var: self.var.clone(),
+            body: self.body.clone(),
+        }
+    }
+}
+
+impl<T: 'static+Clone, R: 'static+Clone> StagedMatchArm<R> for StagedArm<T, R> {
+    fn run_arm(&self, ctx: &EvalContext, v: &Any) -> R {
+        let mut binding = ctx.bind(&self.slot);
+        binding.set(payload(v));
+        self.staged_body.run(ctx)
+    }
+}
+
+// Runs the arm for the variant's tag with its payload bound to the arm's
+// variable, or `otherwise` if no arm has the tag. A variant with neither
+// panics.
+#[derive(Clone)]
+pub struct MatchExp<R: 'static+Clone> {
+    scrutinee: Box<Exp<Output=VariantVal>>,
+    arms: Vec<(Rc<str>, Box<MatchArm<R>>)>,
+    otherwise: Option<Box<Exp<Output=R>>>,
+}
+
+pub struct MatchStagedExp<R: 'static+Clone> {
+    staged_scrutinee: Box<StagedExp<Output=VariantVal>>,
+    staged_arms: Vec<(Rc<str>, Box<StagedMatchArm<R>>)>,
+    staged_otherwise: Option<Box<StagedExp<Output=R>>>,
+}
+
+fn no_arm(tag: &str) -> ! {
+    panic!("no match arm for variant {:?}", tag)
+}
+
+impl<R: 'static+Clone> MatchExp<R> {
+    pub fn arm<T: 'static+Clone+Default>(self, tag: &str, body: Box<Fn(VariableExp<T>) -> Box<Exp<Output=R>>>) -> MatchExp<R> {
+        self.add_arm(tag, box FnArm {
+            body: Rc::from(body),
+        })
+    }
+
+    pub fn bound_arm<T: 'static+Clone>(self, tag: &str, var: VariableExp<T>, body: Box<Exp<Output=R>>) -> MatchExp<R> {
+        self.add_arm(tag, box BoundArm {
+            var,
+            body,
+        })
+    }
+
+    // Replaces any earlier arm for the same tag.
+    fn add_arm(mut self, tag: &str, arm: Box<MatchArm<R>>) -> MatchExp<R> {
+        match self.arms.iter().position(|a| &*a.0 == tag) {
+            Some(i) => self.arms[i].1 = arm,
+            None => self.arms.push((Rc::from(tag), arm)),
+        }
+        self
+    }
+
+    pub fn otherwise(mut self, exp: Box<Exp<Output=R>>) -> MatchExp<R> {
+        self.otherwise = Some(exp);
+        self
+    }
+}
+
+impl<R: 'static+Clone> Exp for MatchExp<R> {
+    type Output = R;
+
+    fn stage(&self) -> Box<StagedExp<Output=Self::Output>> {
+        box MatchStagedExp {
+            staged_scrutinee: self.scrutinee.stage(),
+            staged_arms: self.arms.iter().map(|a| (a.0.clone(), a.1.stage_arm())).collect(),
+            staged_otherwise: self.otherwise.as_ref().map(|e| e.stage()),
+        }
+    }
+    fn interpret(&self) -> Self::Output {
+        let v = self.scrutinee.interpret();
+        match self.arms.iter().find(|a| a.0 == v.tag) {
+            Some(arm) => arm.1.interpret_arm(&*v.v),
+            None => match self.otherwise {
+                Some(ref e) => e.interpret(),
+                None => no_arm(&v.tag),
+            },
+        }
+    }
+
+    fn clone_box(&self) -> Box<Exp<Output=Self::Output>> {
+        box self.clone()
+    }
+
+    // Binds one variable per arm. The children are the scrutinee, then each
+    // arm's tag and body, then the otherwise branch if there is one.
+    fn reify(&self) -> Expr {
+        let mut binds = Vec::new();
+        let mut children = vec![self.scrutinee.reify()];
+        for &(ref tag, ref arm) in &self.arms {
+            let (var, body) = arm.reify_arm();
+            binds.push(var);
+            children.push(Expr::Const(Value::Str(tag.to_string())));
+            children.push(body);
+        }
+        if let Some(ref e) = self.otherwise {
+            children.push(e.reify());
+        }
+        binder("match", binds, children)
+    }
+}
+
+impl<R: 'static+Clone> StagedExp for MatchStagedExp<R> {
+    type Output = R;
+
+    fn run(&self, ctx: &EvalContext) -> Self::Output {
+        let v = self.staged_scrutinee.run(ctx);
+        match self.staged_arms.iter().find(|a| a.0 == v.tag) {
+            Some(arm) => arm.1.run_arm(ctx, &*v.v),
+            None => match self.staged_otherwise {
+                Some(ref e) => e.run(ctx),
+                None => no_arm(&v.tag),
+            },
+        }
+    }
+}
+
+pub fn variant_exp<T: 'static+Clone>(tag: &str, payload: Box<Exp<Output=T>>) -> VariantExp<T> {
+    VariantExp {
+        tag: Rc::from(tag),
+        payload
+    }
+}
+
+// A match with no arms yet; add them with `arm` and `otherwise`.
+pub fn match_exp<R: 'static+Clone>(scrutinee: Box<Exp<Output=VariantVal>>) -> MatchExp<R> {
+    MatchExp {
+        scrutinee,
+        arms: vec![],
+        otherwise: None,
+    }
+}
+
+impl<T: 'static+Clone> E<T> {
+    pub fn tagged(self, tag: &str) -> E<VariantVal> {
+        E::new(variant_exp(tag, self.0))
+    }
+}
@@ -0,0 +1,150 @@
+use wasm_bindgen::prelude::*;
+
+use {NumVal, BoolVal, UnitVal, StrVal, FloatVal};
+use check::{Ty, Typed, check_open};
+use dynamic::DynVal;
+use json::{Json, float_json, from_json, parse_json};
+use patterns::Binder;
+
+// JavaScript bindings through wasm-bindgen, for running programs in the
+// browser. Programs come in as the JSON `json` reads and their values go out
+// as JSON text, so nothing is lost to JavaScript's numbers:
+//
+//     const p = new Program('{"node": "add", "args": [{"var": 1}, {"num": 1}]}', '{"1": "num"}');
+//     p.set(1, 41);
+//     JSON.parse(p.run());    // 42
+//
+// Records come out as objects and variants as {"tag": ..., "value": ...}.
+// Loading and checking errors are thrown as strings. wasm32 builds abort on
+// panic, so a run that fails traps instead, leaving the module unusable, and
+// sandbox budgets, which unwind, can't stop a run; a playground should run
+// programs in a worker it can terminate and restart.
+//
+// The crate builds as a binary, so for now the module comes from a build of
+// it as a library, e.g. with wasm-pack.
+
+type Eval = Box<Fn() -> DynVal>;
+
+fn ty_named(name: &str) -> Result<Ty, JsValue> {
+    match name {
+        "num" => Ok(Ty::Num),
+        "bool" => Ok(Ty::Bool),
+        "unit" => Ok(Ty::Unit),
+        "str" => Ok(Ty::Str),
+        "float" => Ok(Ty::Float),
+        _ => Err(JsValue::from_str(&format!("inputs can't be {}", name))),
+    }
+}
+
+fn value_json(v: DynVal) -> Json {
+    match v {
+        DynVal::Num(n) => Json::Int(n),
+        DynVal::Bool(b) => Json::Bool(b),
+        DynVal::Unit => Json::Null,
+        DynVal::Str(s) => Json::Str(s),
+        DynVal::Float(x) => float_json(x),
+        DynVal::Record(fields) => Json::Object(fields.into_iter().map(|(name, v)| (name, value_json(v))).collect()),
+        DynVal::Variant(tag, v) => Json::Object(vec![
+            ("tag".to_string(), Json::Str(tag)),
+            ("value".to_string(), value_json(*v)),
+        ]),
+    }
+}
+
+// The type of the program in `src`, as `Ty` prints it, or the reason it
+// doesn't load or check; for a playground to show as the program's edited.
+#[wasm_bindgen]
+pub fn typecheck(src: &str) -> Result<String, JsValue> {
+    Program::new(src, None).map(|p| p.ty())
+}
+
+#[wasm_bindgen]
+pub struct Program {
+    typed: Typed,
+    inputs: Vec<(i32, Ty, Box<Binder>)>,
+    staged: Option<Eval>,
+    compiled: Option<Eval>,
+}
+
+#[wasm_bindgen]
+impl Program {
+    // `inputs`, if given, is a JSON object from the ids of the program's free
+    // variables to their types: "num", "bool", "unit", "str" or "float".
+    // Inputs start as their type's default.
+    #[wasm_bindgen(constructor)]
+    pub fn new(src: &str, inputs: Option<String>) -> Result<Program, JsValue> {
+        let error = |e: &ToString| JsValue::from_str(&e.to_string());
+        let expr = from_json(src).map_err(|e| error(&e))?;
+        let mut declared = Vec::new();
+        if let Some(inputs) = inputs {
+            match parse_json(&inputs).map_err(|e| error(&e))? {
+                Json::Object(fields) => for (id, ty) in fields {
+                    let id = id.parse().map_err(|_| JsValue::from_str(&format!("{:?} isn't a variable id", id)))?;
+                    match ty {
+                        Json::Str(ref name) => declared.push((id, ty_named(name)?)),
+                        _ => return Err(JsValue::from_str("input types must be strings")),
+                    }
+                },
+                _ => return Err(JsValue::from_str("inputs must be an object")),
+            }
+        }
+        let (typed, binders) = check_open(&expr, &declared).map_err(|e| error(&e))?;
+        Ok(Program {
+            typed,
+            inputs: declared.into_iter().zip(binders).map(|((id, ty), b)| (id, ty, b)).collect(),
+            staged: None,
+            compiled: None,
+        })
+    }
+
+    #[wasm_bindgen(js_name = type)]
+    pub fn ty(&self) -> String {
+        self.typed.ty().to_string()
+    }
+
+    // Nums must be whole numbers JavaScript holds exactly.
+    pub fn set(&self, id: i32, value: JsValue) -> Result<(), JsValue> {
+        let &(_, ref ty, ref binder) = self.inputs.iter().find(|input| input.0 == id)
+            .ok_or_else(|| JsValue::from_str(&format!("the program has no input {}", id)))?;
+        let wrong = || JsValue::from_str(&format!("input {} must be {}", id, ty));
+        match *ty {
+            Ty::Num => match value.as_f64() {
+                Some(x) if x.fract() == 0.0 && x.abs() <= 9_007_199_254_740_991.0 => binder.bind(&NumVal { v: x as i64 }),
+                _ => return Err(wrong()),
+            },
+            Ty::Bool => binder.bind(&BoolVal { v: value.as_bool().ok_or_else(wrong)? }),
+            Ty::Unit => binder.bind(&UnitVal),
+            Ty::Str => binder.bind(&StrVal { v: value.as_string().ok_or_else(wrong)? }),
+            Ty::Float => binder.bind(&FloatVal { v: value.as_f64().ok_or_else(wrong)? }),
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    pub fn interpret(&self) -> String {
+        value_json(self.typed.eval_interpreted()).to_string()
+    }
+
+    // Runs the staged form, staging the program on the first run.
+    pub fn run(&mut self) -> String {
+        if self.staged.is_none() {
+            self.staged = Some(self.typed.stage_eval());
+        }
+        value_json((self.staged.as_ref().unwrap())()).to_string()
+    }
+
+    // Runs the form compiled to closures, compiling on the first run.
+    #[wasm_bindgen(js_name = runCompiled)]
+    pub fn run_compiled(&mut self) -> String {
+        if self.compiled.is_none() {
+            self.compiled = Some(self.typed.compile_eval());
+        }
+        value_json((self.compiled.as_ref().unwrap())()).to_string()
+    }
+
+    // The program as alpha-normalized JSON, e.g. to share a link to it.
+    #[wasm_bindgen(js_name = toJson)]
+    pub fn to_json(&self) -> String {
+        self.typed.reify().alpha_normalized().to_json().to_string()
+    }
+}
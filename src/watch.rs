@@ -0,0 +1,94 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use VariableExp;
+
+// Callbacks on writes to particular variables, for showing a program's state
+// as it changes. A watch fires on every write the variable takes on this
+// thread, with what it held and what it holds now: set, and let, loops,
+// match arms and patterns binding it, staged binders putting back an outer
+// value, and journal or snapshot restores. Writes to the copy a staged run
+// keeps in its EvalContext fire too. Writing a value equal to the old one
+// still fires.
+//
+// Variables are watched by id, so a watch sees writes through any clone of
+// the variable. A callback runs in the middle of the node doing the write; it
+// may read and write variables, but one writing the variable it watches
+// fires itself again.
+
+pub struct WatchId {
+    var: i32,
+    n: usize,
+}
+
+type Callback = Rc<Fn(&Any, &Any)>;
+
+thread_local! {
+    static WATCHES: RefCell<HashMap<i32, Vec<(usize, Callback)>>> = RefCell::new(HashMap::new());
+    static NEXT: Cell<usize> = const { Cell::new(0) };
+}
+
+// Watches on any thread, so writes skip the thread-local when there are
+// none.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+// Calls `f` with the old and new values each time `var` is written on this
+// thread, until `unwatch`.
+pub fn watch<T: 'static+Clone, F: Fn(&T, &T) + 'static>(var: &VariableExp<T>, f: F) -> WatchId {
+    let n = NEXT.with(|next| {
+        let n = next.get();
+        next.set(n + 1);
+        n
+    });
+    let callback: Callback = Rc::new(move |old: &Any, new: &Any| {
+        f(old.downcast_ref::<T>().unwrap(), new.downcast_ref::<T>().unwrap())
+    });
+    WATCHES.with(|w| w.borrow_mut().entry(var.id).or_default().push((n, callback)));
+    ACTIVE.fetch_add(1, Ordering::Relaxed);
+    WatchId {
+        var: var.id,
+        n,
+    }
+}
+
+// Stops a watch; true if it was still watching.
+pub fn unwatch(watch: WatchId) -> bool {
+    let removed = WATCHES.with(|w| {
+        let mut w = w.borrow_mut();
+        let (removed, empty) = match w.get_mut(&watch.var) {
+            Some(callbacks) => {
+                let before = callbacks.len();
+                callbacks.retain(|c| c.0 != watch.n);
+                (callbacks.len() < before, callbacks.is_empty())
+            }
+            None => (false, false),
+        };
+        if empty {
+            w.remove(&watch.var);
+        }
+        removed
+    });
+    if removed {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+    }
+    removed
+}
+
+pub fn watching() -> bool {
+    ACTIVE.load(Ordering::Relaxed) != 0
+}
+
+// Called by `VariableExp::assign`, and by EvalContext for the variables a
+// staged run binds, once it has written `new` over `old`.
+pub fn notify<T: 'static+Clone>(var: &VariableExp<T>, old: &T, new: &T) {
+    // Copied out, so callbacks can watch and unwatch.
+    let callbacks: Vec<Callback> = WATCHES.with(|w| {
+        w.borrow().get(&var.id).map_or(vec![], |c| c.iter().map(|c| c.1.clone()).collect())
+    });
+    for callback in callbacks {
+        callback(old, new);
+    }
+}